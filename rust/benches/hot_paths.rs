@@ -0,0 +1,914 @@
+#![allow(non_snake_case)]
+
+/*
+  Benchmarks of the hot paths, timed with std::time::Instant.
+
+  Run them with :
+
+    cargo bench --bench hot_paths
+
+  Each benchmark gets run ROUNDS times, after a warm up round, and the fastest and the median round
+  get reported. Compare the medians before and after a change, on the same machine.
+
+  The median time per operation of each timed benchmark also gets checked against the one recorded
+  in hot_paths.baseline, and a run fails once one takes more than the allowed multiple of its
+  baseline. The multiples leave room for slower machines : they catch a hot path getting
+  accidentally quadratic, or taking a lock per byte, not a few percent. To record new baselines
+  (after an intended change, or on the machine the numbers get compared on), run :
+
+    HOT_PATHS_RECORD_BASELINE=1 cargo bench --bench hot_paths
+
+  Whatever the benchmarks send and receive is seeded, so every run does the same work. Only the ISNs
+  differ between runs, which no hot path depends on.
+
+  The memory benchmarks count allocations instead, through the CountingAllocator below. They don't
+  depend on the machine, so they fail outright once over their bounds.
+*/
+
+use {
+  etherparse::{Ipv4HeaderSlice, TcpHeaderSlice},
+  std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::VecDeque,
+    env, fs,
+    hint::{self, black_box},
+    net::Ipv4Addr,
+    sync::{
+      atomic::{AtomicBool, AtomicUsize, Ordering},
+      Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+  },
+  tcp_server::{
+    channel_nic::ChannelNic,
+    control::ControlCommand,
+    error::TcpError,
+    integrity::StreamPattern,
+    interface::{Interface, InterfaceConfig},
+    manager::{self, ConnectionManager, ListenerOptions, SharedConnection},
+    nic::{NicDevice, Readiness},
+    send_buffer::{SendBuffer, SEND_BUFFER_CAPACITY},
+    tcp::{ConnectionQuad, Location, DEFAULT_MAXIMUM_SEGMENT_SIZE},
+  },
+};
+
+const ROUNDS: usize = 20;
+
+// Connections in the connection map, while looking them up.
+const CONNECTIONS: usize = 10_000;
+
+// Size of the write, which gets cut into segments.
+const WRITE_SIZE: usize = 1024 * 1024;
+
+// Bytes streamed through a send buffer, while counting its copies and memory.
+const STREAM_SIZE: usize = 16 * 1024 * 1024;
+
+// Bytes sent over a connection per round, while timing the ACKs or the data segments.
+const TRANSFER_SIZE: usize = 256 * 1024;
+
+// Handshakes completed per round.
+const HANDSHAKES: usize = 1000;
+
+// Idle connections the control socket lists, next to the one receiving data.
+const IDLE_CONNECTIONS: usize = 1000;
+
+// Seeds the data sent over the connections.
+const SEED: u64 = 0x5EED;
+
+const CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const PORT: u16 = 80;
+
+const BASELINE_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/hot_paths.baseline");
+
+// Allowed multiple of its baseline, for a benchmark which has none recorded yet.
+const DEFAULT_ALLOWED_SLOWDOWN: f64 = 3.0;
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator {
+  allocatedLength: AtomicUsize::new(0),
+  liveLength: AtomicUsize::new(0),
+  peakLiveLength: AtomicUsize::new(0),
+};
+
+fn main() {
+  let timings = [
+    ("connection_lookup", bench_connection_lookup()),
+    ("segmentization", bench_segmentization()),
+    ("pure_acknowledgement", bench_pure_acknowledgements()),
+    ("in_order_receive", bench_in_order_receive()),
+    ("handshake", bench_handshakes()),
+    ("contended_receive", bench_contended_receive()),
+  ];
+  bench_send_buffer_memory();
+
+  if env::var_os("HOT_PATHS_RECORD_BASELINE").is_some() {
+    record_baseline(&timings);
+  }
+  else {
+    check_against_baseline(&timings);
+  }
+}
+
+// Looks up every one of the connections, in the connection map.
+fn bench_connection_lookup() -> f64 {
+  // The other end only has to stay around, so that sending the SYNs doesn't fail.
+  let (device, _peerDevice) = ChannelNic::pair();
+  let interface = Interface::with_device(InterfaceConfig::default(), device).unwrap();
+  let connectionManager = interface.connection_manager();
+
+  let peer = Location {
+    address: Ipv4Addr::new(10, 0, 0, 2),
+    port: 80,
+  };
+  for _ in 0..CONNECTIONS {
+    connectionManager.start_connect(peer).unwrap();
+  }
+  let connectionQuads: Vec<ConnectionQuad> = connectionManager
+    .connections()
+    .into_iter()
+    .map(|(connectionQuad, _)| connectionQuad)
+    .collect();
+
+  report(
+    &format!("connection lookup ({} connections)", CONNECTIONS),
+    CONNECTIONS,
+    || {
+      for connectionQuad in &connectionQuads {
+        black_box(connectionManager.connection(connectionQuad));
+      }
+    },
+  )
+}
+
+/*
+  Writes 1MB into a send buffer, and cuts it into MSS sized segments. The send buffer only holds
+  SEND_BUFFER_CAPACITY, so everything sent gets acknowledged right away to make room for the rest,
+  like a peer keeping up would.
+*/
+fn bench_segmentization() -> f64 {
+  let data = vec![0xA5u8; WRITE_SIZE];
+  let segments = WRITE_SIZE.div_ceil(DEFAULT_MAXIMUM_SEGMENT_SIZE);
+
+  report(
+    &format!("segmentization ({} bytes)", WRITE_SIZE),
+    segments,
+    || {
+      let mut sendBuffer = SendBuffer::default();
+      let now = Instant::now();
+
+      let mut sequenceNumber = 0u32;
+      let mut written = 0;
+      while written < data.len() {
+        written += sendBuffer.write(&data[written..]);
+
+        while let Some(segment) =
+          sendBuffer.next_segment(sequenceNumber, DEFAULT_MAXIMUM_SEGMENT_SIZE, now)
+        {
+          sequenceNumber = sequenceNumber.wrapping_add(black_box(segment).payload().len() as u32);
+        }
+        sendBuffer.acknowledge(sequenceNumber);
+      }
+    },
+  )
+}
+
+/*
+  Streams TRANSFER_SIZE bytes from the client to the server, timing only how long the client takes
+  to process the ACKs (pure ones, carrying no data) the server sends back. That includes sending
+  whatever more data the ACKs let through, as it happens in the same call.
+*/
+fn bench_pure_acknowledgements() -> f64 {
+  let pair = Pair::default();
+  let (client, server) = pair.connect();
+  let data = StreamPattern::new(SEED).generate(0, TRANSFER_SIZE);
+
+  report_timed("pure ACKs (per ACK)", || {
+    let (mut elapsed, mut acknowledgements) = (Duration::ZERO, 0);
+
+    let (mut writtenLength, mut readLength) = (0, 0);
+    while readLength < data.len() {
+      writtenLength += write(&pair.client, &client, &data[writtenLength..]);
+
+      for packet in pair.sent_by_client() {
+        pair.server.process_packet(&packet);
+      }
+      readLength += read(&pair.server, &server);
+
+      for packet in pair.sent_by_server() {
+        let isPureAcknowledgement = payload_length(&packet) == 0;
+
+        let startedAt = Instant::now();
+        pair.client.process_packet(&packet);
+        if isPureAcknowledgement {
+          elapsed += startedAt.elapsed();
+          acknowledgements += 1;
+        }
+      }
+    }
+
+    (elapsed, acknowledgements)
+  })
+}
+
+/*
+  Streams TRANSFER_SIZE bytes from the client to the server, where a reader is blocked waiting for
+  them. Times how long the server takes from getting an in-order data segment to the reader having
+  read all of it : processing the segment, waking the reader up and copying the data out.
+*/
+fn bench_in_order_receive() -> f64 {
+  let pair = Pair::default();
+  let (client, server) = pair.connect();
+  let data = StreamPattern::new(SEED).generate(0, TRANSFER_SIZE);
+
+  let receivedLength = Arc::new(AtomicUsize::new(0));
+  {
+    let receivedLength = receivedLength.clone();
+    let connectionManager = pair.server.connection_manager().clone();
+    thread::spawn(move || {
+      let mut ctx = connectionManager.send_context();
+
+      let mut buffer = [0u8; 4096];
+      while let Ok(readLength @ 1..) = server.read(&mut buffer, &mut ctx) {
+        receivedLength.fetch_add(readLength, Ordering::Release);
+      }
+    });
+  }
+
+  let mut deliveredLength = 0;
+  report_timed("in-order receive (per segment)", || {
+    let (mut elapsed, mut segments) = (Duration::ZERO, 0);
+
+    let mut writtenLength = 0;
+    while writtenLength < data.len() || pair.has_pending_packets() {
+      writtenLength += write(&pair.client, &client, &data[writtenLength..]);
+
+      for packet in pair.sent_by_client() {
+        let payloadLength = payload_length(&packet);
+        deliveredLength += payloadLength;
+
+        let startedAt = Instant::now();
+        pair.server.process_packet(&packet);
+        while receivedLength.load(Ordering::Acquire) < deliveredLength {
+          hint::spin_loop();
+        }
+        if payloadLength > 0 {
+          elapsed += startedAt.elapsed();
+          segments += 1;
+        }
+      }
+
+      for packet in pair.sent_by_server() {
+        pair.client.process_packet(&packet);
+      }
+    }
+
+    (elapsed, segments)
+  })
+}
+
+/*
+  Completes HANDSHAKES handshakes, timing only how long the server takes to process the SYN (and
+  answer it), and then the ACK establishing the connection, which moves it into the accept queue.
+  The server's connection map grows by a connection per handshake, as it would under a burst of
+  connection requests. Between rounds, everything gets accepted and aborted.
+*/
+fn bench_handshakes() -> f64 {
+  let pair = Pair::default();
+  pair.server.connection_manager().listen_with(
+    PORT,
+    ListenerOptions {
+      backlog: HANDSHAKES,
+      ..ListenerOptions::default()
+    },
+  );
+  let server = Location {
+    address: SERVER_ADDRESS,
+    port: PORT,
+  };
+
+  report_timed(&format!("handshake ({} connections)", HANDSHAKES), || {
+    let mut elapsed = Duration::ZERO;
+
+    for _ in 0..HANDSHAKES {
+      pair
+        .client
+        .connection_manager()
+        .start_connect(server)
+        .unwrap();
+
+      for packet in pair.sent_by_client() {
+        let startedAt = Instant::now();
+        pair.server.process_packet(&packet);
+        elapsed += startedAt.elapsed();
+      }
+      for packet in pair.sent_by_server() {
+        pair.client.process_packet(&packet);
+      }
+      for packet in pair.sent_by_client() {
+        let startedAt = Instant::now();
+        pair.server.process_packet(&packet);
+        elapsed += startedAt.elapsed();
+      }
+    }
+
+    let serverManager = pair.server.connection_manager();
+    let mut accepted = 0;
+    while serverManager.try_accept(PORT).is_some() {
+      accepted += 1;
+    }
+    assert_eq!(accepted, HANDSHAKES, "Handshakes didn't get completed");
+    pair.abort_every_connection();
+
+    (elapsed, HANDSHAKES)
+  })
+}
+
+/*
+  Streams TRANSFER_SIZE bytes from the client to the server over one hot connection, next to
+  IDLE_CONNECTIONS idle ones, while another thread keeps running list --json --verbose the way the
+  control socket would. Times how long the server takes to process the data segments. The packet
+  loop and the control socket only share the connection map lock, held just long enough to look a
+  connection up or snapshot the map, and the lock of the connection being listed. So the hot path
+  should stay within a small multiple of its uncontended time, rather than waiting out whole
+  listings of the map.
+*/
+fn bench_contended_receive() -> f64 {
+  let pair = Pair::default();
+  let serverManager = pair.server.connection_manager();
+  serverManager.listen_with(
+    PORT,
+    ListenerOptions {
+      backlog: IDLE_CONNECTIONS + 1,
+      ..ListenerOptions::default()
+    },
+  );
+  // The hot connection gets connected last, so that it gets accepted last.
+  let clients: Vec<_> = (0..=IDLE_CONNECTIONS)
+    .map(|_| {
+      pair
+        .client
+        .connection_manager()
+        .start_connect(Location {
+          address: SERVER_ADDRESS,
+          port: PORT,
+        })
+        .unwrap()
+    })
+    .collect();
+  pair.pump();
+  let mut accepted: Vec<_> = (0..=IDLE_CONNECTIONS)
+    .map(|_| serverManager.try_accept(PORT).unwrap())
+    .collect();
+  let server = accepted.pop().unwrap();
+  let client = clients.last().unwrap();
+
+  let data = StreamPattern::new(SEED).generate(0, TRANSFER_SIZE);
+
+  let isDone = Arc::new(AtomicBool::new(false));
+  let listings = Arc::new(AtomicUsize::new(0));
+  let lister = {
+    let (isDone, listings) = (isDone.clone(), listings.clone());
+    let connectionManager = serverManager.clone();
+    thread::spawn(move || {
+      while !isDone.load(Ordering::Relaxed) {
+        let command: ControlCommand = "list --json --verbose".parse().unwrap();
+        black_box(command.execute(&connectionManager));
+        listings.fetch_add(1, Ordering::Relaxed);
+      }
+    })
+  };
+
+  let median = report_timed(
+    &format!("contended receive ({} connections)", IDLE_CONNECTIONS + 1),
+    || {
+      let (mut elapsed, mut segments) = (Duration::ZERO, 0);
+
+      let mut writtenLength = 0;
+      while writtenLength < data.len() || pair.has_pending_packets() {
+        writtenLength += write(&pair.client, client, &data[writtenLength..]);
+
+        for packet in pair.sent_by_client() {
+          let isDataSegment = payload_length(&packet) > 0;
+
+          let startedAt = Instant::now();
+          pair.server.process_packet(&packet);
+          if isDataSegment {
+            elapsed += startedAt.elapsed();
+            segments += 1;
+          }
+        }
+        read(&pair.server, &server);
+
+        for packet in pair.sent_by_server() {
+          pair.client.process_packet(&packet);
+        }
+      }
+
+      (elapsed, segments)
+    },
+  );
+
+  isDone.store(true, Ordering::Relaxed);
+  lister.join().unwrap();
+  let listings = listings.load(Ordering::Relaxed);
+  assert!(
+    listings > 0,
+    "The control socket never got to list the connections"
+  );
+  println!("{:<45} {} listings meanwhile", "", listings);
+  median
+}
+
+/*
+  Streams STREAM_SIZE bytes through a send buffer, with every byte it holds in flight, and the
+  peer acknowledging a segment at a time. Compared to the straightforward design, which keeps the
+  written bytes in a VecDeque and copies each segment's payload into the retransmission queue, the
+  chunked send buffer should copy every byte once rather than twice, and take half the memory.
+
+  The copies of the chunked send buffer are the bytes allocated meanwhile, since each lands in a new
+  allocation (a chunk, or the copied payload of a segment). The copying one counts its own.
+*/
+fn bench_send_buffer_memory() {
+  let data = vec![0xA5u8; STREAM_SIZE];
+
+  let (chunkedCopies, chunkedPeakLength) = ALLOCATOR.measure(|| {
+    black_box(stream(
+      &data,
+      SendBuffer::default(),
+      |sendBuffer, data| sendBuffer.write(data),
+      |sendBuffer, sequenceNumber| {
+        sendBuffer
+          .next_segment(sequenceNumber, DEFAULT_MAXIMUM_SEGMENT_SIZE, Instant::now())
+          .map(|segment| black_box(segment).payload().len())
+      },
+      SendBuffer::acknowledge,
+    ));
+  });
+
+  let mut copyingCopies = 0;
+  let (_, copyingPeakLength) = ALLOCATOR.measure(|| {
+    let sendBuffer = stream(
+      &data,
+      CopyingSendBuffer::default(),
+      CopyingSendBuffer::write,
+      CopyingSendBuffer::next_segment,
+      CopyingSendBuffer::acknowledge,
+    );
+    copyingCopies = sendBuffer.copiedLength;
+  });
+
+  let chunkedCopiesPerByte = chunkedCopies as f64 / STREAM_SIZE as f64;
+  let copyingCopiesPerByte = copyingCopies as f64 / STREAM_SIZE as f64;
+  println!(
+    "{:<45} chunked {:>5.2} / byte   copying {:>5.2} / byte",
+    "send buffer copies", chunkedCopiesPerByte, copyingCopiesPerByte
+  );
+  println!(
+    "{:<45} chunked {:>5} KB     copying {:>5} KB",
+    format!(
+      "send buffer peak memory ({} KB)",
+      SEND_BUFFER_CAPACITY / 1024
+    ),
+    chunkedPeakLength / 1024,
+    copyingPeakLength / 1024
+  );
+
+  assert!(
+    chunkedCopiesPerByte < 1.1,
+    "The chunked send buffer copies bytes more than once"
+  );
+  assert!(
+    chunkedPeakLength * 10 < copyingPeakLength * 6,
+    "The chunked send buffer doesn't take half the memory of the copying one"
+  );
+}
+
+/*
+  Writes the data through the send buffer, cutting segments of at most the MSS out of everything
+  written. Once every written byte is in flight, the oldest segment gets acknowledged.
+*/
+fn stream<Buffer>(
+  data: &[u8],
+  mut sendBuffer: Buffer,
+  write: fn(&mut Buffer, &[u8]) -> usize,
+  next_segment: fn(&mut Buffer, u32) -> Option<usize>,
+  acknowledge: fn(&mut Buffer, u32),
+) -> Buffer {
+  let mut segmentEnds =
+    VecDeque::with_capacity(SEND_BUFFER_CAPACITY / DEFAULT_MAXIMUM_SEGMENT_SIZE + 1);
+  let (mut sequenceNumber, mut writtenLength) = (0u32, 0);
+
+  loop {
+    writtenLength += write(&mut sendBuffer, &data[writtenLength..]);
+
+    while let Some(segmentLength) = next_segment(&mut sendBuffer, sequenceNumber) {
+      sequenceNumber = sequenceNumber.wrapping_add(segmentLength as u32);
+      segmentEnds.push_back(sequenceNumber);
+    }
+
+    let Some(segmentEnd) = segmentEnds.pop_front()
+    else {
+      return sendBuffer;
+    };
+    acknowledge(&mut sendBuffer, segmentEnd);
+  }
+}
+
+// The straightforward send buffer, which the chunked one replaced.
+struct CopyingSendBuffer {
+  // Bytes written, which are yet to be acknowledged.
+  data: VecDeque<u8>,
+  sentLength: usize,
+
+  // The payloads of the segments in flight, by the sequence number they end at.
+  retransmissionQueue: VecDeque<(u32, Vec<u8>)>,
+
+  copiedLength: usize,
+}
+
+impl Default for CopyingSendBuffer {
+  fn default() -> Self {
+    Self {
+      data: VecDeque::with_capacity(SEND_BUFFER_CAPACITY),
+      sentLength: 0,
+      retransmissionQueue: VecDeque::with_capacity(
+        SEND_BUFFER_CAPACITY / DEFAULT_MAXIMUM_SEGMENT_SIZE + 1,
+      ),
+      copiedLength: 0,
+    }
+  }
+}
+
+impl CopyingSendBuffer {
+  fn write(&mut self, data: &[u8]) -> usize {
+    let writtenLength = data.len().min(SEND_BUFFER_CAPACITY - self.data.len());
+    self.data.extend(&data[..writtenLength]);
+
+    self.copiedLength += writtenLength;
+    writtenLength
+  }
+
+  fn next_segment(&mut self, sequenceNumber: u32) -> Option<usize> {
+    let length = DEFAULT_MAXIMUM_SEGMENT_SIZE.min(self.data.len() - self.sentLength);
+    if length == 0 {
+      return None;
+    }
+
+    let payload: Vec<u8> = self
+      .data
+      .range(self.sentLength..self.sentLength + length)
+      .copied()
+      .collect();
+    self.sentLength += length;
+    self.copiedLength += length;
+
+    self.retransmissionQueue.push_back((
+      sequenceNumber.wrapping_add(length as u32),
+      black_box(payload),
+    ));
+    Some(length)
+  }
+
+  // The peer only ever acknowledges whole segments here.
+  fn acknowledge(&mut self, acknowledgementNumber: u32) {
+    while let Some((segmentEnd, payload)) = self.retransmissionQueue.pop_front() {
+      self.data.drain(..payload.len());
+      self.sentLength -= payload.len();
+
+      if segmentEnd == acknowledgementNumber {
+        break;
+      }
+    }
+  }
+}
+
+// The global allocator, counting the bytes allocated in total, and the most ever allocated at once.
+struct CountingAllocator {
+  allocatedLength: AtomicUsize,
+  liveLength: AtomicUsize,
+  peakLiveLength: AtomicUsize,
+}
+
+impl CountingAllocator {
+  fn record_allocation(&self, length: usize) {
+    self.allocatedLength.fetch_add(length, Ordering::Relaxed);
+    let liveLength = self.liveLength.fetch_add(length, Ordering::Relaxed) + length;
+    self.peakLiveLength.fetch_max(liveLength, Ordering::Relaxed);
+  }
+
+  // Runs the given function, and returns how many bytes it allocated, along with the most it had
+  // allocated at once. What was allocated before doesn't count.
+  fn measure(&self, run: impl FnOnce()) -> (usize, usize) {
+    let allocatedLength = self.allocatedLength.load(Ordering::Relaxed);
+    let liveLength = self.liveLength.load(Ordering::Relaxed);
+    self.peakLiveLength.store(liveLength, Ordering::Relaxed);
+
+    run();
+
+    (
+      self.allocatedLength.load(Ordering::Relaxed) - allocatedLength,
+      self.peakLiveLength.load(Ordering::Relaxed) - liveLength,
+    )
+  }
+}
+
+// SAFETY : every call is passed on to the system allocator as is. Only the counting is added.
+unsafe impl GlobalAlloc for CountingAllocator {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    // SAFETY : the caller upholds the contract of GlobalAlloc::alloc( ).
+    let pointer = unsafe { System.alloc(layout) };
+    if !pointer.is_null() {
+      self.record_allocation(layout.size());
+    }
+    pointer
+  }
+
+  unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+    // SAFETY : the caller upholds the contract of GlobalAlloc::alloc_zeroed( ).
+    let pointer = unsafe { System.alloc_zeroed(layout) };
+    if !pointer.is_null() {
+      self.record_allocation(layout.size());
+    }
+    pointer
+  }
+
+  unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+    // SAFETY : the caller upholds the contract of GlobalAlloc::dealloc( ).
+    unsafe { System.dealloc(pointer, layout) };
+    self.liveLength.fetch_sub(layout.size(), Ordering::Relaxed);
+  }
+
+  unsafe fn realloc(&self, pointer: *mut u8, layout: Layout, newSize: usize) -> *mut u8 {
+    // SAFETY : the caller upholds the contract of GlobalAlloc::realloc( ).
+    let newPointer = unsafe { System.realloc(pointer, layout, newSize) };
+    if !newPointer.is_null() {
+      self.liveLength.fetch_sub(layout.size(), Ordering::Relaxed);
+      self.record_allocation(newSize);
+    }
+    newPointer
+  }
+}
+
+/*
+  A client and a server interface, each over its own ChannelNic pair, with the packets between
+  them shuttled by the benchmark itself, so that it can time what either end does with them.
+  Nothing runs in the background, so no timer fires while benchmarking.
+*/
+struct Pair {
+  client: Interface,
+  server: Interface,
+
+  // The other ends of the NICs of the client and the server.
+  clientWire: ChannelNic,
+  serverWire: ChannelNic,
+}
+
+impl Default for Pair {
+  fn default() -> Self {
+    let (clientDevice, clientWire) = ChannelNic::pair();
+    let (serverDevice, serverWire) = ChannelNic::pair();
+
+    let start = |address, device| {
+      let config = InterfaceConfig {
+        address,
+        ..InterfaceConfig::default()
+      };
+      Interface::with_device(config, device).unwrap()
+    };
+
+    Self {
+      client: start(CLIENT_ADDRESS, clientDevice),
+      server: start(SERVER_ADDRESS, serverDevice),
+      clientWire,
+      serverWire,
+    }
+  }
+}
+
+impl Pair {
+  // Connects the client to the server, and returns both ends of the connection.
+  fn connect(&self) -> (Arc<SharedConnection>, Arc<SharedConnection>) {
+    let serverManager = self.server.connection_manager();
+    serverManager.listen(PORT);
+
+    let client = self
+      .client
+      .connection_manager()
+      .start_connect(Location {
+        address: SERVER_ADDRESS,
+        port: PORT,
+      })
+      .unwrap();
+    self.pump();
+
+    (client, serverManager.try_accept(PORT).unwrap())
+  }
+
+  fn sent_by_client(&self) -> Vec<Vec<u8>> {
+    drain(&self.clientWire)
+  }
+
+  fn sent_by_server(&self) -> Vec<Vec<u8>> {
+    drain(&self.serverWire)
+  }
+
+  fn has_pending_packets(&self) -> bool {
+    [&self.clientWire, &self.serverWire]
+      .iter()
+      .any(|wire| wire.wait(Readiness::Readable, Instant::now()).unwrap())
+  }
+
+  // Shuttles packets both ways, till neither end has anything left to send.
+  fn pump(&self) {
+    while self.has_pending_packets() {
+      for packet in self.sent_by_client() {
+        self.server.process_packet(&packet);
+      }
+      for packet in self.sent_by_server() {
+        self.client.process_packet(&packet);
+      }
+    }
+  }
+
+  // Aborts the connections of both ends, and throws away the RSTs.
+  fn abort_every_connection(&self) {
+    for interface in [&self.client, &self.server] {
+      let connectionManager = interface.connection_manager();
+      for (connectionQuad, _) in connectionManager.connections() {
+        connectionManager.abort_quad(&connectionQuad);
+      }
+    }
+    self.sent_by_client();
+    self.sent_by_server();
+  }
+}
+
+// Receives every packet waiting on the given wire.
+fn drain(wire: &ChannelNic) -> Vec<Vec<u8>> {
+  let mut packets = Vec::new();
+
+  let mut buffer = [0u8; 65536];
+  while wire.wait(Readiness::Readable, Instant::now()).unwrap() {
+    let packetLength = wire.recv(&mut buffer).unwrap();
+    packets.push(buffer[..packetLength].to_vec());
+  }
+  packets
+}
+
+fn payload_length(packet: &[u8]) -> usize {
+  let ipv4Header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+  let tcpHeader = TcpHeaderSlice::from_slice(&packet[ipv4Header.slice().len()..]).unwrap();
+  packet.len() - ipv4Header.slice().len() - tcpHeader.slice().len()
+}
+
+// Writes as much of the data as the send buffer takes in, and returns how much that was.
+fn write(interface: &Interface, connection: &SharedConnection, data: &[u8]) -> usize {
+  if data.is_empty() {
+    return 0;
+  }
+
+  let connectionManager: &ConnectionManager = interface.connection_manager();
+  let mut tcb = manager::lock_connection(connection);
+  match tcb.write(data, &mut connectionManager.send_context()) {
+    Ok(writtenLength) => writtenLength,
+    Err(TcpError::WouldBlock) => 0,
+    Err(error) => panic!("Failed writing : {}", error),
+  }
+}
+
+// Reads whatever has arrived, and returns how much that was.
+fn read(interface: &Interface, connection: &SharedConnection) -> usize {
+  let connectionManager: &ConnectionManager = interface.connection_manager();
+  let mut tcb = manager::lock_connection(connection);
+
+  let mut buffer = [0u8; 4096];
+  let mut readLength = 0;
+  loop {
+    match tcb.read(&mut buffer, &mut connectionManager.send_context()) {
+      Ok(0) | Err(TcpError::WouldBlock) => return readLength,
+      Ok(length) => readLength += black_box(&buffer[..length]).len(),
+      Err(error) => panic!("Failed reading : {}", error),
+    }
+  }
+}
+
+// Runs the given round ROUNDS times, and prints how long a round and each of its operations took.
+// Returns the median time per operation, in nanoseconds.
+fn report(name: &str, operationsPerRound: usize, mut round: impl FnMut()) -> f64 {
+  report_timed(name, || {
+    let startedAt = Instant::now();
+    round();
+    (startedAt.elapsed(), operationsPerRound)
+  })
+}
+
+// Like report( ), for a round which times only parts of itself : it returns the time it measured,
+// and how many operations that covered.
+fn report_timed(name: &str, mut round: impl FnMut() -> (Duration, usize)) -> f64 {
+  round();
+
+  let mut timings: Vec<(Duration, usize)> = (0..ROUNDS).map(|_| round()).collect();
+  timings.sort_by(|(left, leftOperations), (right, rightOperations)| {
+    per_operation(*left, *leftOperations).total_cmp(&per_operation(*right, *rightOperations))
+  });
+
+  let (fastest, median) = (timings[0], timings[ROUNDS / 2]);
+  println!(
+    "{:<45} median {:>10.3?} ({:>8.1?} / op)   fastest {:>10.3?}",
+    name,
+    median.0,
+    Duration::from_secs_f64(per_operation(median.0, median.1) / 1e9),
+    fastest.0
+  );
+  per_operation(median.0, median.1)
+}
+
+// In nanoseconds.
+fn per_operation(duration: Duration, operations: usize) -> f64 {
+  duration.as_nanos() as f64 / operations.max(1) as f64
+}
+
+/*
+  The baseline file has a line per timed benchmark :
+
+    <name> <median nanoseconds per operation> <allowed multiple of it>
+
+  Lines starting with a # are comments.
+*/
+fn parse_baseline(baseline: &str) -> Vec<(String, f64, f64)> {
+  baseline
+    .lines()
+    .map(str::trim)
+    .filter(|line| !(line.is_empty() || line.starts_with('#')))
+    .map(|line| {
+      let fields: Vec<&str> = line.split_whitespace().collect();
+      let [name, nanoseconds, allowedSlowdown] = fields[..]
+      else {
+        panic!("Malformed baseline line : {}", line);
+      };
+      let parse = |field: &str| {
+        field
+          .parse::<f64>()
+          .unwrap_or_else(|error| panic!("Malformed baseline line : {} ({})", line, error))
+      };
+      (name.to_string(), parse(nanoseconds), parse(allowedSlowdown))
+    })
+    .collect()
+}
+
+fn check_against_baseline(timings: &[(&str, f64)]) {
+  let baseline = fs::read_to_string(BASELINE_PATH)
+    .unwrap_or_else(|error| panic!("Failed reading {} : {}", BASELINE_PATH, error));
+  let baseline = parse_baseline(&baseline);
+
+  let mut regressions = Vec::new();
+  for (name, nanoseconds) in timings {
+    let Some((_, baselineNanoseconds, allowedSlowdown)) = baseline
+      .iter()
+      .find(|(baselineName, ..)| baselineName == name)
+    else {
+      panic!("{} has no baseline in {}", name, BASELINE_PATH);
+    };
+
+    if *nanoseconds > baselineNanoseconds * allowedSlowdown {
+      regressions.push(format!(
+        "{} : {:.1}ns / op, over {} times its baseline of {:.1}ns / op",
+        name, nanoseconds, allowedSlowdown, baselineNanoseconds
+      ));
+    }
+  }
+
+  assert!(
+    regressions.is_empty(),
+    "Hot paths got slower :\n{}",
+    regressions.join("\n")
+  );
+}
+
+// Rewrites the baseline with the given timings, keeping its comments and allowed multiples.
+fn record_baseline(timings: &[(&str, f64)]) {
+  // A missing baseline file just gets created.
+  let previousBaseline = fs::read_to_string(BASELINE_PATH).unwrap_or_default();
+  let baseline = parse_baseline(&previousBaseline);
+
+  let mut lines: Vec<String> = previousBaseline
+    .lines()
+    .filter(|line| line.starts_with('#'))
+    .map(str::to_string)
+    .collect();
+  for (name, nanoseconds) in timings {
+    let allowedSlowdown = baseline
+      .iter()
+      .find(|(baselineName, ..)| baselineName == name)
+      .map_or(DEFAULT_ALLOWED_SLOWDOWN, |(_, _, allowedSlowdown)| {
+        *allowedSlowdown
+      });
+    lines.push(format!("{} {:.1} {}", name, nanoseconds, allowedSlowdown));
+  }
+
+  fs::write(BASELINE_PATH, lines.join("\n") + "\n")
+    .unwrap_or_else(|error| panic!("Failed writing {} : {}", BASELINE_PATH, error));
+  println!("Recorded the baseline in {}", BASELINE_PATH);
+}
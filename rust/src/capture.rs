@@ -0,0 +1,232 @@
+use {
+  crate::{files, tcp::ConnectionQuad},
+  anyhow::anyhow,
+  std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+  },
+};
+
+/*
+  Per-connection packet captures.
+
+  A capture records every packet of a single connection, both the ones we receive and the ones we
+  send, into a pcap file named after the connection quad, which Wireshark or tcpdump can open. It
+  gets stopped (and flushed) automatically when the connection closes.
+
+  A capture can either be started for an existing connection, or armed for the next connection
+  accepted on a listening port. In the latter case, it starts with the SYN of that connection.
+*/
+
+// Directory, into which the capture files get written. They're only readable by us (see files.rs).
+pub const CAPTURE_DIRECTORY: &str = "/tmp";
+
+// Most captures which may be running (or armed) at once, since each of them holds a file open.
+pub const MAXIMUM_CAPTURES: usize = 8;
+
+// Packets are captured as bare IPv4 packets, with no link layer header in front of them.
+const LINKTYPE_RAW: u32 = 101;
+
+// Largest packet length recorded in the capture files.
+const SNAPSHOT_LENGTH: u32 = 65535;
+
+#[derive(Default)]
+pub struct Captures {
+  running: HashMap<ConnectionQuad, PcapWriter>,
+
+  // Listening ports, the next accepted connection on which gets captured.
+  armedPorts: HashSet<u16>,
+}
+
+impl Captures {
+  // Starts capturing the given connection, and returns the path of the capture file.
+  pub fn start(&mut self, connectionQuad: ConnectionQuad) -> anyhow::Result<PathBuf> {
+    if self.running.contains_key(&connectionQuad) {
+      return Err(anyhow!(
+        "Connection {} is already being captured",
+        connectionQuad
+      ));
+    }
+    self.ensure_room()?;
+
+    let path = Path::new(CAPTURE_DIRECTORY).join(format!(
+      "tcpd-{}-{}.pcap",
+      connectionQuad.source, connectionQuad.destiation
+    ));
+
+    let writer = PcapWriter::create(&path)
+      .map_err(|error| anyhow!("Failed creating {} : {}", path.display(), error))?;
+    self.running.insert(connectionQuad, writer);
+
+    Ok(path)
+  }
+
+  // Arms a capture for the next connection accepted on the given port.
+  pub fn arm(&mut self, port: u16) -> anyhow::Result<()> {
+    if self.armedPorts.contains(&port) {
+      return Err(anyhow!("A capture is already armed on port {}", port));
+    }
+    self.ensure_room()?;
+
+    self.armedPorts.insert(port);
+    Ok(())
+  }
+
+  /*
+    Starts the capture armed on the destination port of the given connection request, if any. If
+    the connection request doesn't end up being accepted, the capture should be given up on with
+    disarm( ).
+  */
+  pub fn on_connection_request(&mut self, connectionQuad: ConnectionQuad) {
+    if !self.armedPorts.remove(&connectionQuad.destiation.port) {
+      return;
+    }
+
+    match self.start(connectionQuad) {
+      Ok(path) => println!("Capturing {} into {}", connectionQuad, path.display()),
+      Err(error) => eprintln!("Failed capturing {} : {}", connectionQuad, error),
+    }
+  }
+
+  // Gives up on the capture of a connection request which didn't get accepted, and re-arms the
+  // capture on its port.
+  pub fn disarm(&mut self, connectionQuad: &ConnectionQuad) {
+    let Some(writer) = self.running.remove(connectionQuad)
+    else {
+      return;
+    };
+
+    let _ = fs::remove_file(&writer.path);
+    self.armedPorts.insert(connectionQuad.destiation.port);
+  }
+
+  // Records a packet received from, or sent to, the peer of the given connection.
+  pub fn record(&mut self, connectionQuad: &ConnectionQuad, packet: &[u8]) {
+    let Some(writer) = self.running.get_mut(connectionQuad)
+    else {
+      return;
+    };
+
+    if let Err(error) = writer.write_packet(packet) {
+      eprintln!(
+        "Failed capturing {}, so stopping the capture : {}",
+        connectionQuad, error
+      );
+      self.running.remove(connectionQuad);
+    }
+  }
+
+  // Stops capturing the given connection, flushing the capture file. Returns the path of the
+  // capture file, or None if the connection wasn't being captured.
+  pub fn stop(&mut self, connectionQuad: &ConnectionQuad) -> Option<PathBuf> {
+    let writer = self.running.remove(connectionQuad)?;
+
+    let path = writer.path.clone();
+    if let Err(error) = writer.finish() {
+      eprintln!("Failed flushing {} : {}", path.display(), error);
+    }
+
+    Some(path)
+  }
+
+  // Lists the running and armed captures.
+  pub fn describe(&self) -> String {
+    let mut description = String::new();
+
+    for (connectionQuad, writer) in &self.running {
+      let _ = writeln!(
+        description,
+        "{} into {} ({} packets)",
+        connectionQuad,
+        writer.path.display(),
+        writer.packets
+      );
+    }
+    for port in &self.armedPorts {
+      let _ = writeln!(description, "next connection on port {} (armed)", port);
+    }
+
+    description
+  }
+
+  fn ensure_room(&self) -> anyhow::Result<()> {
+    if self.running.len() + self.armedPorts.len() >= MAXIMUM_CAPTURES {
+      return Err(anyhow!(
+        "At most {} captures may be running at once",
+        MAXIMUM_CAPTURES
+      ));
+    }
+    Ok(())
+  }
+}
+
+/*
+  Writes packets in the classic pcap format : a global header, followed by a record header and the
+  packet bytes for each packet. Every field is written in our native byte order, which the magic
+  number lets readers detect.
+
+  REFERENCE : https://datatracker.ietf.org/doc/html/draft-ietf-opsawg-pcap
+*/
+pub(crate) struct PcapWriter {
+  path: PathBuf,
+
+  file: BufWriter<File>,
+
+  // Number of packets written so far.
+  packets: u64,
+}
+
+impl PcapWriter {
+  pub(crate) fn create(path: &Path) -> io::Result<Self> {
+    let mut file = BufWriter::new(files::create(path)?);
+
+    file.write_all(&0xa1b2c3d4u32.to_ne_bytes())?; // Magic number (microsecond timestamps).
+    file.write_all(&2u16.to_ne_bytes())?; // Major version.
+    file.write_all(&4u16.to_ne_bytes())?; // Minor version.
+    file.write_all(&0i32.to_ne_bytes())?; // Timezone offset (unused).
+    file.write_all(&0u32.to_ne_bytes())?; // Timestamp accuracy (unused).
+    file.write_all(&SNAPSHOT_LENGTH.to_ne_bytes())?;
+    file.write_all(&LINKTYPE_RAW.to_ne_bytes())?;
+
+    Ok(Self {
+      path: path.to_path_buf(),
+      file,
+      packets: 0,
+    })
+  }
+
+  fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+
+    self.write_packet_at(packet, timestamp)
+  }
+
+  // Like write_packet( ), but timestamped with the given time since the UNIX epoch.
+  pub(crate) fn write_packet_at(&mut self, packet: &[u8], timestamp: Duration) -> io::Result<()> {
+    let packetLength = packet.len() as u32;
+    let capturedLength = packetLength.min(SNAPSHOT_LENGTH);
+
+    self
+      .file
+      .write_all(&(timestamp.as_secs() as u32).to_ne_bytes())?;
+    self
+      .file
+      .write_all(&timestamp.subsec_micros().to_ne_bytes())?;
+    self.file.write_all(&capturedLength.to_ne_bytes())?;
+    self.file.write_all(&packetLength.to_ne_bytes())?;
+    self.file.write_all(&packet[..capturedLength as usize])?;
+
+    self.packets += 1;
+    Ok(())
+  }
+
+  pub(crate) fn finish(mut self) -> io::Result<()> {
+    self.file.flush()
+  }
+}
@@ -0,0 +1,93 @@
+use std::{
+  sync::{Mutex, MutexGuard},
+  time::{Duration, Instant},
+};
+
+/*
+  Where the TCBs and the connection manager read the time from, for their timers and timestamps.
+
+  The daemon runs on the SystemClock. The simulator (and the tests) run on a VirtualClock instead,
+  which only moves when advanced : a minute worth of retransmission timeouts then takes no time at
+  all, and comes out the same on every run.
+*/
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+}
+
+// The monotonic clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/*
+  A clock which stands still, till it gets advanced. It starts at the Instant it got created at, so
+  that the Instants it hands out can be mixed with the ones taken before.
+*/
+pub struct VirtualClock {
+  origin: Instant,
+
+  elapsed: Mutex<Duration>,
+}
+
+impl Default for VirtualClock {
+  fn default() -> Self {
+    Self {
+      origin: Instant::now(),
+      elapsed: Mutex::default(),
+    }
+  }
+}
+
+impl VirtualClock {
+  pub fn advance(&self, by: Duration) {
+    *self.lock_elapsed() += by;
+  }
+
+  // Moves the clock forward to the given Instant. The clock never goes back, so an Instant in the
+  // past leaves it where it is.
+  pub fn advance_to(&self, to: Instant) {
+    let mut elapsed = self.lock_elapsed();
+    *elapsed = (*elapsed).max(to.saturating_duration_since(self.origin));
+  }
+
+  // How far the clock has been advanced since it got created.
+  pub fn elapsed(&self) -> Duration {
+    *self.lock_elapsed()
+  }
+
+  fn lock_elapsed(&self) -> MutexGuard<'_, Duration> {
+    self.elapsed.lock().expect("Virtual clock mutex poisoned")
+  }
+}
+
+impl Clock for VirtualClock {
+  fn now(&self) -> Instant {
+    self.origin + self.elapsed()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn a_virtual_clock_only_moves_forward_when_advanced() {
+    let clock = VirtualClock::default();
+    let startedAt = clock.now();
+    assert_eq!(clock.now(), startedAt);
+
+    clock.advance(Duration::from_secs(60));
+    assert_eq!(clock.now(), startedAt + Duration::from_secs(60));
+
+    clock.advance_to(startedAt);
+    assert_eq!(clock.elapsed(), Duration::from_secs(60));
+
+    clock.advance_to(startedAt + Duration::from_secs(90));
+    assert_eq!(clock.now(), startedAt + Duration::from_secs(90));
+  }
+}
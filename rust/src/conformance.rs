@@ -0,0 +1,542 @@
+use {
+  crate::{
+    extensions::Extension,
+    integrity::StreamPattern,
+    json::{JsonObject, ToJson},
+    manager::{self, ConnectionManager, SharedConnection},
+    stats::ConnectionStats,
+    tcp::{BehaviorOverrides, Location, DEFAULT_MAXIMUM_SEGMENT_SIZE},
+  },
+  std::{
+    cell::Cell,
+    fmt::{self, Display, Formatter},
+    ops::Range,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+  },
+};
+
+/*
+  A battery of checks of how a remote peer behaves, run over a connection to it. Most of them work
+  by making our side of the connection misbehave on purpose (see BehaviorOverrides), and watching
+  how the peer copes.
+
+  The checks needing data from the peer expect it to echo back whatever it receives (like an echo
+  server on port 7, or socat with the exec:cat address). They get skipped otherwise.
+*/
+
+// How long the peer gets to respond to a check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long our window is held at zero, for the peer to probe it.
+const ZERO_WINDOW_PERIOD: Duration = Duration::from_secs(5);
+
+// Every check, in the order they run.
+const CHECKS: [&str; 11] = [
+  "handshake",
+  "mss",
+  "window-scale",
+  "sack-permitted",
+  "timestamps",
+  "ack",
+  "echo",
+  "zero-window",
+  "zero-window-probes",
+  "window-reopen",
+  "out-of-order",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+  Pass,
+
+  // The peer deviates from a SHOULD, or does something legal but unusual.
+  Warn,
+
+  // The peer violates a MUST.
+  Fail,
+
+  // The check couldn't run, since one it depends on failed.
+  Skip,
+}
+
+pub struct CheckResult {
+  pub name: &'static str,
+  pub outcome: CheckOutcome,
+
+  // What was observed.
+  pub detail: String,
+}
+
+pub struct ConformanceReport {
+  pub peer: Location,
+
+  pub checks: Vec<CheckResult>,
+}
+
+impl ConformanceReport {
+  // Whether no check failed.
+  pub fn is_passed(&self) -> bool {
+    self
+      .checks
+      .iter()
+      .all(|check| check.outcome != CheckOutcome::Fail)
+  }
+
+  fn record(&mut self, outcome: CheckOutcome, detail: impl Into<String>) {
+    self.checks.push(CheckResult {
+      name: CHECKS[self.checks.len()],
+      outcome,
+      detail: detail.into(),
+    });
+  }
+
+  // Skips every check left.
+  fn skip_rest(&mut self, detail: &str) {
+    while self.checks.len() < CHECKS.len() {
+      self.record(CheckOutcome::Skip, detail);
+    }
+  }
+
+  fn count(&self, outcome: CheckOutcome) -> usize {
+    self
+      .checks
+      .iter()
+      .filter(|check| check.outcome == outcome)
+      .count()
+  }
+}
+
+// Connects to the given peer, and runs every check against it. The connection gets aborted
+// afterwards.
+pub fn run(connectionManager: &ConnectionManager, peer: Location) -> ConformanceReport {
+  let mut report = ConformanceReport {
+    peer,
+    checks: Vec::new(),
+  };
+
+  let connection = match connectionManager.connect(peer) {
+    Ok(connection) => connection,
+    Err(error) => {
+      report.record(CheckOutcome::Fail, format!("connecting failed : {}", error));
+      report.skip_rest("no connection");
+      return report;
+    }
+  };
+  report.record(
+    CheckOutcome::Pass,
+    "answered our SYN with a SYN-ACK, and the handshake completed",
+  );
+
+  let pattern = StreamPattern::for_connection(&manager::lock_connection(&connection).quad());
+  let probe = Probe {
+    connectionManager,
+    connection,
+    pattern,
+    sentOffset: Cell::new(0),
+    receivedOffset: Cell::new(0),
+  };
+  probe.run(&mut report);
+
+  connectionManager.abort_quad(&manager::lock_connection(&probe.connection).quad());
+  report
+}
+
+struct Probe<'probe> {
+  connectionManager: &'probe ConnectionManager,
+
+  connection: Arc<SharedConnection>,
+
+  // What we send, and how far into it we've written and had it echoed back.
+  pattern: StreamPattern,
+  sentOffset: Cell<u64>,
+  receivedOffset: Cell<u64>,
+}
+
+impl Probe<'_> {
+  fn run(&self, report: &mut ConformanceReport) {
+    self.check_syn_ack_options(report);
+
+    let data = self.probe_data(DEFAULT_MAXIMUM_SEGMENT_SIZE / 2);
+    if let Err(error) = self.write_all(&data) {
+      report.record(CheckOutcome::Fail, format!("writing failed : {}", error));
+      report.skip_rest("the connection broke");
+      return;
+    }
+    match self.wait_acknowledged() {
+      Some(elapsed) => report.record(
+        CheckOutcome::Pass,
+        format!("acknowledged {} bytes in {:.1?}", data.len(), elapsed),
+      ),
+      None => {
+        report.record(
+          CheckOutcome::Fail,
+          format!("left our data unacknowledged for {:?}", CHECK_TIMEOUT),
+        );
+        report.skip_rest("the peer doesn't acknowledge data");
+        return;
+      }
+    }
+
+    let echo = self.read_echo(data.len());
+    match &echo {
+      Ok(()) => {
+        report.record(CheckOutcome::Pass, "echoed our data");
+        self.check_zero_window(report);
+      }
+
+      Err(detail) => {
+        report.record(
+          CheckOutcome::Warn,
+          format!("{}, so the checks needing its data get skipped", detail),
+        );
+        for _ in 0..3 {
+          report.record(CheckOutcome::Skip, "the peer doesn't echo");
+        }
+      }
+    }
+
+    self.check_out_of_order(report, echo.is_ok());
+  }
+
+  // Our SYN offers window scaling, SACK and timestamps, each of which the peer's SYN-ACK may
+  // accept or decline (RFC 7323 sections 1.3 and 3.2, RFC 2018 section 2). Either is conformant.
+  fn check_syn_ack_options(&self, report: &mut ConformanceReport) {
+    let (maximumSegmentSize, mss, isEnabled) = {
+      let tcb = manager::lock_connection(&self.connection);
+
+      (
+        tcb.peer_maximum_segment_size(),
+        tcb.stats().options_received(2),
+        Extension::ALL.map(|extension| tcb.is_extension_enabled(extension)),
+      )
+    };
+
+    if mss > 0 {
+      report.record(
+        CheckOutcome::Pass,
+        format!("offered an MSS of {} bytes", maximumSegmentSize),
+      );
+    }
+    else {
+      report.record(
+        CheckOutcome::Warn,
+        format!(
+          "offered no MSS, so {} bytes are assumed (RFC 9293 section 3.7.1)",
+          maximumSegmentSize
+        ),
+      );
+    }
+
+    for isEnabled in isEnabled {
+      if isEnabled {
+        report.record(CheckOutcome::Pass, "accepted our offer");
+      }
+      else {
+        report.record(CheckOutcome::Pass, "declined our offer");
+      }
+    }
+  }
+
+  /*
+    While our window is held at zero, the peer has the echo of our data to send. It must keep that
+    back, and probe the window instead (RFC 9293 section 3.8.6.1). Probes carry at most a byte,
+    while anything above 64 bytes is data sent into the closed window.
+  */
+  fn check_zero_window(&self, report: &mut ConformanceReport) {
+    let data = self.probe_data(2 * DEFAULT_MAXIMUM_SEGMENT_SIZE);
+    let payloadSizesBefore = self.stats(ConnectionStats::payload_sizes);
+
+    let zeroWindow = BehaviorOverrides {
+      advertiseZeroWindow: true,
+      ..BehaviorOverrides::default()
+    };
+    if let Err(error) = self
+      .set_overrides(zeroWindow)
+      .and_then(|_| self.write_all(&data))
+    {
+      for _ in 0..3 {
+        report.record(CheckOutcome::Skip, format!("writing failed : {}", error));
+      }
+      return;
+    }
+    thread::sleep(ZERO_WINDOW_PERIOD);
+
+    let payloadSizes = self.stats(ConnectionStats::payload_sizes);
+    let received = |buckets: Range<usize>| -> u64 {
+      buckets
+        .map(|bucket| payloadSizes[bucket] - payloadSizesBefore[bucket])
+        .sum()
+    };
+    let (segments, dataSegments) = (received(0..5), received(2..5));
+
+    if dataSegments == 0 {
+      report.record(CheckOutcome::Pass, "kept its data back");
+    }
+    else {
+      report.record(
+        CheckOutcome::Fail,
+        format!("sent {} data segments into our zero window", dataSegments),
+      );
+    }
+
+    if segments > dataSegments {
+      report.record(
+        CheckOutcome::Pass,
+        format!(
+          "probed {} times in {:?}",
+          segments - dataSegments,
+          ZERO_WINDOW_PERIOD
+        ),
+      );
+    }
+    else {
+      report.record(
+        CheckOutcome::Fail,
+        format!("never probed our zero window in {:?}", ZERO_WINDOW_PERIOD),
+      );
+    }
+
+    let startedAt = Instant::now();
+    if let Err(error) = self.set_overrides(BehaviorOverrides::default()) {
+      report.record(
+        CheckOutcome::Skip,
+        format!("reopening the window failed : {}", error),
+      );
+      return;
+    }
+
+    match self.read_echo(data.len()) {
+      Ok(()) => report.record(
+        CheckOutcome::Pass,
+        format!(
+          "resumed sending {:.1?} after the window reopened",
+          startedAt.elapsed()
+        ),
+      ),
+      Err(detail) => report.record(
+        CheckOutcome::Fail,
+        format!("{} after the window reopened", detail),
+      ),
+    }
+  }
+
+  /*
+    Sends two segments the wrong way round. A peer which queues the out-of-order one (RFC 9293
+    section 3.10.7.4 says it SHOULD) acknowledges both right after the first one arrives. One which
+    drops it has us retransmit it.
+  */
+  fn check_out_of_order(&self, report: &mut ConformanceReport, isEchoing: bool) {
+    let data = self.probe_data(2 * DEFAULT_MAXIMUM_SEGMENT_SIZE);
+    let retransmissionsBefore = self.stats(ConnectionStats::retransmissions);
+
+    let reordered = BehaviorOverrides {
+      reorderNextSegments: true,
+      ..BehaviorOverrides::default()
+    };
+    if let Err(error) = self
+      .set_overrides(reordered)
+      .and_then(|_| self.write_all(&data))
+    {
+      report.record(CheckOutcome::Skip, format!("writing failed : {}", error));
+      return;
+    }
+
+    let Some(elapsed) = self.wait_acknowledged()
+    else {
+      report.record(
+        CheckOutcome::Fail,
+        format!(
+          "left our reordered segments unacknowledged for {:?}",
+          CHECK_TIMEOUT
+        ),
+      );
+      return;
+    };
+    let retransmissions = self.stats(ConnectionStats::retransmissions) - retransmissionsBefore;
+
+    let echo = if isEchoing {
+      self.read_echo(data.len())
+    }
+    else {
+      Ok(())
+    };
+
+    if let Err(detail) = echo {
+      report.record(
+        CheckOutcome::Fail,
+        format!("didn't echo our reordered data in order : {}", detail),
+      );
+    }
+    else if retransmissions == 0 {
+      report.record(
+        CheckOutcome::Pass,
+        format!("acknowledged our reordered segments in {:.1?}", elapsed),
+      );
+    }
+    else {
+      report.record(
+        CheckOutcome::Warn,
+        format!(
+          "dropped our out-of-order segment, which took {} retransmissions",
+          retransmissions
+        ),
+      );
+    }
+  }
+
+  fn stats<T>(&self, read: impl FnOnce(&ConnectionStats) -> T) -> T {
+    read(manager::lock_connection(&self.connection).stats())
+  }
+
+  fn set_overrides(&self, overrides: BehaviorOverrides) -> anyhow::Result<()> {
+    let mut ctx = self.connectionManager.send_context();
+    manager::lock_connection(&self.connection).set_overrides(overrides, &mut ctx)
+  }
+
+  fn write_all(&self, mut data: &[u8]) -> anyhow::Result<()> {
+    let mut ctx = self.connectionManager.send_context();
+
+    while !data.is_empty() {
+      let bytesWritten = self.connection.write(data, &mut ctx)?;
+      data = &data[bytesWritten..];
+    }
+    Ok(())
+  }
+
+  // Waits till everything written has been sent and acknowledged, and returns how long that took.
+  // None if it took longer than the check timeout.
+  fn wait_acknowledged(&self) -> Option<Duration> {
+    let startedAt = Instant::now();
+
+    let (_tcb, isTimedOut) = self.connection.wait_timeout_while(CHECK_TIMEOUT, |tcb| {
+      tcb.bytes_queued() > 0 || tcb.bytes_unacked() > 0
+    });
+    (!isTimedOut).then(|| startedAt.elapsed())
+  }
+
+  // The next bytes of the stream we send.
+  fn probe_data(&self, length: usize) -> Vec<u8> {
+    let offset = self.sentOffset.get();
+    self.sentOffset.set(offset + length as u64);
+
+    self.pattern.generate(offset, length)
+  }
+
+  /*
+    Reads back the echo of the given number of bytes, and checks it against the stream we sent.
+    Fails with what went wrong : either the echo fell short, or it diverged from our stream at some
+    offset, which gets reported along with the state of the connection.
+  */
+  fn read_echo(&self, length: usize) -> Result<(), String> {
+    let offset = self.receivedOffset.get();
+
+    let echoed = self.read_exactly(length, CHECK_TIMEOUT);
+    self.receivedOffset.set(offset + echoed.len() as u64);
+
+    if let Some(divergentOffset) = self.pattern.first_divergence(offset, &echoed) {
+      let tcb = manager::lock_connection(&self.connection);
+
+      return Err(format!(
+        "echo diverged from our data at stream offset {} ({}, {} bytes to read)",
+        divergentOffset,
+        tcb.state(),
+        tcb.bytes_to_read()
+      ));
+    }
+    if echoed.len() < length {
+      return Err(format!("echoed {} of {} bytes", echoed.len(), length));
+    }
+    Ok(())
+  }
+
+  // Reads till the given number of bytes has been received, or the timeout elapses.
+  fn read_exactly(&self, length: usize, timeout: Duration) -> Vec<u8> {
+    let mut ctx = self.connectionManager.send_context();
+    let deadline = Instant::now() + timeout;
+
+    let mut data = Vec::with_capacity(length);
+    let mut buffer = [0u8; 1024];
+
+    while data.len() < length {
+      let remaining = deadline.saturating_duration_since(Instant::now());
+      if remaining.is_zero() {
+        break;
+      }
+
+      let (mut tcb, isTimedOut) = self
+        .connection
+        .wait_timeout_while(remaining, |tcb| !tcb.is_readable());
+      if isTimedOut {
+        break;
+      }
+
+      let maximumLength = buffer.len().min(length - data.len());
+      match tcb.read(&mut buffer[..maximumLength], &mut ctx) {
+        Ok(0) | Err(_) => break,
+        Ok(bytesRead) => data.extend_from_slice(&buffer[..bytesRead]),
+      }
+    }
+
+    data
+  }
+}
+
+impl Display for CheckOutcome {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Pass => "pass",
+      Self::Warn => "warn",
+      Self::Fail => "fail",
+      Self::Skip => "skip",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl Display for ConformanceReport {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(f, "Conformance of {} :", self.peer)?;
+
+    for check in &self.checks {
+      writeln!(
+        f,
+        "  {:<4} {:<18} {}",
+        check.outcome.to_string().to_uppercase(),
+        check.name,
+        check.detail
+      )?;
+    }
+
+    writeln!(
+      f,
+      "{} passed | {} warned | {} failed | {} skipped",
+      self.count(CheckOutcome::Pass),
+      self.count(CheckOutcome::Warn),
+      self.count(CheckOutcome::Fail),
+      self.count(CheckOutcome::Skip)
+    )
+  }
+}
+
+impl ToJson for CheckResult {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("name", self.name)
+      .field("outcome", &self.outcome.to_string())
+      .field("detail", &self.detail)
+      .finish();
+  }
+}
+
+impl ToJson for ConformanceReport {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("peer", &self.peer)
+      .field("checks", &self.checks)
+      .field("passed", &self.is_passed())
+      .finish();
+  }
+}
@@ -0,0 +1,612 @@
+use {
+  crate::{
+    filter::FilterRule,
+    json::{JsonObject, ToJson},
+    manager::{self, ConnectionManager},
+    rate_limit::ConnectionRateLimit,
+    tcp::ConnectionQuad,
+  },
+  anyhow::anyhow,
+  std::{
+    cmp::Reverse,
+    fmt::Write as _,
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::Ipv4Addr,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+  },
+};
+
+/*
+  The control socket lets an operator inspect and manage live connections from the command line, for
+  example :
+
+    echo "list" | nc -U /run/tcpd.sock
+    echo "list --verbose" | nc -U /run/tcpd.sock
+    echo "list port 8080" | nc -U /run/tcpd.sock
+    echo "list --stuck" | nc -U /run/tcpd.sock
+    echo "list --json --verbose" | nc -U /run/tcpd.sock
+    echo "stats" | nc -U /run/tcpd.sock
+    echo "stats --json" | nc -U /run/tcpd.sock
+    echo "counters" | nc -U /run/tcpd.sock
+    echo "counters --json --reset" | nc -U /run/tcpd.sock
+    echo "kill 10.0.0.2:51514 10.0.0.1:8080" | nc -U /run/tcpd.sock
+    echo "rule add deny 10.0.0.0/25 22" | nc -U /run/tcpd.sock
+    echo "rule list" | nc -U /run/tcpd.sock
+    echo "rule remove 0" | nc -U /run/tcpd.sock
+    echo "limit 8080 100/10" | nc -U /run/tcpd.sock
+    echo "limit list" | nc -U /run/tcpd.sock
+    echo "alias add 192.168.50.7" | nc -U /run/tcpd.sock
+    echo "alias list" | nc -U /run/tcpd.sock
+    echo "alias remove 192.168.50.7" | nc -U /run/tcpd.sock
+    echo "mtu" | nc -U /run/tcpd.sock
+    echo "mtu 1280" | nc -U /run/tcpd.sock
+    echo "capture 10.0.0.2:51514 10.0.0.1:8080" | nc -U /run/tcpd.sock
+    echo "capture port 8080" | nc -U /run/tcpd.sock
+    echo "capture list" | nc -U /run/tcpd.sock
+    echo "capture stop 10.0.0.2:51514 10.0.0.1:8080" | nc -U /run/tcpd.sock
+    echo "sample 10.0.0.2:51514 10.0.0.1:8080" | nc -U /run/tcpd.sock
+    echo "sample list" | nc -U /run/tcpd.sock
+    echo "sample stop 10.0.0.2:51514 10.0.0.1:8080" | nc -U /run/tcpd.sock
+    echo "drain" | nc -U /run/tcpd.sock
+
+  Each line written to the socket is one command, and gets answered with its response. Clients get
+  served concurrently, and one which sends no command for 30 seconds gets disconnected.
+*/
+
+pub const CONTROL_SOCKET_PATH: &str = "/run/tcpd.sock";
+
+// A client which sends no command for this long gets disconnected.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub enum ControlCommand {
+  /*
+    Lists every connection (or only those on the given local port) along with its state, its age
+    and how long it has been in that state. When verbose, each connection's stats are listed too.
+    When stuck, the connections which have been in their state the longest come first. With json,
+    each connection is listed as a JSON object on its own line.
+  */
+  List {
+    verbose: bool,
+    stuck: bool,
+    json: bool,
+    port: Option<u16>,
+  },
+
+  // Shows the connection manager's and the vNIC's counters, the hit counts of the packet filter
+  // rules and the rate limited listeners. With json, just the counters get shown, as a single JSON
+  // object.
+  Stats {
+    json: bool,
+  },
+
+  // Shows the TCP counters summed up over every connection, as a JSON object with json. With
+  // reset, they start over from 0 once read, so that scraping them yields deltas.
+  Counters {
+    json: bool,
+    reset: bool,
+  },
+
+  // Aborts the connection identified by the given quad.
+  Kill(ConnectionQuad),
+
+  // Appends a rule to the packet filter.
+  AddRule(FilterRule),
+
+  // Lists the packet filter rules, along with their positions and hit counts.
+  ListRules,
+
+  // Removes the packet filter rule at the given position.
+  RemoveRule(usize),
+
+  // Changes the connection rate limit of the given listening port.
+  SetRateLimit {
+    port: u16,
+    rateLimit: ConnectionRateLimit,
+  },
+
+  // Lists the rate limited listeners, along with what their limits refused.
+  ListRateLimits,
+
+  // Starts answering for the given address too.
+  AddAlias(Ipv4Addr),
+
+  // Lists the address of the vNIC, followed by the aliases.
+  ListAddresses,
+
+  // Stops answering for the given alias. Connections to it which are already open carry on.
+  RemoveAlias(Ipv4Addr),
+
+  // Shows the MTU of the vNIC.
+  ShowMtu,
+
+  // Changes the MTU of the vNIC, and thus the MSS of the connections opened afterwards.
+  SetMtu(u16),
+
+  // Starts capturing the packets of the connection identified by the given quad.
+  Capture(ConnectionQuad),
+
+  // Captures the packets of the next connection accepted on the given port.
+  CaptureNextConnection(u16),
+
+  // Lists the running and armed packet captures.
+  ListCaptures,
+
+  // Stops capturing the connection identified by the given quad, before it gets closed.
+  StopCapture(ConnectionQuad),
+
+  // Starts sampling the connection identified by the given quad, into the sample file.
+  Sample(ConnectionQuad),
+
+  // Lists the sampled connections.
+  ListSamples,
+
+  // Stops sampling the connection identified by the given quad, before it gets closed.
+  StopSampling(ConnectionQuad),
+
+  // Starts draining the interface, and shows how many connections are left. Once draining, it
+  // just shows how many connections are left.
+  Drain,
+}
+
+impl FromStr for ControlCommand {
+  type Err = anyhow::Error;
+
+  fn from_str(line: &str) -> anyhow::Result<Self> {
+    let line = line.trim();
+    let (command, arguments) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+
+    match command {
+      "list" => {
+        let mut verbose = false;
+        let mut stuck = false;
+        let mut json = false;
+        let mut port = None;
+
+        let mut arguments = arguments.split_whitespace();
+        while let Some(argument) = arguments.next() {
+          match argument {
+            "-v" | "--verbose" => verbose = true,
+            "--stuck" => stuck = true,
+            "--json" => json = true,
+
+            "port" => {
+              let value = arguments
+                .next()
+                .ok_or_else(|| anyhow!("Expected a port after 'port'"))?;
+
+              port = Some(
+                value
+                  .parse::<u16>()
+                  .map_err(|error| anyhow!("Invalid port '{}' : {}", value, error))?,
+              );
+            }
+
+            argument => return Err(anyhow!("Unknown argument '{}' for list", argument)),
+          }
+        }
+
+        Ok(Self::List {
+          verbose,
+          stuck,
+          json,
+          port,
+        })
+      }
+
+      "stats" => match arguments.trim() {
+        "" => Ok(Self::Stats { json: false }),
+        "--json" => Ok(Self::Stats { json: true }),
+        argument => Err(anyhow!("Unknown argument '{}' for stats", argument)),
+      },
+
+      "counters" => {
+        let (mut json, mut reset) = (false, false);
+        for argument in arguments.split_whitespace() {
+          match argument {
+            "--json" => json = true,
+            "--reset" => reset = true,
+            argument => return Err(anyhow!("Unknown argument '{}' for counters", argument)),
+          }
+        }
+
+        Ok(Self::Counters { json, reset })
+      }
+
+      "kill" => Ok(Self::Kill(arguments.parse()?)),
+
+      "rule" => {
+        let arguments = arguments.trim();
+        let (subcommand, arguments) = arguments
+          .split_once(char::is_whitespace)
+          .unwrap_or((arguments, ""));
+
+        match subcommand {
+          "add" => Ok(Self::AddRule(arguments.parse()?)),
+          "list" => Ok(Self::ListRules),
+          "remove" => Ok(Self::RemoveRule(arguments.trim().parse().map_err(
+            |error| anyhow!("Invalid rule position '{}' : {}", arguments.trim(), error),
+          )?)),
+          _ => Err(anyhow!(
+            "Unknown rule subcommand '{}', expected add, list or remove",
+            subcommand
+          )),
+        }
+      }
+
+      "limit" => {
+        let arguments = arguments.trim();
+        let (subcommand, subcommandArguments) = arguments
+          .split_once(char::is_whitespace)
+          .unwrap_or((arguments, ""));
+
+        match subcommand {
+          "list" => Ok(Self::ListRateLimits),
+          port => Ok(Self::SetRateLimit {
+            port: port
+              .parse()
+              .map_err(|error| anyhow!("Invalid port '{}' : {}", port, error))?,
+            rateLimit: subcommandArguments.parse()?,
+          }),
+        }
+      }
+
+      "alias" => {
+        let arguments = arguments.trim();
+        let (subcommand, subcommandArguments) = arguments
+          .split_once(char::is_whitespace)
+          .unwrap_or((arguments, ""));
+
+        let parse_address = |address: &str| {
+          address
+            .trim()
+            .parse::<Ipv4Addr>()
+            .map_err(|error| anyhow!("Invalid IPv4 address '{}' : {}", address.trim(), error))
+        };
+
+        match subcommand {
+          "add" => Ok(Self::AddAlias(parse_address(subcommandArguments)?)),
+          "list" => Ok(Self::ListAddresses),
+          "remove" => Ok(Self::RemoveAlias(parse_address(subcommandArguments)?)),
+          _ => Err(anyhow!(
+            "Unknown alias subcommand '{}', expected add, list or remove",
+            subcommand
+          )),
+        }
+      }
+
+      "mtu" => match arguments.trim() {
+        "" => Ok(Self::ShowMtu),
+        mtu => {
+          Ok(Self::SetMtu(mtu.parse().map_err(|error| {
+            anyhow!("Invalid MTU '{}' : {}", mtu, error)
+          })?))
+        }
+      },
+
+      "capture" => {
+        let arguments = arguments.trim();
+        let (subcommand, subcommandArguments) = arguments
+          .split_once(char::is_whitespace)
+          .unwrap_or((arguments, ""));
+
+        match subcommand {
+          "port" => Ok(Self::CaptureNextConnection(
+            subcommandArguments.trim().parse().map_err(|error| {
+              anyhow!("Invalid port '{}' : {}", subcommandArguments.trim(), error)
+            })?,
+          )),
+          "list" => Ok(Self::ListCaptures),
+          "stop" => Ok(Self::StopCapture(subcommandArguments.parse()?)),
+          _ => Ok(Self::Capture(arguments.parse()?)),
+        }
+      }
+
+      "sample" => {
+        let arguments = arguments.trim();
+        let (subcommand, subcommandArguments) = arguments
+          .split_once(char::is_whitespace)
+          .unwrap_or((arguments, ""));
+
+        match subcommand {
+          "list" => Ok(Self::ListSamples),
+          "stop" => Ok(Self::StopSampling(subcommandArguments.parse()?)),
+          _ => Ok(Self::Sample(arguments.parse()?)),
+        }
+      }
+
+      "drain" if arguments.trim().is_empty() => Ok(Self::Drain),
+      "drain" => Err(anyhow!("drain doesn't take any arguments")),
+
+      "" => Err(anyhow!("Empty command")),
+      _ => Err(anyhow!("Unknown command '{}'", command)),
+    }
+  }
+}
+
+impl ControlCommand {
+  // Executes the command and returns the response to be sent back to the operator.
+  pub fn execute(self, connectionManager: &ConnectionManager) -> String {
+    match self {
+      Self::List {
+        verbose,
+        stuck,
+        json,
+        port,
+      } => {
+        let mut connections = match port {
+          Some(port) => connectionManager.connections_on_port(port),
+          None => connectionManager.connections(),
+        };
+
+        if stuck {
+          connections.sort_by_cached_key(|(_, connection)| {
+            Reverse(manager::lock_connection(connection).time_in_state())
+          });
+        }
+
+        let mut response = String::new();
+        for (connectionQuad, connection) in connections {
+          let connection = manager::lock_connection(&connection);
+
+          if json {
+            let mut line = String::new();
+            let mut object = JsonObject::new(&mut line);
+            object
+              .field("quad", &connectionQuad)
+              .field("state", &connection.state())
+              .field("age_ms", &connection.age().as_millis())
+              .field("time_in_state_ms", &connection.time_in_state().as_millis())
+              .field(
+                "oldest_unacked_age_ms",
+                &connection.oldest_unacked_age().map(|age| age.as_millis()),
+              );
+            if verbose {
+              object
+                .field("bytes_to_read", &connection.bytes_to_read())
+                .field("bytes_queued", &connection.bytes_queued())
+                .field("bytes_unacked", &connection.bytes_unacked())
+                .field("stats", connection.stats())
+                .field("transitions", &connection.transitions().collect::<Vec<_>>());
+            }
+            object.finish();
+
+            let _ = writeln!(response, "{}", line);
+            continue;
+          }
+
+          let _ = write!(
+            response,
+            "{} {} | age {:.1?} | in state {:.1?}",
+            connectionQuad,
+            connection.state(),
+            connection.age(),
+            connection.time_in_state()
+          );
+          if let Some(oldestUnackedAge) = connection.oldest_unacked_age() {
+            let _ = write!(response, " | oldest unacked {:.1?}", oldestUnackedAge);
+          }
+          response.push('\n');
+          if verbose {
+            let _ = writeln!(
+              response,
+              "  to read {} | queued {} | unacked {}",
+              connection.bytes_to_read(),
+              connection.bytes_queued(),
+              connection.bytes_unacked()
+            );
+            let _ = write!(response, "{}", connection.stats());
+            for transition in connection.transitions() {
+              let _ = writeln!(response, "  {}", transition);
+            }
+          }
+        }
+        response
+      }
+
+      Self::Stats { json: true } => {
+        let mut response = String::new();
+        JsonObject::new(&mut response)
+          .field("connection_manager", connectionManager.counters())
+          .field("nic", connectionManager.nic().counters())
+          .finish();
+        response.push('\n');
+        response
+      }
+
+      Self::Stats { json: false } => format!(
+        "{}{}{}{}",
+        connectionManager.counters(),
+        connectionManager.nic().counters(),
+        connectionManager.describe_filter(),
+        connectionManager.describe_rate_limits()
+      ),
+
+      Self::Counters { json, reset } => {
+        let counters = connectionManager.tcp_counters().read(reset);
+        if json {
+          let mut response = counters.to_json();
+          response.push('\n');
+          response
+        }
+        else {
+          counters.to_string()
+        }
+      }
+
+      Self::Kill(connectionQuad) => {
+        if !connectionManager.abort_quad(&connectionQuad) {
+          return format!("ERROR : connection {} not found\n", connectionQuad);
+        }
+        format!("Killed connection {}\n", connectionQuad)
+      }
+
+      Self::AddRule(rule) => {
+        connectionManager.add_filter_rule(rule);
+        format!("Added rule {}\n", rule)
+      }
+
+      Self::ListRules => connectionManager.describe_filter(),
+
+      Self::RemoveRule(index) => {
+        if !connectionManager.remove_filter_rule(index) {
+          return format!("ERROR : rule {} not found\n", index);
+        }
+        format!("Removed rule {}\n", index)
+      }
+
+      Self::SetRateLimit { port, rateLimit } => {
+        if !connectionManager.set_rate_limit(port, rateLimit) {
+          return format!("ERROR : nobody listens on port {}\n", port);
+        }
+        format!("Rate limited port {} to {}\n", port, rateLimit)
+      }
+
+      Self::ListRateLimits => connectionManager.describe_rate_limits(),
+
+      Self::AddAlias(address) => match connectionManager.add_alias(address) {
+        Ok(()) => format!("Added alias {}\n", address),
+        Err(error) => format!("ERROR : {}\n", error),
+      },
+
+      Self::ListAddresses => {
+        let mut response = String::new();
+        for (index, address) in connectionManager.local_addresses().iter().enumerate() {
+          let kind = if index == 0 { "address" } else { "alias" };
+          let _ = writeln!(response, "{} {}", kind, address);
+        }
+        response
+      }
+
+      Self::RemoveAlias(address) => {
+        if !connectionManager.remove_alias(address) {
+          return format!("ERROR : alias {} not found\n", address);
+        }
+        format!("Removed alias {}\n", address)
+      }
+
+      Self::ShowMtu => format!("mtu {}\n", connectionManager.nic().mtu()),
+
+      Self::SetMtu(mtu) => match connectionManager.nic().set_mtu(mtu) {
+        Ok(()) => format!("Changed the MTU to {}\n", mtu),
+        Err(error) => format!("ERROR : {}\n", error),
+      },
+
+      Self::Capture(connectionQuad) => match connectionManager.capture_connection(connectionQuad) {
+        Ok(path) => format!("Capturing {} into {}\n", connectionQuad, path.display()),
+        Err(error) => format!("ERROR : {}\n", error),
+      },
+
+      Self::CaptureNextConnection(port) => match connectionManager.capture_next_connection(port) {
+        Ok(()) => format!("Capturing the next connection on port {}\n", port),
+        Err(error) => format!("ERROR : {}\n", error),
+      },
+
+      Self::Drain => {
+        let remainingConnections = connectionManager.drain();
+        format!(
+          "Interface {}, {} connections remaining\n",
+          connectionManager.state(),
+          remainingConnections
+        )
+      }
+
+      Self::ListCaptures => connectionManager.describe_captures(),
+
+      Self::StopCapture(connectionQuad) => match connectionManager.stop_capture(&connectionQuad) {
+        Some(path) => format!(
+          "Stopped capturing {} into {}\n",
+          connectionQuad,
+          path.display()
+        ),
+        None => format!(
+          "ERROR : connection {} isn't being captured\n",
+          connectionQuad
+        ),
+      },
+
+      Self::Sample(connectionQuad) => match connectionManager.sample_connection(connectionQuad) {
+        Ok(path) => format!("Sampling {} into {}\n", connectionQuad, path.display()),
+        Err(error) => format!("ERROR : {}\n", error),
+      },
+
+      Self::ListSamples => connectionManager.describe_sampler(),
+
+      Self::StopSampling(connectionQuad) => {
+        match connectionManager.stop_sampling(&connectionQuad) {
+          true => format!("Stopped sampling {}\n", connectionQuad),
+          false => format!(
+            "ERROR : connection {} isn't being sampled\n",
+            connectionQuad
+          ),
+        }
+      }
+    }
+  }
+}
+
+// Binds the control socket at the given path, and serves commands from background threads : one
+// per client, so that a client sitting idle (or a slow command) never holds up the others.
+pub fn serve(
+  path: impl AsRef<Path>,
+  connectionManager: Arc<ConnectionManager>,
+) -> anyhow::Result<()> {
+  let path = path.as_ref();
+
+  // Remove the socket file left behind by a previous run, since binding fails otherwise.
+  if path.exists() {
+    fs::remove_file(path)?;
+  }
+  let listener = UnixListener::bind(path)?;
+
+  thread::spawn(move || {
+    for client in listener.incoming() {
+      let client = match client {
+        Ok(client) => client,
+        Err(error) => {
+          eprintln!("Failed accepting control socket client : {}", error);
+          continue;
+        }
+      };
+
+      let connectionManager = connectionManager.clone();
+      thread::spawn(move || {
+        if let Err(error) = handle_client(client, &connectionManager) {
+          eprintln!("Failed serving control socket client : {}", error);
+        }
+      });
+    }
+  });
+
+  Ok(())
+}
+
+fn handle_client(client: UnixStream, connectionManager: &ConnectionManager) -> anyhow::Result<()> {
+  client.set_read_timeout(Some(CLIENT_IDLE_TIMEOUT))?;
+  let mut writer = client.try_clone()?;
+
+  for line in BufReader::new(client).lines() {
+    let line = match line {
+      Ok(line) => line,
+
+      // The client went idle, so it gets disconnected.
+      Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+
+      Err(error) => return Err(error.into()),
+    };
+
+    let response = match line.parse::<ControlCommand>() {
+      Ok(command) => command.execute(connectionManager),
+      Err(error) => format!("ERROR : {}\n", error),
+    };
+
+    writer.write_all(response.as_bytes())?;
+  }
+
+  Ok(())
+}
@@ -0,0 +1,95 @@
+use {
+  crate::{
+    lifecycle::InterfaceState,
+    tcp::{CloseReason, Location},
+  },
+  std::{
+    fmt::{self, Display, Formatter},
+    io,
+  },
+};
+
+/*
+  Errors returned by the user facing calls : connect( ), accept( ), read( ), write( ) and close( ).
+
+  Unlike an io::ErrorKind, they carry why a connection got closed, so callers can tell a peer reset
+  from a failing vNIC. They still convert into io::Error, for code written against std.
+*/
+#[derive(Debug)]
+pub enum TcpError {
+  // The connection got closed for the given reason, other than a graceful close.
+  ConnectionReset { reason: CloseReason },
+
+  // The peer answered our SYN with a RST.
+  ConnectionRefused,
+
+  // The peer didn't answer our SYN in time, or left our data unacknowledged for longer than the
+  // user timeout.
+  TimedOut,
+
+  // There's nothing to read yet, or no room left in the send buffer.
+  WouldBlock,
+
+  // The connection isn't in a state which allows the call.
+  NotConnected,
+
+  // Every ephemeral port is in use, towards the given peer.
+  AddrNotAvailable,
+
+  // The peer to connect to is a broadcast, multicast, unspecified or loopback address, or port 0.
+  MartianPeer(Location),
+
+  // A segment couldn't be written to the vNIC.
+  Nic(io::Error),
+
+  // The interface is draining or stopped, so no connection can be opened or accepted.
+  InterfaceShutdown { state: InterfaceState },
+}
+
+impl Display for TcpError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ConnectionReset { reason } => write!(f, "Connection closed : {}", reason),
+      Self::ConnectionRefused => write!(f, "Connection refused"),
+      Self::TimedOut => write!(f, "Connection timed out"),
+      Self::WouldBlock => write!(f, "Operation would block"),
+      Self::NotConnected => write!(f, "Not connected"),
+      Self::AddrNotAvailable => write!(f, "No ephemeral port available"),
+      Self::MartianPeer(peer) => write!(f, "{} isn't a unicast peer", peer),
+      Self::Nic(error) => write!(f, "Failed writing to the vNIC : {}", error),
+      Self::InterfaceShutdown { state } => write!(f, "Interface is {}", state),
+    }
+  }
+}
+
+impl std::error::Error for TcpError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Nic(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+impl From<TcpError> for io::Error {
+  fn from(error: TcpError) -> Self {
+    let kind = match &error {
+      // The peer can't be reached anymore, rather than having reset the connection.
+      TcpError::ConnectionReset {
+        reason: CloseReason::NicFailed,
+      } => io::ErrorKind::BrokenPipe,
+      TcpError::ConnectionReset { .. } => io::ErrorKind::ConnectionReset,
+
+      TcpError::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+      TcpError::TimedOut => io::ErrorKind::TimedOut,
+      TcpError::WouldBlock => io::ErrorKind::WouldBlock,
+      TcpError::NotConnected => io::ErrorKind::NotConnected,
+      TcpError::AddrNotAvailable => io::ErrorKind::AddrNotAvailable,
+      TcpError::MartianPeer(_) => io::ErrorKind::InvalidInput,
+
+      TcpError::Nic(_) | TcpError::InterfaceShutdown { .. } => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, error)
+  }
+}
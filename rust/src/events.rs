@@ -0,0 +1,207 @@
+use {
+  crate::{
+    files,
+    json::{JsonObject, ToJson},
+    tcp::{CloseReason, ConnectionQuad},
+  },
+  anyhow::anyhow,
+  std::{
+    io::{BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+  },
+};
+
+/*
+  Lifecycle events of the connections, appended to the event log as JSON lines, like :
+
+    {"event":"opened","timestamp_ms":1760400000000,"quad":{...},"passive":true}
+    {"event":"established","timestamp_ms":1760400000003,"quad":{...}}
+    {"event":"reverse-loss-suspected","timestamp_ms":1760400002210,"quad":{...},"duplicates":9}
+    {"event":"closed","timestamp_ms":1760400004521,"quad":{...},"reason":"graceful"}
+
+  Timestamps are wall clock milliseconds since the UNIX epoch, so that the events can be lined up
+  with the logs of other processes.
+*/
+#[derive(Clone, Copy, Debug)]
+pub enum ConnectionEvent {
+  // A TCB got created, either for a connection request (passive) or by connect( ).
+  Opened {
+    quad: ConnectionQuad,
+    passive: bool,
+  },
+
+  Established {
+    quad: ConnectionQuad,
+  },
+
+  // The peer kept retransmitting data we had acknowledged, so our ACKs are likely getting lost.
+  ReverseLossSuspected {
+    quad: ConnectionQuad,
+    duplicates: u32,
+  },
+
+  // The TCB got deleted.
+  Closed {
+    quad: ConnectionQuad,
+    reason: Option<CloseReason>,
+  },
+}
+
+// Most events which may be waiting to be written at once.
+const EVENT_QUEUE_DEPTH: usize = 1024;
+
+/*
+  Appends the connection events to a file.
+
+  The events get serialized right away, but written by a background thread, so that the packet
+  thread never waits for the disk. If that thread lags behind by EVENT_QUEUE_DEPTH events, further
+  ones get dropped (and counted). Each event gets flushed as soon as it's written, so that whoever
+  tails the file sees it without delay.
+*/
+pub struct EventLog {
+  writer: SyncSender<String>,
+
+  // Events dropped, since the background thread was lagging behind.
+  droppedEvents: u64,
+}
+
+impl EventLog {
+  pub fn open(path: &Path) -> anyhow::Result<Self> {
+    let file = files::append(path)
+      .map_err(|error| anyhow!("Failed opening {} : {}", path.display(), error))?;
+
+    let (writer, receiver) = mpsc::sync_channel::<String>(EVENT_QUEUE_DEPTH);
+    let path = path.to_path_buf();
+
+    thread::spawn(move || {
+      let mut file = BufWriter::new(file);
+
+      for line in receiver {
+        if let Err(error) = file.write_all(line.as_bytes()).and_then(|_| file.flush()) {
+          eprintln!(
+            "ERROR : failed writing events to {}, so events aren't logged anymore : {}",
+            path.display(),
+            error
+          );
+          return;
+        }
+      }
+    });
+
+    Ok(Self {
+      writer,
+      droppedEvents: 0,
+    })
+  }
+
+  pub fn record(&mut self, event: &ConnectionEvent) {
+    let mut line = event.to_json();
+    line.push('\n');
+
+    match self.writer.try_send(line) {
+      Ok(()) => {}
+
+      Err(TrySendError::Full(_)) => {
+        if self.droppedEvents == 0 {
+          eprintln!("WARN : the event log is lagging behind, so events are getting dropped");
+        }
+        self.droppedEvents += 1;
+      }
+
+      // The background thread has reported why it stopped.
+      Err(TrySendError::Disconnected(_)) => {}
+    }
+  }
+
+  pub fn dropped_events(&self) -> u64 {
+    self.droppedEvents
+  }
+}
+
+impl ToJson for ConnectionEvent {
+  fn write_json(&self, json: &mut String) {
+    let timestamp = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+
+    let mut object = JsonObject::new(json);
+    match self {
+      Self::Opened { quad, passive } => object
+        .field("event", "opened")
+        .field("timestamp_ms", &timestamp)
+        .field("quad", quad)
+        .field("passive", passive),
+
+      Self::Established { quad } => object
+        .field("event", "established")
+        .field("timestamp_ms", &timestamp)
+        .field("quad", quad),
+
+      Self::ReverseLossSuspected { quad, duplicates } => object
+        .field("event", "reverse-loss-suspected")
+        .field("timestamp_ms", &timestamp)
+        .field("quad", quad)
+        .field("duplicates", duplicates),
+
+      Self::Closed { quad, reason } => object
+        .field("event", "closed")
+        .field("timestamp_ms", &timestamp)
+        .field("quad", quad)
+        .field("reason", reason),
+    }
+    .finish();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    std::{
+      env, fs, process,
+      time::{Duration, Instant},
+    },
+  };
+
+  #[test]
+  fn events_get_appended_in_order() {
+    let path = env::temp_dir().join(format!("tcpd-events-test-{}.jsonl", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let quad: ConnectionQuad = "10.0.0.2:51514 10.0.0.1:8080".parse().unwrap();
+    let mut eventLog = EventLog::open(&path).unwrap();
+    eventLog.record(&ConnectionEvent::Opened {
+      quad,
+      passive: true,
+    });
+    eventLog.record(&ConnectionEvent::Established { quad });
+    eventLog.record(&ConnectionEvent::Closed {
+      quad,
+      reason: Some(CloseReason::Graceful),
+    });
+    assert_eq!(eventLog.dropped_events(), 0);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let contents = loop {
+      let contents = fs::read_to_string(&path).unwrap_or_default();
+      if contents.lines().count() >= 3 || Instant::now() > deadline {
+        break contents;
+      }
+      thread::sleep(Duration::from_millis(1));
+    };
+
+    let lines = contents.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with(r#"{"event":"opened","timestamp_ms":"#));
+    assert!(lines[0].ends_with(r#""passive":true}"#));
+    assert!(lines[1].starts_with(r#"{"event":"established","#));
+    assert!(lines[2].starts_with(r#"{"event":"closed","#));
+    assert!(lines[2].ends_with(r#""reason":"graceful"}"#));
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,550 @@
+use {
+  crate::tcp::{sequence_le, sequence_lt},
+  etherparse::{TcpHeaderSlice, TcpOptionElement},
+  std::fmt::{self, Display, Formatter},
+};
+
+/*
+  The TCP extensions negotiated during the three way handshake : window scaling and timestamps
+  (RFC 7323), and selective acknowledgments (RFC 2018).
+
+  Our SYN offers every one of them, and our SYN-ACK the ones the peer's SYN offered. Middleboxes
+  sometimes strip these options off one direction of the handshake, leaving each end with its own
+  belief of what got negotiated. So an extension only gets enabled once we both offered it and saw
+  the peer's option for it, which both ends agree on unless the option got stripped off our
+  SYN-ACK. Only the peer's SYN can tell us whether it wanted the extension in the first place, so
+  that case has to be caught afterwards, from the segments which follow :
+
+    (1) Timestamps : every segment but a RST must carry one, once negotiated. The first segment of
+        the peer arriving without one disables them, for the rest of the connection.
+
+    (2) SACK : a peer which takes in out of order data answers it with a duplicate ACK, which
+        carries SACK blocks once negotiated. After DUPLICATE_ACKNOWLEDGEMENT_THRESHOLD duplicate
+        ACKs without any, with no SACK block since, SACK gets disabled.
+
+    (3) Window scaling can't be caught : the peer's windows look no different, whether they're
+        scaled or not. Our own shift count is always 0, since the receive buffer fits in an
+        unscaled window, so at worst the peer's window gets overestimated, and the data sent past
+        it retransmitted.
+
+  The same checks catch the peer stopping to send the options mid-connection, like after a route
+  change through a middlebox which strips them. Either way, the extension gets disabled (which the
+  connection logs once, and counts as a fallback), and the connection carries on without it.
+*/
+
+// Duplicate ACKs without SACK blocks, after which the peer is taken to not be sending any.
+pub const DUPLICATE_ACKNOWLEDGEMENT_THRESHOLD: u32 = 3;
+
+// The shift count we offer, which leaves our windows unscaled.
+const WINDOW_SHIFT: u8 = 0;
+
+// Largest shift count allowed. A larger one is taken as this one (RFC 7323 section 2.3).
+const MAXIMUM_WINDOW_SHIFT: u8 = 14;
+
+// Most SACK blocks we send in an ACK, which still fit along with a timestamp.
+const MAXIMUM_SACK_BLOCKS: usize = 3;
+
+// Most SACKed ranges remembered, beyond which the highest ones get forgotten.
+const MAXIMUM_SCOREBOARD_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extension {
+  WindowScale,
+  SelectiveAcknowledgement,
+  Timestamps,
+}
+
+impl Extension {
+  pub const ALL: [Self; 3] = [
+    Self::WindowScale,
+    Self::SelectiveAcknowledgement,
+    Self::Timestamps,
+  ];
+}
+
+impl Display for Extension {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::WindowScale => "window scale",
+      Self::SelectiveAcknowledgement => "SACK",
+      Self::Timestamps => "timestamp",
+    };
+    write!(f, "{}", name)
+  }
+}
+
+// What the options of a received segment say about the extensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionOptions {
+  pub windowShift: Option<u8>,
+
+  pub isSelectiveAcknowledgementPermitted: bool,
+
+  // The sender's timestamp, and the one it echoes back : (TSval, TSecr).
+  pub timestamp: Option<(u32, u32)>,
+
+  // SACK blocks, as the sequence number of their first byte and the one right after their last.
+  pub selectiveAcknowledgements: [Option<(u32, u32)>; 4],
+}
+
+impl ExtensionOptions {
+  // A malformed option ends the list, like in tcp::option_kinds( ).
+  pub fn of(tcpHeader: &TcpHeaderSlice) -> Self {
+    let mut options = Self::default();
+
+    for option in tcpHeader.options_iterator() {
+      let Ok(option) = option
+      else {
+        break;
+      };
+
+      match option {
+        TcpOptionElement::WindowScale(shift) => options.windowShift = Some(shift),
+        TcpOptionElement::SelectiveAcknowledgementPermitted => {
+          options.isSelectiveAcknowledgementPermitted = true
+        }
+        TcpOptionElement::Timestamp(value, echoReply) => {
+          options.timestamp = Some((value, echoReply))
+        }
+        TcpOptionElement::SelectiveAcknowledgement(first, rest) => {
+          options.selectiveAcknowledgements = [Some(first), rest[0], rest[1], rest[2]];
+        }
+        _ => {}
+      }
+    }
+
+    options
+  }
+
+  // Whether the segment carries the option for the given extension, as a SYN would.
+  fn offers(&self, extension: Extension) -> bool {
+    match extension {
+      Extension::WindowScale => self.windowShift.is_some(),
+      Extension::SelectiveAcknowledgement => self.isSelectiveAcknowledgementPermitted,
+      Extension::Timestamps => self.timestamp.is_some(),
+    }
+  }
+
+  fn selective_acknowledgements(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+    self.selectiveAcknowledgements.iter().flatten().copied()
+  }
+}
+
+// How a received segment fares against the timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampCheck {
+  // Timestamps are disabled, or the segment's is recent enough.
+  Accepted,
+
+  // The segment's timestamp is older than TS.Recent, so it's an old duplicate (PAWS).
+  Old,
+
+  // The segment carries no timestamp, though they got negotiated. They're to be disabled.
+  Missing,
+}
+
+// The state of the extensions, of a connection.
+#[derive(Debug, Default)]
+pub struct Extensions {
+  // The extensions the peer's SYN offered, which our SYN-ACK offers back.
+  peerOffered: ExtensionOptions,
+
+  // The peer's shift count, once window scaling got enabled.
+  peerWindowShift: Option<u8>,
+
+  isSelectiveAcknowledgementEnabled: bool,
+
+  // TS.Recent : the timestamp to echo back to the peer, once timestamps got enabled.
+  recentTimestamp: Option<u32>,
+
+  // Duplicate ACKs received without SACK blocks, since the last SACK block.
+  duplicateAcknowledgementsWithoutSACK: u32,
+
+  // Ranges of our in-flight data the peer has SACKed, in sequence order and not overlapping.
+  scoreboard: Vec<(u32, u32)>,
+}
+
+impl Extensions {
+  // The options for our SYN, carrying the given timestamp. A SYN-ACK only offers the extensions the
+  // peer's SYN offered.
+  pub fn syn_options(&self, isSYNACK: bool, timestamp: u32) -> Vec<TcpOptionElement> {
+    let isOffered = |extension| !isSYNACK || self.peerOffered.offers(extension);
+
+    let mut options = Vec::with_capacity(4);
+    if isOffered(Extension::SelectiveAcknowledgement) {
+      options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+    }
+    if isOffered(Extension::Timestamps) {
+      options.push(TcpOptionElement::Timestamp(
+        timestamp,
+        self.recentTimestamp.unwrap_or_default(),
+      ));
+    }
+    if isOffered(Extension::WindowScale) {
+      options.extend([
+        TcpOptionElement::Noop,
+        TcpOptionElement::WindowScale(WINDOW_SHIFT),
+      ]);
+    }
+    options
+  }
+
+  /*
+    Takes in the options of the peer's SYN (or SYN-ACK). When it answers a SYN of ours, which
+    offered every extension, every one missing from it is a fallback, which gets returned.
+  */
+  pub fn on_syn(&mut self, options: ExtensionOptions, isAnswer: bool) -> Vec<Extension> {
+    self.peerOffered = options;
+
+    self.peerWindowShift = options
+      .windowShift
+      .map(|shift| shift.min(MAXIMUM_WINDOW_SHIFT));
+    self.isSelectiveAcknowledgementEnabled = options.isSelectiveAcknowledgementPermitted;
+    self.recentTimestamp = options.timestamp.map(|(value, _)| value);
+
+    match isAnswer {
+      true => Extension::ALL
+        .into_iter()
+        .filter(|extension| !options.offers(*extension))
+        .collect(),
+      false => Vec::new(),
+    }
+  }
+
+  pub fn is_enabled(&self, extension: Extension) -> bool {
+    match extension {
+      Extension::WindowScale => self.peerWindowShift.is_some(),
+      Extension::SelectiveAcknowledgement => self.isSelectiveAcknowledgementEnabled,
+      Extension::Timestamps => self.recentTimestamp.is_some(),
+    }
+  }
+
+  // The window advertised by a (non SYN) segment of the peer, scaled. Our send window is kept in
+  // 16 bits, so a larger one gets capped.
+  pub fn peer_window(&self, windowSize: u16) -> u16 {
+    let shift = self.peerWindowShift.unwrap_or_default();
+    ((windowSize as u32) << shift).min(u16::MAX as u32) as u16
+  }
+
+  // Length of the options carried by every segment, which the data has to make room for.
+  pub fn options_length(&self) -> usize {
+    match self.recentTimestamp {
+      // Padded to 12 bytes with 2 NOPs.
+      Some(_) => 12,
+      None => 0,
+    }
+  }
+
+  /*
+    PAWS (RFC 7323 section 5.3) : a segment whose timestamp is older than TS.Recent is an old
+    duplicate, to be acknowledged and dropped. RSTs are exempt, and need no timestamp.
+  */
+  pub fn check_timestamp(&self, options: &ExtensionOptions, isReset: bool) -> TimestampCheck {
+    let Some(recentTimestamp) = self.recentTimestamp
+    else {
+      return TimestampCheck::Accepted;
+    };
+    if isReset {
+      return TimestampCheck::Accepted;
+    }
+
+    match options.timestamp {
+      Some((value, _)) if sequence_lt(value, recentTimestamp) => TimestampCheck::Old,
+      Some(_) => TimestampCheck::Accepted,
+      None => TimestampCheck::Missing,
+    }
+  }
+
+  // Stops using the given extension, for the rest of the connection.
+  pub fn disable(&mut self, extension: Extension) {
+    match extension {
+      Extension::WindowScale => self.peerWindowShift = None,
+      Extension::SelectiveAcknowledgement => {
+        self.isSelectiveAcknowledgementEnabled = false;
+        self.scoreboard.clear();
+      }
+      Extension::Timestamps => self.recentTimestamp = None,
+    }
+  }
+
+  // Records the timestamp of a segment which got accepted, and which starts at or before RCV.NXT.
+  pub fn update_recent_timestamp(&mut self, options: &ExtensionOptions) {
+    if let (Some(recentTimestamp), Some((value, _))) =
+      (&mut self.recentTimestamp, options.timestamp)
+    {
+      if sequence_le(*recentTimestamp, value) {
+        *recentTimestamp = value;
+      }
+    }
+  }
+
+  // The timestamp option for our next segment, if timestamps are enabled.
+  pub fn timestamp_option(&self, timestamp: u32) -> Option<TcpOptionElement> {
+    self
+      .recentTimestamp
+      .map(|recentTimestamp| TcpOptionElement::Timestamp(timestamp, recentTimestamp))
+  }
+
+  /*
+    The SACK option for our next ACK, covering the given ranges of out of order data, if SACK is
+    enabled and there are any. Only the first few ranges fit.
+  */
+  pub fn selective_acknowledgement_option(
+    &self,
+    ranges: impl IntoIterator<Item = (u32, u32)>,
+  ) -> Option<TcpOptionElement> {
+    if !self.isSelectiveAcknowledgementEnabled {
+      return None;
+    }
+
+    let mut ranges = ranges.into_iter().take(MAXIMUM_SACK_BLOCKS);
+    let first = ranges.next()?;
+
+    let mut rest = [None; 3];
+    for (block, range) in rest.iter_mut().zip(ranges) {
+      *block = Some(range);
+    }
+    Some(TcpOptionElement::SelectiveAcknowledgement(first, rest))
+  }
+
+  /*
+    Takes in the SACK blocks of an ACK of the peer, for our data in flight between SND.UNA and
+    SND.NXT. A duplicate ACK without any counts towards disabling SACK, which returns true once it
+    does.
+  */
+  pub fn on_acknowledgement(
+    &mut self,
+    options: &ExtensionOptions,
+    isDuplicate: bool,
+    oldestUnacknowledgedSequenceNumber: u32,
+    nextSequenceNumber: u32,
+  ) -> bool {
+    if !self.isSelectiveAcknowledgementEnabled {
+      return false;
+    }
+
+    let mut hasBlocks = false;
+    for (start, end) in options.selective_acknowledgements() {
+      // Blocks outside of what's in flight are bogus, or stale.
+      if !sequence_lt(start, end)
+        || !sequence_le(oldestUnacknowledgedSequenceNumber, start)
+        || !sequence_le(end, nextSequenceNumber)
+      {
+        continue;
+      }
+      hasBlocks = true;
+      self.add_to_scoreboard(start, end);
+    }
+
+    if hasBlocks {
+      self.duplicateAcknowledgementsWithoutSACK = 0;
+      return false;
+    }
+    if !isDuplicate {
+      return false;
+    }
+
+    self.duplicateAcknowledgementsWithoutSACK += 1;
+    if self.duplicateAcknowledgementsWithoutSACK < DUPLICATE_ACKNOWLEDGEMENT_THRESHOLD {
+      return false;
+    }
+
+    self.disable(Extension::SelectiveAcknowledgement);
+    true
+  }
+
+  fn add_to_scoreboard(&mut self, mut start: u32, mut end: u32) {
+    // Merges the range with every one it overlaps or touches.
+    self.scoreboard.retain(|&(rangeStart, rangeEnd)| {
+      let isApart = sequence_lt(rangeEnd, start) || sequence_lt(end, rangeStart);
+      if !isApart {
+        if sequence_lt(rangeStart, start) {
+          start = rangeStart;
+        }
+        if sequence_lt(end, rangeEnd) {
+          end = rangeEnd;
+        }
+      }
+      isApart
+    });
+
+    let index = self
+      .scoreboard
+      .iter()
+      .position(|&(rangeStart, _)| sequence_lt(start, rangeStart))
+      .unwrap_or(self.scoreboard.len());
+    self.scoreboard.insert(index, (start, end));
+    self.scoreboard.truncate(MAXIMUM_SCOREBOARD_LENGTH);
+  }
+
+  // Forgets the SACKed ranges the peer has since acknowledged cumulatively.
+  pub fn forget_acknowledged(&mut self, acknowledgementNumber: u32) {
+    self.scoreboard.retain_mut(
+      |(start, end)| match sequence_le(*end, acknowledgementNumber) {
+        true => false,
+        false => {
+          if sequence_lt(*start, acknowledgementNumber) {
+            *start = acknowledgementNumber;
+          }
+          true
+        }
+      },
+    );
+  }
+
+  /*
+    Whether the segment between the given sequence numbers is presumably lost : the peer has
+    SACKed data after it, but none of it. A retransmission timeout resends these along with the
+    oldest segment, instead of waiting for another timeout each.
+  */
+  pub fn is_lost(&self, start: u32, end: u32) -> bool {
+    let Some(&(_, highestSelectivelyAcknowledged)) = self.scoreboard.last()
+    else {
+      return false;
+    };
+
+    sequence_le(end, highestSelectivelyAcknowledged)
+      && !self
+        .scoreboard
+        .iter()
+        .any(|&(rangeStart, rangeEnd)| sequence_lt(start, rangeEnd) && sequence_lt(rangeStart, end))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, etherparse::TcpHeader};
+
+  fn options(elements: &[TcpOptionElement]) -> ExtensionOptions {
+    let mut tcpHeader = TcpHeader::new(1, 2, 3, 4);
+    tcpHeader.set_options(elements).unwrap();
+    let bytes = tcpHeader.to_bytes();
+
+    ExtensionOptions::of(&TcpHeaderSlice::from_slice(&bytes).unwrap())
+  }
+
+  #[test]
+  fn only_what_both_syns_offered_gets_enabled() {
+    let synACK = options(&[
+      TcpOptionElement::MaximumSegmentSize(1460),
+      TcpOptionElement::Timestamp(7, 1),
+    ]);
+
+    let mut extensions = Extensions::default();
+    assert_eq!(
+      extensions.on_syn(synACK, true),
+      [Extension::WindowScale, Extension::SelectiveAcknowledgement]
+    );
+    assert!(extensions.is_enabled(Extension::Timestamps));
+    assert!(!extensions.is_enabled(Extension::SelectiveAcknowledgement));
+    assert_eq!(extensions.peer_window(1000), 1000);
+
+    // A SYN-ACK only offers back what the SYN offered.
+    let syn = options(&[TcpOptionElement::WindowScale(20)]);
+    let mut extensions = Extensions::default();
+    assert!(extensions.on_syn(syn, false).is_empty());
+    assert_eq!(
+      extensions.syn_options(true, 5),
+      [
+        TcpOptionElement::Noop,
+        TcpOptionElement::WindowScale(WINDOW_SHIFT)
+      ]
+    );
+
+    // The shift count is capped at 14, and the scaled window at 16 bits.
+    assert_eq!(extensions.peer_window(2), 2 << 14);
+    assert_eq!(extensions.peer_window(4), u16::MAX);
+  }
+
+  #[test]
+  fn old_timestamps_get_rejected_and_missing_ones_disable_them() {
+    let mut extensions = Extensions::default();
+    extensions.on_syn(options(&[TcpOptionElement::Timestamp(100, 0)]), false);
+
+    let old = options(&[TcpOptionElement::Timestamp(99, 0)]);
+    assert_eq!(extensions.check_timestamp(&old, false), TimestampCheck::Old);
+    assert_eq!(
+      extensions.check_timestamp(&old, true),
+      TimestampCheck::Accepted
+    );
+
+    let fresh = options(&[TcpOptionElement::Timestamp(u32::MAX / 2, 0)]);
+    assert_eq!(
+      extensions.check_timestamp(&fresh, false),
+      TimestampCheck::Accepted
+    );
+    extensions.update_recent_timestamp(&fresh);
+    assert_eq!(
+      extensions.timestamp_option(3),
+      Some(TcpOptionElement::Timestamp(3, u32::MAX / 2))
+    );
+
+    let missing = options(&[]);
+    assert_eq!(
+      extensions.check_timestamp(&missing, false),
+      TimestampCheck::Missing
+    );
+    extensions.disable(Extension::Timestamps);
+    assert!(!extensions.is_enabled(Extension::Timestamps));
+    assert_eq!(extensions.options_length(), 0);
+    assert_eq!(
+      extensions.check_timestamp(&old, false),
+      TimestampCheck::Accepted
+    );
+  }
+
+  #[test]
+  fn sacked_ranges_merge_and_reveal_the_holes() {
+    let mut extensions = Extensions::default();
+    extensions.on_syn(
+      options(&[TcpOptionElement::SelectiveAcknowledgementPermitted]),
+      false,
+    );
+
+    let sack = options(&[TcpOptionElement::SelectiveAcknowledgement(
+      (300, 400),
+      [Some((500, 600)), Some((400, 450)), Some((900, 2000))],
+    )]);
+    assert!(!extensions.on_acknowledgement(&sack, true, 100, 1000));
+
+    // The block past SND.NXT got ignored.
+    assert_eq!(extensions.scoreboard, [(300, 450), (500, 600)]);
+    assert!(extensions.is_lost(100, 300));
+    assert!(extensions.is_lost(450, 500));
+    assert!(!extensions.is_lost(300, 400));
+    assert!(!extensions.is_lost(600, 700));
+
+    extensions.forget_acknowledged(350);
+    assert_eq!(extensions.scoreboard, [(350, 450), (500, 600)]);
+    extensions.forget_acknowledged(600);
+    assert!(extensions.scoreboard.is_empty());
+    assert!(!extensions.is_lost(600, 700));
+  }
+
+  #[test]
+  fn duplicate_acks_without_sack_blocks_disable_sack() {
+    let mut extensions = Extensions::default();
+    extensions.on_syn(
+      options(&[TcpOptionElement::SelectiveAcknowledgementPermitted]),
+      false,
+    );
+    let bare = options(&[]);
+    let sack = options(&[TcpOptionElement::SelectiveAcknowledgement(
+      (200, 300),
+      [None; 3],
+    )]);
+
+    // A SACK block starts the count over, and ACKs which aren't duplicates don't count.
+    assert!(!extensions.on_acknowledgement(&bare, true, 100, 300));
+    assert!(!extensions.on_acknowledgement(&bare, true, 100, 300));
+    assert!(!extensions.on_acknowledgement(&sack, true, 100, 300));
+    assert!(!extensions.on_acknowledgement(&bare, false, 100, 300));
+    assert!(!extensions.on_acknowledgement(&bare, true, 100, 300));
+    assert!(!extensions.on_acknowledgement(&bare, true, 100, 300));
+    assert!(extensions.on_acknowledgement(&bare, true, 100, 300));
+
+    assert!(!extensions.is_enabled(Extension::SelectiveAcknowledgement));
+    assert!(!extensions.is_lost(100, 200));
+    assert_eq!(extensions.selective_acknowledgement_option([(1, 2)]), None);
+  }
+}
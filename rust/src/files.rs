@@ -0,0 +1,143 @@
+use std::{
+  ffi::c_int,
+  fs::File,
+  io,
+  os::unix::fs::{MetadataExt, OpenOptionsExt},
+  path::Path,
+};
+
+/*
+  Opening the files tcpd writes into : the packet captures, the sample file and the event log.
+
+  tcpd runs as root, while those files live in world writable directories like /tmp by default,
+  where anyone may plant a symlink (or a hard link) named like one of them, pointing at some file
+  tcpd would then clobber. So they get opened without following symlinks, and refused unless
+  they're regular files with a single link, which we own.
+*/
+
+#[cfg(target_os = "linux")]
+const O_NOFOLLOW: c_int = 0o400000;
+
+// macOS and the BSDs.
+#[cfg(not(target_os = "linux"))]
+const O_NOFOLLOW: c_int = 0x100;
+
+// Files we create are only readable and writable by us.
+const MODE: u32 = 0o600;
+
+// Creates the given file, or truncates it if it's there already.
+pub fn create(path: &Path) -> io::Result<File> {
+  let file = File::options()
+    .write(true)
+    .create(true)
+    .mode(MODE)
+    .custom_flags(O_NOFOLLOW)
+    .open(path)?;
+
+  // Only truncated after the check, so that a planted hard link can't get us to wipe its target.
+  ensure_ours(&file)?;
+  file.set_len(0)?;
+
+  Ok(file)
+}
+
+// Opens the given file for appending, creating it if it isn't there yet.
+pub fn append(path: &Path) -> io::Result<File> {
+  let file = File::options()
+    .append(true)
+    .create(true)
+    .mode(MODE)
+    .custom_flags(O_NOFOLLOW)
+    .open(path)?;
+
+  ensure_ours(&file)?;
+  Ok(file)
+}
+
+fn ensure_ours(file: &File) -> io::Result<()> {
+  let metadata = file.metadata()?;
+
+  let isOurs = metadata.is_file() && metadata.nlink() == 1 && metadata.uid() == effective_uid();
+  if !isOurs {
+    return Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      "not a regular file owned by us, with a single link",
+    ));
+  }
+  Ok(())
+}
+
+fn effective_uid() -> u32 {
+  // SAFETY : geteuid( ) has no preconditions and can't fail.
+  unsafe { geteuid() }
+}
+
+extern "C" {
+  fn geteuid() -> u32;
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    std::{env, fs, io::Write, os::unix, path::PathBuf, process},
+  };
+
+  fn scratch_path(name: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("tcpd-files-test-{}-{}", process::id(), name));
+    let _ = fs::remove_file(&path);
+    path
+  }
+
+  #[test]
+  fn create_truncates_our_own_file() {
+    let path = scratch_path("own");
+    fs::write(&path, b"stale contents").unwrap();
+
+    create(&path).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn append_keeps_our_own_file() {
+    let path = scratch_path("append");
+    fs::write(&path, b"earlier ").unwrap();
+
+    append(&path).unwrap().write_all(b"later").unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"earlier later");
+
+    fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn create_refuses_symlinks() {
+    let target = scratch_path("symlink-target");
+    let link = scratch_path("symlink");
+    fs::write(&target, b"precious").unwrap();
+    unix::fs::symlink(&target, &link).unwrap();
+
+    assert!(create(&link).is_err());
+    assert!(append(&link).is_err());
+    assert_eq!(fs::read(&target).unwrap(), b"precious");
+
+    fs::remove_file(&link).unwrap();
+    fs::remove_file(&target).unwrap();
+  }
+
+  #[test]
+  fn create_refuses_hard_links() {
+    let target = scratch_path("hardlink-target");
+    let link = scratch_path("hardlink");
+    fs::write(&target, b"precious").unwrap();
+    fs::hard_link(&target, &link).unwrap();
+
+    assert!(create(&link).is_err());
+    assert!(append(&link).is_err());
+    assert_eq!(fs::read(&target).unwrap(), b"precious");
+
+    fs::remove_file(&link).unwrap();
+    fs::remove_file(&target).unwrap();
+  }
+}
@@ -0,0 +1,424 @@
+use {
+  anyhow::anyhow,
+  std::{
+    fmt::{self, Display, Formatter},
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+  },
+};
+
+/*
+  Filtering of connection requests, by their source address and destination port.
+
+  The rules are evaluated in order, and the first one matching a segment decides its fate. A
+  segment matching no rule is allowed. Rules are written like this :
+
+    allow 10.0.0.2/32 22
+    reject 10.0.0.0/25 8000-8999
+    deny 0.0.0.0/0 22
+
+  Only segments which don't belong to an existing connection get filtered. This way, changing the
+  rules never breaks connections which are already established.
+*/
+#[derive(Default)]
+pub struct PacketFilter {
+  rules: Vec<CountedFilterRule>,
+}
+
+struct CountedFilterRule {
+  rule: FilterRule,
+
+  // Number of segments this rule has decided the fate of.
+  hits: AtomicU64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FilterRule {
+  pub action: FilterAction,
+
+  pub source: Ipv4Cidr,
+
+  pub destinationPorts: PortRange,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterAction {
+  Allow,
+
+  // The segment is silently dropped.
+  Deny,
+
+  // The segment is dropped, and answered with a RST.
+  Reject,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+  pub address: Ipv4Addr,
+  pub prefixLength: u8,
+}
+
+// An inclusive range of ports. A single port is a range starting and ending with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortRange {
+  pub first: u16,
+  pub last: u16,
+}
+
+impl PacketFilter {
+  pub fn new(rules: Vec<FilterRule>) -> Self {
+    Self {
+      rules: rules.into_iter().map(CountedFilterRule::new).collect(),
+    }
+  }
+
+  // Returns the action of the first rule matching the given segment, and counts the hit.
+  pub fn evaluate(&self, source: Ipv4Addr, destinationPort: u16) -> FilterAction {
+    let Some(countedRule) = self
+      .rules
+      .iter()
+      .find(|countedRule| countedRule.rule.matches(source, destinationPort))
+    else {
+      return FilterAction::Allow;
+    };
+
+    countedRule.hits.fetch_add(1, Ordering::Relaxed);
+    countedRule.rule.action
+  }
+
+  pub fn rules(&self) -> Vec<FilterRule> {
+    self
+      .rules
+      .iter()
+      .map(|countedRule| countedRule.rule)
+      .collect()
+  }
+
+  pub fn add(&mut self, rule: FilterRule) {
+    self.rules.push(CountedFilterRule::new(rule));
+  }
+
+  // Removes the rule at the given (zero based) position. Returns false if there's no such rule.
+  pub fn remove(&mut self, index: usize) -> bool {
+    if index >= self.rules.len() {
+      return false;
+    }
+
+    self.rules.remove(index);
+    true
+  }
+}
+
+// Lists the rules in evaluation order, along with their hit counts.
+impl Display for PacketFilter {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    for (index, countedRule) in self.rules.iter().enumerate() {
+      writeln!(
+        f,
+        "{} : {} (hits {})",
+        index,
+        countedRule.rule,
+        countedRule.hits.load(Ordering::Relaxed)
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl CountedFilterRule {
+  fn new(rule: FilterRule) -> Self {
+    Self {
+      rule,
+      hits: AtomicU64::default(),
+    }
+  }
+}
+
+impl FilterRule {
+  pub fn matches(&self, source: Ipv4Addr, destinationPort: u16) -> bool {
+    self.source.contains(source) && self.destinationPorts.contains(destinationPort)
+  }
+}
+
+impl Display for FilterRule {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{} {} {}",
+      self.action, self.source, self.destinationPorts
+    )
+  }
+}
+
+// Parses a rule of the form <allow | deny | reject> <source CIDR> <destination port range>.
+impl FromStr for FilterRule {
+  type Err = anyhow::Error;
+
+  fn from_str(rule: &str) -> anyhow::Result<Self> {
+    let parts = rule.split_whitespace().collect::<Vec<_>>();
+
+    let [action, source, destinationPorts] = parts[..]
+    else {
+      return Err(anyhow!(
+        "Expected a rule of the form <allow | deny | reject> <source CIDR> <ports>, got '{}'",
+        rule
+      ));
+    };
+
+    Ok(Self {
+      action: action.parse()?,
+      source: source.parse()?,
+      destinationPorts: destinationPorts.parse()?,
+    })
+  }
+}
+
+impl Display for FilterAction {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Allow => "allow",
+      Self::Deny => "deny",
+      Self::Reject => "reject",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for FilterAction {
+  type Err = anyhow::Error;
+
+  fn from_str(action: &str) -> anyhow::Result<Self> {
+    match action {
+      "allow" => Ok(Self::Allow),
+      "deny" => Ok(Self::Deny),
+      "reject" => Ok(Self::Reject),
+      _ => Err(anyhow!(
+        "Unknown filter action '{}', expected allow, deny or reject",
+        action
+      )),
+    }
+  }
+}
+
+impl Ipv4Cidr {
+  // The given address, along with the subnet which it belongs to (like 10.0.0.1/24). None if the
+  // bits of the netmask aren't contiguous.
+  pub fn with_netmask(address: Ipv4Addr, netmask: Ipv4Addr) -> Option<Self> {
+    let netmask = u32::from(netmask);
+    if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+      return None;
+    }
+
+    Some(Self {
+      address,
+      prefixLength: netmask.leading_ones() as u8,
+    })
+  }
+
+  pub fn network(&self) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(self.address) & self.netmask())
+  }
+
+  pub fn broadcast(&self) -> Ipv4Addr {
+    Ipv4Addr::from(u32::from(self.address) | !self.netmask())
+  }
+
+  pub fn contains(&self, address: Ipv4Addr) -> bool {
+    let netmask = self.netmask();
+    u32::from(address) & netmask == u32::from(self.address) & netmask
+  }
+
+  fn netmask(&self) -> u32 {
+    // Shifting a u32 by 32 overflows, so a /0 needs special casing.
+    match self.prefixLength {
+      0 => 0,
+      prefixLength => u32::MAX << (32 - prefixLength as u32),
+    }
+  }
+}
+
+impl Display for Ipv4Cidr {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}/{}", self.address, self.prefixLength)
+  }
+}
+
+// Parses a CIDR of the form <IPv4 address>/<prefix length>, like 10.0.0.0/24. A bare address is
+// taken as a /32.
+impl FromStr for Ipv4Cidr {
+  type Err = anyhow::Error;
+
+  fn from_str(cidr: &str) -> anyhow::Result<Self> {
+    let (address, prefixLength) = cidr.split_once('/').unwrap_or((cidr, "32"));
+
+    let address = address
+      .parse::<Ipv4Addr>()
+      .map_err(|error| anyhow!("Invalid IPv4 address '{}' : {}", address, error))?;
+
+    let prefixLength = prefixLength
+      .parse::<u8>()
+      .ok()
+      .filter(|prefixLength| *prefixLength <= 32)
+      .ok_or_else(|| anyhow!("Invalid prefix length '{}', expected 0-32", prefixLength))?;
+
+    Ok(Self {
+      address,
+      prefixLength,
+    })
+  }
+}
+
+impl PortRange {
+  pub fn contains(&self, port: u16) -> bool {
+    (self.first..=self.last).contains(&port)
+  }
+}
+
+impl Display for PortRange {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    if self.first == self.last {
+      return write!(f, "{}", self.first);
+    }
+    write!(f, "{}-{}", self.first, self.last)
+  }
+}
+
+// Parses either a single port like 22, or an inclusive range of ports like 8000-8999.
+impl FromStr for PortRange {
+  type Err = anyhow::Error;
+
+  fn from_str(ports: &str) -> anyhow::Result<Self> {
+    let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+
+    let parse_port = |port: &str| {
+      port
+        .parse::<u16>()
+        .map_err(|error| anyhow!("Invalid port '{}' : {}", port, error))
+    };
+    let (first, last) = (parse_port(first)?, parse_port(last)?);
+
+    if first > last {
+      return Err(anyhow!("Invalid port range '{}' : it's empty", ports));
+    }
+    Ok(Self { first, last })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn address(address: &str) -> Ipv4Addr {
+    address.parse().unwrap()
+  }
+
+  #[test]
+  fn cidrs_parse_and_match() {
+    let cidr: Ipv4Cidr = "10.0.0.0/25".parse().unwrap();
+    assert_eq!(cidr.prefixLength, 25);
+    assert!(cidr.contains(address("10.0.0.127")));
+    assert!(!cidr.contains(address("10.0.0.128")));
+    assert_eq!(cidr.to_string(), "10.0.0.0/25");
+
+    let everyone: Ipv4Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(everyone.contains(address("255.255.255.255")));
+
+    let host: Ipv4Cidr = "10.0.0.2".parse().unwrap();
+    assert_eq!(host.prefixLength, 32);
+    assert!(host.contains(address("10.0.0.2")));
+    assert!(!host.contains(address("10.0.0.3")));
+
+    assert!("10.0.0.0/33".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0.0/".parse::<Ipv4Cidr>().is_err());
+    assert!("10.0.0/24".parse::<Ipv4Cidr>().is_err());
+  }
+
+  #[test]
+  fn cidrs_from_netmasks() {
+    let cidr = Ipv4Cidr::with_netmask(address("10.0.0.1"), address("255.255.255.0")).unwrap();
+    assert_eq!(cidr.prefixLength, 24);
+    assert_eq!(cidr.network(), address("10.0.0.0"));
+    assert_eq!(cidr.broadcast(), address("10.0.0.255"));
+
+    let everyone = Ipv4Cidr::with_netmask(address("10.0.0.1"), address("0.0.0.0")).unwrap();
+    assert_eq!(everyone.broadcast(), address("255.255.255.255"));
+
+    assert_eq!(
+      Ipv4Cidr::with_netmask(address("10.0.0.1"), address("255.0.255.0")),
+      None
+    );
+  }
+
+  #[test]
+  fn port_ranges_parse_and_match() {
+    let range: PortRange = "8000-8999".parse().unwrap();
+    assert!(range.contains(8000) && range.contains(8999));
+    assert!(!range.contains(7999) && !range.contains(9000));
+    assert_eq!(range.to_string(), "8000-8999");
+
+    let port: PortRange = "22".parse().unwrap();
+    assert_eq!(
+      port,
+      PortRange {
+        first: 22,
+        last: 22
+      }
+    );
+    assert_eq!(port.to_string(), "22");
+
+    assert!("9000-8000".parse::<PortRange>().is_err());
+    assert!("65536".parse::<PortRange>().is_err());
+    assert!("1-".parse::<PortRange>().is_err());
+  }
+
+  #[test]
+  fn rules_round_trip_through_their_text_form() {
+    for text in [
+      "allow 10.0.0.2/32 22",
+      "reject 10.0.0.0/25 8000-8999",
+      "deny 0.0.0.0/0 1-65535",
+    ] {
+      assert_eq!(text.parse::<FilterRule>().unwrap().to_string(), text);
+    }
+
+    assert!("drop 10.0.0.0/8 22".parse::<FilterRule>().is_err());
+    assert!("allow 10.0.0.0/8".parse::<FilterRule>().is_err());
+    assert!("allow 10.0.0.0/8 22 extra".parse::<FilterRule>().is_err());
+  }
+
+  #[test]
+  fn the_first_matching_rule_decides() {
+    let filter = PacketFilter::new(
+      [
+        "allow 10.0.0.2/32 22",
+        "reject 10.0.0.0/24 22",
+        "deny 0.0.0.0/0 22",
+      ]
+      .into_iter()
+      .map(|rule| rule.parse().unwrap())
+      .collect(),
+    );
+
+    assert_eq!(
+      filter.evaluate(address("10.0.0.2"), 22),
+      FilterAction::Allow
+    );
+    assert_eq!(
+      filter.evaluate(address("10.0.0.3"), 22),
+      FilterAction::Reject
+    );
+    assert_eq!(filter.evaluate(address("10.0.1.3"), 22), FilterAction::Deny);
+    assert_eq!(
+      filter.evaluate(address("10.0.1.3"), 80),
+      FilterAction::Allow
+    );
+
+    assert_eq!(
+      filter.to_string(),
+      "0 : allow 10.0.0.2/32 22 (hits 1)\n1 : reject 10.0.0.0/24 22 (hits 1)\n2 : deny \
+       0.0.0.0/0 22 (hits 1)\n"
+    );
+  }
+}
@@ -0,0 +1,115 @@
+use crate::tcp::ConnectionQuad;
+
+/*
+  Verifiable stream data : pseudo-random bytes, each determined by the seed and its offset in the
+  stream. Whatever gets read back can then be checked against where it should sit in the stream,
+  so a corrupted, duplicated, dropped or misplaced byte shows up at the first offset where the
+  stream diverges. Comparing lengths alone catches none of these.
+
+  Unlike a repeating pattern, a run of bytes shifted by a few positions doesn't match by accident.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamPattern {
+  seed: u64,
+}
+
+impl StreamPattern {
+  pub fn new(seed: u64) -> Self {
+    Self { seed }
+  }
+
+  // Seeded from the connection quad, so that no two connections carry the same bytes.
+  pub fn for_connection(quad: &ConnectionQuad) -> Self {
+    let addresses =
+      (u32::from(quad.source.address) as u64) << 32 | u32::from(quad.destiation.address) as u64;
+    let ports = (quad.source.port as u64) << 16 | quad.destiation.port as u64;
+
+    Self::new(mix(addresses) ^ ports)
+  }
+
+  pub fn byte_at(&self, offset: u64) -> u8 {
+    // Every 8 bytes of the stream come from hashing their position.
+    let word = mix(self.seed ^ (offset / 8));
+    (word >> ((offset % 8) * 8)) as u8
+  }
+
+  // The given number of bytes of the stream, starting at the given offset.
+  pub fn generate(&self, offset: u64, length: usize) -> Vec<u8> {
+    (0..length as u64)
+      .map(|index| self.byte_at(offset + index))
+      .collect()
+  }
+
+  // The stream offset of the first byte which doesn't match, of the data expected to start at the
+  // given offset. None if all of it matches.
+  pub fn first_divergence(&self, offset: u64, data: &[u8]) -> Option<u64> {
+    (offset..)
+      .zip(data)
+      .find(|(offset, byte)| self.byte_at(*offset) != **byte)
+      .map(|(offset, _)| offset)
+  }
+}
+
+// SplitMix64's finalizer, which spreads every bit of the input over the whole output.
+fn mix(mut value: u64) -> u64 {
+  value = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+  value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+  value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+  value ^ (value >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bytes_depend_only_on_the_seed_and_their_offset() {
+    let pattern = StreamPattern::new(42);
+    let stream = pattern.generate(0, 1000);
+
+    assert_eq!(pattern.generate(123, 400), stream[123..523]);
+    assert_eq!(StreamPattern::new(42).generate(0, 1000), stream);
+    assert_ne!(StreamPattern::new(43).generate(0, 1000), stream);
+  }
+
+  #[test]
+  fn divergences_are_found_at_their_offset() {
+    let pattern = StreamPattern::new(7);
+    let mut data = pattern.generate(5000, 100);
+    assert_eq!(pattern.first_divergence(5000, &data), None);
+
+    data[37] ^= 1;
+    assert_eq!(pattern.first_divergence(5000, &data), Some(5037));
+
+    // Data misplaced by a single byte diverges right away.
+    let shifted = pattern.generate(5001, 100);
+    assert_eq!(pattern.first_divergence(5000, &shifted), Some(5000));
+  }
+
+  #[test]
+  fn shifted_runs_do_not_match_by_accident() {
+    let pattern = StreamPattern::new(0);
+    let stream = pattern.generate(0, 4096);
+
+    for shift in 1..64 {
+      let matching = stream
+        .iter()
+        .zip(&stream[shift..])
+        .filter(|(a, b)| a == b)
+        .count();
+      // About 1 byte in 256 matches by chance.
+      assert!(matching < 64, "shift {} matched {} bytes", shift, matching);
+    }
+  }
+
+  #[test]
+  fn connections_get_their_own_streams() {
+    let quad = |quad: &str| StreamPattern::for_connection(&quad.parse().unwrap());
+
+    let pattern = quad("10.0.0.2:51514 10.0.0.1:8080");
+    assert_eq!(pattern, quad("10.0.0.2:51514 10.0.0.1:8080"));
+    assert_ne!(pattern, quad("10.0.0.2:51515 10.0.0.1:8080"));
+    assert_ne!(pattern, quad("10.0.0.3:51514 10.0.0.1:8080"));
+    assert_ne!(pattern, quad("10.0.0.1:8080 10.0.0.2:51514"));
+  }
+}
@@ -0,0 +1,1086 @@
+use {
+  crate::{
+    clock::{Clock, SystemClock},
+    filter::{FilterRule, Ipv4Cidr},
+    lifecycle::{DrainPolicy, InterfaceState},
+    manager::{assert_send_sync, ConnectionManager, ListenerOptions, TICK_INTERVAL},
+    nic::{self, Nic, NicDevice, NicSendPolicy},
+    refusal::RefusalPolicy,
+    sampler::SamplerConfig,
+    send_buffer::{CHUNK_SIZE, SEND_BUFFER_CAPACITY},
+    stats::TcpCounters,
+    tcp::{self, ConnectionQuad, Location},
+    tuning::{StuckStateThresholds, TcpTuning},
+  },
+  anyhow::anyhow,
+  etherparse::IpNumber,
+  std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter},
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+  },
+};
+
+#[derive(Clone)]
+pub struct InterfaceConfig {
+  pub name: String,
+
+  pub address: Ipv4Addr,
+
+  /*
+    Range of IPs that are considered "directly reachable" via this interface. This tells your OS :
+    if you're sending a packet to anything in 10.0.0.0/24, route it through utun4.
+  */
+  pub netmask: Ipv4Addr,
+
+  // The point-to-point destination of the vNIC. Defaults to the broadcast address of the subnet.
+  pub destination: Option<Ipv4Addr>,
+
+  // Largest IP packet the vNIC carries, which the MSS of new connections gets derived from.
+  pub mtu: u16,
+
+  // Addresses outside the subnet which the interface answers for too.
+  pub aliases: Vec<Ipv4Addr>,
+
+  pub tuning: TcpTuning,
+
+  // Packet filter rules, in evaluation order.
+  pub filterRules: Vec<FilterRule>,
+
+  // What happens to segments which can't be written to the vNIC right away.
+  pub sendPolicy: NicSendPolicy,
+
+  // How the interface gets drained, before the daemon exits.
+  pub drainPolicy: DrainPolicy,
+
+  // How refused connection requests get answered, for each cause.
+  pub refusalPolicy: RefusalPolicy,
+
+  // Where and how often the connections picked via the control socket get sampled.
+  pub samplerConfig: SamplerConfig,
+}
+
+impl Default for InterfaceConfig {
+  fn default() -> Self {
+    Self {
+      name: "utun4".to_string(),
+      address: Ipv4Addr::new(10, 0, 0, 1),
+      netmask: Ipv4Addr::new(255, 255, 255, 0),
+      destination: None,
+      mtu: 1500,
+      aliases: Vec::new(),
+      tuning: TcpTuning::default(),
+      filterRules: Vec::new(),
+      sendPolicy: NicSendPolicy::default(),
+      drainPolicy: DrainPolicy::default(),
+      refusalPolicy: RefusalPolicy::default(),
+      samplerConfig: SamplerConfig::default(),
+    }
+  }
+}
+
+impl InterfaceConfig {
+  // Shortest and longest prefixes the vNIC may be configured with. A /31 or a /32 leaves no room
+  // for a host address besides the network and broadcast ones.
+  pub const PREFIX_LENGTHS: RangeInclusive<u8> = 8..=30;
+
+  /*
+    The subnet of the vNIC, computed from its address and netmask. Fails unless the netmask is
+    within PREFIX_LENGTHS, and the address is a host address of the subnet (neither the network
+    nor the broadcast address). A configured destination must be within the subnet too, other
+    than its network address and the address of the vNIC.
+  */
+  pub fn subnet(&self) -> anyhow::Result<Ipv4Cidr> {
+    let subnet = Ipv4Cidr::with_netmask(self.address, self.netmask)
+      .ok_or_else(|| anyhow!("netmask {} : bits must be contiguous", self.netmask))?;
+
+    if !Self::PREFIX_LENGTHS.contains(&subnet.prefixLength) {
+      return Err(anyhow!(
+        "netmask {} : prefix /{} must be between /{} and /{}",
+        self.netmask,
+        subnet.prefixLength,
+        Self::PREFIX_LENGTHS.start(),
+        Self::PREFIX_LENGTHS.end()
+      ));
+    }
+
+    if self.address == subnet.network() || self.address == subnet.broadcast() {
+      return Err(anyhow!(
+        "address {} : must be a host address of {}, not its network or broadcast address",
+        self.address,
+        subnet
+      ));
+    }
+
+    if let Some(destination) = self.destination {
+      if !subnet.contains(destination)
+        || destination == subnet.network()
+        || destination == self.address
+      {
+        return Err(anyhow!(
+          "destination {} : must be an address of {}, other than {} and the network address",
+          destination,
+          subnet,
+          self.address
+        ));
+      }
+    }
+
+    Ok(subnet)
+  }
+}
+
+/*
+  Everything needed to recreate an Interface : the vNIC configuration and the listeners. Live
+  connections are deliberately not a part of it.
+
+  It's stored in a TOML file like this :
+
+    name = "utun4"
+    address = "10.0.0.1"
+    netmask = "255.255.255.0"
+    destination = "10.0.0.255"
+    mtu = 1500
+    aliases = ["192.168.50.7"]
+    peer_violation_policy = "lenient"
+    user_timeout_ms = 30000
+    expected_connections = 10000
+    receive_coalescing_budget_us = 1000
+    receive_coalescing_threshold = 4096
+    send_buffer_capacity = 65536
+    send_low_watermark = 16384
+    fin_wait_2_timeout_ms = 60000
+    stuck_state_thresholds = ["FIN-WAIT-2 600000", "CLOSE-WAIT off"]
+    control_segments_when_queue_full = "retry"
+    data_segments_when_queue_full = "drop"
+    queue_full_retry_timeout_ms = 10
+    drain_deadline_ms = 30000
+    drain_deadline_action = "close"
+    refusals = ["draining reset", "rate-limited defer", "closed-port reset"]
+    sample_file = "/tmp/tcpd-samples.csv"
+    sample_format = "csv"
+    sample_interval_ms = 1000
+    listeners = [8080, 9090]
+    accept_queues = ["9090 16 abort-oldest", "8080 128 refuse-newest rate 100/10"]
+    filter_rules = ["deny 10.0.0.0/25 22", "allow 0.0.0.0/0 1-65535"]
+*/
+#[derive(Default)]
+pub struct InterfaceSnapshot {
+  pub config: InterfaceConfig,
+
+  pub listeningPorts: Vec<u16>,
+
+  // Accept queue options of the listeners which don't use the default ones, as
+  // "<port> <backlog> <overflow policy>".
+  pub listenerOptions: Vec<(u16, ListenerOptions)>,
+}
+
+impl Display for InterfaceSnapshot {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let listeningPorts = self
+      .listeningPorts
+      .iter()
+      .map(u16::to_string)
+      .collect::<Vec<_>>()
+      .join(", ");
+
+    writeln!(f, "name = \"{}\"", self.config.name)?;
+    writeln!(f, "address = \"{}\"", self.config.address)?;
+    writeln!(f, "netmask = \"{}\"", self.config.netmask)?;
+    if let Some(destination) = self.config.destination {
+      writeln!(f, "destination = \"{}\"", destination)?;
+    }
+    writeln!(f, "mtu = {}", self.config.mtu)?;
+    if !self.config.aliases.is_empty() {
+      let aliases = self
+        .config
+        .aliases
+        .iter()
+        .map(|alias| format!("\"{}\"", alias))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+      writeln!(f, "aliases = [{}]", aliases)?;
+    }
+    writeln!(
+      f,
+      "peer_violation_policy = \"{}\"",
+      self.config.tuning.peerViolationPolicy
+    )?;
+    if let Some(userTimeout) = self.config.tuning.userTimeout {
+      writeln!(f, "user_timeout_ms = {}", userTimeout.as_millis())?;
+    }
+    if let Some(expectedConnections) = self.config.tuning.expectedConnections {
+      writeln!(f, "expected_connections = {}", expectedConnections)?;
+    }
+    if let Some(receiveCoalescing) = self.config.tuning.receiveCoalescing {
+      writeln!(
+        f,
+        "receive_coalescing_budget_us = {}",
+        receiveCoalescing.budget.as_micros()
+      )?;
+      writeln!(
+        f,
+        "receive_coalescing_threshold = {}",
+        receiveCoalescing.threshold
+      )?;
+    }
+
+    if let Some(sendBufferCapacity) = self.config.tuning.sendBufferCapacity {
+      writeln!(f, "send_buffer_capacity = {}", sendBufferCapacity)?;
+    }
+    if let Some(sendLowWatermark) = self.config.tuning.sendLowWatermark {
+      writeln!(f, "send_low_watermark = {}", sendLowWatermark)?;
+    }
+
+    // 0 disables the FIN-WAIT-2 timeout.
+    let finWait2Timeout = self.config.tuning.finWait2Timeout.unwrap_or_default();
+    writeln!(f, "fin_wait_2_timeout_ms = {}", finWait2Timeout.as_millis())?;
+
+    let stuckStateThresholds = self.config.tuning.stuckStateThresholds;
+    if stuckStateThresholds != StuckStateThresholds::default() {
+      let entries = StuckStateThresholds::STATES
+        .iter()
+        .map(|state| match stuckStateThresholds.threshold(*state) {
+          Some(threshold) => format!("\"{} {}\"", state, threshold.as_millis()),
+          None => format!("\"{} off\"", state),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+      writeln!(f, "stuck_state_thresholds = [{}]", entries)?;
+    }
+
+    let sendPolicy = &self.config.sendPolicy;
+    writeln!(
+      f,
+      "control_segments_when_queue_full = \"{}\"",
+      sendPolicy.controlSegments
+    )?;
+    writeln!(
+      f,
+      "data_segments_when_queue_full = \"{}\"",
+      sendPolicy.dataSegments
+    )?;
+    writeln!(
+      f,
+      "queue_full_retry_timeout_ms = {}",
+      sendPolicy.retryTimeout.as_millis()
+    )?;
+
+    let drainPolicy = &self.config.drainPolicy;
+    writeln!(
+      f,
+      "drain_deadline_ms = {}",
+      drainPolicy.deadline.as_millis()
+    )?;
+    writeln!(f, "drain_deadline_action = \"{}\"", drainPolicy.atDeadline)?;
+
+    let refusals = self
+      .config
+      .refusalPolicy
+      .entries()
+      .map(|entry| format!("\"{}\"", entry))
+      .collect::<Vec<_>>()
+      .join(", ");
+    writeln!(f, "refusals = [{}]", refusals)?;
+
+    let samplerConfig = &self.config.samplerConfig;
+    writeln!(f, "sample_file = \"{}\"", samplerConfig.path.display())?;
+    writeln!(f, "sample_format = \"{}\"", samplerConfig.format)?;
+    writeln!(
+      f,
+      "sample_interval_ms = {}",
+      samplerConfig.interval.as_millis()
+    )?;
+
+    writeln!(f, "listeners = [{}]", listeningPorts)?;
+
+    if !self.listenerOptions.is_empty() {
+      let listenerOptions = self
+        .listenerOptions
+        .iter()
+        .map(|(port, options)| format!("\"{} {}\"", port, options))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+      writeln!(f, "accept_queues = [{}]", listenerOptions)?;
+    }
+
+    if !self.config.filterRules.is_empty() {
+      let filterRules = self
+        .config
+        .filterRules
+        .iter()
+        .map(|rule| format!("\"{}\"", rule))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+      writeln!(f, "filter_rules = [{}]", filterRules)?;
+    }
+    Ok(())
+  }
+}
+
+// Parses the TOML written by Display. Keys which are missing keep their default values. Every
+// invalid line gets reported, instead of just the first one.
+impl FromStr for InterfaceSnapshot {
+  type Err = anyhow::Error;
+
+  fn from_str(file: &str) -> anyhow::Result<Self> {
+    let mut snapshot = Self::default();
+    let mut errors = Vec::new();
+
+    for (index, line) in file.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      if let Err(error) = snapshot.parse_line(line) {
+        errors.push(format!("line {} : {}", index + 1, error));
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(anyhow!(
+        "Invalid configuration :\n  {}",
+        errors.join("\n  ")
+      ));
+    }
+    Ok(snapshot)
+  }
+}
+
+impl InterfaceSnapshot {
+  fn parse_line(&mut self, line: &str) -> anyhow::Result<()> {
+    let (key, value) = line
+      .split_once('=')
+      .ok_or_else(|| anyhow!("Expected a line of the form <key> = <value>"))?;
+    let value = value.trim();
+
+    match key.trim() {
+      "name" => self.config.name = parse_string(value)?.to_string(),
+      "address" => self.config.address = parse_address(value)?,
+      "netmask" => self.config.netmask = parse_address(value)?,
+      "destination" => self.config.destination = Some(parse_address(value)?),
+      "mtu" => {
+        self.config.mtu = value
+          .parse::<u16>()
+          .map_err(|error| anyhow!("Invalid MTU '{}' : {}", value, error))?;
+      }
+      "aliases" => {
+        self.config.aliases = parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|alias| !alias.is_empty())
+          .map(parse_address)
+          .collect::<anyhow::Result<_>>()?;
+      }
+
+      "peer_violation_policy" => {
+        self.config.tuning.peerViolationPolicy = parse_string(value)?.parse()?
+      }
+
+      "user_timeout_ms" => {
+        let milliseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid user timeout '{}' : {}", value, error))?;
+
+        self.config.tuning.userTimeout = Some(Duration::from_millis(milliseconds));
+      }
+
+      "expected_connections" => {
+        let expectedConnections = value
+          .parse::<usize>()
+          .map_err(|error| anyhow!("Invalid expected connections '{}' : {}", value, error))?;
+
+        self.config.tuning.expectedConnections = Some(expectedConnections);
+      }
+
+      "control_segments_when_queue_full" => {
+        self.config.sendPolicy.controlSegments = parse_string(value)?.parse()?
+      }
+      "data_segments_when_queue_full" => {
+        self.config.sendPolicy.dataSegments = parse_string(value)?.parse()?
+      }
+      "queue_full_retry_timeout_ms" => {
+        let milliseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid retry timeout '{}' : {}", value, error))?;
+
+        self.config.sendPolicy.retryTimeout = Duration::from_millis(milliseconds);
+      }
+
+      // Superseded by refusals, but still read from older configuration files.
+      "drain_new_connections" => {
+        self.config.refusalPolicy.draining = parse_string(value)?.parse()?
+      }
+      "drain_deadline_ms" => {
+        let milliseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid drain deadline '{}' : {}", value, error))?;
+
+        self.config.drainPolicy.deadline = Duration::from_millis(milliseconds);
+      }
+      "drain_deadline_action" => {
+        self.config.drainPolicy.atDeadline = parse_string(value)?.parse()?
+      }
+
+      "sample_file" => self.config.samplerConfig.path = parse_string(value)?.into(),
+      "sample_format" => self.config.samplerConfig.format = parse_string(value)?.parse()?,
+      "sample_interval_ms" => {
+        let milliseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid sample interval '{}' : {}", value, error))?;
+
+        self.config.samplerConfig.interval = Duration::from_millis(milliseconds);
+      }
+
+      // Either key enables receive coalescing, with the other one keeping its default.
+      "receive_coalescing_budget_us" => {
+        let microseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid coalescing budget '{}' : {}", value, error))?;
+
+        self
+          .config
+          .tuning
+          .receiveCoalescing
+          .get_or_insert_default()
+          .budget = Duration::from_micros(microseconds);
+      }
+      "receive_coalescing_threshold" => {
+        let threshold = value
+          .parse::<usize>()
+          .map_err(|error| anyhow!("Invalid coalescing threshold '{}' : {}", value, error))?;
+
+        self
+          .config
+          .tuning
+          .receiveCoalescing
+          .get_or_insert_default()
+          .threshold = threshold;
+      }
+
+      "send_buffer_capacity" => {
+        let sendBufferCapacity = value
+          .parse::<usize>()
+          .map_err(|error| anyhow!("Invalid send buffer capacity '{}' : {}", value, error))?;
+
+        self.config.tuning.sendBufferCapacity = Some(sendBufferCapacity);
+      }
+
+      "send_low_watermark" => {
+        let sendLowWatermark = value
+          .parse::<usize>()
+          .map_err(|error| anyhow!("Invalid send low watermark '{}' : {}", value, error))?;
+
+        self.config.tuning.sendLowWatermark = Some(sendLowWatermark);
+      }
+
+      "fin_wait_2_timeout_ms" => {
+        let milliseconds = value
+          .parse::<u64>()
+          .map_err(|error| anyhow!("Invalid FIN-WAIT-2 timeout '{}' : {}", value, error))?;
+
+        self.config.tuning.finWait2Timeout =
+          Some(Duration::from_millis(milliseconds)).filter(|timeout| !timeout.is_zero());
+      }
+
+      "refusals" => {
+        for entry in parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|entry| !entry.is_empty())
+        {
+          self.config.refusalPolicy.set(parse_string(entry)?)?;
+        }
+      }
+
+      "stuck_state_thresholds" => {
+        for entry in parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|entry| !entry.is_empty())
+        {
+          self
+            .config
+            .tuning
+            .stuckStateThresholds
+            .set(parse_string(entry)?)?;
+        }
+      }
+
+      "listeners" => {
+        self.listeningPorts = parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|port| !port.is_empty())
+          .map(|port| {
+            port
+              .parse::<u16>()
+              .map_err(|error| anyhow!("Invalid port '{}' : {}", port, error))
+          })
+          .collect::<anyhow::Result<_>>()?;
+      }
+
+      "accept_queues" => {
+        self.listenerOptions = parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|entry| !entry.is_empty())
+          .map(|entry| {
+            let entry = parse_string(entry)?;
+            let (port, options) = entry.split_once(' ').ok_or_else(|| {
+              anyhow!("Expected <port> <backlog> <overflow policy> [rate <rate limit>]")
+            })?;
+
+            let port = port
+              .parse::<u16>()
+              .map_err(|error| anyhow!("Invalid port '{}' : {}", port, error))?;
+            Ok((port, options.parse()?))
+          })
+          .collect::<anyhow::Result<_>>()?;
+      }
+
+      "filter_rules" => {
+        self.config.filterRules = parse_array(value)?
+          .split(',')
+          .map(str::trim)
+          .filter(|rule| !rule.is_empty())
+          .map(|rule| parse_string(rule)?.parse())
+          .collect::<anyhow::Result<_>>()?;
+      }
+
+      key => return Err(anyhow!("Unknown key '{}'", key)),
+    }
+
+    Ok(())
+  }
+
+  // Reports every problem which would prevent the snapshot from being restored, one per item.
+  fn validate(&self) -> anyhow::Result<()> {
+    let mut errors = Vec::new();
+
+    if self.config.name.is_empty() {
+      errors.push("name : must not be empty".to_string());
+    }
+
+    if !is_unicast(self.config.address) {
+      errors.push(format!(
+        "address {} : must be a unicast address",
+        self.config.address
+      ));
+    }
+
+    let subnet = self.config.subnet();
+    if let Err(error) = &subnet {
+      errors.push(error.to_string());
+    }
+
+    if !nic::MTUS.contains(&self.config.mtu) {
+      errors.push(format!(
+        "mtu {} : must be between {} and {}",
+        self.config.mtu,
+        nic::MTUS.start(),
+        nic::MTUS.end()
+      ));
+    }
+
+    let mut aliases = HashSet::new();
+    for alias in &self.config.aliases {
+      if tcp::is_martian_address(*alias) {
+        errors.push(format!("alias {} : must be a unicast address", alias));
+      }
+      else if subnet.as_ref().is_ok_and(|subnet| subnet.contains(*alias)) {
+        errors.push(format!("alias {} : is within the subnet already", alias));
+      }
+      else if !aliases.insert(alias) {
+        errors.push(format!("alias {} : is listed more than once", alias));
+      }
+    }
+
+    if self.config.samplerConfig.interval < TICK_INTERVAL {
+      errors.push(format!(
+        "sample_interval_ms : must be at least the tick interval of {}",
+        TICK_INTERVAL.as_millis()
+      ));
+    }
+
+    if self.config.tuning.userTimeout == Some(Duration::ZERO) {
+      errors.push("user_timeout_ms : must be positive".to_string());
+    }
+
+    let sendBufferCapacity = self
+      .config
+      .tuning
+      .sendBufferCapacity
+      .unwrap_or(SEND_BUFFER_CAPACITY);
+    if sendBufferCapacity == 0 || !sendBufferCapacity.is_multiple_of(CHUNK_SIZE) {
+      errors.push(format!(
+        "send_buffer_capacity : must be a positive multiple of {}",
+        CHUNK_SIZE
+      ));
+    }
+
+    if let Some(sendLowWatermark) = self.config.tuning.sendLowWatermark {
+      if !(1..=sendBufferCapacity).contains(&sendLowWatermark) {
+        errors.push(format!(
+          "send_low_watermark : must be between 1 and the send buffer capacity of {}",
+          sendBufferCapacity
+        ));
+      }
+    }
+
+    let mut listeningPorts = HashSet::new();
+    for port in &self.listeningPorts {
+      if *port == 0 {
+        errors.push("listener 0 : not a valid port".to_string());
+      }
+      else if !listeningPorts.insert(port) {
+        errors.push(format!("listener {} : port is bound more than once", port));
+      }
+    }
+
+    for (port, _) in &self.listenerOptions {
+      if !listeningPorts.contains(port) {
+        errors.push(format!(
+          "accept queue {} : nobody listens on the port",
+          port
+        ));
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(anyhow!(
+        "Invalid interface snapshot :\n  {}",
+        errors.join("\n  ")
+      ));
+    }
+    Ok(())
+  }
+}
+
+pub(crate) fn parse_string(value: &str) -> anyhow::Result<&str> {
+  value
+    .strip_prefix('"')
+    .and_then(|value| value.strip_suffix('"'))
+    .ok_or_else(|| anyhow!("Expected a quoted string, got {}", value))
+}
+
+pub(crate) fn parse_array(value: &str) -> anyhow::Result<&str> {
+  value
+    .strip_prefix('[')
+    .and_then(|value| value.strip_suffix(']'))
+    .ok_or_else(|| anyhow!("Expected an array, got {}", value))
+}
+
+fn parse_address(value: &str) -> anyhow::Result<Ipv4Addr> {
+  let address = parse_string(value)?;
+
+  address
+    .parse()
+    .map_err(|error| anyhow!("Invalid IPv4 address '{}' : {}", address, error))
+}
+
+fn is_unicast(address: Ipv4Addr) -> bool {
+  !(address.is_unspecified() || address.is_broadcast() || address.is_multicast())
+}
+
+// The vNIC, along with the connections flowing through it. Clones share both, and can be handed to
+// other threads.
+#[derive(Clone)]
+pub struct Interface {
+  config: InterfaceConfig,
+
+  nic: Arc<Nic>,
+
+  connectionManager: Arc<ConnectionManager>,
+}
+
+const _: () = assert_send_sync::<Interface>();
+
+impl Interface {
+  pub fn new(config: InterfaceConfig) -> anyhow::Result<Self> {
+    /*
+      TUN and TAP are kernel virtual network devices.
+
+      TUN, namely network TUNnel (acts like a virtual Network Interface Card), simulates a network
+      layer device and operates in layer 3 carrying IP packets. TUN is used with routing.
+
+      TAP, namely network TAP (acts like a virtual Ethernet cable), simulates a link layer device
+      and operates in layer 2 carrying Ethernet frames. TAP can be used to create a user space
+      network bridge.
+
+      Packets sent by an operating system via a TUN/TAP device, are delivered to a user space
+      program which attaches itself to the device.
+      A user space program may also pass packets into a TUN/TAP device. In this case the TUN/TAP
+      device delivers (or injects) these packets to the operating-system network stack thus
+      emulating their reception from an external source.
+
+      REFERENCE : https://en.wikipedia.org/wiki/TUN/TAP
+    */
+
+    let mut vNICConfig = tun::Configuration::default();
+    vNICConfig
+      .tun_name(&config.name)
+      .address(config.address)
+      .netmask(config.netmask)
+      .destination(config.destination.unwrap_or(config.subnet()?.broadcast()))
+      .mtu(config.mtu)
+      .up();
+
+    let device = tun::create(&vNICConfig)?;
+    Self::with_device(config, device)
+  }
+
+  // Creates the interface over the given device, instead of a TUN device. The device is expected
+  // to already carry the address and the MTU from the configuration.
+  pub fn with_device(
+    config: InterfaceConfig,
+    device: impl NicDevice + 'static,
+  ) -> anyhow::Result<Self> {
+    Self::with_device_and_clock(config, device, Arc::new(SystemClock))
+  }
+
+  // Like with_device( ), but with the timers running on the given clock.
+  pub fn with_device_and_clock(
+    config: InterfaceConfig,
+    device: impl NicDevice + 'static,
+    clock: Arc<dyn Clock>,
+  ) -> anyhow::Result<Self> {
+    let subnet = config.subnet()?;
+
+    let nic = Arc::new(Nic::new(device, config.mtu, config.sendPolicy));
+
+    let connectionManager = Arc::new(
+      ConnectionManager::new(
+        nic.clone(),
+        subnet,
+        config.tuning,
+        config.filterRules.clone(),
+        config.drainPolicy,
+        config.refusalPolicy,
+        config.samplerConfig.clone(),
+      )
+      .with_clock(clock),
+    );
+    for alias in &config.aliases {
+      connectionManager.add_alias(*alias)?;
+    }
+
+    Ok(Self {
+      config,
+      nic,
+      connectionManager,
+    })
+  }
+
+  // Rebuilds the vNIC from the given snapshot, and re-binds all of its listeners.
+  pub fn from_snapshot(snapshot: InterfaceSnapshot) -> anyhow::Result<Self> {
+    snapshot.validate()?;
+
+    let interface = Self::new(snapshot.config)?;
+    for port in snapshot.listeningPorts {
+      let options = snapshot
+        .listenerOptions
+        .iter()
+        .find(|(optionsPort, _)| *optionsPort == port)
+        .map(|(_, options)| *options)
+        .unwrap_or_default();
+
+      interface.connectionManager.listen_with(port, options);
+    }
+
+    Ok(interface)
+  }
+
+  pub fn snapshot_config(&self) -> InterfaceSnapshot {
+    InterfaceSnapshot {
+      config: InterfaceConfig {
+        mtu: self.nic.mtu(),
+        aliases: self.connectionManager.aliases(),
+        filterRules: self.connectionManager.filter_rules(),
+        ..self.config.clone()
+      },
+      listeningPorts: self.connectionManager.listening_ports(),
+      listenerOptions: self.connectionManager.listener_options(),
+    }
+  }
+
+  pub fn nic(&self) -> &Nic {
+    &self.nic
+  }
+
+  pub fn name(&self) -> &str {
+    &self.config.name
+  }
+
+  // The address of the vNIC, followed by the aliases added so far.
+  pub fn local_addrs(&self) -> Vec<Ipv4Addr> {
+    self.connectionManager.local_addresses()
+  }
+
+  pub fn mtu(&self) -> u16 {
+    self.nic.mtu()
+  }
+
+  // Starts answering for the given address too. See ConnectionManager::add_alias( ).
+  pub fn add_alias(&self, address: Ipv4Addr) -> anyhow::Result<()> {
+    self.connectionManager.add_alias(address)
+  }
+
+  // Stops answering for the given alias. Returns false if there's no such alias.
+  pub fn remove_alias(&self, address: Ipv4Addr) -> bool {
+    self.connectionManager.remove_alias(address)
+  }
+
+  /*
+    Changes the MTU of the vNIC. Connections opened afterwards get an MSS derived from it, while
+    those already open keep the one they started with. The packet thread grows its read buffer
+    before reading the next packet, should it no longer fit one.
+  */
+  pub fn set_mtu(&self, mtu: u16) -> anyhow::Result<()> {
+    self.nic.set_mtu(mtu)
+  }
+
+  pub fn state(&self) -> InterfaceState {
+    self.connectionManager.state()
+  }
+
+  // Why the vNIC failed, if it did. The interface is stopped then.
+  pub fn nic_failure(&self) -> Option<String> {
+    self.connectionManager.nic_failure()
+  }
+
+  // The TCP counters summed up over every connection of the interface.
+  pub fn tcp_counters(&self) -> &TcpCounters {
+    self.connectionManager.tcp_counters()
+  }
+
+  pub fn connection_manager(&self) -> &Arc<ConnectionManager> {
+    &self.connectionManager
+  }
+
+  // Reads packets from the vNIC and hands the TCP segments over to the connections. Only returns
+  // once the vNIC fails.
+  pub fn process_packets(&self) -> anyhow::Result<()> {
+    // Sized to the MTU, so that a packet never gets truncated.
+    let mut buffer = vec![0u8; self.mtu() as usize];
+
+    loop {
+      // The MTU may have been raised through the control socket, since the last packet. The buffer
+      // never shrinks, since packets queued before lowering it may still be larger.
+      let mtu = self.mtu() as usize;
+      if buffer.len() < mtu {
+        buffer.resize(mtu, 0);
+      }
+
+      let bytesRead = match self.nic.recv(&mut buffer) {
+        Ok(bytesRead) => bytesRead,
+        Err(error) => {
+          self.connectionManager.on_nic_failure(&error);
+          return Err(error.into());
+        }
+      };
+
+      self.process_packet(&buffer[..bytesRead]);
+    }
+  }
+
+  // Hands the TCP segment carried by the given IPv4 packet over to its connection. Anything else
+  // gets ignored.
+  pub fn process_packet(&self, packet: &[u8]) {
+    /*
+      TCP segments are sent as internet datagrams.
+
+      A datagram is s self-contained, independent entity of data carrying sufficient information
+      to be routed from the source to the destination computer without reliance on earlier
+      exchanges between this source and destination computer and the transporting network.
+
+      Each datagram has two components :
+
+        (1) Header : contains all the information sufficient for routing from the originating
+            equipment to the destination without relying on prior exchanges between the
+            equipment and the network.
+
+        (2) Payload : the data to be transported.
+    */
+    let ipv4PacketHeader = match etherparse::Ipv4HeaderSlice::from_slice(packet) {
+      Ok(ipv4PacketHeader) => ipv4PacketHeader,
+      _ => {
+        eprintln!("Ignoring packet, since it doesn't follow the IPv4 protocol");
+        return;
+      }
+    };
+    let ipv4PacketHeaderLen = ipv4PacketHeader.slice().len();
+
+    if ipv4PacketHeader.protocol() != IpNumber::TCP {
+      println!("Ignoring non TCP IPv4 packet");
+      return;
+    }
+
+    let ipv4PacketPayload = &packet[ipv4PacketHeaderLen..];
+
+    let tcpPacketHeader = match etherparse::TcpHeaderSlice::from_slice(ipv4PacketPayload) {
+      Ok(tcpPacketHeader) => tcpPacketHeader,
+      _ => {
+        eprintln!("Ignoring packet, since it doesn't have a valid TCP header section");
+        return;
+      }
+    };
+    let tcpPacketHeaderLen = tcpPacketHeader.slice().len();
+
+    let tcpPacketPayload = &packet[(ipv4PacketHeaderLen + tcpPacketHeaderLen)..];
+
+    let connectionQuad = ConnectionQuad {
+      source: Location {
+        address: ipv4PacketHeader.source_addr(),
+        port: tcpPacketHeader.source_port(),
+      },
+      destiation: Location {
+        address: ipv4PacketHeader.destination_addr(),
+        port: tcpPacketHeader.destination_port(),
+      },
+    };
+
+    self
+      .connectionManager
+      .on_segment(connectionQuad, packet, tcpPacketHeader, tcpPacketPayload);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::channel_nic::ChannelNic, std::thread};
+
+  // Sets every key, the way Display writes them out : the stuck state thresholds and the refusals
+  // get written in full, defaults included.
+  const SNAPSHOT: &str = r#"name = "utun4"
+address = "10.0.0.1"
+netmask = "255.255.255.0"
+destination = "10.0.0.255"
+mtu = 1500
+aliases = ["192.168.50.7"]
+peer_violation_policy = "lenient"
+user_timeout_ms = 30000
+expected_connections = 10000
+receive_coalescing_budget_us = 1000
+receive_coalescing_threshold = 4096
+send_buffer_capacity = 65536
+send_low_watermark = 16384
+fin_wait_2_timeout_ms = 60000
+stuck_state_thresholds = ["SYN-RECEIVED 60000", "FIN-WAIT-1 300000", "FIN-WAIT-2 600000", "CLOSING 300000", "CLOSE-WAIT off", "LAST-ACK 300000"]
+control_segments_when_queue_full = "retry"
+data_segments_when_queue_full = "drop"
+queue_full_retry_timeout_ms = 10
+drain_deadline_ms = 30000
+drain_deadline_action = "close"
+refusals = ["draining reset", "accept-queue-full defer", "rate-limited defer", "closed-port reset"]
+sample_file = "/tmp/tcpd-samples.csv"
+sample_format = "csv"
+sample_interval_ms = 1000
+listeners = [8080, 9090]
+accept_queues = ["9090 16 abort-oldest", "8080 128 refuse-newest rate 100/10"]
+filter_rules = ["deny 10.0.0.0/25 22", "allow 0.0.0.0/0 1-65535"]
+"#;
+
+  #[test]
+  fn snapshots_round_trip_through_their_text_form() {
+    let snapshot: InterfaceSnapshot = SNAPSHOT.parse().unwrap();
+    assert_eq!(snapshot.to_string(), SNAPSHOT);
+
+    assert_eq!(
+      snapshot.config.destination,
+      Some(Ipv4Addr::new(10, 0, 0, 255))
+    );
+    assert_eq!(snapshot.listeningPorts, [8080, 9090]);
+    assert_eq!(snapshot.config.filterRules.len(), 2);
+  }
+
+  #[test]
+  fn the_default_snapshot_round_trips_too() {
+    let text = InterfaceSnapshot::default().to_string();
+    assert_eq!(text.parse::<InterfaceSnapshot>().unwrap().to_string(), text);
+  }
+
+  #[test]
+  fn every_invalid_line_gets_reported() {
+    let error = "name = \"utun4\"\nmtu = big\n# comment\n\nwhatever = 1\nlisteners\n"
+      .parse::<InterfaceSnapshot>()
+      .err()
+      .unwrap()
+      .to_string();
+
+    assert!(error.contains("line 2 : "), "{}", error);
+    assert!(error.contains("line 5 : "), "{}", error);
+    assert!(error.contains("line 6 : "), "{}", error);
+    assert!(!error.contains("line 1 : "), "{}", error);
+  }
+
+  // Runs the interface over the given device, with the packet and the tick threads main( ) would
+  // spawn.
+  fn start(address: Ipv4Addr, device: ChannelNic) -> Interface {
+    let config = InterfaceConfig {
+      address,
+      ..InterfaceConfig::default()
+    };
+    let interface = Interface::with_device(config, device).unwrap();
+
+    let packetThreadInterface = interface.clone();
+    thread::spawn(move || packetThreadInterface.process_packets());
+
+    let connectionManager = interface.connection_manager().clone();
+    thread::spawn(move || loop {
+      thread::sleep(TICK_INTERVAL);
+      connectionManager.on_tick();
+    });
+
+    interface
+  }
+
+  #[test]
+  fn interfaces_talk_over_a_channel_nic() {
+    let (left, right) = ChannelNic::pair();
+    let client = start(Ipv4Addr::new(10, 0, 0, 1), left);
+    let server = start(Ipv4Addr::new(10, 0, 0, 2), right);
+
+    let serverManager = server.connection_manager().clone();
+    serverManager.listen(8080);
+    let echo = thread::spawn(move || {
+      let connection = serverManager.accept(8080).unwrap();
+
+      let mut buffer = [0u8; 64];
+      let bytesRead = connection
+        .read(&mut buffer, &mut serverManager.send_context())
+        .unwrap();
+      connection
+        .write(&buffer[..bytesRead], &mut serverManager.send_context())
+        .unwrap();
+    });
+
+    let clientManager = client.connection_manager();
+    let connection = clientManager
+      .connect(Location {
+        address: Ipv4Addr::new(10, 0, 0, 2),
+        port: 8080,
+      })
+      .unwrap();
+    connection
+      .write(b"hello", &mut clientManager.send_context())
+      .unwrap();
+
+    let mut buffer = [0u8; 64];
+    let bytesRead = connection
+      .read(&mut buffer, &mut clientManager.send_context())
+      .unwrap();
+    assert_eq!(&buffer[..bytesRead], b"hello");
+
+    echo.join().unwrap();
+  }
+}
@@ -0,0 +1,113 @@
+use {
+  crate::tcp::ConnectionQuad,
+  std::{collections::hash_map::RandomState, hash::BuildHasher, sync::OnceLock, time::Instant},
+};
+
+/*
+  Initial sequence numbers, picked as per RFC 6528 section 3 :
+
+    ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+
+  where M is a timer ticking every 4 microseconds, and F a keyed hash of the connection quad. The
+  timer keeps successive incarnations of a quad from reusing sequence numbers, while the hash keeps
+  an off-path attacker from predicting the ISN of a connection from those of others.
+
+  The hash is SipHash, keyed with a random secret picked when the daemon starts.
+*/
+pub struct IsnGenerator {
+  secretKey: RandomState,
+
+  // The timer counts from here.
+  startedAt: Instant,
+}
+
+// How long a tick of the timer lasts, in microseconds.
+const TICK_MICROSECONDS: u128 = 4;
+
+impl IsnGenerator {
+  pub fn new() -> Self {
+    Self {
+      secretKey: RandomState::new(),
+      startedAt: Instant::now(),
+    }
+  }
+
+  // The ISN of the given connection, if it were opened at the given time.
+  pub fn generate(&self, connectionQuad: &ConnectionQuad, now: Instant) -> u32 {
+    let ticks = now.saturating_duration_since(self.startedAt).as_micros() / TICK_MICROSECONDS;
+
+    let hash = self.secretKey.hash_one(connectionQuad);
+    (hash as u32).wrapping_add(ticks as u32)
+  }
+}
+
+impl Default for IsnGenerator {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// The ISN of the given connection, opened now, from the generator shared by every connection.
+pub fn initial_sequence_number(connectionQuad: &ConnectionQuad) -> u32 {
+  static GENERATOR: OnceLock<IsnGenerator> = OnceLock::new();
+
+  GENERATOR
+    .get_or_init(IsnGenerator::new)
+    .generate(connectionQuad, Instant::now())
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    std::{collections::HashSet, time::Duration},
+  };
+
+  fn quad(connectionQuad: &str) -> ConnectionQuad {
+    connectionQuad.parse().unwrap()
+  }
+
+  #[test]
+  fn isns_advance_with_the_clock() {
+    let generator = IsnGenerator::new();
+    let connectionQuad = quad("10.0.0.2:51514 10.0.0.1:8080");
+    let now = generator.startedAt;
+
+    let first = generator.generate(&connectionQuad, now);
+    assert_eq!(generator.generate(&connectionQuad, now), first);
+    assert_eq!(
+      generator.generate(&connectionQuad, now + Duration::from_micros(3)),
+      first
+    );
+    assert_eq!(
+      generator.generate(&connectionQuad, now + Duration::from_micros(4)),
+      first.wrapping_add(1)
+    );
+    assert_eq!(
+      generator.generate(&connectionQuad, now + Duration::from_secs(1)),
+      first.wrapping_add(250_000)
+    );
+  }
+
+  #[test]
+  fn isns_differ_across_quads_and_keys() {
+    let generator = IsnGenerator::new();
+    let now = generator.startedAt;
+
+    let isns = (50000..50100)
+      .map(|port| generator.generate(&quad(&format!("10.0.0.2:{} 10.0.0.1:80", port)), now))
+      .collect::<HashSet<_>>();
+    assert!(isns.len() > 95);
+
+    // Another daemon picks another secret key, and thus another ISN for the same quad.
+    let otherGenerator = IsnGenerator {
+      secretKey: RandomState::new(),
+      startedAt: now,
+    };
+    let connectionQuad = quad("10.0.0.2:51514 10.0.0.1:8080");
+    assert_ne!(
+      otherGenerator.generate(&connectionQuad, now),
+      generator.generate(&connectionQuad, now)
+    );
+  }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Write as _;
+
+/*
+  Machine readable output, for the tooling around the daemon : list --json, stats --json and the
+  event log.
+
+  The field names are a compatibility surface. Once a field is out, it doesn't get renamed or
+  retyped, only new fields get added. Names are snake_case, durations are in milliseconds and
+  enums are lowercase (hyphenated) strings, except for the connection states, which keep their
+  RFC 9293 names.
+*/
+pub trait ToJson {
+  fn write_json(&self, json: &mut String);
+
+  fn to_json(&self) -> String {
+    let mut json = String::new();
+    self.write_json(&mut json);
+    json
+  }
+}
+
+// Writes the fields of a JSON object, one after the other.
+pub struct JsonObject<'json> {
+  json: &'json mut String,
+
+  isEmpty: bool,
+}
+
+impl<'json> JsonObject<'json> {
+  pub fn new(json: &'json mut String) -> Self {
+    json.push('{');
+    Self {
+      json,
+      isEmpty: true,
+    }
+  }
+
+  pub fn field(&mut self, name: &str, value: &(impl ToJson + ?Sized)) -> &mut Self {
+    if !self.isEmpty {
+      self.json.push(',');
+    }
+    self.isEmpty = false;
+
+    name.write_json(self.json);
+    self.json.push(':');
+    value.write_json(self.json);
+    self
+  }
+
+  pub fn finish(&mut self) {
+    self.json.push('}');
+  }
+}
+
+// Already serialized JSON, nested as is.
+pub struct RawJson(pub String);
+
+impl ToJson for RawJson {
+  fn write_json(&self, json: &mut String) {
+    json.push_str(&self.0)
+  }
+}
+
+impl ToJson for str {
+  fn write_json(&self, json: &mut String) {
+    json.push('"');
+    for character in self.chars() {
+      match character {
+        '"' => json.push_str("\\\""),
+        '\\' => json.push_str("\\\\"),
+        '\n' => json.push_str("\\n"),
+        '\r' => json.push_str("\\r"),
+        '\t' => json.push_str("\\t"),
+        character if character.is_control() => {
+          let _ = write!(json, "\\u{:04x}", character as u32);
+        }
+        character => json.push(character),
+      }
+    }
+    json.push('"');
+  }
+}
+
+impl ToJson for String {
+  fn write_json(&self, json: &mut String) {
+    self.as_str().write_json(json)
+  }
+}
+
+impl ToJson for bool {
+  fn write_json(&self, json: &mut String) {
+    json.push_str(if *self { "true" } else { "false" });
+  }
+}
+
+macro_rules! impl_to_json_for_numbers {
+  ($($number:ty),*) => {
+    $(
+      impl ToJson for $number {
+        fn write_json(&self, json: &mut String) {
+          let _ = write!(json, "{}", self);
+        }
+      }
+    )*
+  };
+}
+impl_to_json_for_numbers!(u16, u32, u64, u128, usize);
+
+impl<T: ToJson + ?Sized> ToJson for &T {
+  fn write_json(&self, json: &mut String) {
+    (**self).write_json(json)
+  }
+}
+
+impl<T: ToJson> ToJson for Option<T> {
+  fn write_json(&self, json: &mut String) {
+    match self {
+      Some(value) => value.write_json(json),
+      None => json.push_str("null"),
+    }
+  }
+}
+
+impl<T: ToJson> ToJson for [T] {
+  fn write_json(&self, json: &mut String) {
+    json.push('[');
+    for (index, value) in self.iter().enumerate() {
+      if index > 0 {
+        json.push(',');
+      }
+      value.write_json(json);
+    }
+    json.push(']');
+  }
+}
+
+impl<T: ToJson> ToJson for Vec<T> {
+  fn write_json(&self, json: &mut String) {
+    self.as_slice().write_json(json)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn strings_get_escaped() {
+    assert_eq!("plain".to_json(), r#""plain""#);
+    assert_eq!(r#"say "hi""#.to_json(), r#""say \"hi\"""#);
+    assert_eq!(r"C:\tmp".to_json(), r#""C:\\tmp""#);
+    assert_eq!("a\nb\rc\td".to_json(), r#""a\nb\rc\td""#);
+    assert_eq!("\u{0}\u{1b}\u{7f}".to_json(), r#""\u0000\u001b\u007f""#);
+    assert_eq!("é – ☃".to_json(), "\"é – ☃\"");
+  }
+
+  #[test]
+  fn objects_nest_values_in_order() {
+    let mut json = String::new();
+    JsonObject::new(&mut json)
+      .field("name", "tun0")
+      .field("up", &true)
+      .field("mtu", &1500u16)
+      .field("aliases", &vec!["10.0.0.2", "10.0.0.3"])
+      .field("gateway", &None::<&str>)
+      .field("raw", &RawJson("{\"a\":1}".to_string()))
+      .finish();
+
+    assert_eq!(
+      json,
+      r#"{"name":"tun0","up":true,"mtu":1500,"aliases":["10.0.0.2","10.0.0.3"],"gateway":null,"raw":{"a":1}}"#
+    );
+  }
+
+  #[test]
+  fn field_names_get_escaped_too() {
+    let mut json = String::new();
+    JsonObject::new(&mut json).field("a\"b", &0u32).finish();
+    assert_eq!(json, r#"{"a\"b":0}"#);
+
+    let mut json = String::new();
+    JsonObject::new(&mut json).finish();
+    assert_eq!(json, "{}");
+
+    assert_eq!(Vec::<u32>::new().to_json(), "[]");
+  }
+}
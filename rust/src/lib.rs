@@ -0,0 +1,28 @@
+#![allow(non_snake_case)]
+
+pub mod capture;
+pub mod channel_nic;
+pub mod clock;
+pub mod conformance;
+pub mod control;
+pub mod error;
+pub mod events;
+pub mod extensions;
+pub mod files;
+pub mod filter;
+pub mod integrity;
+pub mod interface;
+pub mod isn;
+pub mod json;
+pub mod lifecycle;
+pub mod manager;
+pub mod nic;
+pub mod proxy;
+pub mod rate_limit;
+pub mod refusal;
+pub mod sampler;
+pub mod send_buffer;
+pub mod simulate;
+pub mod stats;
+pub mod tcp;
+pub mod tuning;
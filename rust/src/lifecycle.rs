@@ -0,0 +1,132 @@
+use {
+  anyhow::anyhow,
+  std::{
+    ffi::c_int,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+  },
+};
+
+/*
+  Lifecycle of an Interface, which lets the daemon get restarted without resetting the live
+  connections :
+
+    (1) Running : connections come and go.
+
+    (2) Draining : entered on SIGTERM, or on the drain control command. New connection requests
+        get refused (as per the RefusalPolicy), while the existing connections run to completion.
+        Draining ends when no connection is left, or when the drain deadline passes. The remaining
+        connections then get closed (or reset) as per the DrainPolicy.
+
+    (3) Stopped : nothing is left, and the daemon may exit.
+
+  Connections in the TIME-WAIT state don't hold the drain up, since there's no data left to
+  deliver on them.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterfaceState {
+  #[default]
+  Running,
+
+  Draining,
+
+  Stopped,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DrainPolicy {
+  // How long the existing connections have to complete.
+  pub deadline: Duration,
+
+  // What happens to the connections still open when the deadline passes.
+  pub atDeadline: DrainDeadlineAction,
+}
+
+impl Default for DrainPolicy {
+  fn default() -> Self {
+    Self {
+      deadline: Duration::from_secs(30),
+      atDeadline: DrainDeadlineAction::Close,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DrainDeadlineAction {
+  // A FIN is sent on each remaining connection, which then gets DRAIN_CLOSE_GRACE to complete the
+  // close. Whatever is still left afterwards gets reset.
+  Close,
+
+  // Each remaining connection gets reset right away.
+  Reset,
+}
+
+// How long the connections closed at the drain deadline may take, to complete the close.
+pub const DRAIN_CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+impl Display for InterfaceState {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Running => "running",
+      Self::Draining => "draining",
+      Self::Stopped => "stopped",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl Display for DrainDeadlineAction {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Close => "close",
+      Self::Reset => "reset",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for DrainDeadlineAction {
+  type Err = anyhow::Error;
+
+  fn from_str(action: &str) -> anyhow::Result<Self> {
+    match action {
+      "close" => Ok(Self::Close),
+      "reset" => Ok(Self::Reset),
+      _ => Err(anyhow!(
+        "Unknown drain deadline action '{}', expected close or reset",
+        action
+      )),
+    }
+  }
+}
+
+// Set by the SIGTERM handler, and picked up by whoever polls termination_requested( ).
+static TERMINATION_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+const SIGTERM: c_int = 15;
+
+// Makes SIGTERM request a drain, instead of killing the process.
+pub fn handle_termination_signal() {
+  // SAFETY : the handler only stores to an atomic, which is async-signal-safe.
+  unsafe {
+    signal(SIGTERM, on_termination_signal);
+  }
+}
+
+// Whether SIGTERM has been received, since the last time this got called.
+pub fn termination_requested() -> bool {
+  TERMINATION_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+extern "C" fn on_termination_signal(_: c_int) {
+  TERMINATION_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+extern "C" {
+  // The previous handler gets returned, which we have no use for.
+  fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
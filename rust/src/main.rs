@@ -1,131 +1,283 @@
 #![allow(non_snake_case)]
 
 use {
-  etherparse::IpNumber,
-  std::collections::hash_map::{Entry, HashMap},
-  tcp::{ConnectionQuad, Location, TCPConnection},
+  anyhow::anyhow,
+  std::{fs, net::SocketAddr, path::Path, process, thread, time::Duration},
+  tcp_server::{
+    conformance,
+    control::{self, CONTROL_SOCKET_PATH},
+    interface::{Interface, InterfaceSnapshot},
+    json::ToJson,
+    lifecycle::{self, InterfaceState},
+    manager::TICK_INTERVAL,
+    proxy::{self, ForwardOptions},
+    simulate::{self, Scenario},
+    tcp::Location,
+  },
 };
 
-mod tcp;
+#[derive(Default)]
+struct Args {
+  // Local ports to listen on, in addition to the ones from the configuration file.
+  listeningPorts: Vec<u16>,
 
-fn main() -> anyhow::Result<()> {
-  /*
-    TUN and TAP are kernel virtual network devices.
-
-    TUN, namely network TUNnel (acts like a virtual Network Interface Card), simulates a network
-    layer device and operates in layer 3 carrying IP packets. TUN is used with routing.
-
-    TAP, namely network TAP (acts like a virtual Ethernet cable), simulates a link layer device and
-    operates in layer 2 carrying Ethernet frames. TAP can be used to create a user space network
-    bridge.
-
-    Packets sent by an operating system via a TUN/TAP device, are delivered to a user space program
-    which attaches itself to the device.
-    A user space program may also pass packets into a TUN/TAP device. In this case the TUN/TAP
-    device delivers (or injects) these packets to the operating-system network stack thus emulating
-    their reception from an external source.
-
-    REFERENCE : https://en.wikipedia.org/wiki/TUN/TAP
-  */
-
-  let mut vNICConfig = tun::Configuration::default();
-  vNICConfig
-    .tun_name("utun4")
-    .address("10.0.0.1")
-    /*
-      Range of IPs that are considered "directly reachable" via this interface. This tells your
-      OS : if you're sending a packet to anything in 10.0.0.0/24, route it through utun4.
-    */
-    .netmask((255, 255, 255, 0))
-    .destination("10.0.0.255")
-    .up();
-
-  let mut vNIC = tun::create(&vNICConfig)?;
-  println!("Created virtual Network Interface Card (vNIC)");
+  // Configuration file, from which the interface gets restored.
+  configFilePath: Option<String>,
 
-  let mut connections = HashMap::<ConnectionQuad, TCPConnection>::default();
+  // File, to which the configuration of the interface gets written once it's created.
+  writeConfigFilePath: Option<String>,
 
-  let mut buffer = [0u8; 1024]; // size = 1 KB.
+  // File, to which the connection events get appended as JSON lines.
+  eventsFilePath: Option<String>,
 
-  loop {
-    /*
-      TCP segments are sent as internet datagrams.
+  // Set by the proxy subcommand.
+  proxy: Option<ProxyArgs>,
 
-      A datagram is s self-contained, independent entity of data carrying sufficient information to
-      be routed from the source to the destination computer without reliance on earlier exchanges
-      between this source and destination computer and the transporting network.
+  // Set by the conformance subcommand.
+  conformance: Option<ConformanceArgs>,
 
-      Each datagram has two components :
+  // Scenario file, set by the simulate subcommand.
+  scenarioFilePath: Option<String>,
+}
 
-        (1) Header : contains all the information sufficient for routing from the originating
-            equipment to the destination without relying on prior exchanges between the equipment
-            and the network.
+struct ProxyArgs {
+  port: u16,
+  upstream: SocketAddr,
+  options: ForwardOptions,
+}
 
-        (2) Payload : the data to be transported.
-    */
-    let bytesRead = vNIC.recv(&mut buffer)?;
+struct ConformanceArgs {
+  peer: Location,
 
-    let ipv4PacketHeader = match etherparse::Ipv4HeaderSlice::from_slice(&buffer[..bytesRead]) {
-      Ok(ipv4PacketHeader) => ipv4PacketHeader,
-      _ => {
-        eprintln!("Ignoring packet, since it doesn't follow the IPv4 protocol");
-        continue;
-      }
-    };
-    let ipv4PacketHeaderLen = ipv4PacketHeader.slice().len();
+  // Whether the report gets printed as JSON.
+  json: bool,
+}
+
+impl Args {
+  const USAGE: &str = "Usage :
+  tcp-server [--config <file.toml>] [--write-config <file.toml>] [--events-json <file>] [<port>...]
+  tcp-server proxy [--defer-upstream-until-data] [--first-data-timeout <ms>] [--copy-client-options]
+                   [--nodelay <on | off | infer>] <port> <upstream>
+  tcp-server conformance [--json] <address:port>
+  tcp-server simulate <scenario.toml>";
+
+  fn parse(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+    let mut parsedArgs = Self::default();
 
-    if ipv4PacketHeader.protocol() != IpNumber::TCP {
-      println!("Ignoring non TCP IPv4 packet");
-      continue;
+    let mut args = args.peekable();
+    if args.next_if(|arg| arg == "proxy").is_some() {
+      return Self::parse_proxy(args);
     }
+    if args.next_if(|arg| arg == "conformance").is_some() {
+      return Self::parse_conformance(args);
+    }
+    if args.next_if(|arg| arg == "simulate").is_some() {
+      let (Some(scenarioFilePath), None) = (args.next(), args.next())
+      else {
+        return Err(anyhow!("{}", Self::USAGE));
+      };
+
+      return Ok(Self {
+        scenarioFilePath: Some(scenarioFilePath),
+        ..Self::default()
+      });
+    }
+
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--config" => parsedArgs.configFilePath = Some(Self::value_of(&arg, args.next())?),
+        "--write-config" => {
+          parsedArgs.writeConfigFilePath = Some(Self::value_of(&arg, args.next())?)
+        }
+        "--events-json" => parsedArgs.eventsFilePath = Some(Self::value_of(&arg, args.next())?),
+
+        port => parsedArgs.listeningPorts.push(Self::parse_port(port)?),
+      }
+    }
+
+    Ok(parsedArgs)
+  }
+
+  fn parse_proxy(mut args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+    let mut options = ForwardOptions::default();
+    let mut positionalArgs = Vec::new();
+
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--defer-upstream-until-data" => options.deferUpstreamUntilData = true,
+        "--first-data-timeout" => {
+          let milliseconds = Self::value_of(&arg, args.next())?;
+          options.firstDataTimeout =
+            Duration::from_millis(milliseconds.parse().map_err(|error| {
+              anyhow!(
+                "Invalid timeout '{}' : {}\n{}",
+                milliseconds,
+                error,
+                Self::USAGE
+              )
+            })?);
+        }
 
-    let ipv4PacketPayload = &buffer[ipv4PacketHeaderLen..bytesRead];
+        "--copy-client-options" => options.copyClientOptions = true,
+        "--nodelay" => options.noDelay = Self::value_of(&arg, args.next())?.parse()?,
 
-    let tcpPacketHeader = match etherparse::TcpHeaderSlice::from_slice(ipv4PacketPayload) {
-      Ok(tcpPacketHeader) => tcpPacketHeader,
-      _ => {
-        eprintln!("Ignoring packet, since it doesn't have a valid TCP header section");
-        continue;
+        _ => positionalArgs.push(arg),
       }
+    }
+
+    let [port, upstream] = &positionalArgs[..]
+    else {
+      return Err(anyhow!("{}", Self::USAGE));
     };
-    let tcpPacketHeaderLen = tcpPacketHeader.slice().len();
-
-    let tcpPacketPayload = &buffer[(ipv4PacketHeaderLen + tcpPacketHeaderLen)..bytesRead];
-
-    let connectionQuad = ConnectionQuad {
-      source: Location {
-        address: ipv4PacketHeader.source_addr(),
-        port: tcpPacketHeader.source_port(),
-      },
-      destiation: Location {
-        address: ipv4PacketHeader.destination_addr(),
-        port: tcpPacketHeader.destination_port(),
-      },
+    let port = Self::parse_port(port)?;
+    let upstream = upstream.parse::<SocketAddr>().map_err(|error| {
+      anyhow!(
+        "Invalid upstream '{}' : {}\n{}",
+        upstream,
+        error,
+        Self::USAGE
+      )
+    })?;
+
+    Ok(Self {
+      listeningPorts: vec![port],
+      proxy: Some(ProxyArgs {
+        port,
+        upstream,
+        options,
+      }),
+      ..Self::default()
+    })
+  }
+
+  fn parse_conformance(args: impl Iterator<Item = String>) -> anyhow::Result<Self> {
+    let mut json = false;
+    let mut peer = None;
+
+    for arg in args {
+      match arg.as_str() {
+        "--json" => json = true,
+        _ if peer.is_none() => peer = Some(arg.parse::<Location>()?),
+        _ => return Err(anyhow!("{}", Self::USAGE)),
+      }
+    }
+
+    let Some(peer) = peer
+    else {
+      return Err(anyhow!("{}", Self::USAGE));
     };
-    match connections.entry(connectionQuad) {
-      // No existing connection.
-      // So accept and save the new connection.
-      Entry::Vacant(entry) => {
-        let newConnection = match TCPConnection::accept(
-          ipv4PacketHeader,
-          tcpPacketHeader,
-          tcpPacketPayload,
-          &mut vNIC,
-        ) {
-          Ok(newConnection) => newConnection,
-
-          Err(error) => {
-            println!("Failed accepting new connection : {}", error);
-            continue;
-          }
-        };
-
-        entry.insert(newConnection);
+
+    Ok(Self {
+      conformance: Some(ConformanceArgs { peer, json }),
+      ..Self::default()
+    })
+  }
+
+  fn parse_port(port: &str) -> anyhow::Result<u16> {
+    port
+      .parse::<u16>()
+      .map_err(|error| anyhow!("Invalid port '{}' : {}\n{}", port, error, Self::USAGE))
+  }
+
+  fn value_of(flag: &str, value: Option<String>) -> anyhow::Result<String> {
+    value.ok_or_else(|| anyhow!("{} expects a value\n{}", flag, Self::USAGE))
+  }
+}
+
+fn main() -> anyhow::Result<()> {
+  let args = Args::parse(std::env::args().skip(1))?;
+
+  // The simulation runs on interfaces of its own, which need no TUN device.
+  if let Some(scenarioFilePath) = &args.scenarioFilePath {
+    let scenario = fs::read_to_string(scenarioFilePath)
+      .map_err(|error| anyhow!("Failed reading {} : {}", scenarioFilePath, error))?
+      .parse::<Scenario>()?;
+
+    let summary = simulate::run(&scenario)?;
+    print!("{}", summary);
+    process::exit(if summary.isCompleted { 0 } else { 1 });
+  }
+
+  let mut snapshot = match &args.configFilePath {
+    Some(configFilePath) => fs::read_to_string(configFilePath)
+      .map_err(|error| anyhow!("Failed reading {} : {}", configFilePath, error))?
+      .parse::<InterfaceSnapshot>()?,
+
+    None => InterfaceSnapshot::default(),
+  };
+  snapshot.listeningPorts.extend(&args.listeningPorts);
+
+  // The conformance checks only make outgoing connections.
+  if snapshot.listeningPorts.is_empty() && args.conformance.is_none() {
+    return Err(anyhow!("{}", Args::USAGE));
+  }
+
+  let interface = Interface::from_snapshot(snapshot)?;
+  println!("Created virtual Network Interface Card (vNIC)");
+
+  let connectionManager = interface.connection_manager().clone();
+  if let Some(eventsFilePath) = &args.eventsFilePath {
+    connectionManager.log_events_to(Path::new(eventsFilePath))?;
+    println!("Logging connection events to {}", eventsFilePath);
+  }
+  for port in connectionManager.listening_ports() {
+    println!("Listening on port {}", port);
+  }
+
+  if let Some(writeConfigFilePath) = &args.writeConfigFilePath {
+    fs::write(writeConfigFilePath, interface.snapshot_config().to_string())?;
+    println!("Wrote configuration to {}", writeConfigFilePath);
+  }
+
+  control::serve(CONTROL_SOCKET_PATH, connectionManager.clone())?;
+  println!("Serving control commands on {}", CONTROL_SOCKET_PATH);
+
+  if let Some(proxy) = &args.proxy {
+    proxy::forward(
+      connectionManager.clone(),
+      proxy.port,
+      proxy.upstream,
+      proxy.options,
+    );
+    println!("Forwarding port {} to {}", proxy.port, proxy.upstream);
+  }
+
+  if let Some(conformance) = &args.conformance {
+    let connectionManager = connectionManager.clone();
+    let (peer, json) = (conformance.peer, conformance.json);
+
+    thread::spawn(move || {
+      let report = conformance::run(&connectionManager, peer);
+      if json {
+        println!("{}", report.to_json());
+      }
+      else {
+        print!("{}", report);
       }
 
-      // Connection exists.
-      // Process the packet.
-      Entry::Occupied(mut existingConnection) => unimplemented!(),
-    }
+      process::exit(if report.is_passed() { 0 } else { 1 });
+    });
   }
+
+  // SIGTERM drains the interface, and the process exits once it's stopped.
+  lifecycle::handle_termination_signal();
+
+  // Fires the connection timers (the user timeout etc.), and moves the drain forward.
+  {
+    let connectionManager = connectionManager.clone();
+    thread::spawn(move || loop {
+      thread::sleep(TICK_INTERVAL);
+
+      if lifecycle::termination_requested() {
+        connectionManager.drain();
+      }
+      connectionManager.on_tick();
+
+      if connectionManager.state() == InterfaceState::Stopped {
+        process::exit(0);
+      }
+    });
+  }
+
+  interface.process_packets()
 }
@@ -0,0 +1,2073 @@
+use {
+  crate::{
+    clock::{Clock, SystemClock},
+    error::TcpError,
+    events::{ConnectionEvent, EventLog},
+    filter::{FilterAction, FilterRule, Ipv4Cidr, PacketFilter},
+    json::{JsonObject, ToJson},
+    lifecycle::{DrainDeadlineAction, DrainPolicy, InterfaceState, DRAIN_CLOSE_GRACE},
+    nic::{Nic, NicError},
+    rate_limit::{ConnectionRateLimit, ListenerRateLimiter},
+    refusal::{RefusalCause, RefusalCounters, RefusalLimiter, RefusalPolicy, RefusalResponse},
+    sampler::{Sampler, SamplerConfig},
+    stats::TcpCounters,
+    tcp::{
+      self, Action, CloseReason, ConnectionQuad, Location, SegmentView, SendContext, TCPConnection,
+      TCPConnectionState,
+    },
+    tuning::TcpTuning,
+  },
+  anyhow::anyhow,
+  etherparse::TcpHeaderSlice,
+  std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Formatter, Write as _},
+    iter,
+    net::Ipv4Addr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+      atomic::{AtomicBool, AtomicU64, Ordering},
+      Arc, Condvar, Mutex, MutexGuard, RwLock,
+    },
+    time::{Duration, Instant},
+  },
+};
+
+// How often the timers of every connection get checked.
+pub const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+// How many established connections may wait to be accepted on a listening port, by default.
+pub const ACCEPT_QUEUE_BACKLOG: usize = 128;
+
+// The connection map gets shrunk, once it has stayed less than a quarter full for this long.
+const CONNECTION_MAP_SHRINK_DELAY: Duration = Duration::from_secs(60);
+
+// Local ports handed out to actively opened connections (RFC 6335 section 6).
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/*
+  Locking :
+
+  The connection map is behind its own lock, which is only held for looking up, inserting (which
+  includes answering the SYN of a new connection) or removing a TCB. Each TCB is then behind a lock
+  of its own, held while that connection processes a segment, fires its timers or serves a user
+  call. This way, work on one connection never stalls behind work on an unrelated one.
+
+  To avoid deadlocks, the locks are always taken in this order : the connection map before a
+  connection. A connection's lock must never be held while taking the connection map's lock.
+
+  The accept queues, the lifecycle and the vNIC failure are behind locks of their own too, which
+  are never held while taking any other lock. So are the packet captures of the vNIC, which get
+  locked while sending a segment, and thus possibly while a connection or the connection map is
+  locked. And so are the sampler, the refusal rate limiter, the listeners' connection rate limiters,
+  the aliases and the event log.
+
+  Whoever blocks on a connection (connect( ) for instance), waits on its condition variable, which
+  gets notified every time the connection processes a segment (unless receive coalescing defers
+  it) or fires its timers.
+
+  The vNIC needs no lock : every segment is written with a single write(2) call on the TUN file
+  descriptor, which the kernel delivers as one whole packet.
+
+  Every segment of a connection gets written while holding that connection's lock, be it by the
+  packet thread, the timer thread or a user thread writing data. A retransmission and a new
+  segment thus never interleave : the vNIC sees them in the order they were serialized, on one
+  thread at a time. And SND.NXT only moves inside send_segment( ), so it can't be advanced by two
+  of them at once.
+*/
+
+// Owns the TCB of every connection, keyed by its connection quad, along with the vNIC through which
+// segments are written back to the peers.
+pub struct ConnectionManager {
+  nic: Arc<Nic>,
+
+  // Address and subnet of the vNIC. Actively opened connections originate from the address, and
+  // the network and broadcast addresses of the subnet are never peers.
+  subnet: Ipv4Cidr,
+
+  // Addresses outside the subnet which we answer for too, added and removed at runtime.
+  aliases: RwLock<BTreeSet<Ipv4Addr>>,
+
+  tuning: TcpTuning,
+
+  // Where the timers of every connection, and of the drain, read the time from.
+  clock: Arc<dyn Clock>,
+
+  // Local ports on which incoming connection requests are accepted.
+  listeningPorts: RwLock<HashMap<u16, ListenerOptions>>,
+
+  // Decides which connection requests get through.
+  filter: RwLock<PacketFilter>,
+
+  connections: Mutex<ConnectionTable>,
+
+  // Passively opened connections which have completed the handshake, waiting to be accepted, keyed
+  // by the local port. Notified whenever a connection gets queued.
+  acceptQueues: Mutex<HashMap<u16, VecDeque<Arc<SharedConnection>>>>,
+  accepted: Condvar,
+
+  // Set once the interface gets stopped, so that accept( ) stops waiting. Only changed while
+  // holding the accept queues' lock.
+  isAcceptingStopped: AtomicBool,
+
+  // Where the search for a free ephemeral port starts from, the next time.
+  nextEphemeralPort: Mutex<u16>,
+
+  drainPolicy: DrainPolicy,
+  lifecycle: Mutex<Lifecycle>,
+
+  // How refused connection requests get answered, and how many RSTs have been sent for them lately.
+  refusalPolicy: RefusalPolicy,
+  refusalLimiter: Mutex<RefusalLimiter>,
+
+  // The token buckets of the listeners with a connection rate limit, keyed by the local port.
+  rateLimiters: Mutex<HashMap<u16, ListenerRateLimiter>>,
+
+  // Why the vNIC failed, if it did.
+  nicFailure: Mutex<Option<String>>,
+
+  sampler: Mutex<Sampler>,
+
+  // Where the connection events get appended to, if anywhere.
+  events: Mutex<Option<EventLog>>,
+
+  counters: ConnectionManagerCounters,
+
+  // Summed up over every connection, which each TCB shares.
+  tcpCounters: Arc<TcpCounters>,
+}
+
+/*
+  The connection map, along with an index of the connections on each local port, so that looking
+  up the connections of a listener doesn't take scanning every connection. Both get changed
+  together, through insert( ) and remove( ) only.
+*/
+#[derive(Default)]
+struct ConnectionTable {
+  connections: HashMap<ConnectionQuad, Arc<SharedConnection>>,
+
+  byLocalPort: HashMap<u16, HashSet<ConnectionQuad>>,
+
+  // The connection map never gets shrunk below this.
+  minimumCapacity: usize,
+
+  // Since when the connection map has been less than a quarter full, if it has.
+  sparseSince: Option<Instant>,
+}
+
+// A TCB, shared between the segment processing and the users of the connection.
+pub struct SharedConnection {
+  tcb: Mutex<TCPConnection>,
+
+  // Notified every time the TCB might have changed.
+  changed: Condvar,
+
+  // Notified only when a writer held back by a full send buffer may go on, so that it doesn't get
+  // woken up by every segment and tick in between. See TCPConnection::take_writer_wakeup( ).
+  writable: Condvar,
+}
+
+/*
+  Threads and handles :
+
+  A connection gets handed out as an Arc<SharedConnection>, which is Send + Sync. It can be moved
+  to a worker thread, or shared between several. Every call locks the TCB once, so concurrent calls
+  never corrupt it, and each read( ) takes a contiguous run of the received bytes. Which of two
+  threads reading at once gets the earlier run is up to the scheduler though, so a byte stream
+  still wants one reader and one writer at a time (which may be two different threads).
+
+  A Drain holds the connection's lock, and thus isn't Send : it gets dropped by the thread which
+  created it.
+
+  The ConnectionManager, along with its listeners, is shared by the packet, timer and control
+  threads and the users, so it's Send + Sync too. So are the errors, which end up in anyhow.
+
+  The assertions below keep these from getting lost by accident, when a field changes.
+*/
+pub(crate) const fn assert_send_sync<T: ?Sized + Send + Sync>() {}
+
+const _: () = assert_send_sync::<SharedConnection>();
+const _: () = assert_send_sync::<Arc<SharedConnection>>();
+const _: () = assert_send_sync::<ConnectionManager>();
+const _: () = assert_send_sync::<TcpError>();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ListenerOptions {
+  // How many established connections may wait to be accepted.
+  pub backlog: usize,
+
+  // What happens to a connection completing its handshake, while the accept queue is full.
+  pub overflowPolicy: AcceptQueueOverflow,
+
+  // How many connection requests get taken in per second.
+  pub rateLimit: ConnectionRateLimit,
+}
+
+impl Default for ListenerOptions {
+  fn default() -> Self {
+    Self {
+      backlog: ACCEPT_QUEUE_BACKLOG,
+      overflowPolicy: AcceptQueueOverflow::RefuseNewest,
+      rateLimit: ConnectionRateLimit::default(),
+    }
+  }
+}
+
+/*
+  What to do when a passively opened connection completes its handshake, but the accept queue of
+  its listener is full :
+
+    (1) RefuseNewest : the ACK completing the handshake gets dropped, so the connection stays in the
+        SYN-RECEIVED state. Our SYN-ACK retransmission timer keeps running meanwhile, and each
+        retransmitted SYN-ACK makes the peer acknowledge it again. Once accept( ) has made room, the
+        next such ACK gets the connection established and queued. If no room is made before the
+        SYN-ACK retransmissions run out, the connection is given up on like a failed handshake.
+
+    (2) AbortOldest : the connection which has waited the longest to be accepted gets reset, making
+        room for the new one.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcceptQueueOverflow {
+  RefuseNewest,
+
+  AbortOldest,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Lifecycle {
+  state: InterfaceState,
+
+  // When the connections still open when draining get closed, or reset.
+  drainDeadline: Option<Instant>,
+
+  // When the connections closed at the drain deadline, which are still open, get reset.
+  closeGraceDeadline: Option<Instant>,
+}
+
+#[derive(Default)]
+pub struct ConnectionManagerCounters {
+  // RSTs sent in response to segments which don't belong to any connection we know of, like the
+  // data segments of a connection established before we restarted.
+  pub resetsToUnknownConnections: AtomicU64,
+
+  // RSTs sent in response to connection requests for ports nobody is listening on.
+  pub resetsToClosedPortSYNs: AtomicU64,
+
+  // RSTs sent in response to SYN+FINs for listening ports.
+  pub resetsToSYNFINs: AtomicU64,
+
+  // ACKs completing a handshake, which got dropped since the accept queue was full.
+  pub handshakesRefusedByFullAcceptQueue: AtomicU64,
+
+  // Connections reset since the accept queue was full : queued ones reset to make room, or (with
+  // RefuseNewest) ones which completed their handshake as the queue filled up.
+  pub acceptQueueOverflowAborts: AtomicU64,
+
+  // Times the connection map had to grow, rehashing every connection. Growing at all hints that
+  // the expected number of connections is set too low.
+  pub connectionMapGrowths: AtomicU64,
+
+  // Times the connection map got shrunk, after a spike of connections went away.
+  pub connectionMapShrinks: AtomicU64,
+
+  // Connection requests refused, by cause.
+  pub refusals: RefusalCounters,
+
+  // Segments dropped for not belonging to any connection, and coming from a martian source.
+  pub martianSegments: AtomicU64,
+
+  // SYNs dropped since their source equals their destination, as in a LAND attack.
+  pub landSYNs: AtomicU64,
+
+  // Segments dropped for not belonging to any connection, and being addressed to none of our
+  // addresses, like an alias which got removed.
+  pub foreignSegments: AtomicU64,
+}
+
+impl Display for ConnectionManagerCounters {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "resetsToUnknownConnections {}",
+      self.resetsToUnknownConnections.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "resetsToClosedPortSYNs {}",
+      self.resetsToClosedPortSYNs.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "resetsToSYNFINs {}",
+      self.resetsToSYNFINs.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "handshakesRefusedByFullAcceptQueue {}",
+      self
+        .handshakesRefusedByFullAcceptQueue
+        .load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "acceptQueueOverflowAborts {}",
+      self.acceptQueueOverflowAborts.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "connectionMapGrowths {}",
+      self.connectionMapGrowths.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "connectionMapShrinks {}",
+      self.connectionMapShrinks.load(Ordering::Relaxed)
+    )?;
+    write!(f, "{}", self.refusals)?;
+    writeln!(
+      f,
+      "martianSegments {}",
+      self.martianSegments.load(Ordering::Relaxed)
+    )?;
+    writeln!(f, "landSYNs {}", self.landSYNs.load(Ordering::Relaxed))?;
+    writeln!(
+      f,
+      "foreignSegments {}",
+      self.foreignSegments.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "invalidSegmentsSent {}",
+      tcp::INVALID_SEGMENTS_SENT.load(Ordering::Relaxed)
+    )
+  }
+}
+
+impl ToJson for ConnectionManagerCounters {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field(
+        "resets_to_unknown_connections",
+        &self.resetsToUnknownConnections.load(Ordering::Relaxed),
+      )
+      .field(
+        "resets_to_closed_port_syns",
+        &self.resetsToClosedPortSYNs.load(Ordering::Relaxed),
+      )
+      .field(
+        "resets_to_syn_fins",
+        &self.resetsToSYNFINs.load(Ordering::Relaxed),
+      )
+      .field(
+        "handshakes_refused_by_full_accept_queue",
+        &self
+          .handshakesRefusedByFullAcceptQueue
+          .load(Ordering::Relaxed),
+      )
+      .field(
+        "accept_queue_overflow_aborts",
+        &self.acceptQueueOverflowAborts.load(Ordering::Relaxed),
+      )
+      .field(
+        "connection_map_growths",
+        &self.connectionMapGrowths.load(Ordering::Relaxed),
+      )
+      .field(
+        "connection_map_shrinks",
+        &self.connectionMapShrinks.load(Ordering::Relaxed),
+      )
+      .field("refusals", &self.refusals)
+      .field(
+        "martian_segments",
+        &self.martianSegments.load(Ordering::Relaxed),
+      )
+      .field("land_syns", &self.landSYNs.load(Ordering::Relaxed))
+      .field(
+        "foreign_segments",
+        &self.foreignSegments.load(Ordering::Relaxed),
+      )
+      .field(
+        "invalid_segments_sent",
+        &tcp::INVALID_SEGMENTS_SENT.load(Ordering::Relaxed),
+      )
+      .finish();
+  }
+}
+
+impl ConnectionManager {
+  pub fn new(
+    nic: Arc<Nic>,
+    subnet: Ipv4Cidr,
+    tuning: TcpTuning,
+    filterRules: Vec<FilterRule>,
+    drainPolicy: DrainPolicy,
+    refusalPolicy: RefusalPolicy,
+    samplerConfig: SamplerConfig,
+  ) -> Self {
+    Self {
+      nic,
+      subnet,
+      aliases: RwLock::default(),
+      tuning,
+      clock: Arc::new(SystemClock),
+      listeningPorts: RwLock::default(),
+      filter: RwLock::new(PacketFilter::new(filterRules)),
+      connections: Mutex::new(ConnectionTable::with_capacity(
+        tuning.expectedConnections.unwrap_or_default(),
+      )),
+      acceptQueues: Mutex::default(),
+      accepted: Condvar::new(),
+      isAcceptingStopped: AtomicBool::new(false),
+      nextEphemeralPort: Mutex::new(*EPHEMERAL_PORTS.start()),
+      drainPolicy,
+      lifecycle: Mutex::default(),
+      refusalPolicy,
+      refusalLimiter: Mutex::default(),
+      rateLimiters: Mutex::default(),
+      nicFailure: Mutex::default(),
+      sampler: Mutex::new(Sampler::new(samplerConfig)),
+      events: Mutex::default(),
+      counters: ConnectionManagerCounters::default(),
+      tcpCounters: Arc::default(),
+    }
+  }
+
+  // Runs the timers on the given clock, rather than on the system's. Only takes effect for
+  // connections opened afterwards, so it's meant to be called right after new( ).
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  pub fn clock(&self) -> &Arc<dyn Clock> {
+    &self.clock
+  }
+
+  pub fn listen(&self, port: u16) {
+    self.listen_with(port, ListenerOptions::default());
+  }
+
+  // Like listen( ), but with the given accept queue options. Listening on a port again updates its
+  // options.
+  pub fn listen_with(&self, port: u16, options: ListenerOptions) {
+    self
+      .listeningPorts
+      .write()
+      .expect("Listening ports lock poisoned")
+      .insert(port, options);
+  }
+
+  pub fn listening_ports(&self) -> Vec<u16> {
+    let mut listeningPorts = self
+      .listeningPorts
+      .read()
+      .expect("Listening ports lock poisoned")
+      .keys()
+      .copied()
+      .collect::<Vec<_>>();
+
+    listeningPorts.sort_unstable();
+    listeningPorts
+  }
+
+  // The listeners whose accept queue options aren't the default ones, sorted by port.
+  pub fn listener_options(&self) -> Vec<(u16, ListenerOptions)> {
+    let mut listenerOptions = self
+      .listeningPorts
+      .read()
+      .expect("Listening ports lock poisoned")
+      .iter()
+      .filter(|(_, options)| **options != ListenerOptions::default())
+      .map(|(port, options)| (*port, *options))
+      .collect::<Vec<_>>();
+
+    listenerOptions.sort_unstable_by_key(|(port, _)| *port);
+    listenerOptions
+  }
+
+  pub fn filter_rules(&self) -> Vec<FilterRule> {
+    self
+      .filter
+      .read()
+      .expect("Packet filter lock poisoned")
+      .rules()
+  }
+
+  // Appends a rule to the packet filter. Rules only apply to connections established afterwards.
+  pub fn add_filter_rule(&self, rule: FilterRule) {
+    self
+      .filter
+      .write()
+      .expect("Packet filter lock poisoned")
+      .add(rule);
+  }
+
+  // Removes the packet filter rule at the given position. Returns false if there's no such rule.
+  pub fn remove_filter_rule(&self, index: usize) -> bool {
+    self
+      .filter
+      .write()
+      .expect("Packet filter lock poisoned")
+      .remove(index)
+  }
+
+  // Lists the packet filter rules along with their hit counts.
+  pub fn describe_filter(&self) -> String {
+    self
+      .filter
+      .read()
+      .expect("Packet filter lock poisoned")
+      .to_string()
+  }
+
+  // The address of the vNIC, followed by the aliases.
+  pub fn local_addresses(&self) -> Vec<Ipv4Addr> {
+    iter::once(self.subnet.address)
+      .chain(self.aliases())
+      .collect()
+  }
+
+  pub fn aliases(&self) -> Vec<Ipv4Addr> {
+    self
+      .aliases
+      .read()
+      .expect("Aliases lock poisoned")
+      .iter()
+      .copied()
+      .collect()
+  }
+
+  /*
+    Starts answering for the given address, which must be a unicast one outside our subnet. Every
+    listener accepts connections on it right away. The kernel only routes the subnet through the
+    vNIC though, so the alias needs a route of its own, like :
+
+      ip route add 192.168.50.7/32 dev utun4
+  */
+  pub fn add_alias(&self, address: Ipv4Addr) -> anyhow::Result<()> {
+    if tcp::is_martian_address(address) {
+      return Err(anyhow!("alias {} : must be a unicast address", address));
+    }
+    if self.subnet.contains(address) {
+      return Err(anyhow!(
+        "alias {} : is within the subnet {} already",
+        address,
+        self.subnet
+      ));
+    }
+
+    let isAdded = self
+      .aliases
+      .write()
+      .expect("Aliases lock poisoned")
+      .insert(address);
+    if !isAdded {
+      return Err(anyhow!("alias {} : has been added already", address));
+    }
+    Ok(())
+  }
+
+  /*
+    Stops answering for the given alias. Connections to it which are already open carry on till
+    they get closed, but no new ones get accepted. Returns false if there's no such alias.
+  */
+  pub fn remove_alias(&self, address: Ipv4Addr) -> bool {
+    self
+      .aliases
+      .write()
+      .expect("Aliases lock poisoned")
+      .remove(&address)
+  }
+
+  // Whether segments addressed to the given address are for us : it's a host address of our
+  // subnet, or an alias.
+  fn is_local_address(&self, address: Ipv4Addr) -> bool {
+    let isHostAddress = self.subnet.contains(address)
+      && address != self.subnet.network()
+      && address != self.subnet.broadcast();
+
+    isHostAddress
+      || self
+        .aliases
+        .read()
+        .expect("Aliases lock poisoned")
+        .contains(&address)
+  }
+
+  // Returns a snapshot of the current connections. The connection map's lock is released before
+  // returning, so the caller is free to lock the individual connections.
+  pub fn connections(&self) -> Vec<(ConnectionQuad, Arc<SharedConnection>)> {
+    self
+      .lock_connections()
+      .connections
+      .iter()
+      .map(|(connectionQuad, connection)| (*connectionQuad, connection.clone()))
+      .collect()
+  }
+
+  // How many connections the connection map has room for, before it has to grow.
+  pub fn connection_map_capacity(&self) -> usize {
+    self.lock_connections().connections.capacity()
+  }
+
+  pub fn connection(&self, connectionQuad: &ConnectionQuad) -> Option<Arc<SharedConnection>> {
+    self.lock_connections().get(connectionQuad).cloned()
+  }
+
+  // Like connections( ), but only for the connections on the given local port.
+  pub fn connections_on_port(&self, port: u16) -> Vec<(ConnectionQuad, Arc<SharedConnection>)> {
+    let connections = self.lock_connections();
+
+    connections
+      .on_port(port)
+      .map(|connectionQuad| {
+        (
+          *connectionQuad,
+          connections.connections[connectionQuad].clone(),
+        )
+      })
+      .collect()
+  }
+
+  pub fn counters(&self) -> &ConnectionManagerCounters {
+    &self.counters
+  }
+
+  pub fn nic(&self) -> &Nic {
+    &self.nic
+  }
+
+  pub fn tcp_counters(&self) -> &TcpCounters {
+    &self.tcpCounters
+  }
+
+  // Processes a segment received in the given IPv4 packet.
+  pub fn on_segment(
+    &self,
+    connectionQuad: ConnectionQuad,
+    ipv4Packet: &[u8],
+    tcpPacketHeader: TcpHeaderSlice,
+    tcpPacketPayload: &[u8],
+  ) {
+    self.nic.tap_inbound(&connectionQuad, ipv4Packet);
+
+    let segment = SegmentView {
+      header: tcpPacketHeader,
+      payload: tcpPacketPayload,
+    };
+    let mut ctx = SendContext { nic: &self.nic };
+
+    let mut connections = self.lock_connections();
+
+    match connections.get(&connectionQuad).cloned() {
+      /*
+        No existing connection.
+
+        Segments from martian sources, segments addressed to none of our addresses, and SYNs from
+        ourselves to ourselves, get dropped right away : no TCB may be created for them, and
+        nothing may be sent back to them.
+
+        The segment then goes through the packet filter. Then, if someone is listening on the
+        destination port, then a TCB in the LISTEN state processes the segment (RFC 9293 section
+        3.10.7.2), and is kept if the segment was a connection request. Otherwise the segment is
+        processed as per the CLOSED state (RFC 9293 section 3.10.7.1).
+
+        A connection request can get refused along the way, which refuse( ) answers.
+      */
+      None => {
+        if self.is_martian(connectionQuad.source) {
+          self
+            .counters
+            .martianSegments
+            .fetch_add(1, Ordering::Relaxed);
+          return;
+        }
+        if !self.is_local_address(connectionQuad.destiation.address) {
+          self
+            .counters
+            .foreignSegments
+            .fetch_add(1, Ordering::Relaxed);
+          return;
+        }
+        if segment.header.syn() && connectionQuad.source == connectionQuad.destiation {
+          self.counters.landSYNs.fetch_add(1, Ordering::Relaxed);
+          return;
+        }
+
+        let isListening = self
+          .listeningPorts
+          .read()
+          .expect("Listening ports lock poisoned")
+          .contains_key(&connectionQuad.destiation.port);
+
+        if segment.header.rst() {
+          return;
+        }
+        let isConnectionRequest = segment.header.syn() && !segment.header.ack();
+
+        if self.state() != InterfaceState::Running && isConnectionRequest {
+          self.refuse(
+            RefusalCause::Draining,
+            self.refusalPolicy.draining,
+            &connectionQuad,
+            &segment,
+          );
+          return;
+        }
+
+        let filterAction = self
+          .filter
+          .read()
+          .expect("Packet filter lock poisoned")
+          .evaluate(
+            connectionQuad.source.address,
+            connectionQuad.destiation.port,
+          );
+
+        let filterResponse = match filterAction {
+          FilterAction::Allow => None,
+          FilterAction::Deny => Some(RefusalResponse::Drop),
+          FilterAction::Reject => Some(RefusalResponse::Reset),
+        };
+        if let Some(filterResponse) = filterResponse {
+          self.refuse(
+            RefusalCause::Filtered,
+            filterResponse,
+            &connectionQuad,
+            &segment,
+          );
+          return;
+        }
+
+        if isListening {
+          if isConnectionRequest && self.is_refusing_handshakes(connectionQuad.destiation.port) {
+            self.refuse(
+              RefusalCause::AcceptQueueFull,
+              self.refusalPolicy.acceptQueueFull,
+              &connectionQuad,
+              &segment,
+            );
+            return;
+          }
+          if isConnectionRequest && self.is_rate_limited(&connectionQuad) {
+            self.refuse(
+              RefusalCause::RateLimited,
+              self.refusalPolicy.rateLimited,
+              &connectionQuad,
+              &segment,
+            );
+            return;
+          }
+
+          // A capture armed on the port starts with the connection request, so that the SYN-ACK
+          // gets captured too.
+          if isConnectionRequest {
+            let mut captures = self.nic.lock_captures();
+
+            captures.on_connection_request(connectionQuad);
+            captures.record(&connectionQuad, ipv4Packet);
+          }
+
+          let mut newConnection = TCPConnection::listen(
+            connectionQuad,
+            self.tuning,
+            tcp::maximum_segment_size(self.nic.mtu()),
+            self.tcpCounters.clone(),
+            self.clock.clone(),
+          );
+
+          match newConnection.handle(&segment, &mut ctx) {
+            // The LISTEN state answers any acknowledgment, and any SYN+FIN, with a RST.
+            Action::Remove => {
+              if isConnectionRequest {
+                self.nic.lock_captures().disarm(&connectionQuad);
+              }
+
+              let counter = if segment.header.ack() {
+                Some(&self.counters.resetsToUnknownConnections)
+              }
+              else if segment.header.syn() && segment.header.fin() {
+                Some(&self.counters.resetsToSYNFINs)
+              }
+              else {
+                None
+              };
+
+              if let Some(counter) = counter {
+                counter.fetch_add(1, Ordering::Relaxed);
+              }
+            }
+
+            Action::Keep | Action::MoveToAcceptQueue => {
+              let hasGrown = connections.insert(
+                connectionQuad,
+                Arc::new(SharedConnection::new(newConnection)),
+              );
+              self.record_connection_map_growth(hasGrown);
+              self.tcpCounters.record_connection_count(connections.len());
+              drop(connections);
+
+              self.record_event(ConnectionEvent::Opened {
+                quad: connectionQuad,
+                passive: true,
+              });
+            }
+          }
+          return;
+        }
+
+        if isConnectionRequest {
+          let isResetSent = self.refuse(
+            RefusalCause::ClosedPort,
+            self.refusalPolicy.closedPort,
+            &connectionQuad,
+            &segment,
+          );
+          if isResetSent {
+            self
+              .counters
+              .resetsToClosedPortSYNs
+              .fetch_add(1, Ordering::Relaxed);
+          }
+          return;
+        }
+
+        if let Err(error) = tcp::send_reset(
+          &connectionQuad,
+          &segment.header,
+          segment.payload.len(),
+          &self.nic,
+        ) {
+          eprintln!("Failed sending RST to {} : {}", connectionQuad, error);
+          return;
+        }
+        self
+          .counters
+          .resetsToUnknownConnections
+          .fetch_add(1, Ordering::Relaxed);
+        self.tcpCounters.resetsSent.fetch_add(1, Ordering::Relaxed);
+      }
+
+      // Connection exists.
+      // Process the packet.
+      Some(existingConnection) => {
+        drop(connections);
+
+        let (action, isWakeupDeferred, isEstablished, reverseLossSuspicion, isWriterWoken) = {
+          let mut tcb = lock_connection(&existingConnection);
+
+          /*
+            Only the handshakes of passively opened connections may get refused, so only the
+            segments which may complete theirs (carrying an ACK, but no SYN or RST) pay for a look
+            at the accept queue. Anything else, like a retransmitted SYN, gets processed as usual.
+            The connection gets unlocked meanwhile, since the accept queues' lock must not be
+            taken while holding it.
+          */
+          let mayCompleteHandshake =
+            segment.header.ack() && !segment.header.syn() && !segment.header.rst();
+          if tcb.is_passive_open()
+            && tcb.state() == TCPConnectionState::SYNReceived
+            && mayCompleteHandshake
+          {
+            drop(tcb);
+            let isAcceptQueueFull = self.is_refusing_handshakes(connectionQuad.destiation.port);
+
+            tcb = lock_connection(&existingConnection);
+            if isAcceptQueueFull && tcb.state() == TCPConnectionState::SYNReceived {
+              self
+                .counters
+                .handshakesRefusedByFullAcceptQueue
+                .fetch_add(1, Ordering::Relaxed);
+              return;
+            }
+          }
+
+          let previousState = tcb.state();
+          let action = tcb.handle(&segment, &mut ctx);
+          (
+            action,
+            tcb.is_wakeup_deferred(),
+            !previousState.is_synchronized() && tcb.state().is_synchronized(),
+            tcb.take_reverse_loss_suspicion(),
+            tcb.take_writer_wakeup(),
+          )
+        };
+        if !isWakeupDeferred {
+          existingConnection.changed.notify_all();
+        }
+        if isWriterWoken {
+          existingConnection.writable.notify_all();
+        }
+
+        if isEstablished {
+          self.record_event(ConnectionEvent::Established {
+            quad: connectionQuad,
+          });
+        }
+        if let Some(duplicates) = reverseLossSuspicion {
+          self.record_event(ConnectionEvent::ReverseLossSuspected {
+            quad: connectionQuad,
+            duplicates,
+          });
+        }
+
+        match action {
+          // The connection lock has been released by now, so the connection map can be locked to
+          // delete the TCB.
+          Action::Remove => self.remove(&connectionQuad, &existingConnection),
+
+          Action::MoveToAcceptQueue => {
+            self.enqueue_accepted(connectionQuad.destiation.port, existingConnection)
+          }
+
+          Action::Keep => {}
+        }
+      }
+    }
+  }
+
+  // Blocks till a connection to the given listening port completes its handshake, and returns it.
+  // Fails once the interface gets stopped.
+  pub fn accept(&self, port: u16) -> Result<Arc<SharedConnection>, TcpError> {
+    let mut acceptQueues = self
+      .accepted
+      .wait_while(self.lock_accept_queues(), |acceptQueues| {
+        !self.isAcceptingStopped.load(Ordering::Relaxed)
+          && acceptQueues
+            .get(&port)
+            .is_none_or(|acceptQueue| acceptQueue.is_empty())
+      })
+      .expect("Accept queue mutex poisoned");
+
+    acceptQueues
+      .get_mut(&port)
+      .and_then(VecDeque::pop_front)
+      .ok_or(TcpError::InterfaceShutdown {
+        state: InterfaceState::Stopped,
+      })
+  }
+
+  // Like accept( ), but returns None right away if no connection is waiting to be accepted.
+  pub fn try_accept(&self, port: u16) -> Option<Arc<SharedConnection>> {
+    self
+      .lock_accept_queues()
+      .get_mut(&port)
+      .and_then(VecDeque::pop_front)
+  }
+
+  /*
+    Queues a connection which just completed its handshake. With the RefuseNewest policy, the
+    accept queue can still turn out to be full here, if it got filled after the handshake
+    completing ACK got let through. The connection is established by then, so it can't go back to
+    waiting in the SYN-RECEIVED state : it gets reset instead, rather than being left in the
+    connection map with nobody to ever accept it.
+  */
+  fn enqueue_accepted(&self, port: u16, connection: Arc<SharedConnection>) {
+    let options = self.listener(port).unwrap_or_default();
+
+    let abortedConnection = {
+      let mut acceptQueues = self.lock_accept_queues();
+
+      let acceptQueue = acceptQueues.entry(port).or_default();
+      if acceptQueue.len() < options.backlog {
+        acceptQueue.push_back(connection);
+        self.accepted.notify_all();
+        None
+      }
+      else if options.overflowPolicy == AcceptQueueOverflow::AbortOldest {
+        let abortedConnection = acceptQueue.pop_front();
+        acceptQueue.push_back(connection);
+        self.accepted.notify_all();
+        abortedConnection
+      }
+      else {
+        Some(connection)
+      }
+    };
+
+    // The accept queues' lock has been released by now, so the connection can be locked.
+    if let Some(abortedConnection) = abortedConnection {
+      let connectionQuad = lock_connection(&abortedConnection).quad();
+
+      self
+        .counters
+        .acceptQueueOverflowAborts
+        .fetch_add(1, Ordering::Relaxed);
+      self.abort_quad(&connectionQuad);
+    }
+  }
+
+  /*
+    Answers a refused connection request with the given response, which is the one place where
+    refusals get counted, logged and (when resetting) rate limited. Returns whether a RST got sent.
+  */
+  fn refuse(
+    &self,
+    cause: RefusalCause,
+    response: RefusalResponse,
+    connectionQuad: &ConnectionQuad,
+    segment: &SegmentView,
+  ) -> bool {
+    self.counters.refusals.record(cause);
+
+    let (maySendReset, unloggedRefusals) = self
+      .refusalLimiter
+      .lock()
+      .expect("Refusal limiter mutex poisoned")
+      .admit(response, self.clock.now());
+
+    if let Some(unloggedRefusals) = unloggedRefusals {
+      println!(
+        "Refused connection request {} ({}), responding with {} | {} more refusals not logged",
+        connectionQuad, cause, response, unloggedRefusals
+      );
+    }
+
+    if response != RefusalResponse::Reset {
+      return false;
+    }
+    if !maySendReset {
+      self
+        .counters
+        .refusals
+        .rateLimitedResets
+        .fetch_add(1, Ordering::Relaxed);
+      return false;
+    }
+
+    if let Err(error) = tcp::send_reset(
+      connectionQuad,
+      &segment.header,
+      segment.payload.len(),
+      &self.nic,
+    ) {
+      eprintln!("Failed sending RST to {} : {}", connectionQuad, error);
+      return false;
+    }
+    self.tcpCounters.resetsSent.fetch_add(1, Ordering::Relaxed);
+    true
+  }
+
+  // Whether handshakes on the given listening port must not be completed, since its accept queue
+  // is full and the RefuseNewest policy applies.
+  fn is_refusing_handshakes(&self, port: u16) -> bool {
+    let Some(options) = self.listener(port)
+    else {
+      return false;
+    };
+    if options.overflowPolicy != AcceptQueueOverflow::RefuseNewest {
+      return false;
+    }
+
+    self
+      .lock_accept_queues()
+      .get(&port)
+      .is_some_and(|acceptQueue| acceptQueue.len() >= options.backlog)
+  }
+
+  // Whether the connection request exceeds the rate limit of its listener, or of its source. If it
+  // doesn't, it's counted against both.
+  fn is_rate_limited(&self, connectionQuad: &ConnectionQuad) -> bool {
+    let port = connectionQuad.destiation.port;
+    let Some(options) = self
+      .listener(port)
+      .filter(|options| options.rateLimit.is_limited())
+    else {
+      return false;
+    };
+
+    self
+      .rateLimiters
+      .lock()
+      .expect("Rate limiters mutex poisoned")
+      .entry(port)
+      .or_default()
+      .admit(
+        options.rateLimit,
+        connectionQuad.source.address,
+        self.clock.now(),
+      )
+      .is_some()
+  }
+
+  // Changes the connection rate limit of the given listening port. Returns false if nobody listens
+  // on it.
+  pub fn set_rate_limit(&self, port: u16, rateLimit: ConnectionRateLimit) -> bool {
+    let mut listeningPorts = self
+      .listeningPorts
+      .write()
+      .expect("Listening ports lock poisoned");
+
+    let Some(options) = listeningPorts.get_mut(&port)
+    else {
+      return false;
+    };
+    options.rateLimit = rateLimit;
+    true
+  }
+
+  // One line per rate limited listener, with its limit, how many connection requests each limit
+  // refused and the sources refused the most.
+  pub fn describe_rate_limits(&self) -> String {
+    let listenerOptions = self.listener_options();
+    let rateLimiters = self
+      .rateLimiters
+      .lock()
+      .expect("Rate limiters mutex poisoned");
+
+    let mut description = String::new();
+    for (port, options) in listenerOptions {
+      if !options.rateLimit.is_limited() {
+        continue;
+      }
+
+      let _ = write!(description, "port {} : rate {}", port, options.rateLimit);
+      if let Some(rateLimiter) = rateLimiters.get(&port) {
+        let _ = write!(description, " | {}", rateLimiter);
+      }
+      description.push('\n');
+    }
+    description
+  }
+
+  fn listener(&self, port: u16) -> Option<ListenerOptions> {
+    self
+      .listeningPorts
+      .read()
+      .expect("Listening ports lock poisoned")
+      .get(&port)
+      .copied()
+  }
+
+  // Whatever the TCBs need, for sending segments on behalf of a user call.
+  pub fn send_context(&self) -> SendContext<'_> {
+    SendContext { nic: &self.nic }
+  }
+
+  /*
+    Actively opens a connection to the given peer, from an ephemeral port. Blocks till the
+    connection gets established, or fails with ConnectionRefused when the peer resets it, or with
+    TimedOut when the peer doesn't answer in time. After a simultaneous open, the connection goes
+    through the SYN-RECEIVED state first, where it can still get refused.
+  */
+  pub fn connect(&self, peer: Location) -> Result<Arc<SharedConnection>, TcpError> {
+    let connection = self.start_connect(peer)?;
+
+    let outcome = {
+      let tcb = connection.wait_while(|tcb| {
+        matches!(
+          tcb.state(),
+          TCPConnectionState::SYNSent | TCPConnectionState::SYNReceived
+        )
+      });
+      (tcb.state(), tcb.close_reason())
+    };
+
+    match outcome {
+      (TCPConnectionState::Closed, Some(CloseReason::Refused)) => Err(TcpError::ConnectionRefused),
+      (TCPConnectionState::Closed, Some(CloseReason::ConnectTimeout)) => Err(TcpError::TimedOut),
+      (TCPConnectionState::Closed, reason) => Err(TcpError::ConnectionReset {
+        reason: reason.unwrap_or(CloseReason::Aborted),
+      }),
+
+      _ => Ok(connection),
+    }
+  }
+
+  // Whether the given location can't be a peer : besides the martians, the network and the
+  // (directed) broadcast addresses of our subnet.
+  fn is_martian(&self, location: Location) -> bool {
+    location.is_martian()
+      || location.address == self.subnet.network()
+      || location.address == self.subnet.broadcast()
+  }
+
+  /*
+    Like connect( ), but returns as soon as the SYN has been sent. Data written to the connection
+    in the meantime gets transmitted once it's established. If it never gets established, the
+    data is discarded, and writing fails with the reason (ConnectionRefused, TimedOut etc.).
+  */
+  pub fn start_connect(&self, peer: Location) -> Result<Arc<SharedConnection>, TcpError> {
+    if self.is_martian(peer) {
+      return Err(TcpError::MartianPeer(peer));
+    }
+
+    let state = self.state();
+    if state != InterfaceState::Running {
+      return Err(TcpError::InterfaceShutdown { state });
+    }
+
+    let (connectionQuad, connection) = {
+      let mut connections = self.lock_connections();
+
+      let connectionQuad = self
+        .allocate_ephemeral_port(&connections, peer)
+        .ok_or(TcpError::AddrNotAvailable)?;
+
+      let connection = Arc::new(SharedConnection::new(TCPConnection::connect(
+        connectionQuad,
+        self.tuning,
+        tcp::maximum_segment_size(self.nic.mtu()),
+        self.tcpCounters.clone(),
+        self.clock.clone(),
+      )));
+      let hasGrown = connections.insert(connectionQuad, connection.clone());
+      self.record_connection_map_growth(hasGrown);
+      self.tcpCounters.record_connection_count(connections.len());
+
+      (connectionQuad, connection)
+    };
+
+    self.record_event(ConnectionEvent::Opened {
+      quad: connectionQuad,
+      passive: false,
+    });
+
+    if let Err(error) = lock_connection(&connection).open(&self.nic) {
+      eprintln!("Failed sending SYN to {} : {}", connectionQuad, error);
+    }
+
+    Ok(connection)
+  }
+
+  // Picks a local port, which no other connection to the given peer uses, and nobody listens on.
+  fn allocate_ephemeral_port(
+    &self,
+    connections: &ConnectionTable,
+    peer: Location,
+  ) -> Option<ConnectionQuad> {
+    let listeningPorts = self
+      .listeningPorts
+      .read()
+      .expect("Listening ports lock poisoned");
+    let mut nextEphemeralPort = self
+      .nextEphemeralPort
+      .lock()
+      .expect("Ephemeral port mutex poisoned");
+
+    for _ in EPHEMERAL_PORTS {
+      let port = *nextEphemeralPort;
+      *nextEphemeralPort = if port == *EPHEMERAL_PORTS.end() {
+        *EPHEMERAL_PORTS.start()
+      }
+      else {
+        port + 1
+      };
+
+      let connectionQuad = ConnectionQuad {
+        source: peer,
+        destiation: Location {
+          address: self.subnet.address,
+          port,
+        },
+      };
+
+      if !listeningPorts.contains_key(&port) && !connections.contains_key(&connectionQuad) {
+        return Some(connectionQuad);
+      }
+    }
+    None
+  }
+
+  // Aborts the connection identified by the given quad : a RST is sent to the peer (unless the
+  // state calls for none, see abort( )) and the TCB is deleted. Returns false if no such
+  // connection exists.
+  pub fn abort_quad(&self, connectionQuad: &ConnectionQuad) -> bool {
+    let connection = {
+      let mut connections = self.lock_connections();
+
+      let Some(connection) = connections.remove(connectionQuad)
+      else {
+        return false;
+      };
+      self.tcpCounters.record_connection_count(connections.len());
+
+      connection
+    };
+
+    let (result, isWriterWoken) = {
+      let mut tcb = lock_connection(&connection);
+      (
+        tcb.abort(CloseReason::Aborted, &self.nic),
+        tcb.take_writer_wakeup(),
+      )
+    };
+    connection.notify(isWriterWoken);
+
+    if let Err(error) = result {
+      eprintln!("Failed sending RST to {} : {}", connectionQuad, error);
+    }
+    self.record_event(ConnectionEvent::Closed {
+      quad: *connectionQuad,
+      reason: Some(CloseReason::Aborted),
+    });
+    self.stop_capture(connectionQuad);
+    self.stop_sampling(connectionQuad);
+
+    true
+  }
+
+  // Starts capturing the packets of the given connection, and returns the path of the capture file.
+  pub fn capture_connection(&self, connectionQuad: ConnectionQuad) -> anyhow::Result<PathBuf> {
+    // Holding the connection map's lock, the connection can't get removed (and its capture
+    // stopped) before the capture gets started.
+    let connections = self.lock_connections();
+    if !connections.contains_key(&connectionQuad) {
+      return Err(anyhow!("connection {} not found", connectionQuad));
+    }
+
+    self.nic.lock_captures().start(connectionQuad)
+  }
+
+  // Captures the packets of the next connection accepted on the given listening port.
+  pub fn capture_next_connection(&self, port: u16) -> anyhow::Result<()> {
+    if !self
+      .listeningPorts
+      .read()
+      .expect("Listening ports lock poisoned")
+      .contains_key(&port)
+    {
+      return Err(anyhow!("nobody is listening on port {}", port));
+    }
+
+    self.nic.lock_captures().arm(port)
+  }
+
+  // Stops capturing the given connection, if it's being captured, and returns the path of the
+  // capture file.
+  pub fn stop_capture(&self, connectionQuad: &ConnectionQuad) -> Option<PathBuf> {
+    let path = self.nic.lock_captures().stop(connectionQuad)?;
+
+    println!(
+      "Stopped capturing {} into {}",
+      connectionQuad,
+      path.display()
+    );
+    Some(path)
+  }
+
+  // Lists the running and armed packet captures.
+  pub fn describe_captures(&self) -> String {
+    self.nic.lock_captures().describe()
+  }
+
+  // Starts sampling the given connection, and returns the path of the sample file.
+  pub fn sample_connection(&self, connectionQuad: ConnectionQuad) -> anyhow::Result<PathBuf> {
+    // Holding the connection map's lock, the connection can't get removed (and its sampling
+    // stopped) before the sampling gets started.
+    let connections = self.lock_connections();
+    if !connections.contains_key(&connectionQuad) {
+      return Err(anyhow!("connection {} not found", connectionQuad));
+    }
+
+    self
+      .lock_sampler()
+      .start(connectionQuad)
+      .map(|path| path.to_path_buf())
+  }
+
+  // Stops sampling the given connection. Returns whether it was being sampled.
+  pub fn stop_sampling(&self, connectionQuad: &ConnectionQuad) -> bool {
+    self.lock_sampler().stop(connectionQuad)
+  }
+
+  pub fn describe_sampler(&self) -> String {
+    self.lock_sampler().describe()
+  }
+
+  // Starts appending the connection events to the given file, as JSON lines.
+  pub fn log_events_to(&self, path: &Path) -> anyhow::Result<()> {
+    *self.lock_events() = Some(EventLog::open(path)?);
+    Ok(())
+  }
+
+  fn record_event(&self, event: ConnectionEvent) {
+    if let Some(events) = self.lock_events().as_mut() {
+      events.record(&event);
+    }
+  }
+
+  pub fn state(&self) -> InterfaceState {
+    self.lock_lifecycle().state
+  }
+
+  // Starts draining, unless already draining or stopped. Returns the number of connections left.
+  pub fn drain(&self) -> usize {
+    {
+      let mut lifecycle = self.lock_lifecycle();
+
+      if lifecycle.state == InterfaceState::Running {
+        lifecycle.state = InterfaceState::Draining;
+        lifecycle.drainDeadline = Some(self.clock.now() + self.drainPolicy.deadline);
+      }
+    }
+
+    let remainingConnections = self.remaining_connections();
+    println!("Draining : {} connections remaining", remainingConnections);
+
+    for port in self.listening_ports() {
+      let remainingConnectionsOnPort = self
+        .connections_on_port(port)
+        .iter()
+        .filter(|(_, connection)| is_remaining(connection))
+        .count();
+
+      if remainingConnectionsOnPort > 0 {
+        println!(
+          "Draining : {} connections remaining on port {}",
+          remainingConnectionsOnPort, port
+        );
+      }
+    }
+
+    remainingConnections
+  }
+
+  fn remaining_connections(&self) -> usize {
+    self
+      .connections()
+      .iter()
+      .filter(|(_, connection)| is_remaining(connection))
+      .count()
+  }
+
+  // Moves the drain forward, once the connections are gone or the deadlines have passed.
+  fn progress_drain(&self, now: Instant) {
+    let lifecycle = *self.lock_lifecycle();
+    if lifecycle.state != InterfaceState::Draining {
+      return;
+    }
+
+    let mut nextLifecycle = lifecycle;
+
+    if self.remaining_connections() == 0 {
+      nextLifecycle.state = InterfaceState::Stopped;
+    }
+    else {
+      match lifecycle.closeGraceDeadline {
+        None
+          if lifecycle
+            .drainDeadline
+            .is_some_and(|deadline| now >= deadline) =>
+        {
+          match self.drainPolicy.atDeadline {
+            DrainDeadlineAction::Close => {
+              self.close_every_connection();
+              nextLifecycle.closeGraceDeadline = Some(now + DRAIN_CLOSE_GRACE);
+            }
+
+            DrainDeadlineAction::Reset => {
+              self.abort_every_connection();
+              nextLifecycle.state = InterfaceState::Stopped;
+            }
+          }
+        }
+
+        Some(closeGraceDeadline) if now >= closeGraceDeadline => {
+          self.abort_every_connection();
+          nextLifecycle.state = InterfaceState::Stopped;
+        }
+
+        _ => {}
+      }
+    }
+
+    *self.lock_lifecycle() = nextLifecycle;
+
+    if nextLifecycle.state == InterfaceState::Stopped {
+      println!("Drained every connection");
+      self.stop_accepting();
+    }
+  }
+
+  /*
+    Shuts the interface down after the vNIC failed for good : every connection gets closed without
+    telling its peer, and whoever is blocked on a connection (or in accept( )) gets woken up with
+    an error.
+  */
+  pub fn on_nic_failure(&self, error: &NicError) {
+    eprintln!("ERROR : shutting down, since the vNIC failed : {}", error);
+
+    *self.nicFailure.lock().expect("vNIC failure mutex poisoned") = Some(error.to_string());
+    self.lock_lifecycle().state = InterfaceState::Stopped;
+
+    for (connectionQuad, connection) in self.connections() {
+      let isWriterWoken = {
+        let mut tcb = lock_connection(&connection);
+        tcb.discard(CloseReason::NicFailed);
+        tcb.take_writer_wakeup()
+      };
+      connection.notify(isWriterWoken);
+
+      self.remove(&connectionQuad, &connection);
+    }
+    self.stop_accepting();
+  }
+
+  // Why the vNIC failed, if it did.
+  pub fn nic_failure(&self) -> Option<String> {
+    self
+      .nicFailure
+      .lock()
+      .expect("vNIC failure mutex poisoned")
+      .clone()
+  }
+
+  fn stop_accepting(&self) {
+    let _acceptQueues = self.lock_accept_queues();
+
+    self.isAcceptingStopped.store(true, Ordering::Relaxed);
+    self.accepted.notify_all();
+  }
+
+  fn close_every_connection(&self) {
+    let mut ctx = self.send_context();
+
+    for (connectionQuad, connection) in self.connections() {
+      let (result, isWriterWoken) = {
+        let mut tcb = lock_connection(&connection);
+        (tcb.close(&mut ctx), tcb.take_writer_wakeup())
+      };
+      connection.notify(isWriterWoken);
+
+      // Connections which are already closing are left alone.
+      match result {
+        Ok(()) | Err(TcpError::NotConnected) => {}
+        Err(error) => eprintln!("Failed closing {} : {}", connectionQuad, error),
+      }
+    }
+  }
+
+  fn abort_every_connection(&self) {
+    for (connectionQuad, _) in self.connections() {
+      self.abort_quad(&connectionQuad);
+    }
+  }
+
+  fn record_connection_map_growth(&self, hasGrown: bool) {
+    if hasGrown {
+      self
+        .counters
+        .connectionMapGrowths
+        .fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  // Fires the expired timers of every connection, moves the drain forward and shrinks the
+  // connection map when it has stayed sparse. Expected to be called every TICK_INTERVAL.
+  pub fn on_tick(&self) {
+    let now = self.clock.now();
+
+    for (connectionQuad, connection) in self.connections() {
+      let (result, state, isWriterWoken) = {
+        let mut connection = lock_connection(&connection);
+
+        let result = connection.on_tick(now, &self.nic);
+        (result, connection.state(), connection.take_writer_wakeup())
+      };
+      connection.notify(isWriterWoken);
+
+      if let Err(error) = result {
+        eprintln!("Failed firing timers of {} : {}", connectionQuad, error);
+      }
+
+      if state == TCPConnectionState::Closed {
+        self.remove(&connectionQuad, &connection);
+      }
+    }
+
+    self.progress_drain(now);
+    self.take_samples(now);
+
+    if self.lock_connections().shrink_if_sparse(now) {
+      self
+        .counters
+        .connectionMapShrinks
+        .fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  // Samples the sampled connections, if a sample is due.
+  fn take_samples(&self, now: Instant) {
+    let Some(sampledConnections) = self.lock_sampler().due(now)
+    else {
+      return;
+    };
+
+    // The connections get locked once the connection map has been released, so that the packet
+    // loop isn't held up by the sampling.
+    let sampledConnections: Vec<_> = {
+      let connections = self.lock_connections();
+
+      sampledConnections
+        .into_iter()
+        .filter_map(|connectionQuad| {
+          Some((connectionQuad, connections.get(&connectionQuad)?.clone()))
+        })
+        .collect()
+    };
+    let samples: Vec<_> = sampledConnections
+      .iter()
+      .map(|(connectionQuad, connection)| (*connectionQuad, lock_connection(connection).sample()))
+      .collect();
+
+    self.lock_sampler().submit(now, &samples);
+  }
+
+  // Deletes the TCB of the given connection, unless it has already been replaced by a newer one.
+  fn remove(&self, connectionQuad: &ConnectionQuad, connection: &Arc<SharedConnection>) {
+    let mut connections = self.lock_connections();
+
+    let isRemoved = connections
+      .get(connectionQuad)
+      .is_some_and(|entry| Arc::ptr_eq(entry, connection));
+    if isRemoved {
+      connections.remove(connectionQuad);
+      self.tcpCounters.record_connection_count(connections.len());
+    }
+    drop(connections);
+
+    let closeReason = lock_connection(connection).close_reason();
+    if let Some(closeReason) = closeReason {
+      println!("Connection {} closed : {}", connectionQuad, closeReason);
+    }
+    self.record_event(ConnectionEvent::Closed {
+      quad: *connectionQuad,
+      reason: closeReason,
+    });
+
+    // The capture (and the sampling) belongs to the newer connection otherwise.
+    if isRemoved {
+      self.stop_capture(connectionQuad);
+      self.stop_sampling(connectionQuad);
+    }
+  }
+
+  fn lock_events(&self) -> MutexGuard<'_, Option<EventLog>> {
+    self.events.lock().expect("Event log mutex poisoned")
+  }
+
+  fn lock_sampler(&self) -> MutexGuard<'_, Sampler> {
+    self.sampler.lock().expect("Sampler mutex poisoned")
+  }
+
+  fn lock_lifecycle(&self) -> MutexGuard<'_, Lifecycle> {
+    self.lifecycle.lock().expect("Lifecycle mutex poisoned")
+  }
+
+  fn lock_accept_queues(&self) -> MutexGuard<'_, HashMap<u16, VecDeque<Arc<SharedConnection>>>> {
+    self
+      .acceptQueues
+      .lock()
+      .expect("Accept queue mutex poisoned")
+  }
+
+  fn lock_connections(&self) -> MutexGuard<'_, ConnectionTable> {
+    self
+      .connections
+      .lock()
+      .expect("Connection map mutex poisoned")
+  }
+}
+
+impl ConnectionTable {
+  fn with_capacity(capacity: usize) -> Self {
+    Self {
+      connections: HashMap::with_capacity(capacity),
+      byLocalPort: HashMap::with_capacity(capacity.min(u16::MAX as usize + 1)),
+      minimumCapacity: capacity,
+      sparseSince: None,
+    }
+  }
+
+  fn get(&self, connectionQuad: &ConnectionQuad) -> Option<&Arc<SharedConnection>> {
+    self.connections.get(connectionQuad)
+  }
+
+  fn len(&self) -> usize {
+    self.connections.len()
+  }
+
+  fn contains_key(&self, connectionQuad: &ConnectionQuad) -> bool {
+    self.connections.contains_key(connectionQuad)
+  }
+
+  // Returns whether the connection map had to grow, to make room for the connection.
+  fn insert(&mut self, connectionQuad: ConnectionQuad, connection: Arc<SharedConnection>) -> bool {
+    let capacity = self.connections.capacity();
+
+    self.connections.insert(connectionQuad, connection);
+    self
+      .byLocalPort
+      .entry(connectionQuad.destiation.port)
+      .or_default()
+      .insert(connectionQuad);
+
+    self.debug_assert_indexed(&connectionQuad);
+    self.connections.capacity() > capacity
+  }
+
+  /*
+    Gives the memory of a past spike of connections back, once the connection map has stayed less
+    than a quarter full for CONNECTION_MAP_SHRINK_DELAY. It keeps room for twice the connections
+    left, so that it doesn't have to grow again right away. Returns whether it shrunk.
+  */
+  fn shrink_if_sparse(&mut self, now: Instant) -> bool {
+    self.debug_assert_consistent();
+
+    let capacity = self.connections.capacity();
+    // Small maps aren't worth shrinking.
+    let isSparse = capacity > self.minimumCapacity.max(64) && self.connections.len() < capacity / 4;
+
+    if !isSparse {
+      self.sparseSince = None;
+      return false;
+    }
+
+    let sparseSince = *self.sparseSince.get_or_insert(now);
+    if now.duration_since(sparseSince) < CONNECTION_MAP_SHRINK_DELAY {
+      return false;
+    }
+    self.sparseSince = None;
+
+    let targetCapacity = (self.connections.len() * 2).max(self.minimumCapacity);
+    self.connections.shrink_to(targetCapacity);
+    self.byLocalPort.shrink_to(self.byLocalPort.len() * 2);
+    for connectionQuads in self.byLocalPort.values_mut() {
+      connectionQuads.shrink_to(connectionQuads.len() * 2);
+    }
+
+    self.connections.capacity() < capacity
+  }
+
+  fn remove(&mut self, connectionQuad: &ConnectionQuad) -> Option<Arc<SharedConnection>> {
+    let connection = self.connections.remove(connectionQuad)?;
+
+    let port = connectionQuad.destiation.port;
+    if let Some(connectionQuads) = self.byLocalPort.get_mut(&port) {
+      connectionQuads.remove(connectionQuad);
+      if connectionQuads.is_empty() {
+        self.byLocalPort.remove(&port);
+      }
+    }
+
+    self.debug_assert_indexed(connectionQuad);
+    Some(connection)
+  }
+
+  fn on_port(&self, port: u16) -> impl Iterator<Item = &ConnectionQuad> {
+    self.byLocalPort.get(&port).into_iter().flatten()
+  }
+
+  /*
+    Checks, in debug builds, that the index holds exactly the quads of the connection map, each
+    under its local port. That takes a pass over both, so it only runs once per tick. Inserting or
+    removing a connection checks just its quad, along with the counts.
+  */
+  fn debug_assert_consistent(&self) {
+    if !cfg!(debug_assertions) {
+      return;
+    }
+    self.debug_assert_counts_match();
+
+    for (port, connectionQuads) in &self.byLocalPort {
+      debug_assert!(
+        !connectionQuads.is_empty(),
+        "The local port index kept port {} without connections",
+        port
+      );
+      for connectionQuad in connectionQuads {
+        debug_assert_eq!(
+          connectionQuad.destiation.port, *port,
+          "The local port index holds {} under port {}",
+          connectionQuad, port
+        );
+        debug_assert!(
+          self.connections.contains_key(connectionQuad),
+          "The local port index holds {}, which the connection map doesn't",
+          connectionQuad
+        );
+      }
+    }
+    for connectionQuad in self.connections.keys() {
+      debug_assert!(
+        self.is_indexed(connectionQuad),
+        "The connection map holds {}, which the local port index doesn't",
+        connectionQuad
+      );
+    }
+  }
+
+  // Checks, in debug builds, that the quad is in the index exactly when it's in the map.
+  fn debug_assert_indexed(&self, connectionQuad: &ConnectionQuad) {
+    self.debug_assert_counts_match();
+    debug_assert_eq!(
+      self.is_indexed(connectionQuad),
+      self.connections.contains_key(connectionQuad),
+      "The local port index drifted from the connection map on {}",
+      connectionQuad
+    );
+  }
+
+  fn is_indexed(&self, connectionQuad: &ConnectionQuad) -> bool {
+    self
+      .byLocalPort
+      .get(&connectionQuad.destiation.port)
+      .is_some_and(|connectionQuads| connectionQuads.contains(connectionQuad))
+  }
+
+  fn debug_assert_counts_match(&self) {
+    debug_assert_eq!(
+      self.byLocalPort.values().map(HashSet::len).sum::<usize>(),
+      self.connections.len(),
+      "The local port index drifted from the connection map"
+    );
+  }
+}
+
+impl SharedConnection {
+  fn new(tcb: TCPConnection) -> Self {
+    Self {
+      tcb: Mutex::new(tcb),
+      changed: Condvar::new(),
+      writable: Condvar::new(),
+    }
+  }
+
+  // Wakes up whoever waits for the TCB to change, and the writer too if it may go on.
+  fn notify(&self, isWriterWoken: bool) {
+    self.changed.notify_all();
+    if isWriterWoken {
+      self.writable.notify_all();
+    }
+  }
+
+  // Blocks while the given condition holds for the TCB, and then returns the TCB locked.
+  pub fn wait_while(
+    &self,
+    mut condition: impl FnMut(&TCPConnection) -> bool,
+  ) -> MutexGuard<'_, TCPConnection> {
+    self
+      .changed
+      .wait_while(lock_connection(self), |tcb| condition(tcb))
+      .expect("Connection mutex poisoned")
+  }
+
+  /*
+    Locks the connection once, for processing up to the given number of received bytes in place.
+    The bytes get consumed when the returned Drain is dropped.
+
+    Since the Drain holds the connection's lock, the connection can't be torn down while it's
+    alive.
+  */
+  pub fn drain<'connection>(
+    &'connection self,
+    maximumLength: usize,
+    ctx: SendContext<'connection>,
+  ) -> Drain<'connection> {
+    let tcb = lock_connection(self);
+
+    let (front, back) = tcb.received_data();
+    let length = maximumLength.min(front.len() + back.len());
+
+    Drain { tcb, length, ctx }
+  }
+
+  // See TCPConnection::bytes_to_read( ) and the like. Each takes the connection's lock once, and
+  // performs no I/O.
+  pub fn bytes_to_read(&self) -> usize {
+    lock_connection(self).bytes_to_read()
+  }
+
+  pub fn bytes_queued(&self) -> usize {
+    lock_connection(self).bytes_queued()
+  }
+
+  pub fn bytes_unacked(&self) -> usize {
+    lock_connection(self).bytes_unacked()
+  }
+
+  pub fn oldest_unacked_age(&self) -> Option<Duration> {
+    lock_connection(self).oldest_unacked_age()
+  }
+
+  /*
+    Blocks till there's something to read, and reads it like TCPConnection::read( ) : Ok(0) means
+    the end of the stream. Reading into an empty buffer never blocks.
+  */
+  pub fn read(&self, buffer: &mut [u8], ctx: &mut SendContext) -> Result<usize, TcpError> {
+    if buffer.is_empty() {
+      return Ok(0);
+    }
+
+    self.wait_while(|tcb| !tcb.is_readable()).read(buffer, ctx)
+  }
+
+  // Blocks till the send buffer has room, and writes as much of the given data as fits. Writing no
+  // data never blocks.
+  pub fn write(&self, data: &[u8], ctx: &mut SendContext) -> Result<usize, TcpError> {
+    if data.is_empty() {
+      return lock_connection(self).write(data, ctx);
+    }
+
+    self
+      .writable
+      .wait_while(lock_connection(self), |tcb| !tcb.is_writable())
+      .expect("Connection mutex poisoned")
+      .write(data, ctx)
+  }
+
+  // Like wait_while( ), but gives up after the given timeout. Also returns whether it timed out.
+  pub fn wait_timeout_while(
+    &self,
+    timeout: Duration,
+    mut condition: impl FnMut(&TCPConnection) -> bool,
+  ) -> (MutexGuard<'_, TCPConnection>, bool) {
+    let (tcb, result) = self
+      .changed
+      .wait_timeout_while(lock_connection(self), timeout, |tcb| condition(tcb))
+      .expect("Connection mutex poisoned");
+
+    (tcb, result.timed_out())
+  }
+}
+
+// Whether the connection still has anything left to deliver, which holds a drain up.
+fn is_remaining(connection: &SharedConnection) -> bool {
+  !matches!(
+    lock_connection(connection).state(),
+    TCPConnectionState::TimeWait | TCPConnectionState::Closed
+  )
+}
+
+pub fn lock_connection(connection: &SharedConnection) -> MutexGuard<'_, TCPConnection> {
+  connection.tcb.lock().expect("Connection mutex poisoned")
+}
+
+// Received data being processed in place, which gets consumed once this is dropped, updating the
+// receive window just once.
+pub struct Drain<'connection> {
+  tcb: MutexGuard<'connection, TCPConnection>,
+
+  length: usize,
+
+  // For sending the window update, once the bytes get consumed.
+  ctx: SendContext<'connection>,
+}
+
+impl Drain<'_> {
+  pub fn len(&self) -> usize {
+    self.length
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.length == 0
+  }
+
+  // The drained bytes, as at most two contiguous slices.
+  pub fn slices(&self) -> impl Iterator<Item = &[u8]> {
+    let (front, back) = self.tcb.received_data();
+
+    let frontLength = self.length.min(front.len());
+    let backLength = self.length - frontLength;
+
+    [&front[..frontLength], &back[..backLength]]
+      .into_iter()
+      .filter(|slice| !slice.is_empty())
+  }
+}
+
+impl Drop for Drain<'_> {
+  fn drop(&mut self) {
+    // Nothing got consumed, so the receive window hasn't changed either.
+    if self.length == 0 {
+      return;
+    }
+
+    if let Err(error) = self.tcb.consume_received_data(self.length, self.ctx.nic) {
+      eprintln!(
+        "Failed sending window update to {} : {}",
+        self.tcb.quad(),
+        error
+      );
+    }
+  }
+}
+
+impl Display for ListenerOptions {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.backlog, self.overflowPolicy)?;
+    if self.rateLimit.is_limited() {
+      write!(f, " rate {}", self.rateLimit)?;
+    }
+    Ok(())
+  }
+}
+
+// Parses the "<backlog> <overflow policy> [rate <rate limit>]" form written by Display.
+impl FromStr for ListenerOptions {
+  type Err = anyhow::Error;
+
+  fn from_str(options: &str) -> anyhow::Result<Self> {
+    let mut words = options.split_whitespace();
+    let (Some(backlog), Some(overflowPolicy)) = (words.next(), words.next())
+    else {
+      return Err(anyhow!(
+        "Expected <backlog> <overflow policy> [rate <rate limit>], got '{}'",
+        options
+      ));
+    };
+
+    let backlog = backlog
+      .parse::<usize>()
+      .map_err(|error| anyhow!("Invalid backlog '{}' : {}", backlog, error))?;
+    if backlog == 0 {
+      return Err(anyhow!("Backlog must be positive"));
+    }
+
+    let rateLimit = match (words.next(), words.next(), words.next()) {
+      (None, ..) => ConnectionRateLimit::default(),
+      (Some("rate"), Some(rateLimit), None) => rateLimit.parse()?,
+      _ => {
+        return Err(anyhow!(
+          "Expected rate <rate limit> after the overflow policy, got '{}'",
+          options
+        ))
+      }
+    };
+
+    Ok(Self {
+      backlog,
+      overflowPolicy: overflowPolicy.parse()?,
+      rateLimit,
+    })
+  }
+}
+
+impl Display for AcceptQueueOverflow {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::RefuseNewest => "refuse-newest",
+      Self::AbortOldest => "abort-oldest",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for AcceptQueueOverflow {
+  type Err = anyhow::Error;
+
+  fn from_str(policy: &str) -> anyhow::Result<Self> {
+    match policy {
+      "refuse-newest" => Ok(Self::RefuseNewest),
+      "abort-oldest" => Ok(Self::AbortOldest),
+      _ => Err(anyhow!(
+        "Unknown accept queue overflow policy '{}', expected refuse-newest or abort-oldest",
+        policy
+      )),
+    }
+  }
+}
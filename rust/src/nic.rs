@@ -0,0 +1,559 @@
+use {
+  crate::{
+    capture::Captures,
+    json::{JsonObject, ToJson},
+    tcp::ConnectionQuad,
+  },
+  anyhow::anyhow,
+  std::{
+    ffi::{c_int, c_short, c_ulong},
+    fmt::{self, Display, Formatter},
+    io,
+    ops::RangeInclusive,
+    os::fd::AsRawFd,
+    process::Command,
+    str::FromStr,
+    sync::{
+      atomic::{AtomicU16, AtomicU64, Ordering},
+      Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+  },
+};
+
+// After how many transient errors in a row, reading from the vNIC is given up on.
+const MAXIMUM_CONSECUTIVE_TRANSIENT_ERRORS: u32 = 16;
+
+// How long the first retry after a transient error waits. The wait doubles with every further
+// transient error in a row, up to a second.
+const INITIAL_TRANSIENT_ERROR_BACKOFF: Duration = Duration::from_millis(1);
+
+/*
+  MTUs the vNIC may be configured with. Every IPv4 host must take in datagrams of 576 bytes (RFC
+  791), which the default MSS of 536 bytes is derived from, and segments get serialized into a 1500
+  byte buffer.
+*/
+pub const MTUS: RangeInclusive<u16> = 576..=1500;
+
+/*
+  The vNIC, with every packet flowing through it tapped by the per-connection captures. The packets
+  go through a NicDevice : the TUN device, or a ChannelNic standing in for it.
+
+  Writing a packet to the TUN file descriptor can fail in two ways which don't mean the vNIC is
+  broken :
+
+    (1) The write is partial. The kernel would then deliver a truncated IP packet, so such a write
+        can't be continued. It gets logged, counted and treated as a lost packet.
+
+    (2) The transmit queue is full (EAGAIN), which can only happen when the file descriptor is
+        non-blocking. What happens then is decided by the NicSendPolicy, separately for control
+        segments and data segments.
+
+  Either way, IP is allowed to lose packets : a lost data segment gets retransmitted, and so does a
+  lost SYN or FIN. Losing a RST or a SYN-ACK is costlier though, which is why control segments get
+  retried by default.
+*/
+pub struct Nic {
+  device: Box<dyn NicDevice>,
+
+  // Largest IP packet the vNIC carries. Changed at runtime through set_mtu( ).
+  mtu: AtomicU16,
+
+  sendPolicy: NicSendPolicy,
+
+  captures: Mutex<Captures>,
+
+  counters: NicCounters,
+}
+
+/*
+  Where the vNIC's packets come from and go to. Calls which can't complete right away fail with
+  WouldBlock, just like those on a non-blocking file descriptor, and the Nic then waits for the
+  device to become ready.
+*/
+pub trait NicDevice: Send + Sync {
+  // Reads the next packet. A packet too large for the buffer gets truncated.
+  fn recv(&self, buffer: &mut [u8]) -> io::Result<usize>;
+
+  // Writes a packet, and returns how many of its bytes got written.
+  fn send(&self, packet: &[u8]) -> io::Result<usize>;
+
+  // Blocks till the device becomes readable or writable, or the deadline passes. Returns whether
+  // it did.
+  fn wait(&self, readiness: Readiness, deadline: Instant) -> io::Result<bool>;
+
+  fn name(&self) -> anyhow::Result<String>;
+
+  // Reconfigures the MTU of the device. The Nic has checked it's within MTUS.
+  fn set_mtu(&self, mtu: u16) -> anyhow::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Readiness {
+  Readable,
+
+  Writable,
+}
+
+// Whether a segment carries any payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SegmentKind {
+  // SYNs, SYN-ACKs, RSTs, FINs and pure ACKs.
+  Control,
+
+  Data,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NicSendPolicy {
+  pub controlSegments: QueueFullPolicy,
+  pub dataSegments: QueueFullPolicy,
+
+  // How long a retried segment may wait for room in the transmit queue, before getting dropped.
+  pub retryTimeout: Duration,
+}
+
+impl Default for NicSendPolicy {
+  fn default() -> Self {
+    Self {
+      controlSegments: QueueFullPolicy::Retry,
+      dataSegments: QueueFullPolicy::Drop,
+      retryTimeout: Duration::from_millis(10),
+    }
+  }
+}
+
+// What to do with a segment, when the transmit queue of the vNIC is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+  // Wait for the file descriptor to become writable, and write the segment again.
+  Retry,
+
+  // Drop the segment, as if it got lost on the wire.
+  Drop,
+}
+
+// How an error of the vNIC's file descriptor gets dealt with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NicErrorKind {
+  // The call got interrupted (EINTR), or would have blocked (EAGAIN). It's simply retried.
+  Retry,
+
+  // A momentary shortage, like ENOBUFS or ENOMEM. The call is retried after a short backoff, and
+  // the error becomes fatal if it persists.
+  Transient,
+
+  // The device is gone or unusable, like ENODEV, EBADF or EIO.
+  Fatal,
+}
+
+// An error, which the vNIC can't recover from.
+#[derive(Debug)]
+pub struct NicError {
+  pub kind: NicErrorKind,
+
+  pub error: io::Error,
+}
+
+#[derive(Default)]
+pub struct NicCounters {
+  // Packets dropped, since only a part of them got written.
+  pub partialWrites: AtomicU64,
+
+  // Packets which found the transmit queue full, and got written after waiting.
+  pub queueFullRetries: AtomicU64,
+
+  // Packets dropped, since the transmit queue was full.
+  pub queueFullDrops: AtomicU64,
+
+  // Transient errors, which reading from the vNIC recovered from.
+  pub transientReceiveErrors: AtomicU64,
+}
+
+impl Nic {
+  pub fn new(device: impl NicDevice + 'static, mtu: u16, sendPolicy: NicSendPolicy) -> Self {
+    Self {
+      device: Box::new(device),
+      mtu: AtomicU16::new(mtu),
+      sendPolicy,
+      captures: Mutex::default(),
+      counters: NicCounters::default(),
+    }
+  }
+
+  /*
+    Reads the next packet sent to us. It's up to the caller to tap it, once it has figured out
+    which connection the packet belongs to.
+
+    Errors get retried as per their NicErrorKind, so only fatal ones get returned (a transient
+    error which persists becomes fatal).
+  */
+  pub fn recv(&self, buffer: &mut [u8]) -> Result<usize, NicError> {
+    let mut consecutiveTransientErrors = 0;
+    let mut backoff = INITIAL_TRANSIENT_ERROR_BACKOFF;
+
+    loop {
+      let error = match self.device.recv(buffer) {
+        Ok(bytesRead) => return Ok(bytesRead),
+        Err(error) => error,
+      };
+
+      match NicErrorKind::of(&error) {
+        NicErrorKind::Retry => {
+          if error.kind() == io::ErrorKind::WouldBlock {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            self
+              .device
+              .wait(Readiness::Readable, deadline)
+              .map_err(|error| NicError::new(NicErrorKind::of(&error), error))?;
+          }
+        }
+
+        NicErrorKind::Transient
+          if consecutiveTransientErrors < MAXIMUM_CONSECUTIVE_TRANSIENT_ERRORS =>
+        {
+          consecutiveTransientErrors += 1;
+          self
+            .counters
+            .transientReceiveErrors
+            .fetch_add(1, Ordering::Relaxed);
+          eprintln!(
+            "WARN : failed reading from the vNIC, retrying in {:?} : {}",
+            backoff, error
+          );
+
+          thread::sleep(backoff);
+          backoff = (backoff * 2).min(Duration::from_secs(1));
+        }
+
+        kind => return Err(NicError::new(kind, error)),
+      }
+    }
+  }
+
+  /*
+    Writes a packet sent to the peer of the given connection. A packet which gets dropped, as
+    described above, isn't an error : the caller carries on as if it got lost on the wire.
+  */
+  pub fn send(
+    &self,
+    connectionQuad: &ConnectionQuad,
+    packet: &[u8],
+    segmentKind: SegmentKind,
+  ) -> io::Result<()> {
+    let queueFullPolicy = match segmentKind {
+      SegmentKind::Control => self.sendPolicy.controlSegments,
+      SegmentKind::Data => self.sendPolicy.dataSegments,
+    };
+
+    let mut retryDeadline = None;
+
+    let bytesWritten = loop {
+      match self.device.send(packet) {
+        Ok(bytesWritten) => break bytesWritten,
+
+        Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+          let retryDeadline =
+            *retryDeadline.get_or_insert_with(|| Instant::now() + self.sendPolicy.retryTimeout);
+
+          if queueFullPolicy == QueueFullPolicy::Drop
+            || !self.device.wait(Readiness::Writable, retryDeadline)?
+          {
+            self.counters.queueFullDrops.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+              "WARN : dropped {:?} segment of {}, since the vNIC transmit queue is full",
+              segmentKind, connectionQuad
+            );
+            return Ok(());
+          }
+        }
+
+        Err(error) => return Err(error),
+      }
+    };
+
+    if retryDeadline.is_some() {
+      self
+        .counters
+        .queueFullRetries
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    if bytesWritten != packet.len() {
+      self.counters.partialWrites.fetch_add(1, Ordering::Relaxed);
+      eprintln!(
+        "ERROR : dropped {:?} segment of {}, since only {} of its {} bytes got written",
+        segmentKind,
+        connectionQuad,
+        bytesWritten,
+        packet.len()
+      );
+      return Ok(());
+    }
+
+    self.lock_captures().record(connectionQuad, packet);
+    Ok(())
+  }
+
+  // Records a packet received from the peer of the given connection, if it's being captured.
+  pub fn tap_inbound(&self, connectionQuad: &ConnectionQuad, packet: &[u8]) {
+    self.lock_captures().record(connectionQuad, packet);
+  }
+
+  pub fn lock_captures(&self) -> MutexGuard<'_, Captures> {
+    self.captures.lock().expect("Captures mutex poisoned")
+  }
+
+  pub fn counters(&self) -> &NicCounters {
+    &self.counters
+  }
+
+  pub fn mtu(&self) -> u16 {
+    self.mtu.load(Ordering::Relaxed)
+  }
+
+  pub fn name(&self) -> anyhow::Result<String> {
+    self.device.name()
+  }
+
+  // Changes the MTU of the vNIC, which must be within MTUS.
+  pub fn set_mtu(&self, mtu: u16) -> anyhow::Result<()> {
+    if !MTUS.contains(&mtu) {
+      return Err(anyhow!(
+        "MTU {} : must be between {} and {}",
+        mtu,
+        MTUS.start(),
+        MTUS.end()
+      ));
+    }
+
+    self.device.set_mtu(mtu)?;
+    self.mtu.store(mtu, Ordering::Relaxed);
+    Ok(())
+  }
+}
+
+impl NicDevice for tun::Device {
+  fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    tun::Device::recv(self, buffer)
+  }
+
+  fn send(&self, packet: &[u8]) -> io::Result<usize> {
+    tun::Device::send(self, packet)
+  }
+
+  fn wait(&self, readiness: Readiness, deadline: Instant) -> io::Result<bool> {
+    let events = match readiness {
+      Readiness::Readable => POLLIN,
+      Readiness::Writable => POLLOUT,
+    };
+
+    loop {
+      let timeout = deadline.saturating_duration_since(Instant::now());
+      if timeout.is_zero() {
+        return Ok(false);
+      }
+
+      let mut pollFd = PollFd {
+        fd: self.as_raw_fd(),
+        events,
+        revents: 0,
+      };
+
+      // SAFETY : pollFd outlives the call, and exactly 1 entry is passed.
+      let result = unsafe { poll(&mut pollFd, 1, timeout.as_millis().max(1) as c_int) };
+
+      match result {
+        0 => return Ok(false),
+        result if result > 0 => return Ok(true),
+
+        _ => {
+          let error = io::Error::last_os_error();
+          if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+          }
+        }
+      }
+    }
+  }
+
+  fn name(&self) -> anyhow::Result<String> {
+    tun::AbstractDevice::tun_name(self)
+      .map_err(|error| anyhow!("Failed getting the name of the vNIC : {}", error))
+  }
+
+  /*
+    The tun crate only changes the MTU through a mutable borrow of the device, which the packet
+    thread shares while blocked in recv( ). So the link gets reconfigured the way an operator
+    would, with ip(8) (or ifconfig(8) on macOS).
+  */
+  fn set_mtu(&self, mtu: u16) -> anyhow::Result<()> {
+    let name = NicDevice::name(self)?;
+
+    let mut command = if cfg!(target_os = "macos") {
+      let mut command = Command::new("ifconfig");
+      command.args([name.as_str(), "mtu", &mtu.to_string()]);
+      command
+    }
+    else {
+      let mut command = Command::new("ip");
+      command.args(["link", "set", "dev", &name, "mtu", &mtu.to_string()]);
+      command
+    };
+
+    let output = command
+      .output()
+      .map_err(|error| anyhow!("Failed running {:?} : {}", command, error))?;
+    if !output.status.success() {
+      return Err(anyhow!(
+        "Failed setting the MTU of {} to {} : {}",
+        name,
+        mtu,
+        String::from_utf8_lossy(&output.stderr).trim()
+      ));
+    }
+    Ok(())
+  }
+}
+
+impl NicErrorKind {
+  pub fn of(error: &io::Error) -> Self {
+    if matches!(
+      error.kind(),
+      io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) {
+      return Self::Retry;
+    }
+
+    // The other end of a ChannelNic went away.
+    if error.kind() == io::ErrorKind::NotConnected {
+      return Self::Fatal;
+    }
+
+    match error.raw_os_error() {
+      // EIO, ENXIO, EBADF, EFAULT, ENODEV, EINVAL and EBADFD.
+      Some(5 | 6 | 9 | 14 | 19 | 22 | 77) => Self::Fatal,
+
+      // Anything else, like ENOBUFS, ENOMEM or ENETDOWN, gets the benefit of the doubt.
+      _ => Self::Transient,
+    }
+  }
+}
+
+impl Display for NicErrorKind {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Retry => "retry",
+      Self::Transient => "transient",
+      Self::Fatal => "fatal",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl NicError {
+  fn new(kind: NicErrorKind, error: io::Error) -> Self {
+    Self { kind, error }
+  }
+}
+
+impl Display for NicError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{} vNIC error : {}", self.kind, self.error)
+  }
+}
+
+impl std::error::Error for NicError {}
+
+impl Display for NicCounters {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "partialWrites {}",
+      self.partialWrites.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "queueFullRetries {}",
+      self.queueFullRetries.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "queueFullDrops {}",
+      self.queueFullDrops.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "transientReceiveErrors {}",
+      self.transientReceiveErrors.load(Ordering::Relaxed)
+    )
+  }
+}
+
+impl ToJson for NicCounters {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field(
+        "partial_writes",
+        &self.partialWrites.load(Ordering::Relaxed),
+      )
+      .field(
+        "queue_full_retries",
+        &self.queueFullRetries.load(Ordering::Relaxed),
+      )
+      .field(
+        "queue_full_drops",
+        &self.queueFullDrops.load(Ordering::Relaxed),
+      )
+      .field(
+        "transient_receive_errors",
+        &self.transientReceiveErrors.load(Ordering::Relaxed),
+      )
+      .finish();
+  }
+}
+
+impl Display for QueueFullPolicy {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Retry => "retry",
+      Self::Drop => "drop",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for QueueFullPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(policy: &str) -> anyhow::Result<Self> {
+    match policy {
+      "retry" => Ok(Self::Retry),
+      "drop" => Ok(Self::Drop),
+      _ => Err(anyhow!(
+        "Unknown queue full policy '{}', expected retry or drop",
+        policy
+      )),
+    }
+  }
+}
+
+// Mirrors struct pollfd from <poll.h>.
+#[repr(C)]
+struct PollFd {
+  fd: c_int,
+  events: c_short,
+  revents: c_short,
+}
+
+const POLLIN: c_short = 0x1;
+const POLLOUT: c_short = 0x4;
+
+extern "C" {
+  fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+}
@@ -0,0 +1,441 @@
+use {
+  crate::{
+    error::TcpError,
+    manager::{self, ConnectionManager, SharedConnection},
+    tcp::DEFAULT_MAXIMUM_SEGMENT_SIZE,
+  },
+  anyhow::anyhow,
+  std::{
+    ffi::{c_int, c_void},
+    fmt::{self, Display, Formatter},
+    io::{self, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+  },
+};
+
+/*
+  Forwards the connections accepted on a listening port, to an upstream server reached through the
+  kernel's TCP stack :
+
+    tcp-server proxy 8080 127.0.0.1:80
+
+  Each connection gets a thread per direction.
+*/
+
+#[derive(Clone, Copy)]
+pub struct ForwardOptions {
+  /*
+    Delays dialing the upstream till the client sends its first data, which gets buffered till
+    then. A client which doesn't send anything within firstDataTimeout gets reset. This shields the
+    upstream from spoofed SYN floods (the handshake never completes) and from clients which connect
+    and then idle.
+  */
+  pub deferUpstreamUntilData: bool,
+  pub firstDataTimeout: Duration,
+
+  /*
+    Applies what got negotiated on the client's connection to the upstream one, so the upstream
+    sees what it would have seen had the client connected directly : the client's MSS and its TCP
+    User Timeout. Otherwise, the upstream socket keeps the kernel's defaults.
+  */
+  pub copyClientOptions: bool,
+
+  // Whether Nagle's algorithm gets disabled on the upstream socket.
+  pub noDelay: NoDelayPolicy,
+}
+
+impl Default for ForwardOptions {
+  fn default() -> Self {
+    Self {
+      deferUpstreamUntilData: false,
+      firstDataTimeout: Duration::from_secs(10),
+      copyClientOptions: false,
+      noDelay: NoDelayPolicy::Off,
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NoDelayPolicy {
+  On,
+
+  Off,
+
+  /*
+    Guessed from the client's first data, which needs deferUpstreamUntilData : a client whose first
+    data fits in a single short segment is taken to be interactive (a shell, a request-response
+    protocol) and gets TCP_NODELAY. Without deferring, it falls back to Off.
+  */
+  Infer,
+}
+
+// The options an upstream socket gets created with, as derived from the client's connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UpstreamSocketOptions {
+  // TCP_MAXSEG. None keeps the one the kernel derives from the route's MTU.
+  pub maximumSegmentSize: Option<usize>,
+
+  // TCP_USER_TIMEOUT. None keeps the kernel's default.
+  pub userTimeout: Option<Duration>,
+
+  pub noDelay: bool,
+}
+
+// Accepts connections on the given listening port from a background thread, and forwards each of
+// them to the upstream.
+pub fn forward(
+  connectionManager: Arc<ConnectionManager>,
+  port: u16,
+  upstream: SocketAddr,
+  options: ForwardOptions,
+) {
+  thread::spawn(move || loop {
+    let connection = match connectionManager.accept(port) {
+      Ok(connection) => connection,
+      Err(error) => {
+        eprintln!("Stopped forwarding port {} : {}", port, error);
+        return;
+      }
+    };
+    let connectionManager = connectionManager.clone();
+
+    thread::spawn(move || {
+      let connectionQuad = manager::lock_connection(&connection).quad();
+
+      if let Err(error) = forward_connection(&connectionManager, &connection, upstream, options) {
+        eprintln!(
+          "Failed forwarding {} to {} : {}",
+          connectionQuad, upstream, error
+        );
+        connectionManager.abort_quad(&connectionQuad);
+      }
+    });
+  });
+}
+
+fn forward_connection(
+  connectionManager: &Arc<ConnectionManager>,
+  connection: &Arc<SharedConnection>,
+  upstream: SocketAddr,
+  options: ForwardOptions,
+) -> anyhow::Result<()> {
+  let mut firstData = Vec::new();
+
+  if options.deferUpstreamUntilData {
+    let (mut tcb, timedOut) =
+      connection.wait_timeout_while(options.firstDataTimeout, |tcb| !tcb.is_readable());
+
+    if timedOut {
+      let connectionQuad = tcb.quad();
+      drop(tcb);
+
+      println!(
+        "Resetting {}, since it sent no data within {:?}",
+        connectionQuad, options.firstDataTimeout
+      );
+      connectionManager.abort_quad(&connectionQuad);
+      return Ok(());
+    }
+
+    let mut buffer = [0u8; 4096];
+    loop {
+      match tcb.read(&mut buffer, &mut connectionManager.send_context()) {
+        // The client closed or reset the connection without sending anything.
+        Ok(0) if firstData.is_empty() => return Ok(()),
+        Ok(0) => break,
+
+        Ok(bytesRead) => firstData.extend_from_slice(&buffer[..bytesRead]),
+
+        Err(TcpError::WouldBlock) => break,
+        Err(error) => return Err(error.into()),
+      }
+    }
+  }
+
+  let upstreamSocketOptions = {
+    let tcb = manager::lock_connection(connection);
+
+    let noDelay = match options.noDelay {
+      NoDelayPolicy::On => true,
+      NoDelayPolicy::Off => false,
+      NoDelayPolicy::Infer => {
+        !firstData.is_empty() && firstData.len() < tcb.peer_maximum_segment_size()
+      }
+    };
+
+    match options.copyClientOptions {
+      // Our side of the client's connection never sends (nor accepts) segments larger than the
+      // default MSS, so that's what the path between the client and the upstream carries.
+      true => UpstreamSocketOptions {
+        maximumSegmentSize: Some(
+          tcb
+            .peer_maximum_segment_size()
+            .min(DEFAULT_MAXIMUM_SEGMENT_SIZE),
+        ),
+        userTimeout: tcb.user_timeout(),
+        noDelay,
+      },
+      false => UpstreamSocketOptions {
+        noDelay,
+        ..UpstreamSocketOptions::default()
+      },
+    }
+  };
+
+  let mut upstreamStream = connect_upstream(upstream, &upstreamSocketOptions)?;
+  println!(
+    "Forwarding {} to {} with {}",
+    manager::lock_connection(connection).quad(),
+    upstream,
+    upstreamSocketOptions
+  );
+  upstreamStream.write_all(&firstData)?;
+
+  {
+    let connectionManager = connectionManager.clone();
+    let connection = connection.clone();
+    let upstreamStream = upstreamStream.try_clone()?;
+
+    thread::spawn(move || {
+      if let Err(error) = copy_to_client(&connectionManager, &connection, upstreamStream) {
+        eprintln!("Failed forwarding from {} : {}", upstream, error);
+      }
+    });
+  }
+
+  copy_to_upstream(connectionManager, connection, &mut upstreamStream)
+}
+
+/*
+  Connects to the upstream through the kernel's TCP stack. The MSS has to be set before connecting,
+  since it's advertised on the SYN, which std's TcpStream::connect( ) gives no chance to do. So
+  the socket gets created by hand, when there's an MSS to set.
+*/
+pub fn connect_upstream(
+  upstream: SocketAddr,
+  options: &UpstreamSocketOptions,
+) -> io::Result<TcpStream> {
+  if options.maximumSegmentSize.is_none() && options.userTimeout.is_none() {
+    let upstreamStream = TcpStream::connect(upstream)?;
+    upstreamStream.set_nodelay(options.noDelay)?;
+    return Ok(upstreamStream);
+  }
+
+  let family = match upstream {
+    SocketAddr::V4(_) => AF_INET,
+    SocketAddr::V6(_) => AF_INET6,
+  };
+
+  // SAFETY : socket( ) takes no pointers. Its file descriptor gets owned right away, so it's
+  // closed on every error below.
+  let socket = match unsafe { socket(family, SOCK_STREAM | SOCK_CLOEXEC, 0) } {
+    -1 => return Err(io::Error::last_os_error()),
+    fd => unsafe { OwnedFd::from_raw_fd(fd) },
+  };
+
+  if let Some(maximumSegmentSize) = options.maximumSegmentSize {
+    set_socket_option(&socket, TCP_MAXSEG, maximumSegmentSize as c_int)?;
+  }
+  if let Some(userTimeout) = options.userTimeout {
+    let milliseconds = userTimeout.as_millis().min(c_int::MAX as u128) as c_int;
+    set_socket_option(&socket, TCP_USER_TIMEOUT, milliseconds)?;
+  }
+
+  let result = match upstream {
+    SocketAddr::V4(upstream) => {
+      let address = SockAddrIn {
+        sin_family: AF_INET as u16,
+        sin_port: upstream.port().to_be(),
+        sin_addr: upstream.ip().octets(),
+        sin_zero: [0; 8],
+      };
+
+      // SAFETY : address outlives the call, and its size is the one passed.
+      unsafe {
+        connect(
+          socket.as_raw_fd(),
+          &address as *const SockAddrIn as *const c_void,
+          mem::size_of::<SockAddrIn>() as u32,
+        )
+      }
+    }
+
+    SocketAddr::V6(upstream) => {
+      let address = SockAddrIn6 {
+        sin6_family: AF_INET6 as u16,
+        sin6_port: upstream.port().to_be(),
+        sin6_flowinfo: upstream.flowinfo().to_be(),
+        sin6_addr: upstream.ip().octets(),
+        sin6_scope_id: upstream.scope_id(),
+      };
+
+      // SAFETY : address outlives the call, and its size is the one passed.
+      unsafe {
+        connect(
+          socket.as_raw_fd(),
+          &address as *const SockAddrIn6 as *const c_void,
+          mem::size_of::<SockAddrIn6>() as u32,
+        )
+      }
+    }
+  };
+  if result == -1 {
+    return Err(io::Error::last_os_error());
+  }
+
+  let upstreamStream = TcpStream::from(socket);
+  upstreamStream.set_nodelay(options.noDelay)?;
+  Ok(upstreamStream)
+}
+
+fn set_socket_option(socket: &OwnedFd, option: c_int, value: c_int) -> io::Result<()> {
+  // SAFETY : value outlives the call, and its size is the one passed.
+  let result = unsafe {
+    setsockopt(
+      socket.as_raw_fd(),
+      IPPROTO_TCP,
+      option,
+      &value as *const c_int as *const c_void,
+      mem::size_of::<c_int>() as u32,
+    )
+  };
+
+  match result {
+    -1 => Err(io::Error::last_os_error()),
+    _ => Ok(()),
+  }
+}
+
+// Copies whatever the client sends to the upstream, till the client closes its side.
+fn copy_to_upstream(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  upstreamStream: &mut TcpStream,
+) -> anyhow::Result<()> {
+  let mut buffer = [0u8; 4096];
+
+  loop {
+    let bytesRead = connection.read(&mut buffer, &mut connectionManager.send_context())?;
+
+    if bytesRead == 0 {
+      upstreamStream.shutdown(Shutdown::Write)?;
+      return Ok(());
+    }
+    upstreamStream.write_all(&buffer[..bytesRead])?;
+  }
+}
+
+// Copies whatever the upstream sends to the client, till the upstream closes its side.
+fn copy_to_client(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  mut upstreamStream: TcpStream,
+) -> anyhow::Result<()> {
+  let mut buffer = [0u8; 4096];
+
+  loop {
+    let bytesRead = upstreamStream.read(&mut buffer)?;
+    if bytesRead == 0 {
+      manager::lock_connection(connection).close(&mut connectionManager.send_context())?;
+      return Ok(());
+    }
+
+    let mut data = &buffer[..bytesRead];
+    while !data.is_empty() {
+      let bytesWritten = connection.write(data, &mut connectionManager.send_context())?;
+
+      data = &data[bytesWritten..];
+    }
+  }
+}
+
+impl Display for NoDelayPolicy {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::On => "on",
+      Self::Off => "off",
+      Self::Infer => "infer",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for NoDelayPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(policy: &str) -> anyhow::Result<Self> {
+    match policy {
+      "on" => Ok(Self::On),
+      "off" => Ok(Self::Off),
+      "infer" => Ok(Self::Infer),
+      _ => Err(anyhow!(
+        "Unknown nodelay policy '{}', expected on, off or infer",
+        policy
+      )),
+    }
+  }
+}
+
+// Like "mss 1460, user timeout 30s, nodelay on", for the per-connection log line.
+impl Display for UpstreamSocketOptions {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self.maximumSegmentSize {
+      Some(maximumSegmentSize) => write!(f, "mss {}, ", maximumSegmentSize)?,
+      None => write!(f, "mss default, ")?,
+    }
+    match self.userTimeout {
+      Some(userTimeout) => write!(f, "user timeout {:?}, ", userTimeout)?,
+      None => write!(f, "user timeout default, ")?,
+    }
+
+    let noDelay = match self.noDelay {
+      true => "on",
+      false => "off",
+    };
+    write!(f, "nodelay {}", noDelay)
+  }
+}
+
+// Mirrors struct sockaddr_in from <netinet/in.h>. The port and address are in network byte order.
+#[repr(C)]
+struct SockAddrIn {
+  sin_family: u16,
+  sin_port: u16,
+  sin_addr: [u8; 4],
+  sin_zero: [u8; 8],
+}
+
+// Mirrors struct sockaddr_in6 from <netinet/in.h>.
+#[repr(C)]
+struct SockAddrIn6 {
+  sin6_family: u16,
+  sin6_port: u16,
+  sin6_flowinfo: u32,
+  sin6_addr: [u8; 16],
+  sin6_scope_id: u32,
+}
+
+const AF_INET: c_int = 2;
+const AF_INET6: c_int = 10;
+
+const SOCK_STREAM: c_int = 1;
+const SOCK_CLOEXEC: c_int = 0o2000000;
+
+const IPPROTO_TCP: c_int = 6;
+const TCP_MAXSEG: c_int = 2;
+const TCP_USER_TIMEOUT: c_int = 18;
+
+extern "C" {
+  fn socket(domain: c_int, kind: c_int, protocol: c_int) -> c_int;
+
+  fn setsockopt(fd: c_int, level: c_int, name: c_int, value: *const c_void, length: u32) -> c_int;
+
+  fn connect(fd: c_int, address: *const c_void, length: u32) -> c_int;
+}
@@ -0,0 +1,378 @@
+use {
+  anyhow::anyhow,
+  std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display, Formatter},
+    net::Ipv4Addr,
+    str::FromStr,
+    time::Instant,
+  },
+};
+
+/*
+  How many connection requests a listener takes in per second : from everyone together, and from
+  any single source address. A client reconnecting in a tight loop (or a scanner) then only burns
+  through its own share, while the others keep getting through.
+
+  Each limit is a token bucket, holding up to a second worth of tokens. So a limit of 10 lets a
+  burst of 10 connection requests through at once, and then one every 100ms.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionRateLimit {
+  pub perSecond: Option<u32>,
+
+  pub perSourcePerSecond: Option<u32>,
+}
+
+// Which limit a refused connection request exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExceededRateLimit {
+  Listener,
+
+  Source,
+}
+
+/*
+  Most source addresses tracked per listener. Beyond it, the least recently seen one gets evicted,
+  so that SYNs from spoofed sources can't make the tracking itself eat up memory. An evicted source
+  just starts over with a full bucket.
+*/
+pub const MAXIMUM_TRACKED_SOURCES: usize = 4096;
+
+// How many of the sources refused the most get shown.
+pub const TOP_TALKERS: usize = 5;
+
+// The token buckets of a listener, along with how many connection requests they've refused.
+#[derive(Default)]
+pub struct ListenerRateLimiter {
+  listener: TokenBucket,
+
+  sources: HashMap<Ipv4Addr, TrackedSource>,
+
+  // The tracked sources, from the least to the most recently seen one.
+  recency: BTreeMap<u64, Ipv4Addr>,
+  nextRecency: u64,
+
+  refusedByListenerLimit: u64,
+  refusedBySourceLimit: u64,
+}
+
+struct TrackedSource {
+  bucket: TokenBucket,
+
+  // Its key in the recency index.
+  recency: u64,
+
+  refused: u64,
+}
+
+#[derive(Default)]
+struct TokenBucket {
+  tokens: f64,
+
+  // None till the bucket first gets used, meaning it's full.
+  refilledAt: Option<Instant>,
+}
+
+impl ListenerRateLimiter {
+  /*
+    Takes a token from each bucket the limit applies to, for a connection request from the given
+    source. If either bucket is empty, nothing gets taken and the exceeded limit is returned, with
+    the source's limit checked first.
+  */
+  pub fn admit(
+    &mut self,
+    limit: ConnectionRateLimit,
+    source: Ipv4Addr,
+    now: Instant,
+  ) -> Option<ExceededRateLimit> {
+    if let Some(perSourcePerSecond) = limit.perSourcePerSecond {
+      let trackedSource = self.track(source);
+
+      if !trackedSource.bucket.refill(perSourcePerSecond, now) {
+        trackedSource.refused += 1;
+        self.refusedBySourceLimit += 1;
+        return Some(ExceededRateLimit::Source);
+      }
+    }
+
+    if let Some(perSecond) = limit.perSecond {
+      if !self.listener.refill(perSecond, now) {
+        if let Some(trackedSource) = self.sources.get_mut(&source) {
+          trackedSource.refused += 1;
+        }
+        self.refusedByListenerLimit += 1;
+        return Some(ExceededRateLimit::Listener);
+      }
+      self.listener.tokens -= 1.0;
+    }
+
+    if limit.perSourcePerSecond.is_some() {
+      if let Some(trackedSource) = self.sources.get_mut(&source) {
+        trackedSource.bucket.tokens -= 1.0;
+      }
+    }
+    None
+  }
+
+  // The sources refused the most, among the tracked ones, along with their refusal counts.
+  pub fn top_talkers(&self) -> Vec<(Ipv4Addr, u64)> {
+    let mut talkers = self
+      .sources
+      .iter()
+      .filter(|(_, trackedSource)| trackedSource.refused > 0)
+      .map(|(address, trackedSource)| (*address, trackedSource.refused))
+      .collect::<Vec<_>>();
+
+    talkers.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    talkers.truncate(TOP_TALKERS);
+    talkers
+  }
+
+  // The source's bucket, marked as the most recently seen. Starts tracking the source if needed,
+  // evicting the least recently seen one when at capacity.
+  fn track(&mut self, source: Ipv4Addr) -> &mut TrackedSource {
+    let recency = self.nextRecency;
+    self.nextRecency += 1;
+
+    if let Some(trackedSource) = self.sources.get(&source) {
+      self.recency.remove(&trackedSource.recency);
+    }
+    else if self.sources.len() >= MAXIMUM_TRACKED_SOURCES {
+      if let Some((_, leastRecentlySeen)) = self.recency.pop_first() {
+        self.sources.remove(&leastRecentlySeen);
+      }
+    }
+    self.recency.insert(recency, source);
+
+    let trackedSource = self.sources.entry(source).or_insert(TrackedSource {
+      bucket: TokenBucket::default(),
+      recency,
+      refused: 0,
+    });
+    trackedSource.recency = recency;
+    trackedSource
+  }
+}
+
+impl TokenBucket {
+  // Adds the tokens accrued since the last refill, and returns whether there's one to be taken.
+  fn refill(&mut self, perSecond: u32, now: Instant) -> bool {
+    let capacity = perSecond as f64;
+
+    self.tokens = match self.refilledAt {
+      None => capacity,
+      Some(refilledAt) => {
+        let elapsed = now.saturating_duration_since(refilledAt).as_secs_f64();
+        (self.tokens + elapsed * capacity).min(capacity)
+      }
+    };
+    self.refilledAt = Some(now);
+
+    self.tokens >= 1.0
+  }
+}
+
+impl Display for ListenerRateLimiter {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "refused by listener limit {} | by source limit {} | tracked sources {}",
+      self.refusedByListenerLimit,
+      self.refusedBySourceLimit,
+      self.sources.len()
+    )?;
+
+    let topTalkers = self.top_talkers();
+    if !topTalkers.is_empty() {
+      let topTalkers = topTalkers
+        .iter()
+        .map(|(address, refused)| format!("{} ({})", address, refused))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+      write!(f, " | top talkers {}", topTalkers)?;
+    }
+    Ok(())
+  }
+}
+
+impl ConnectionRateLimit {
+  pub fn is_limited(&self) -> bool {
+    self.perSecond.is_some() || self.perSourcePerSecond.is_some()
+  }
+}
+
+// Written as <per second>/<per source per second>, with off for no limit, like 100/10 or off/5.
+impl Display for ConnectionRateLimit {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let format = |limit: Option<u32>| limit.map_or("off".to_string(), |limit| limit.to_string());
+
+    write!(
+      f,
+      "{}/{}",
+      format(self.perSecond),
+      format(self.perSourcePerSecond)
+    )
+  }
+}
+
+impl FromStr for ConnectionRateLimit {
+  type Err = anyhow::Error;
+
+  fn from_str(limit: &str) -> anyhow::Result<Self> {
+    let (perSecond, perSourcePerSecond) = limit.trim().split_once('/').ok_or_else(|| {
+      anyhow!(
+        "Expected <per second>/<per source per second>, got '{}'",
+        limit
+      )
+    })?;
+
+    let parse = |value: &str| match value {
+      "off" => Ok(None),
+      value => match value.parse::<u32>() {
+        Ok(0) => Err(anyhow!("Rate limit must be positive, or off")),
+        Ok(limit) => Ok(Some(limit)),
+        Err(error) => Err(anyhow!("Invalid rate limit '{}' : {}", value, error)),
+      },
+    };
+
+    Ok(Self {
+      perSecond: parse(perSecond)?,
+      perSourcePerSecond: parse(perSourcePerSecond)?,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::time::Duration};
+
+  const PER_SOURCE: ConnectionRateLimit = ConnectionRateLimit {
+    perSecond: None,
+    perSourcePerSecond: Some(2),
+  };
+
+  fn address(index: u32) -> Ipv4Addr {
+    Ipv4Addr::from(0x0a00_0000 + index)
+  }
+
+  #[test]
+  fn buckets_start_full_and_refill_over_time() {
+    let mut bucket = TokenBucket::default();
+    let now = Instant::now();
+
+    assert!(bucket.refill(10, now));
+    assert_eq!(bucket.tokens, 10.0);
+
+    bucket.tokens = 0.0;
+    assert!(!bucket.refill(10, now + Duration::from_millis(50)));
+    assert!(bucket.refill(10, now + Duration::from_millis(100)));
+
+    // Never holds more than a second worth of tokens.
+    assert!(bucket.refill(10, now + Duration::from_secs(60)));
+    assert_eq!(bucket.tokens, 10.0);
+  }
+
+  #[test]
+  fn the_listener_limit_lets_a_burst_through() {
+    let mut limiter = ListenerRateLimiter::default();
+    let limit = ConnectionRateLimit {
+      perSecond: Some(3),
+      perSourcePerSecond: None,
+    };
+    let now = Instant::now();
+
+    for index in 0..3 {
+      assert_eq!(limiter.admit(limit, address(index), now), None);
+    }
+    assert_eq!(
+      limiter.admit(limit, address(3), now),
+      Some(ExceededRateLimit::Listener)
+    );
+    assert_eq!(
+      limiter.admit(limit, address(3), now + Duration::from_millis(400)),
+      None
+    );
+    assert_eq!(limiter.refusedByListenerLimit, 1);
+
+    // Without a per source limit, no source gets tracked.
+    assert!(limiter.sources.is_empty());
+  }
+
+  #[test]
+  fn a_busy_source_only_burns_through_its_own_share() {
+    let mut limiter = ListenerRateLimiter::default();
+    let limit = ConnectionRateLimit {
+      perSecond: Some(10),
+      perSourcePerSecond: Some(2),
+    };
+    let now = Instant::now();
+
+    assert_eq!(limiter.admit(limit, address(1), now), None);
+    assert_eq!(limiter.admit(limit, address(1), now), None);
+    for _ in 0..5 {
+      assert_eq!(
+        limiter.admit(limit, address(1), now),
+        Some(ExceededRateLimit::Source)
+      );
+    }
+
+    // The refused requests took no tokens from the listener.
+    for index in 2..10 {
+      assert_eq!(limiter.admit(limit, address(index), now), None);
+    }
+    assert_eq!(
+      limiter.admit(limit, address(10), now),
+      Some(ExceededRateLimit::Listener)
+    );
+
+    assert_eq!(limiter.refusedBySourceLimit, 5);
+    assert_eq!(limiter.refusedByListenerLimit, 1);
+    assert_eq!(
+      limiter.top_talkers(),
+      vec![(address(1), 5), (address(10), 1)]
+    );
+  }
+
+  #[test]
+  fn the_least_recently_seen_source_gets_evicted() {
+    let mut limiter = ListenerRateLimiter::default();
+    let now = Instant::now();
+
+    for index in 0..MAXIMUM_TRACKED_SOURCES as u32 {
+      limiter.admit(PER_SOURCE, address(index), now);
+    }
+    // Seeing the first source again makes the second one the least recently seen.
+    limiter.admit(PER_SOURCE, address(0), now);
+    limiter.admit(PER_SOURCE, address(u32::MAX >> 8), now);
+
+    assert_eq!(limiter.sources.len(), MAXIMUM_TRACKED_SOURCES);
+    assert_eq!(limiter.recency.len(), MAXIMUM_TRACKED_SOURCES);
+    assert!(limiter.sources.contains_key(&address(0)));
+    assert!(!limiter.sources.contains_key(&address(1)));
+
+    // An evicted source starts over with a full bucket.
+    limiter.admit(PER_SOURCE, address(0), now);
+    assert_eq!(
+      limiter.admit(PER_SOURCE, address(0), now),
+      Some(ExceededRateLimit::Source)
+    );
+  }
+
+  #[test]
+  fn limits_round_trip_through_their_text_form() {
+    for text in ["100/10", "off/5", "7/off", "off/off"] {
+      let limit: ConnectionRateLimit = text.parse().unwrap();
+      assert_eq!(limit.to_string(), text);
+    }
+
+    assert!(!"off/off"
+      .parse::<ConnectionRateLimit>()
+      .unwrap()
+      .is_limited());
+    assert!("0/10".parse::<ConnectionRateLimit>().is_err());
+    assert!("10".parse::<ConnectionRateLimit>().is_err());
+    assert!("ten/10".parse::<ConnectionRateLimit>().is_err());
+  }
+}
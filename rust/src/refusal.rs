@@ -0,0 +1,399 @@
+use {
+  crate::json::{JsonObject, ToJson},
+  anyhow::anyhow,
+  std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+  },
+};
+
+/*
+  Connection requests can get refused for several reasons, before any TCB gets created for them.
+  Whatever the reason, the refusal is answered in one of three ways :
+
+    (1) Drop : the SYN is silently dropped. The peer retransmits it for a while, and then gives up.
+
+    (2) Reset : the SYN is answered with a RST, so the peer gives up right away. These RSTs are
+        rate limited, since a flood of SYNs would otherwise turn us into a flood of RSTs. A RST
+        which exceeds the rate limit becomes a drop.
+
+    (3) Defer : the SYN is dropped too, but only because the pressure causing the refusal is
+        expected to ease by the time the peer retransmits it.
+
+  The RefusalPolicy picks the response to each cause, except for the packet filter, whose rules
+  each pick their own response (deny drops, reject resets).
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefusalCause {
+  // The interface is draining, before the daemon exits.
+  Draining,
+
+  // A packet filter rule denied or rejected the connection request.
+  Filtered,
+
+  // The accept queue of the listener is full, and it refuses the newest connections.
+  AcceptQueueFull,
+
+  // The listener, or the source of the connection request, exceeded its rate limit.
+  RateLimited,
+
+  // Nobody listens on the destination port.
+  ClosedPort,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RefusalResponse {
+  Drop,
+
+  Reset,
+
+  Defer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RefusalPolicy {
+  pub draining: RefusalResponse,
+
+  pub acceptQueueFull: RefusalResponse,
+
+  pub rateLimited: RefusalResponse,
+
+  pub closedPort: RefusalResponse,
+}
+
+impl Default for RefusalPolicy {
+  fn default() -> Self {
+    Self {
+      // The peer can try elsewhere right away.
+      draining: RefusalResponse::Reset,
+
+      // accept( ) is expected to catch up soon.
+      acceptQueueFull: RefusalResponse::Defer,
+
+      // A legitimate client retransmits its SYN once its share has refilled.
+      rateLimited: RefusalResponse::Defer,
+
+      // As RFC 9293 section 3.10.7.1 requires.
+      closedPort: RefusalResponse::Reset,
+    }
+  }
+}
+
+// Most RSTs sent in response to refused connection requests, per second.
+pub const MAXIMUM_REFUSAL_RESETS_PER_SECOND: u64 = 100;
+
+#[derive(Default)]
+pub struct RefusalCounters {
+  pub draining: AtomicU64,
+  pub filtered: AtomicU64,
+  pub acceptQueueFull: AtomicU64,
+  pub rateLimited: AtomicU64,
+  pub closedPort: AtomicU64,
+
+  // RSTs which the rate limit turned into drops.
+  pub rateLimitedResets: AtomicU64,
+}
+
+/*
+  Counts the RSTs sent and the refusals made within the current second. Only the first refusal of
+  each second gets logged, along with how many went unlogged before it.
+*/
+pub struct RefusalLimiter {
+  windowStartedAt: Instant,
+
+  resetsInWindow: u64,
+  refusalsInWindow: u64,
+}
+
+impl RefusalPolicy {
+  // The response to the given cause. None for the packet filter, whose rules pick one themselves.
+  pub fn response(&self, cause: RefusalCause) -> Option<RefusalResponse> {
+    match cause {
+      RefusalCause::Draining => Some(self.draining),
+      RefusalCause::Filtered => None,
+      RefusalCause::AcceptQueueFull => Some(self.acceptQueueFull),
+      RefusalCause::RateLimited => Some(self.rateLimited),
+      RefusalCause::ClosedPort => Some(self.closedPort),
+    }
+  }
+
+  // Parses "<cause> <response>", like "accept-queue-full defer", and sets the response to that
+  // cause.
+  pub fn set(&mut self, entry: &str) -> anyhow::Result<()> {
+    let (cause, response) = entry
+      .split_once(' ')
+      .ok_or_else(|| anyhow!("Expected <cause> <response>"))?;
+    let response = response.trim().parse()?;
+
+    match cause.parse()? {
+      RefusalCause::Draining => self.draining = response,
+      RefusalCause::AcceptQueueFull => self.acceptQueueFull = response,
+      RefusalCause::RateLimited => self.rateLimited = response,
+      RefusalCause::ClosedPort => self.closedPort = response,
+
+      RefusalCause::Filtered => {
+        return Err(anyhow!(
+          "The packet filter rules pick their own response, through deny or reject"
+        ))
+      }
+    }
+    Ok(())
+  }
+
+  // Every configurable cause along with its response, like "draining reset".
+  pub fn entries(&self) -> impl Iterator<Item = String> + '_ {
+    [
+      RefusalCause::Draining,
+      RefusalCause::AcceptQueueFull,
+      RefusalCause::RateLimited,
+      RefusalCause::ClosedPort,
+    ]
+    .into_iter()
+    .filter_map(|cause| Some(format!("{} {}", cause, self.response(cause)?)))
+  }
+}
+
+impl RefusalCounters {
+  pub fn record(&self, cause: RefusalCause) {
+    let counter = match cause {
+      RefusalCause::Draining => &self.draining,
+      RefusalCause::Filtered => &self.filtered,
+      RefusalCause::AcceptQueueFull => &self.acceptQueueFull,
+      RefusalCause::RateLimited => &self.rateLimited,
+      RefusalCause::ClosedPort => &self.closedPort,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+impl Default for RefusalLimiter {
+  fn default() -> Self {
+    Self {
+      windowStartedAt: Instant::now(),
+      resetsInWindow: 0,
+      refusalsInWindow: 0,
+    }
+  }
+}
+
+impl RefusalLimiter {
+  /*
+    Records a refusal made with the given response. Returns whether a RST may be sent (when the
+    response is Reset) and, if this refusal should be logged, how many went unlogged before it.
+  */
+  pub fn admit(&mut self, response: RefusalResponse, now: Instant) -> (bool, Option<u64>) {
+    let mut shouldLog = None;
+
+    if now.saturating_duration_since(self.windowStartedAt) >= Duration::from_secs(1) {
+      shouldLog = Some(self.refusalsInWindow.saturating_sub(1));
+
+      self.windowStartedAt = now;
+      self.resetsInWindow = 0;
+      self.refusalsInWindow = 0;
+    }
+    else if self.refusalsInWindow == 0 {
+      shouldLog = Some(0);
+    }
+    self.refusalsInWindow += 1;
+
+    let maySendReset =
+      response == RefusalResponse::Reset && self.resetsInWindow < MAXIMUM_REFUSAL_RESETS_PER_SECOND;
+    if maySendReset {
+      self.resetsInWindow += 1;
+    }
+
+    (maySendReset, shouldLog)
+  }
+}
+
+impl Display for RefusalCause {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Draining => "draining",
+      Self::Filtered => "filtered",
+      Self::AcceptQueueFull => "accept-queue-full",
+      Self::RateLimited => "rate-limited",
+      Self::ClosedPort => "closed-port",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for RefusalCause {
+  type Err = anyhow::Error;
+
+  fn from_str(cause: &str) -> anyhow::Result<Self> {
+    match cause {
+      "draining" => Ok(Self::Draining),
+      "filtered" => Ok(Self::Filtered),
+      "accept-queue-full" => Ok(Self::AcceptQueueFull),
+      "rate-limited" => Ok(Self::RateLimited),
+      "closed-port" => Ok(Self::ClosedPort),
+      _ => Err(anyhow!(
+        "Unknown refusal cause '{}', expected draining, filtered, accept-queue-full, rate-limited \
+         or closed-port",
+        cause
+      )),
+    }
+  }
+}
+
+impl Display for RefusalResponse {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Drop => "drop",
+      Self::Reset => "reset",
+      Self::Defer => "defer",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for RefusalResponse {
+  type Err = anyhow::Error;
+
+  fn from_str(response: &str) -> anyhow::Result<Self> {
+    match response {
+      "drop" => Ok(Self::Drop),
+      "reset" => Ok(Self::Reset),
+      "defer" => Ok(Self::Defer),
+      _ => Err(anyhow!(
+        "Unknown refusal response '{}', expected drop, reset or defer",
+        response
+      )),
+    }
+  }
+}
+
+impl Display for RefusalCounters {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "refusedWhileDraining {}",
+      self.draining.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "refusedByFilter {}",
+      self.filtered.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "refusedByFullAcceptQueue {}",
+      self.acceptQueueFull.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "refusedByRateLimit {}",
+      self.rateLimited.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "refusedToClosedPorts {}",
+      self.closedPort.load(Ordering::Relaxed)
+    )?;
+    writeln!(
+      f,
+      "rateLimitedRefusalResets {}",
+      self.rateLimitedResets.load(Ordering::Relaxed)
+    )
+  }
+}
+
+impl ToJson for RefusalCounters {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("draining", &self.draining.load(Ordering::Relaxed))
+      .field("filtered", &self.filtered.load(Ordering::Relaxed))
+      .field(
+        "accept_queue_full",
+        &self.acceptQueueFull.load(Ordering::Relaxed),
+      )
+      .field("rate_limited", &self.rateLimited.load(Ordering::Relaxed))
+      .field("closed_port", &self.closedPort.load(Ordering::Relaxed))
+      .field(
+        "rate_limited_resets",
+        &self.rateLimitedResets.load(Ordering::Relaxed),
+      )
+      .finish();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn only_the_first_refusal_of_each_second_gets_logged() {
+    let mut limiter = RefusalLimiter::default();
+    let now = limiter.windowStartedAt;
+
+    assert_eq!(limiter.admit(RefusalResponse::Drop, now), (false, Some(0)));
+    for _ in 0..4 {
+      assert_eq!(
+        limiter.admit(RefusalResponse::Drop, now + Duration::from_millis(500)),
+        (false, None)
+      );
+    }
+
+    // The next second logs the 4 refusals which went unlogged.
+    let nextSecond = now + Duration::from_secs(1);
+    assert_eq!(
+      limiter.admit(RefusalResponse::Defer, nextSecond),
+      (false, Some(4))
+    );
+    assert_eq!(
+      limiter.admit(RefusalResponse::Defer, nextSecond),
+      (false, None)
+    );
+  }
+
+  #[test]
+  fn resets_are_rate_limited_per_second() {
+    let mut limiter = RefusalLimiter::default();
+    let now = limiter.windowStartedAt;
+
+    for _ in 0..MAXIMUM_REFUSAL_RESETS_PER_SECOND {
+      assert!(limiter.admit(RefusalResponse::Reset, now).0);
+    }
+    assert!(!limiter.admit(RefusalResponse::Reset, now).0);
+
+    // Drops don't use up the RST budget of the next second.
+    let nextSecond = now + Duration::from_secs(1);
+    limiter.admit(RefusalResponse::Drop, nextSecond);
+    assert!(limiter.admit(RefusalResponse::Reset, nextSecond).0);
+    assert_eq!(limiter.resetsInWindow, 1);
+  }
+
+  #[test]
+  fn policies_round_trip_through_their_entries() {
+    let mut policy = RefusalPolicy::default();
+    policy.set("closed-port drop").unwrap();
+    policy.set("accept-queue-full reset").unwrap();
+
+    assert_eq!(
+      policy.entries().collect::<Vec<_>>(),
+      [
+        "draining reset",
+        "accept-queue-full reset",
+        "rate-limited defer",
+        "closed-port drop"
+      ]
+    );
+
+    let mut restored = RefusalPolicy::default();
+    for entry in policy.entries() {
+      restored.set(&entry).unwrap();
+    }
+    assert_eq!(restored, policy);
+
+    assert!(policy.set("filtered drop").is_err());
+    assert!(policy.set("draining ignore").is_err());
+    assert!(policy.set("draining").is_err());
+    assert_eq!(policy.response(RefusalCause::Filtered), None);
+  }
+}
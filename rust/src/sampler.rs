@@ -0,0 +1,370 @@
+use {
+  crate::{
+    files,
+    tcp::{ConnectionQuad, ConnectionSample},
+  },
+  anyhow::anyhow,
+  std::{
+    collections::HashSet,
+    fmt::{self, Display, Formatter, Write as _},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant},
+  },
+};
+
+/*
+  Periodic samples of the connections picked via the control socket, for plotting them offline :
+  bytes in flight, the peer's window, the queue depths and the retransmissions so far.
+
+  Every sample interval, the timer thread takes a sample of each picked connection and hands the
+  whole batch to a background thread, which appends it to the sample file. The timer thread never
+  waits for that thread : if the previous batch hasn't been written yet, the batch gets skipped
+  (and counted), so sampling costs at most a lock of each picked connection per interval.
+*/
+
+// Most connections which may be sampled at once.
+pub const MAXIMUM_SAMPLED_CONNECTIONS: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SamplerConfig {
+  // File, to which the samples get appended.
+  pub path: PathBuf,
+
+  pub format: SampleFormat,
+
+  pub interval: Duration,
+}
+
+impl Default for SamplerConfig {
+  fn default() -> Self {
+    Self {
+      path: PathBuf::from("/tmp/tcpd-samples.csv"),
+      format: SampleFormat::Csv,
+      interval: Duration::from_secs(1),
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+  // A header line, followed by a line of comma separated values per sample.
+  Csv,
+
+  // A JSON object per line.
+  JsonLines,
+}
+
+pub struct Sampler {
+  config: SamplerConfig,
+
+  sampled: HashSet<ConnectionQuad>,
+
+  // Timestamps of the samples are taken relative to this, so that they're monotonic.
+  startedAt: Instant,
+  nextSampleAt: Instant,
+
+  // Started along with the first sampled connection.
+  writer: Option<SyncSender<String>>,
+
+  // Batches skipped, since the background thread was still writing the previous one.
+  skippedBatches: u64,
+}
+
+impl Sampler {
+  pub fn new(config: SamplerConfig) -> Self {
+    Self {
+      config,
+      sampled: HashSet::new(),
+      startedAt: Instant::now(),
+      nextSampleAt: Instant::now(),
+      writer: None,
+      skippedBatches: 0,
+    }
+  }
+
+  // Starts sampling the given connection, and returns the path of the sample file.
+  pub fn start(&mut self, connectionQuad: ConnectionQuad) -> anyhow::Result<&Path> {
+    if self.sampled.contains(&connectionQuad) {
+      return Err(anyhow!(
+        "Connection {} is already being sampled",
+        connectionQuad
+      ));
+    }
+    if self.sampled.len() >= MAXIMUM_SAMPLED_CONNECTIONS {
+      return Err(anyhow!(
+        "At most {} connections may be sampled at once",
+        MAXIMUM_SAMPLED_CONNECTIONS
+      ));
+    }
+
+    if self.writer.is_none() {
+      self.writer = Some(spawn_writer(&self.config)?);
+    }
+
+    self.sampled.insert(connectionQuad);
+    Ok(&self.config.path)
+  }
+
+  // Stops sampling the given connection. Returns whether it was being sampled.
+  pub fn stop(&mut self, connectionQuad: &ConnectionQuad) -> bool {
+    self.sampled.remove(connectionQuad)
+  }
+
+  // The connections to sample now, if a sample is due.
+  pub fn due(&mut self, now: Instant) -> Option<Vec<ConnectionQuad>> {
+    if self.sampled.is_empty() || now < self.nextSampleAt {
+      return None;
+    }
+    self.nextSampleAt = now + self.config.interval;
+
+    Some(self.sampled.iter().copied().collect())
+  }
+
+  // Hands the samples taken at the given time to the background thread, unless it's still busy.
+  pub fn submit(&mut self, now: Instant, samples: &[(ConnectionQuad, ConnectionSample)]) {
+    let Some(writer) = &self.writer
+    else {
+      return;
+    };
+
+    let timestamp = now.saturating_duration_since(self.startedAt).as_micros();
+
+    let mut batch = String::new();
+    for (connectionQuad, sample) in samples {
+      self
+        .config
+        .format
+        .write_record(&mut batch, timestamp, connectionQuad, sample);
+    }
+
+    match writer.try_send(batch) {
+      Ok(()) => {}
+      Err(TrySendError::Full(_)) => self.skippedBatches += 1,
+
+      Err(TrySendError::Disconnected(_)) => {
+        eprintln!("ERROR : the sample writer stopped, so sampling stops too");
+        self.writer = None;
+        self.sampled.clear();
+      }
+    }
+  }
+
+  // Lists the sampled connections.
+  pub fn describe(&self) -> String {
+    let mut description = String::new();
+
+    for connectionQuad in &self.sampled {
+      let _ = writeln!(description, "{}", connectionQuad);
+    }
+    let _ = writeln!(
+      description,
+      "into {} ({}, every {:?}) | skipped batches {}",
+      self.config.path.display(),
+      self.config.format,
+      self.config.interval,
+      self.skippedBatches
+    );
+
+    description
+  }
+}
+
+impl SampleFormat {
+  fn write_header(&self, file: &mut impl Write) -> std::io::Result<()> {
+    match self {
+      Self::Csv => writeln!(
+        file,
+        "timestamp_us,source,destination,state,bytes_in_flight,send_window,receive_window,\
+         send_queue,receive_queue,retransmissions"
+      ),
+      Self::JsonLines => Ok(()),
+    }
+  }
+
+  fn write_record(
+    &self,
+    batch: &mut String,
+    timestamp: u128,
+    connectionQuad: &ConnectionQuad,
+    sample: &ConnectionSample,
+  ) {
+    let _ = match self {
+      Self::Csv => writeln!(
+        batch,
+        "{},{},{},{},{},{},{},{},{},{}",
+        timestamp,
+        connectionQuad.source,
+        connectionQuad.destiation,
+        sample.state,
+        sample.bytesInFlight,
+        sample.sendWindow,
+        sample.receiveWindow,
+        sample.sendQueue,
+        sample.receiveQueue,
+        sample.retransmissions
+      ),
+
+      Self::JsonLines => writeln!(
+        batch,
+        "{{\"timestamp_us\":{},\"source\":\"{}\",\"destination\":\"{}\",\"state\":\"{}\",\
+         \"bytes_in_flight\":{},\"send_window\":{},\"receive_window\":{},\"send_queue\":{},\
+         \"receive_queue\":{},\"retransmissions\":{}}}",
+        timestamp,
+        connectionQuad.source,
+        connectionQuad.destiation,
+        sample.state,
+        sample.bytesInFlight,
+        sample.sendWindow,
+        sample.receiveWindow,
+        sample.sendQueue,
+        sample.receiveQueue,
+        sample.retransmissions
+      ),
+    };
+  }
+}
+
+// Opens the sample file for appending, and starts the background thread writing into it. The
+// channel holds a single batch, which is what bounds the sampling cost.
+fn spawn_writer(config: &SamplerConfig) -> anyhow::Result<SyncSender<String>> {
+  let file = files::append(&config.path)
+    .map_err(|error| anyhow!("Failed opening {} : {}", config.path.display(), error))?;
+
+  let mut file = BufWriter::new(file);
+  config
+    .format
+    .write_header(&mut file)
+    .map_err(|error| anyhow!("Failed writing {} : {}", config.path.display(), error))?;
+
+  let (sender, receiver) = mpsc::sync_channel::<String>(1);
+  let path = config.path.clone();
+
+  thread::spawn(move || {
+    for batch in receiver {
+      if let Err(error) = file.write_all(batch.as_bytes()).and_then(|_| file.flush()) {
+        eprintln!(
+          "ERROR : failed writing samples to {} : {}",
+          path.display(),
+          error
+        );
+        return;
+      }
+    }
+  });
+
+  Ok(sender)
+}
+
+impl Display for SampleFormat {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Csv => "csv",
+      Self::JsonLines => "json-lines",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for SampleFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(format: &str) -> anyhow::Result<Self> {
+    match format {
+      "csv" => Ok(Self::Csv),
+      "json-lines" => Ok(Self::JsonLines),
+      _ => Err(anyhow!(
+        "Unknown sample format '{}', expected csv or json-lines",
+        format
+      )),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::tcp::TCPConnectionState,
+    std::{env, fs, process},
+  };
+
+  // Waits for the background thread to have written the given number of lines.
+  fn wait_for_lines(path: &Path, count: usize) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+      let contents = fs::read_to_string(path).unwrap_or_default();
+      if contents.lines().count() >= count || Instant::now() > deadline {
+        return contents;
+      }
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  #[test]
+  fn csv_samples_parse_and_are_monotonic() {
+    let path = env::temp_dir().join(format!("tcpd-sampler-test-{}.csv", process::id()));
+    let _ = fs::remove_file(&path);
+
+    let mut sampler = Sampler::new(SamplerConfig {
+      path: path.clone(),
+      format: SampleFormat::Csv,
+      interval: Duration::from_millis(10),
+    });
+    let connectionQuad: ConnectionQuad = "10.0.0.2:51514 10.0.0.1:8080".parse().unwrap();
+    sampler.start(connectionQuad).unwrap();
+
+    const SAMPLES: usize = 5;
+    for index in 0..SAMPLES {
+      let sample = ConnectionSample {
+        state: TCPConnectionState::Established,
+        bytesInFlight: 1000 * index as u32,
+        sendWindow: 64240,
+        receiveWindow: 65535,
+        sendQueue: 10 * index,
+        receiveQueue: 0,
+        retransmissions: index as u64,
+      };
+      let now = sampler.startedAt + Duration::from_millis(10 * index as u64);
+      sampler.submit(now, &[(connectionQuad, sample)]);
+
+      // The channel holds a single batch, so let it drain before submitting the next one.
+      wait_for_lines(&path, index + 2);
+    }
+    assert_eq!(sampler.skippedBatches, 0);
+
+    let contents = wait_for_lines(&path, SAMPLES + 1);
+    let mut lines = contents.lines();
+    let header = lines.next().unwrap();
+    let columns = header.split(',').count();
+    assert_eq!(columns, 10);
+
+    let mut previousTimestamp = None;
+    for (index, line) in lines.enumerate() {
+      let fields = line.split(',').collect::<Vec<_>>();
+      assert_eq!(fields.len(), columns, "line {:?}", line);
+
+      let timestamp: u128 = fields[0].parse().unwrap();
+      assert!(previousTimestamp < Some(timestamp));
+      previousTimestamp = Some(timestamp);
+
+      assert_eq!(
+        format!("{} {}", fields[1], fields[2])
+          .parse::<ConnectionQuad>()
+          .unwrap(),
+        connectionQuad
+      );
+      assert_eq!(fields[3], "ESTABLISHED");
+      assert_eq!(fields[4].parse::<u32>().unwrap(), 1000 * index as u32);
+      assert_eq!(fields[8].parse::<usize>().unwrap(), 0);
+      assert_eq!(fields[9].parse::<u64>().unwrap(), index as u64);
+    }
+    assert!(previousTimestamp.is_some());
+
+    fs::remove_file(&path).unwrap();
+  }
+}
@@ -0,0 +1,585 @@
+use {
+  crate::tcp::{sequence_le, sequence_lt},
+  std::{
+    collections::VecDeque,
+    sync::{
+      atomic::{AtomicUsize, Ordering},
+      Arc,
+    },
+    time::Instant,
+  },
+};
+
+/*
+  Most bytes written by the user which may be waiting to be sent or acknowledged at once, by
+  default. The chunks holding them may not take up more than this either, except for the copied
+  payloads of segments (see SendBuffer).
+*/
+pub const SEND_BUFFER_CAPACITY: usize = 64 * 1024;
+
+// Send buffer capacities are a multiple of this.
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+/*
+  Data written by the user, which is yet to be acknowledged by the peer.
+
+  Written bytes get copied once, into reference counted chunks. Once a chunk is full, it's sealed :
+  a segment being sent out of it doesn't get its own copy of the payload, but refers to a range of
+  the chunk instead, and retransmissions get serialized from that very range. A chunk is freed once
+  every segment referring to it has been fully acknowledged.
+
+  Writes keep getting appended to the last chunk till it's full, even once part of it has been
+  sent. So lots of small writes, each sent out right away, share a chunk instead of pinning one
+  each. Since only the send buffer may refer to a chunk which isn't sealed yet, the segments cut out
+  of it get a copy of their payload.
+
+  So does a segment which would span two chunks, so that chunk boundaries never cut segments short
+  of the MSS.
+*/
+pub struct SendBuffer {
+  // Chunks holding data which is yet to be sent, oldest first. The last one stays here, even once
+  // all of it has been sent, till it's full, so that further writes fill it up.
+  unsentChunks: VecDeque<Chunk>,
+
+  // Offset of the first unsent byte in the front unsent chunk.
+  unsentOffset: usize,
+
+  // Sent segments which are yet to be (fully) acknowledged, in sequence order.
+  inFlightSegments: VecDeque<InFlightSegment>,
+
+  // Bytes written by the user which are yet to be acknowledged, counted against the capacity.
+  length: usize,
+
+  capacity: usize,
+
+  // How many of those have been sent.
+  inFlightLength: usize,
+
+  // Bytes taken up by the chunks which are still alive, either here or through in-flight segments.
+  allocatedLength: Arc<AtomicUsize>,
+}
+
+struct Chunk {
+  data: Arc<ChunkData>,
+
+  // How much of the chunk has been written to. It's sealed once full.
+  filled: usize,
+}
+
+/*
+  Storage of a chunk, which only the send buffer refers to till it's sealed. It gets written to
+  through Arc::get_mut( ) till then, and shared by the in-flight segments cut out of it afterwards.
+*/
+struct ChunkData {
+  bytes: Box<[u8]>,
+
+  // Of the send buffer the chunk belongs to, which it gets subtracted from once it's dropped. The
+  // copied payloads of segments aren't counted.
+  allocatedLength: Option<Arc<AtomicUsize>>,
+}
+
+#[derive(Clone)]
+pub struct InFlightSegment {
+  pub sequenceNumber: u32,
+
+  chunk: Arc<ChunkData>,
+  offset: usize,
+  length: usize,
+
+  pub sentAt: Instant,
+
+  // When the segment was first sent. Retransmitting it doesn't change this, and neither does a
+  // partial acknowledgment, which leaves the rest of it waiting for just as long.
+  pub firstSentAt: Instant,
+}
+
+impl Default for SendBuffer {
+  fn default() -> Self {
+    Self::with_capacity(SEND_BUFFER_CAPACITY)
+  }
+}
+
+impl SendBuffer {
+  // The capacity gets rounded up to a whole number of chunks.
+  pub fn with_capacity(capacity: usize) -> Self {
+    Self {
+      unsentChunks: VecDeque::default(),
+      unsentOffset: 0,
+      inFlightSegments: VecDeque::default(),
+      length: 0,
+      capacity: capacity.max(1).div_ceil(CHUNK_SIZE) * CHUNK_SIZE,
+      inFlightLength: 0,
+      allocatedLength: Arc::default(),
+    }
+  }
+
+  pub fn capacity(&self) -> usize {
+    self.capacity
+  }
+
+  // Takes in as much of the given data as there's room for, and returns how much that was.
+  pub fn write(&mut self, data: &[u8]) -> usize {
+    let acceptedLength = data.len().min(self.room());
+    let mut data = &data[..acceptedLength];
+
+    while !data.is_empty() {
+      let writableChunk = self
+        .unsentChunks
+        .back_mut()
+        .filter(|chunk| !chunk.is_sealed());
+
+      let Some(chunk) = writableChunk
+      else {
+        self.unsentChunks.push_back(Chunk {
+          data: ChunkData::new(CHUNK_SIZE, &self.allocatedLength),
+          filled: 0,
+        });
+        continue;
+      };
+
+      let copiedLength = data.len().min(chunk.data.len() - chunk.filled);
+      let chunkData =
+        Arc::get_mut(&mut chunk.data).expect("A chunk got shared before being sealed");
+      chunkData.bytes[chunk.filled..chunk.filled + copiedLength]
+        .copy_from_slice(&data[..copiedLength]);
+
+      chunk.filled += copiedLength;
+      data = &data[copiedLength..];
+    }
+
+    self.length += acceptedLength;
+    acceptedLength
+  }
+
+  // Bytes written by the user which are yet to be acknowledged.
+  pub fn len(&self) -> usize {
+    self.length
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.length == 0
+  }
+
+  // Bytes written by the user which are yet to be sent.
+  pub fn unsent_len(&self) -> usize {
+    self.length - self.inFlightLength
+  }
+
+  // Bytes sent, which are yet to be acknowledged.
+  pub fn in_flight_len(&self) -> usize {
+    self.inFlightLength
+  }
+
+  pub fn has_unsent_data(&self) -> bool {
+    self.unsent_len() > 0
+  }
+
+  /*
+    How many bytes write( ) would take in right now : as many as fit into the last chunk, and the
+    new chunks there's still room for. Those only count whole, so that a writer which keeps the send
+    buffer full fills (and seals) them at once, rather than leaving one with its segments copied.
+  */
+  pub fn room(&self) -> usize {
+    let unusedLength = self.capacity - self.length;
+
+    let lastChunkRoom = self
+      .unsentChunks
+      .back()
+      .map_or(0, |chunk| chunk.data.len() - chunk.filled)
+      .min(unusedLength);
+    let newChunks = self
+      .capacity
+      .saturating_sub(self.allocated_len())
+      .min(unusedLength - lastChunkRoom)
+      / CHUNK_SIZE;
+
+    lastChunkRoom + newChunks * CHUNK_SIZE
+  }
+
+  // Bytes taken up by the chunks holding the data which is yet to be acknowledged.
+  pub fn allocated_len(&self) -> usize {
+    self.allocatedLength.load(Ordering::Relaxed)
+  }
+
+  /*
+    Cuts the next segment out of the unsent data and records it as in flight. The segment is
+    exactly the given length long, unless less data than that is left unsent.
+  */
+  pub fn next_segment(
+    &mut self,
+    sequenceNumber: u32,
+    maximumLength: usize,
+    now: Instant,
+  ) -> Option<InFlightSegment> {
+    let chunk = self.unsentChunks.front()?;
+
+    // Never cut an empty segment, which would go out as a zero-length data segment. The front
+    // chunk only has no unsent data left if it's the last one, waiting for further writes.
+    if maximumLength == 0 || chunk.filled == self.unsentOffset {
+      return None;
+    }
+
+    let offset = self.unsentOffset;
+    let isReferable =
+      chunk.is_sealed() && (maximumLength <= chunk.filled - offset || self.unsentChunks.len() == 1);
+    let segment = if isReferable {
+      InFlightSegment {
+        sequenceNumber,
+        chunk: chunk.data.clone(),
+        offset,
+        length: maximumLength.min(chunk.filled - offset),
+        sentAt: now,
+        firstSentAt: now,
+      }
+    }
+    else {
+      let payload = self.copy_unsent(maximumLength);
+
+      InFlightSegment {
+        sequenceNumber,
+        length: payload.len(),
+        chunk: ChunkData::copied(payload),
+        offset: 0,
+        sentAt: now,
+        firstSentAt: now,
+      }
+    };
+
+    self.consume_unsent(segment.length);
+    self.inFlightLength += segment.length;
+
+    self.inFlightSegments.push_back(segment.clone());
+    Some(segment)
+  }
+
+  // Copies up to the given number of unsent bytes, across chunks.
+  fn copy_unsent(&self, maximumLength: usize) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(maximumLength);
+    let mut offset = self.unsentOffset;
+
+    for chunk in &self.unsentChunks {
+      let length = (maximumLength - payload.len()).min(chunk.filled - offset);
+      payload.extend_from_slice(&chunk.data.bytes[offset..offset + length]);
+
+      if payload.len() == maximumLength {
+        break;
+      }
+      offset = 0;
+    }
+
+    payload
+  }
+
+  /*
+    Moves the start of the unsent data forward by the given number of bytes, dropping the chunks
+    left behind, unless it's the last one and isn't full yet. Those still referred to by in-flight
+    segments live on through them.
+  */
+  fn consume_unsent(&mut self, mut length: usize) {
+    while let Some(chunk) = self.unsentChunks.front() {
+      let consumedLength = length.min(chunk.filled - self.unsentOffset);
+      self.unsentOffset += consumedLength;
+      length -= consumedLength;
+
+      let isDrained = self.unsentOffset == chunk.filled;
+      if !isDrained || !chunk.is_sealed() {
+        return;
+      }
+
+      self.unsentChunks.pop_front();
+      self.unsentOffset = 0;
+    }
+  }
+
+  // Forgets every in-flight byte before the given acknowledgment number. A partially acknowledged
+  // segment keeps its chunk alive, till its last byte gets acknowledged too.
+  pub fn acknowledge(&mut self, acknowledgementNumber: u32) {
+    while let Some(segment) = self.inFlightSegments.front_mut() {
+      let segmentEnd = segment.sequenceNumber.wrapping_add(segment.length as u32);
+
+      if sequence_le(segmentEnd, acknowledgementNumber) {
+        self.length -= segment.length;
+        self.inFlightLength -= segment.length;
+        self.inFlightSegments.pop_front();
+        continue;
+      }
+
+      if sequence_lt(segment.sequenceNumber, acknowledgementNumber) {
+        let acknowledgedLength =
+          acknowledgementNumber.wrapping_sub(segment.sequenceNumber) as usize;
+
+        segment.sequenceNumber = acknowledgementNumber;
+        segment.offset += acknowledgedLength;
+        segment.length -= acknowledgedLength;
+        self.length -= acknowledgedLength;
+        self.inFlightLength -= acknowledgedLength;
+      }
+      break;
+    }
+
+    // Don't keep the last chunk around for further writes, once everything has been acknowledged.
+    if self.length == 0 {
+      self.unsentChunks.clear();
+      self.unsentOffset = 0;
+    }
+  }
+
+  // When the oldest unacknowledged byte was first sent, if any byte is in flight.
+  pub fn oldest_in_flight_sent_at(&self) -> Option<Instant> {
+    self
+      .inFlightSegments
+      .front()
+      .map(|segment| segment.firstSentAt)
+  }
+
+  // The segment a retransmission timeout would resend.
+  pub fn oldest_in_flight_segment_mut(&mut self) -> Option<&mut InFlightSegment> {
+    self.inFlightSegments.front_mut()
+  }
+
+  // Every segment in flight, oldest first.
+  pub fn in_flight_segments_mut(&mut self) -> impl Iterator<Item = &mut InFlightSegment> {
+    self.inFlightSegments.iter_mut()
+  }
+}
+
+impl InFlightSegment {
+  pub fn payload(&self) -> &[u8] {
+    &self.chunk.bytes[self.offset..self.offset + self.length]
+  }
+}
+
+impl Chunk {
+  // Whether the chunk is full, so that segments may refer to it.
+  fn is_sealed(&self) -> bool {
+    self.filled == self.data.len()
+  }
+}
+
+impl ChunkData {
+  // A chunk of the send buffer, counted in its allocated length.
+  fn new(length: usize, allocatedLength: &Arc<AtomicUsize>) -> Arc<Self> {
+    allocatedLength.fetch_add(length, Ordering::Relaxed);
+
+    Arc::new(Self {
+      bytes: vec![0; length].into_boxed_slice(),
+      allocatedLength: Some(allocatedLength.clone()),
+    })
+  }
+
+  // The copied payload of a single segment.
+  fn copied(payload: Vec<u8>) -> Arc<Self> {
+    Arc::new(Self {
+      bytes: payload.into_boxed_slice(),
+      allocatedLength: None,
+    })
+  }
+
+  fn len(&self) -> usize {
+    self.bytes.len()
+  }
+}
+
+impl Drop for ChunkData {
+  fn drop(&mut self) {
+    if let Some(allocatedLength) = &self.allocatedLength {
+      allocatedLength.fetch_sub(self.bytes.len(), Ordering::Relaxed);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MSS: usize = 1460;
+
+  fn pattern(length: usize) -> Vec<u8> {
+    (0..length).map(|index| (index % 251) as u8).collect()
+  }
+
+  // Cuts segments of at most the MSS till nothing is left unsent, and returns their payloads.
+  fn send_all(sendBuffer: &mut SendBuffer, sequenceNumber: &mut u32) -> Vec<Vec<u8>> {
+    let mut payloads = Vec::new();
+
+    while let Some(segment) = sendBuffer.next_segment(*sequenceNumber, MSS, Instant::now()) {
+      *sequenceNumber = sequenceNumber.wrapping_add(segment.payload().len() as u32);
+      payloads.push(segment.payload().to_vec());
+    }
+    payloads
+  }
+
+  #[test]
+  fn small_writes_share_a_chunk() {
+    let mut sendBuffer = SendBuffer::default();
+    let mut sequenceNumber = 1000;
+    let mut written = Vec::new();
+
+    for index in 0..1000 {
+      let data = pattern(10 + index % 7);
+      assert_eq!(sendBuffer.write(&data), data.len());
+      written.extend_from_slice(&data);
+
+      let payloads = send_all(&mut sendBuffer, &mut sequenceNumber);
+      assert_eq!(payloads, vec![data]);
+    }
+
+    // 13000 bytes or so, sent as 1000 segments, fit in a single chunk.
+    assert_eq!(sendBuffer.len(), written.len());
+    assert_eq!(sendBuffer.in_flight_len(), written.len());
+    assert_eq!(sendBuffer.allocated_len(), CHUNK_SIZE);
+
+    sendBuffer.acknowledge(sequenceNumber);
+    assert!(sendBuffer.is_empty());
+    assert_eq!(sendBuffer.allocated_len(), 0);
+  }
+
+  #[test]
+  fn segments_are_cut_at_the_mss_across_chunks() {
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(3 * CHUNK_SIZE + 123);
+    assert_eq!(sendBuffer.write(&data), data.len());
+    assert_eq!(sendBuffer.unsent_len(), data.len());
+
+    let mut sequenceNumber = u32::MAX - 5000;
+    let payloads = send_all(&mut sendBuffer, &mut sequenceNumber);
+
+    let (last, full) = payloads.split_last().unwrap();
+    assert!(full.iter().all(|payload| payload.len() == MSS));
+    assert!(!last.is_empty() && last.len() <= MSS);
+    assert_eq!(payloads.concat(), data);
+
+    assert!(!sendBuffer.has_unsent_data());
+    assert_eq!(sendBuffer.in_flight_len(), data.len());
+    assert!(sendBuffer
+      .next_segment(sequenceNumber, MSS, Instant::now())
+      .is_none());
+  }
+
+  #[test]
+  fn segments_ending_exactly_at_a_chunk_boundary_refer_to_their_chunk() {
+    const SEGMENT_SIZE: usize = CHUNK_SIZE / 4;
+
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(2 * CHUNK_SIZE);
+    assert_eq!(sendBuffer.write(&data), data.len());
+
+    let mut sequenceNumber = 0;
+    while let Some(segment) = sendBuffer.next_segment(sequenceNumber, SEGMENT_SIZE, Instant::now())
+    {
+      sequenceNumber = sequenceNumber.wrapping_add(segment.payload().len() as u32);
+    }
+    assert_eq!(sequenceNumber as usize, data.len());
+
+    // None of them needed a copy of its payload, nor came out short.
+    let segments = &sendBuffer.inFlightSegments;
+    assert_eq!(segments.len(), 8);
+    for (index, segment) in segments.iter().enumerate() {
+      assert_eq!(segment.length, SEGMENT_SIZE);
+      assert!(segment.chunk.allocatedLength.is_some());
+      assert_eq!(
+        segment.payload(),
+        &data[index * SEGMENT_SIZE..(index + 1) * SEGMENT_SIZE]
+      );
+    }
+    assert!(!Arc::ptr_eq(&segments[3].chunk, &segments[4].chunk));
+  }
+
+  #[test]
+  fn a_write_of_exactly_the_capacity_fills_it() {
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(SEND_BUFFER_CAPACITY);
+
+    assert_eq!(
+      sendBuffer.write(&data[..SEND_BUFFER_CAPACITY - 1]),
+      SEND_BUFFER_CAPACITY - 1
+    );
+    assert_eq!(sendBuffer.room(), 1);
+    assert_eq!(sendBuffer.write(&data[SEND_BUFFER_CAPACITY - 1..]), 1);
+    assert_eq!(sendBuffer.room(), 0);
+    assert_eq!(sendBuffer.len(), SEND_BUFFER_CAPACITY);
+    assert_eq!(sendBuffer.allocated_len(), SEND_BUFFER_CAPACITY);
+    assert_eq!(sendBuffer.write(&data[..1]), 0);
+  }
+
+  #[test]
+  fn writes_are_capped() {
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(SEND_BUFFER_CAPACITY + 1000);
+
+    assert_eq!(sendBuffer.write(&data), SEND_BUFFER_CAPACITY);
+    assert_eq!(sendBuffer.room(), 0);
+    assert_eq!(sendBuffer.write(&data), 0);
+    assert!(sendBuffer.allocated_len() <= SEND_BUFFER_CAPACITY);
+
+    // Acknowledging the first chunk frees it, making room for another one.
+    let mut sequenceNumber = 0;
+    send_all(&mut sendBuffer, &mut sequenceNumber);
+    sendBuffer.acknowledge(CHUNK_SIZE as u32);
+    assert_eq!(sendBuffer.len(), SEND_BUFFER_CAPACITY - CHUNK_SIZE);
+    assert_eq!(sendBuffer.room(), CHUNK_SIZE);
+  }
+
+  #[test]
+  fn only_segments_of_sealed_chunks_share_them() {
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(CHUNK_SIZE + 100);
+
+    // The first chunk is full, so its segments refer to it.
+    sendBuffer.write(&data[..CHUNK_SIZE]);
+    let mut sequenceNumber = 0;
+    send_all(&mut sendBuffer, &mut sequenceNumber);
+    let chunk = &sendBuffer.inFlightSegments[0].chunk;
+    assert!(sendBuffer
+      .inFlightSegments
+      .iter()
+      .all(|segment| Arc::ptr_eq(&segment.chunk, chunk)));
+
+    // The second one isn't, so its segment gets a copy of the payload...
+    sendBuffer.write(&data[CHUNK_SIZE..]);
+    send_all(&mut sendBuffer, &mut sequenceNumber);
+    let openSegment = sendBuffer.inFlightSegments.back().unwrap();
+    assert!(openSegment.chunk.allocatedLength.is_none());
+    assert_eq!(openSegment.payload(), &data[CHUNK_SIZE..]);
+
+    // ...while the chunk still gets written to.
+    assert_eq!(sendBuffer.write(&data[..50]), 50);
+    assert_eq!(sendBuffer.unsentChunks.len(), 1);
+    assert_eq!(sendBuffer.allocated_len(), 2 * CHUNK_SIZE);
+  }
+
+  #[test]
+  fn capacities_are_rounded_up_to_whole_chunks() {
+    let mut sendBuffer = SendBuffer::with_capacity(CHUNK_SIZE + 1);
+    assert_eq!(sendBuffer.capacity(), 2 * CHUNK_SIZE);
+
+    let data = pattern(3 * CHUNK_SIZE);
+    assert_eq!(sendBuffer.write(&data), 2 * CHUNK_SIZE);
+    assert_eq!(sendBuffer.room(), 0);
+  }
+
+  #[test]
+  fn partial_acknowledgments_trim_the_oldest_segment() {
+    let mut sendBuffer = SendBuffer::default();
+    let data = pattern(2 * MSS);
+    sendBuffer.write(&data);
+
+    let mut sequenceNumber = 500;
+    send_all(&mut sendBuffer, &mut sequenceNumber);
+
+    sendBuffer.acknowledge(500 + 100);
+    assert_eq!(sendBuffer.len(), 2 * MSS - 100);
+
+    let oldest = sendBuffer.oldest_in_flight_segment_mut().unwrap();
+    assert_eq!(oldest.sequenceNumber, 600);
+    assert_eq!(oldest.payload(), &data[100..MSS]);
+
+    // Acknowledgments of already acknowledged data change nothing.
+    sendBuffer.acknowledge(500);
+    assert_eq!(sendBuffer.len(), 2 * MSS - 100);
+
+    sendBuffer.acknowledge(sequenceNumber);
+    assert!(sendBuffer.is_empty());
+    assert!(sendBuffer.oldest_in_flight_sent_at().is_none());
+  }
+}
@@ -0,0 +1,1077 @@
+use {
+  crate::{
+    capture::PcapWriter,
+    clock::{Clock, VirtualClock},
+    error::TcpError,
+    interface::{self, Interface, InterfaceConfig},
+    manager::{self, SharedConnection, TICK_INTERVAL},
+    nic::{NicDevice, Readiness},
+    tcp::{Location, TCPConnectionState},
+  },
+  anyhow::anyhow,
+  std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Formatter},
+    io,
+    net::Ipv4Addr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+  },
+};
+
+/*
+  Runs a scenario between 2 hosts, a client and a server, each being an Interface of its own. They
+  talk over a simulated link, which may delay, lose and reorder their packets, and limits how fast
+  they get sent. Everything runs on a VirtualClock, within the calling thread : the clock jumps
+  straight to the next thing which happens (a packet arriving, a tick, a sleep ending), so a run
+  takes the same (virtual) time on every machine, and the same seed loses the same packets.
+
+  A scenario is a TOML file like this :
+
+    seed = 7
+    time_limit_ms = 600000
+    delay_ms = 10
+    bandwidth_kbps = 10000
+    loss_percent = 5
+    reorder_percent = 0
+    pcap_directory = "/tmp"
+    client = ["connect 80", "send 262144", "close", "receive-all"]
+    server = ["listen 80", "accept 80", "receive-all", "close"]
+
+  The client and the server each run a program of actions (see Action), one after another. The
+  data sent follows a fixed pattern, which the receiving end checks. The run is over once both
+  programs have finished, and every connection left is in the TIME-WAIT state.
+*/
+
+// Address of the client and of the server, on the simulated link.
+pub const CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+// How much data a single send or receive action hands over to the connection at once.
+const CHUNK_SIZE: usize = 4096;
+
+pub struct Scenario {
+  pub seed: u64,
+
+  // The run gets stopped (and reported as incomplete) after so much virtual time.
+  pub timeLimit: Duration,
+
+  pub link: LinkModel,
+
+  pub client: Vec<Action>,
+  pub server: Vec<Action>,
+
+  // Where client.pcap and server.pcap get written, if anywhere.
+  pub pcapDirectory: Option<PathBuf>,
+}
+
+impl Default for Scenario {
+  fn default() -> Self {
+    Self {
+      seed: 1,
+      timeLimit: Duration::from_secs(600),
+      link: LinkModel::default(),
+      client: Vec::new(),
+      server: Vec::new(),
+      pcapDirectory: None,
+    }
+  }
+}
+
+// How the link treats each packet, the same way in both directions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkModel {
+  // One way propagation delay.
+  pub delay: Duration,
+
+  // In bits per second, with 0 meaning unlimited. Packets queue up behind each other, while the
+  // link is busy sending.
+  pub bandwidth: u64,
+
+  // Probabilities, between 0 and 1. A reordered packet gets delayed twice, so that the ones sent
+  // right after it overtake it.
+  pub loss: f64,
+  pub reorder: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  // Listens on the given port.
+  Listen(u16),
+
+  // Waits for a connection on the given port, which the following actions then use.
+  Accept(u16),
+
+  // Connects to the given port of the other host, and waits till the connection gets established.
+  Connect(u16),
+
+  // Writes the given number of bytes.
+  Send(usize),
+
+  // Reads the given number of bytes.
+  Receive(usize),
+
+  // Reads till the peer closes its side of the connection.
+  ReceiveAll,
+
+  Sleep(Duration),
+
+  // Closes our side of the connection.
+  Close,
+
+  // Runs the actions up to the matching End the given number of times.
+  Loop(usize),
+  End,
+}
+
+#[derive(Debug, Default)]
+pub struct SimulationSummary {
+  // Whether both programs finished without an error, and the connections settled, in time.
+  pub isCompleted: bool,
+
+  // Virtual time the run took.
+  pub elapsed: Duration,
+
+  pub link: LinkCounters,
+
+  pub client: HostSummary,
+  pub server: HostSummary,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkCounters {
+  pub sent: u64,
+  pub delivered: u64,
+  pub dropped: u64,
+  pub reordered: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct HostSummary {
+  // When the program of the host finished, since the start of the run.
+  pub finishedAt: Option<Duration>,
+
+  pub bytesSent: u64,
+  pub bytesReceived: u64,
+
+  // Received bytes which didn't match the pattern the other host sent.
+  pub corruptBytes: u64,
+
+  // The action which failed, and why.
+  pub error: Option<String>,
+
+  pub retransmittedSegments: u64,
+}
+
+impl HostSummary {
+  // Bytes received per second, till the program finished.
+  pub fn goodput(&self) -> f64 {
+    match self.finishedAt {
+      Some(finishedAt) if !finishedAt.is_zero() => {
+        self.bytesReceived as f64 / finishedAt.as_secs_f64()
+      }
+      _ => 0.0,
+    }
+  }
+}
+
+// Runs the given scenario till it's over, or till its time limit.
+pub fn run(scenario: &Scenario) -> anyhow::Result<SimulationSummary> {
+  let clock = Arc::new(VirtualClock::default());
+  let startedAt = clock.now();
+  let link = Arc::new(Link::new(scenario, clock.clone())?);
+
+  let mut client = Host::new(
+    "client",
+    CLIENT_ADDRESS,
+    SERVER_ADDRESS,
+    &scenario.client,
+    LinkEnd {
+      link: link.clone(),
+      side: Side::Client,
+    },
+    clock.clone(),
+  )?;
+  let mut server = Host::new(
+    "server",
+    SERVER_ADDRESS,
+    CLIENT_ADDRESS,
+    &scenario.server,
+    LinkEnd {
+      link: link.clone(),
+      side: Side::Server,
+    },
+    clock.clone(),
+  )?;
+
+  let stopAt = startedAt + scenario.timeLimit;
+  let mut nextTickAt = startedAt + TICK_INTERVAL;
+
+  let isCompleted = loop {
+    // Both hosts get to run till neither of them can do anything more, at this point in time.
+    while client.step(startedAt) | server.step(startedAt) {}
+
+    if client.is_settled() && server.is_settled() {
+      break client.error.is_none() && server.error.is_none();
+    }
+
+    let nextEventAt = [
+      Some(nextTickAt),
+      link.next_delivery_at(),
+      client.wakeAt,
+      server.wakeAt,
+    ]
+    .into_iter()
+    .flatten()
+    .min()
+    .unwrap_or(nextTickAt);
+
+    if nextEventAt > stopAt {
+      break false;
+    }
+    clock.advance_to(nextEventAt);
+    let now = clock.now();
+
+    for (side, packet) in link.take_due(now) {
+      match side {
+        Side::Client => client.interface.process_packet(&packet),
+        Side::Server => server.interface.process_packet(&packet),
+      }
+    }
+
+    if now >= nextTickAt {
+      client.interface.connection_manager().on_tick();
+      server.interface.connection_manager().on_tick();
+      nextTickAt += TICK_INTERVAL;
+    }
+  };
+
+  link.finish_captures()?;
+
+  Ok(SimulationSummary {
+    isCompleted,
+    elapsed: clock.elapsed(),
+    link: link.counters(),
+    client: client.summary(),
+    server: server.summary(),
+  })
+}
+
+// One of the 2 ends of the link.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Side {
+  Client,
+  Server,
+}
+
+impl Side {
+  fn index(self) -> usize {
+    self as usize
+  }
+
+  fn other(self) -> Self {
+    match self {
+      Self::Client => Self::Server,
+      Self::Server => Self::Client,
+    }
+  }
+}
+
+struct Link {
+  model: LinkModel,
+
+  clock: Arc<VirtualClock>,
+
+  state: Mutex<LinkState>,
+}
+
+struct LinkState {
+  random: XorShift,
+
+  // Packets on their way, by when they arrive (and in which order they got sent, among the ones
+  // arriving at once), along with the side they arrive at.
+  inFlight: BTreeMap<(Instant, u64), (Side, Vec<u8>)>,
+  packetsSent: u64,
+
+  // Till when each side is busy sending the packets queued up already, by the index of the side.
+  busyUntil: [Instant; 2],
+
+  counters: LinkCounters,
+
+  // By the index of the side. Each records the packets the side sends, and the ones it receives.
+  captures: [Option<PcapWriter>; 2],
+  startedAt: Instant,
+}
+
+impl Link {
+  fn new(scenario: &Scenario, clock: Arc<VirtualClock>) -> anyhow::Result<Self> {
+    let captures = match &scenario.pcapDirectory {
+      Some(directory) => {
+        let create = |name: &str| {
+          let path = directory.join(name);
+          PcapWriter::create(&path)
+            .map_err(|error| anyhow!("Failed creating {} : {}", path.display(), error))
+        };
+        [Some(create("client.pcap")?), Some(create("server.pcap")?)]
+      }
+      None => [None, None],
+    };
+
+    let now = clock.now();
+    Ok(Self {
+      model: scenario.link,
+      clock,
+      state: Mutex::new(LinkState {
+        random: XorShift::new(scenario.seed),
+        inFlight: BTreeMap::new(),
+        packetsSent: 0,
+        busyUntil: [now; 2],
+        counters: LinkCounters::default(),
+        captures,
+        startedAt: now,
+      }),
+    })
+  }
+
+  fn send(&self, from: Side, packet: &[u8]) {
+    let now = self.clock.now();
+    let mut state = self.lock_state();
+    state.counters.sent += 1;
+    state.capture(from, packet, now);
+
+    // A lost packet still keeps the link busy, since it got lost on the way.
+    let sentAt = state.busyUntil[from.index()].max(now) + self.transmission_time(packet.len());
+    state.busyUntil[from.index()] = sentAt;
+
+    if state.random.chance(self.model.loss) {
+      state.counters.dropped += 1;
+      return;
+    }
+
+    let mut arrivesAt = sentAt + self.model.delay;
+    if state.random.chance(self.model.reorder) {
+      state.counters.reordered += 1;
+      arrivesAt += self.model.delay.max(Duration::from_millis(1));
+    }
+
+    let id = state.packetsSent;
+    state.packetsSent += 1;
+    state
+      .inFlight
+      .insert((arrivesAt, id), (from.other(), packet.to_vec()));
+  }
+
+  // How long the link takes to send a packet of the given length.
+  fn transmission_time(&self, length: usize) -> Duration {
+    if self.model.bandwidth == 0 {
+      return Duration::ZERO;
+    }
+    Duration::from_nanos((length as u64 * 8).saturating_mul(1_000_000_000) / self.model.bandwidth)
+  }
+
+  fn next_delivery_at(&self) -> Option<Instant> {
+    self
+      .lock_state()
+      .inFlight
+      .keys()
+      .next()
+      .map(|(arrivesAt, _)| *arrivesAt)
+  }
+
+  // Removes the packets which have arrived by now, in the order they arrived.
+  fn take_due(&self, now: Instant) -> Vec<(Side, Vec<u8>)> {
+    let mut state = self.lock_state();
+
+    let mut due = Vec::new();
+    while let Some(entry) = state.inFlight.first_entry() {
+      if entry.key().0 > now {
+        break;
+      }
+      let (to, packet) = entry.remove();
+      state.capture(to, &packet, now);
+      due.push((to, packet));
+    }
+
+    state.counters.delivered += due.len() as u64;
+    due
+  }
+
+  fn counters(&self) -> LinkCounters {
+    self.lock_state().counters
+  }
+
+  fn finish_captures(&self) -> anyhow::Result<()> {
+    for capture in self.lock_state().captures.iter_mut() {
+      if let Some(capture) = capture.take() {
+        capture.finish()?;
+      }
+    }
+    Ok(())
+  }
+
+  fn lock_state(&self) -> MutexGuard<'_, LinkState> {
+    self.state.lock().expect("Link mutex poisoned")
+  }
+}
+
+impl LinkState {
+  // The capture files carry the virtual time since the start of the run, as their timestamps.
+  fn capture(&mut self, side: Side, packet: &[u8], now: Instant) {
+    let timestamp = now.saturating_duration_since(self.startedAt);
+
+    if let Some(capture) = &mut self.captures[side.index()] {
+      if let Err(error) = capture.write_packet_at(packet, timestamp) {
+        eprintln!("Failed capturing packet of the {:?} : {}", side, error);
+      }
+    }
+  }
+}
+
+// The device a host sends its packets to the link through. Packets get delivered by run( ) itself,
+// so there's never anything to receive.
+struct LinkEnd {
+  link: Arc<Link>,
+
+  side: Side,
+}
+
+impl NicDevice for LinkEnd {
+  fn recv(&self, _buffer: &mut [u8]) -> io::Result<usize> {
+    Err(io::ErrorKind::WouldBlock.into())
+  }
+
+  fn send(&self, packet: &[u8]) -> io::Result<usize> {
+    self.link.send(self.side, packet);
+    Ok(packet.len())
+  }
+
+  fn wait(&self, readiness: Readiness, _deadline: Instant) -> io::Result<bool> {
+    Ok(readiness == Readiness::Writable)
+  }
+
+  fn name(&self) -> anyhow::Result<String> {
+    Ok(format!("sim-{:?}", self.side).to_lowercase())
+  }
+
+  fn set_mtu(&self, _mtu: u16) -> anyhow::Result<()> {
+    Ok(())
+  }
+}
+
+// A host, running its program.
+struct Host {
+  interface: Interface,
+
+  peerAddress: Ipv4Addr,
+
+  program: Vec<Action>,
+
+  // Index of the current action.
+  position: usize,
+
+  // The loops being run, innermost last, as the index of their first action and how many times
+  // they still run (including this time).
+  loops: Vec<(usize, usize)>,
+
+  // Bytes the current send or receive action has handed over so far.
+  progress: usize,
+
+  // When the current sleep action ends.
+  wakeAt: Option<Instant>,
+
+  connection: Option<Arc<SharedConnection>>,
+
+  bytesSent: u64,
+  bytesReceived: u64,
+  corruptBytes: u64,
+
+  finishedAt: Option<Duration>,
+  error: Option<String>,
+}
+
+impl Host {
+  fn new(
+    name: &'static str,
+    address: Ipv4Addr,
+    peerAddress: Ipv4Addr,
+    program: &[Action],
+    device: LinkEnd,
+    clock: Arc<VirtualClock>,
+  ) -> anyhow::Result<Self> {
+    let config = InterfaceConfig {
+      name: format!("sim-{}", name),
+      address,
+      ..InterfaceConfig::default()
+    };
+
+    Ok(Self {
+      interface: Interface::with_device_and_clock(config, device, clock)?,
+      peerAddress,
+      program: program.to_vec(),
+      position: 0,
+      loops: Vec::new(),
+      progress: 0,
+      wakeAt: None,
+      connection: None,
+      bytesSent: 0,
+      bytesReceived: 0,
+      corruptBytes: 0,
+      finishedAt: None,
+      error: None,
+    })
+  }
+
+  fn is_running(&self) -> bool {
+    self.error.is_none() && self.position < self.program.len()
+  }
+
+  // Whether the program is over, and the connections have nothing left to exchange.
+  fn is_settled(&self) -> bool {
+    !self.is_running()
+      && self
+        .interface
+        .connection_manager()
+        .connections()
+        .iter()
+        .all(|(_, connection)| {
+          matches!(
+            manager::lock_connection(connection).state(),
+            TCPConnectionState::TimeWait | TCPConnectionState::Closed
+          )
+        })
+  }
+
+  // Runs the program till it blocks. Returns whether it got anywhere.
+  fn step(&mut self, startedAt: Instant) -> bool {
+    let mut hasProgressed = false;
+
+    while self.is_running() {
+      match self.perform() {
+        Ok(true) => hasProgressed = true,
+        Ok(false) => break,
+
+        Err(error) => {
+          self.error = Some(format!("{} : {}", self.program[self.position], error));
+          hasProgressed = true;
+        }
+      }
+    }
+
+    if !self.is_running() && self.finishedAt.is_none() {
+      let now = self.interface.connection_manager().clock().now();
+      self.finishedAt = Some(now.saturating_duration_since(startedAt));
+    }
+    hasProgressed
+  }
+
+  // Moves the current action forward. Returns whether it did, or whether it's blocked instead.
+  fn perform(&mut self) -> anyhow::Result<bool> {
+    let connectionManager = self.interface.connection_manager().clone();
+    let mut ctx = connectionManager.send_context();
+
+    match self.program[self.position] {
+      Action::Listen(port) => connectionManager.listen(port),
+
+      Action::Accept(port) => match connectionManager.try_accept(port) {
+        Some(connection) => self.connection = Some(connection),
+        None => return Ok(false),
+      },
+
+      Action::Connect(port) => {
+        let Some(connection) = &self.connection
+        else {
+          let peer = Location {
+            address: self.peerAddress,
+            port,
+          };
+          self.connection = Some(connectionManager.start_connect(peer)?);
+          return Ok(true);
+        };
+
+        let tcb = manager::lock_connection(connection);
+        match tcb.state() {
+          TCPConnectionState::SYNSent | TCPConnectionState::SYNReceived => return Ok(false),
+
+          TCPConnectionState::Closed => {
+            return Err(anyhow!(
+              "Connection failed : {}",
+              tcb
+                .close_reason()
+                .map_or("closed".to_string(), |reason| reason.to_string())
+            ))
+          }
+
+          _ => {}
+        }
+      }
+
+      Action::Send(length) => {
+        let connection = self.connection()?;
+        let mut tcb = manager::lock_connection(&connection);
+
+        let data: Vec<u8> = (0..(length - self.progress).min(CHUNK_SIZE) as u64)
+          .map(|offset| pattern(self.bytesSent + offset))
+          .collect();
+        let bytesWritten = match tcb.write(&data, &mut ctx) {
+          Ok(bytesWritten) => bytesWritten,
+          Err(TcpError::WouldBlock) => return Ok(false),
+          Err(error) => return Err(error.into()),
+        };
+
+        self.bytesSent += bytesWritten as u64;
+        self.progress += bytesWritten;
+        if self.progress < length {
+          return Ok(true);
+        }
+      }
+
+      action @ (Action::Receive(_) | Action::ReceiveAll) => {
+        let connection = self.connection()?;
+        let mut tcb = manager::lock_connection(&connection);
+
+        let wanted = match action {
+          Action::Receive(length) => (length - self.progress).min(CHUNK_SIZE),
+          _ => CHUNK_SIZE,
+        };
+        let mut buffer = vec![0u8; wanted];
+        let bytesRead = match tcb.read(&mut buffer, &mut ctx) {
+          Ok(bytesRead) => bytesRead,
+          Err(TcpError::WouldBlock) => return Ok(false),
+          Err(error) => return Err(error.into()),
+        };
+
+        if bytesRead == 0 {
+          if let Action::Receive(length) = action {
+            return Err(anyhow!(
+              "Peer closed the connection after {} of {} bytes",
+              self.progress,
+              length
+            ));
+          }
+        }
+        else {
+          for (offset, byte) in buffer[..bytesRead].iter().enumerate() {
+            if *byte != pattern(self.bytesReceived + offset as u64) {
+              self.corruptBytes += 1;
+            }
+          }
+          self.bytesReceived += bytesRead as u64;
+          self.progress += bytesRead;
+
+          if action == Action::ReceiveAll || self.progress < action_length(action) {
+            return Ok(true);
+          }
+        }
+      }
+
+      Action::Sleep(duration) => {
+        let now = connectionManager.clock().now();
+        match self.wakeAt {
+          None => {
+            self.wakeAt = Some(now + duration);
+            return Ok(true);
+          }
+          Some(wakeAt) if now < wakeAt => return Ok(false),
+          Some(_) => self.wakeAt = None,
+        }
+      }
+
+      Action::Close => manager::lock_connection(&*self.connection()?).close(&mut ctx)?,
+
+      Action::Loop(count) => self.loops.push((self.position + 1, count)),
+      Action::End => {
+        let (firstAction, count) = self
+          .loops
+          .last_mut()
+          .expect("Loops got checked to be balanced");
+
+        if *count > 1 {
+          *count -= 1;
+          self.position = *firstAction;
+          return Ok(true);
+        }
+        self.loops.pop();
+      }
+    }
+
+    self.position += 1;
+    self.progress = 0;
+    Ok(true)
+  }
+
+  fn connection(&self) -> anyhow::Result<Arc<SharedConnection>> {
+    self
+      .connection
+      .clone()
+      .ok_or_else(|| anyhow!("No connection, accept or connect first"))
+  }
+
+  fn summary(&self) -> HostSummary {
+    HostSummary {
+      finishedAt: self.finishedAt,
+      bytesSent: self.bytesSent,
+      bytesReceived: self.bytesReceived,
+      corruptBytes: self.corruptBytes,
+      error: self.error.clone(),
+      retransmittedSegments: self
+        .interface
+        .tcp_counters()
+        .read(false)
+        .retransmittedSegments,
+    }
+  }
+}
+
+fn action_length(action: Action) -> usize {
+  match action {
+    Action::Send(length) | Action::Receive(length) => length,
+    _ => 0,
+  }
+}
+
+// The byte sent at the given offset of the stream.
+fn pattern(offset: u64) -> u8 {
+  (offset % 251) as u8
+}
+
+// A seeded xorshift64 generator, deciding which packets get lost or reordered.
+struct XorShift(u64);
+
+impl XorShift {
+  fn new(seed: u64) -> Self {
+    // Scrambled, since xorshift gets stuck at 0 and starts off poorly from small seeds.
+    Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+  }
+
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  // Whether an event of the given probability happens. Nothing gets drawn for one which can't.
+  fn chance(&mut self, probability: f64) -> bool {
+    probability > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < probability
+  }
+}
+
+// Parses the TOML described above. Every invalid line gets reported, instead of just the first one.
+impl FromStr for Scenario {
+  type Err = anyhow::Error;
+
+  fn from_str(file: &str) -> anyhow::Result<Self> {
+    let mut scenario = Self::default();
+    let mut errors = Vec::new();
+
+    for (index, line) in file.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      if let Err(error) = scenario.parse_line(line) {
+        errors.push(format!("line {} : {}", index + 1, error));
+      }
+    }
+
+    if !errors.is_empty() {
+      return Err(anyhow!("Invalid scenario :\n  {}", errors.join("\n  ")));
+    }
+    Ok(scenario)
+  }
+}
+
+impl Scenario {
+  fn parse_line(&mut self, line: &str) -> anyhow::Result<()> {
+    let (key, value) = line
+      .split_once('=')
+      .ok_or_else(|| anyhow!("Expected a line of the form <key> = <value>"))?;
+    let value = value.trim();
+
+    match key.trim() {
+      "seed" => {
+        self.seed = value
+          .parse()
+          .map_err(|error| anyhow!("Invalid seed '{}' : {}", value, error))?
+      }
+      "time_limit_ms" => self.timeLimit = Duration::from_millis(parse_number(value)?),
+      "delay_ms" => self.link.delay = Duration::from_millis(parse_number(value)?),
+      "bandwidth_kbps" => self.link.bandwidth = parse_number(value)?.saturating_mul(1000),
+      "loss_percent" => self.link.loss = parse_percentage(value)?,
+      "reorder_percent" => self.link.reorder = parse_percentage(value)?,
+      "pcap_directory" => self.pcapDirectory = Some(interface::parse_string(value)?.into()),
+      "client" => self.client = parse_program(value)?,
+      "server" => self.server = parse_program(value)?,
+
+      key => return Err(anyhow!("Unknown key '{}'", key)),
+    }
+
+    Ok(())
+  }
+}
+
+fn parse_number(value: &str) -> anyhow::Result<u64> {
+  value
+    .parse()
+    .map_err(|error| anyhow!("Invalid number '{}' : {}", value, error))
+}
+
+fn parse_percentage(value: &str) -> anyhow::Result<f64> {
+  let percentage = value
+    .parse::<f64>()
+    .map_err(|error| anyhow!("Invalid percentage '{}' : {}", value, error))?;
+
+  if !(0.0..=100.0).contains(&percentage) {
+    return Err(anyhow!(
+      "Invalid percentage '{}' : must be between 0 and 100",
+      value
+    ));
+  }
+  Ok(percentage / 100.0)
+}
+
+// Parses an array of actions, each of which is a quoted string. Every loop must have its end.
+fn parse_program(value: &str) -> anyhow::Result<Vec<Action>> {
+  let program = interface::parse_array(value)?
+    .split(',')
+    .map(str::trim)
+    .filter(|action| !action.is_empty())
+    .map(|action| interface::parse_string(action)?.parse())
+    .collect::<anyhow::Result<Vec<Action>>>()?;
+
+  let mut depth = 0usize;
+  for action in &program {
+    match action {
+      Action::Loop(_) => depth += 1,
+      Action::End => {
+        depth = depth
+          .checked_sub(1)
+          .ok_or_else(|| anyhow!("end without a loop"))?
+      }
+      _ => {}
+    }
+  }
+  if depth > 0 {
+    return Err(anyhow!("loop without an end"));
+  }
+
+  Ok(program)
+}
+
+impl FromStr for Action {
+  type Err = anyhow::Error;
+
+  fn from_str(action: &str) -> anyhow::Result<Self> {
+    let mut words = action.split_whitespace();
+    let name = words.next().unwrap_or_default();
+    let argument = words.next();
+    if words.next().is_some() {
+      return Err(anyhow!("Too many arguments in '{}'", action));
+    }
+
+    let number = || -> anyhow::Result<u64> {
+      let argument = argument.ok_or_else(|| anyhow!("'{}' expects an argument", name))?;
+      parse_number(argument)
+    };
+    let port = || -> anyhow::Result<u16> {
+      u16::try_from(number()?)
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| anyhow!("Invalid port in '{}'", action))
+    };
+
+    let action = match name {
+      "listen" => Self::Listen(port()?),
+      "accept" => Self::Accept(port()?),
+      "connect" => Self::Connect(port()?),
+      "send" => Self::Send(number()? as usize),
+      "receive" => Self::Receive(number()? as usize),
+      "sleep" => Self::Sleep(Duration::from_millis(number()?)),
+      "loop" => match number()? {
+        0 => return Err(anyhow!("'{}' must run at least once", action)),
+        count => Self::Loop(count as usize),
+      },
+
+      "receive-all" | "close" | "end" if argument.is_some() => {
+        return Err(anyhow!("'{}' takes no argument", name))
+      }
+      "receive-all" => Self::ReceiveAll,
+      "close" => Self::Close,
+      "end" => Self::End,
+
+      _ => return Err(anyhow!("Unknown action '{}'", action)),
+    };
+    Ok(action)
+  }
+}
+
+impl Display for Action {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Listen(port) => write!(f, "listen {}", port),
+      Self::Accept(port) => write!(f, "accept {}", port),
+      Self::Connect(port) => write!(f, "connect {}", port),
+      Self::Send(length) => write!(f, "send {}", length),
+      Self::Receive(length) => write!(f, "receive {}", length),
+      Self::ReceiveAll => write!(f, "receive-all"),
+      Self::Sleep(duration) => write!(f, "sleep {}", duration.as_millis()),
+      Self::Close => write!(f, "close"),
+      Self::Loop(count) => write!(f, "loop {}", count),
+      Self::End => write!(f, "end"),
+    }
+  }
+}
+
+impl Display for SimulationSummary {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(
+      f,
+      "Simulation {} after {:.3?} of virtual time",
+      if self.isCompleted {
+        "completed"
+      }
+      else {
+        "stopped"
+      },
+      self.elapsed
+    )?;
+    writeln!(
+      f,
+      "  link   : {} packets sent, {} delivered, {} dropped, {} reordered",
+      self.link.sent, self.link.delivered, self.link.dropped, self.link.reordered
+    )?;
+    writeln!(f, "  client : {}", self.client)?;
+    writeln!(f, "  server : {}", self.server)
+  }
+}
+
+impl Display for HostSummary {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "sent {} bytes, received {} bytes ({} corrupt), {} retransmissions",
+      self.bytesSent, self.bytesReceived, self.corruptBytes, self.retransmittedSegments
+    )?;
+
+    if let Some(finishedAt) = self.finishedAt {
+      write!(
+        f,
+        ", finished at {:.3?} ({:.1} KB/s)",
+        finishedAt,
+        self.goodput() / 1024.0
+      )?;
+    }
+    if let Some(error) = &self.error {
+      write!(f, ", failed at {}", error)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn run_scenario(scenario: &str) -> SimulationSummary {
+    let summary = run(&scenario.parse().unwrap()).unwrap();
+    print!("{}", summary);
+    summary
+  }
+
+  /*
+    The window is RECEIVE_BUFFER_CAPACITY, so a bulk transfer moves at most a window (1KB) per
+    round trip (20ms) : around 50KB/s. The envelopes leave room for the handshake and the closes.
+  */
+  #[test]
+  fn a_lossless_bulk_transfer_moves_a_window_per_round_trip() {
+    let summary = run_scenario(include_str!("../scenarios/bulk-lossless.toml"));
+
+    assert!(summary.isCompleted);
+    assert_eq!(summary.server.bytesReceived, 256 * 1024);
+    assert_eq!(summary.server.corruptBytes, 0);
+
+    assert_eq!(summary.link.dropped, 0);
+    assert_eq!(summary.link.sent, summary.link.delivered);
+    assert_eq!(summary.client.retransmittedSegments, 0);
+    assert_eq!(summary.server.retransmittedSegments, 0);
+
+    assert!((Duration::from_secs(4)..Duration::from_secs(7)).contains(&summary.elapsed));
+    assert!(summary.server.goodput() > 40.0 * 1024.0);
+  }
+
+  #[test]
+  fn a_lossy_bulk_transfer_recovers_every_lost_segment() {
+    let summary = run_scenario(include_str!("../scenarios/bulk-lossy.toml"));
+
+    assert!(summary.isCompleted);
+    assert_eq!(summary.server.bytesReceived, 256 * 1024);
+    assert_eq!(summary.server.corruptBytes, 0);
+
+    // Around 5% of the packets get lost, each costing a retransmission timeout of the client.
+    assert!((20..=80).contains(&summary.link.dropped));
+    assert!(summary.client.retransmittedSegments > 0);
+    assert!(summary.client.retransmittedSegments <= summary.link.dropped * 2);
+
+    assert!((Duration::from_secs(10)..Duration::from_secs(90)).contains(&summary.elapsed));
+    assert!(summary.server.goodput() > 3.0 * 1024.0);
+  }
+
+  #[test]
+  fn interactive_requests_take_a_round_trip_each() {
+    let summary = run_scenario(include_str!("../scenarios/interactive.toml"));
+
+    assert!(summary.isCompleted);
+    assert_eq!(summary.client.bytesReceived, 50 * 1024);
+    assert_eq!(summary.server.bytesReceived, 50 * 64);
+    assert_eq!(summary.client.corruptBytes + summary.server.corruptBytes, 0);
+    assert_eq!(summary.client.retransmittedSegments, 0);
+
+    // 50 round trips of 20ms, along with the handshake and the closes.
+    assert!((Duration::from_millis(1000)..Duration::from_millis(1500)).contains(&summary.elapsed));
+  }
+
+  #[test]
+  fn the_same_seed_gives_the_same_run() {
+    let first = run_scenario(include_str!("../scenarios/bulk-lossy.toml"));
+    let second = run_scenario(include_str!("../scenarios/bulk-lossy.toml"));
+
+    assert_eq!(first.link, second.link);
+    assert_eq!(first.elapsed, second.elapsed);
+  }
+
+  #[test]
+  fn a_peer_which_never_accepts_stops_the_run_at_its_time_limit() {
+    let summary = run_scenario(
+      r#"
+        time_limit_ms = 5000
+        client = ["connect 80", "send 100000", "close"]
+        server = ["listen 80", "sleep 60000"]
+      "#,
+    );
+
+    assert!(!summary.isCompleted);
+    assert_eq!(summary.elapsed, Duration::from_secs(5));
+    assert!(summary.client.finishedAt.is_none());
+  }
+
+  #[test]
+  fn invalid_scenarios_report_every_invalid_line() {
+    let error = r#"
+      seed = seven
+      loss_percent = 120
+      client = ["loop 3", "send 10"]
+      server = ["jump 80"]
+    "#
+    .parse::<Scenario>()
+    .err()
+    .unwrap()
+    .to_string();
+
+    for line in ["line 2", "line 3", "line 4", "line 5"] {
+      assert!(error.contains(line), "{}", error);
+    }
+  }
+}
@@ -0,0 +1,836 @@
+use {
+  crate::{
+    extensions::Extension,
+    json::{JsonObject, RawJson, ToJson},
+    tcp,
+  },
+  etherparse::TcpHeaderSlice,
+  std::{
+    fmt::{self, Display, Formatter},
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Arc,
+    },
+  },
+};
+
+/*
+  Statistics about the segments received on a connection : which flags and options the peer uses,
+  and how large its segments are. Knowing these is surprisingly useful when debugging interop
+  against exotic peers (industrial equipment, old stacks etc.).
+
+  Recording a segment only costs a handful of integer increments. Some of these get summed up over
+  the whole interface too, in the TcpCounters shared by every connection.
+*/
+pub struct ConnectionStats {
+  interface: Arc<TcpCounters>,
+
+  flags: FlagCounters,
+
+  options: OptionCounters,
+
+  // Received segment payload sizes, bucketed as 0, 1-64, 65-512, 513-MSS and >MSS bytes.
+  payloadSizes: [u64; 5],
+
+  // Retransmissions of the SYN-ACK which established the connection, received after our
+  // handshake ACK got lost.
+  duplicateSYNACKs: u64,
+
+  // ACKs sent in reply to a SYN or a RST with an inexact sequence number, on the synchronized
+  // connection (RFC 5961). A peer which lost the connection answers them with a RST, aborting it.
+  challengeAcknowledgements: u64,
+
+  // Received segments which started below RCV.NXT, but carried new data past it. Peers send these
+  // when retransmitting from their SND.UNA with a larger segment than the one which got lost.
+  overlappingSegments: u64,
+
+  // Times the peer kept retransmitting data we had acknowledged, suggesting our ACKs got lost.
+  reverseLossSuspicions: u64,
+
+  // Bare ACKs deferred to the next tick, since they went over the control segment budget.
+  controlSegmentBudgetHits: u64,
+
+  // Received segments, after which the readers weren't woken up due to receive coalescing.
+  deferredWakeups: u64,
+
+  // Times a writer, blocked on a full send buffer, got let through again.
+  writerWakeups: u64,
+
+  // Unsolicited window update ACKs sent, and the ones held back by the rate limit.
+  windowUpdatesSent: u64,
+  windowUpdatesSuppressed: u64,
+
+  // Data segments and FINs sent again, since they went unacknowledged for too long.
+  retransmissions: u64,
+
+  // Received segments which arrived ahead of a gap, and got stashed till it's filled.
+  outOfOrderSegments: u64,
+
+  // Extensions we offered, but didn't get to use : the peer declined them, or its options for
+  // them went missing (see extensions.rs).
+  windowScaleFallbacks: u64,
+  selectiveAcknowledgementFallbacks: u64,
+  timestampFallbacks: u64,
+
+  // Whether we sent our FIN first (an active close) or the peer did (a passive one), once either
+  // did.
+  isActiveClose: Option<bool>,
+}
+
+/*
+  Counters summed up over every connection of the interface, in the spirit of Linux's
+  /proc/net/snmp and TcpExt counters. Each one is bumped by a single atomic increment, right where
+  the matching per-connection stat gets recorded, so no lock is involved.
+
+  The number of connections is a gauge instead, kept up to date by the connection manager.
+*/
+#[derive(Default)]
+pub struct TcpCounters {
+  // Handshakes completed, for connections opened by a peer or by us.
+  pub passiveOpens: AtomicU64,
+  pub activeOpens: AtomicU64,
+
+  // Connections closed by the peer sending its FIN first (entering CLOSE-WAIT), or by us sending
+  // ours first (entering FIN-WAIT-1).
+  pub passiveCloses: AtomicU64,
+  pub activeCloses: AtomicU64,
+
+  // RSTs sent, including those answering segments which belong to no connection, and RSTs
+  // received on a connection.
+  pub resetsSent: AtomicU64,
+  pub resetsReceived: AtomicU64,
+
+  // Data segments and FINs sent again, since they went unacknowledged for too long.
+  pub retransmittedSegments: AtomicU64,
+
+  // Connections given up on after a timer expired : the user, connect or FIN-WAIT-2 timeout.
+  pub timeouts: AtomicU64,
+
+  pub challengeAcknowledgements: AtomicU64,
+
+  pub outOfOrderSegments: AtomicU64,
+
+  pub windowScaleFallbacks: AtomicU64,
+  pub selectiveAcknowledgementFallbacks: AtomicU64,
+  pub timestampFallbacks: AtomicU64,
+
+  // Connections in the connection map now, and the most there have been at once.
+  pub currentConnections: AtomicU64,
+  pub peakConnections: AtomicU64,
+}
+
+// The TcpCounters as read at some point.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpCountersSnapshot {
+  pub passiveOpens: u64,
+  pub activeOpens: u64,
+  pub passiveCloses: u64,
+  pub activeCloses: u64,
+  pub resetsSent: u64,
+  pub resetsReceived: u64,
+  pub retransmittedSegments: u64,
+  pub timeouts: u64,
+  pub challengeAcknowledgements: u64,
+  pub outOfOrderSegments: u64,
+  pub windowScaleFallbacks: u64,
+  pub selectiveAcknowledgementFallbacks: u64,
+  pub timestampFallbacks: u64,
+  pub currentConnections: u64,
+  pub peakConnections: u64,
+}
+
+#[derive(Default)]
+struct FlagCounters {
+  syn: u64,
+  fin: u64,
+  rst: u64,
+  psh: u64,
+  urg: u64,
+  ece: u64,
+  cwr: u64,
+
+  // Segments dropped, since they carried a combination of flags no sane peer sends.
+  nonsensical: u64,
+}
+
+#[derive(Default)]
+struct OptionCounters {
+  nop: u64,
+  maximumSegmentSize: u64,
+  windowScale: u64,
+  selectiveAcknowledgementPermitted: u64,
+  selectiveAcknowledgement: u64,
+  timestamp: u64,
+  unknown: u64,
+}
+
+impl ConnectionStats {
+  // Stats of a new connection, summed up into the given interface counters.
+  pub fn new(interface: Arc<TcpCounters>) -> Self {
+    Self {
+      interface,
+      flags: FlagCounters::default(),
+      options: OptionCounters::default(),
+      payloadSizes: [0; 5],
+      duplicateSYNACKs: 0,
+      challengeAcknowledgements: 0,
+      overlappingSegments: 0,
+      reverseLossSuspicions: 0,
+      controlSegmentBudgetHits: 0,
+      deferredWakeups: 0,
+      writerWakeups: 0,
+      windowUpdatesSent: 0,
+      windowUpdatesSuppressed: 0,
+      retransmissions: 0,
+      outOfOrderSegments: 0,
+      windowScaleFallbacks: 0,
+      selectiveAcknowledgementFallbacks: 0,
+      timestampFallbacks: 0,
+      isActiveClose: None,
+    }
+  }
+
+  // Records a received segment, whichever path it takes (pure ACKs included). The MSS is the
+  // largest payload we're prepared to receive.
+  pub fn record_segment(
+    &mut self,
+    tcpHeader: &TcpHeaderSlice,
+    payloadLength: usize,
+    maximumSegmentSize: usize,
+  ) {
+    self.flags.syn += tcpHeader.syn() as u64;
+    self.flags.fin += tcpHeader.fin() as u64;
+    self.flags.rst += tcpHeader.rst() as u64;
+    self.flags.psh += tcpHeader.psh() as u64;
+    self.flags.urg += tcpHeader.urg() as u64;
+    self.flags.ece += tcpHeader.ece() as u64;
+    self.flags.cwr += tcpHeader.cwr() as u64;
+
+    if tcpHeader.rst() {
+      self
+        .interface
+        .resetsReceived
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    self.options.record(tcpHeader.options());
+
+    let bucket = match payloadLength {
+      0 => 0,
+      1..=64 => 1,
+      65..=512 => 2,
+      _ if payloadLength <= maximumSegmentSize => 3,
+      _ => 4,
+    };
+    self.payloadSizes[bucket] += 1;
+  }
+
+  pub fn record_nonsensical_segment(&mut self) {
+    self.flags.nonsensical += 1;
+  }
+
+  pub fn record_duplicate_syn_ack(&mut self) {
+    self.duplicateSYNACKs += 1;
+  }
+
+  pub fn record_challenge_acknowledgement(&mut self) {
+    self.challengeAcknowledgements += 1;
+    self
+      .interface
+      .challengeAcknowledgements
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_overlapping_segment(&mut self) {
+    self.overlappingSegments += 1;
+  }
+
+  pub fn record_reverse_loss_suspicion(&mut self) {
+    self.reverseLossSuspicions += 1;
+  }
+
+  pub fn record_control_segment_budget_hit(&mut self) {
+    self.controlSegmentBudgetHits += 1;
+  }
+
+  pub fn record_deferred_wakeup(&mut self) {
+    self.deferredWakeups += 1;
+  }
+
+  pub fn record_writer_wakeup(&mut self) {
+    self.writerWakeups += 1;
+  }
+
+  pub fn record_window_update_sent(&mut self) {
+    self.windowUpdatesSent += 1;
+  }
+
+  pub fn record_window_update_suppressed(&mut self) {
+    self.windowUpdatesSuppressed += 1;
+  }
+
+  pub fn record_retransmission(&mut self) {
+    self.retransmissions += 1;
+    self
+      .interface
+      .retransmittedSegments
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_out_of_order_segment(&mut self) {
+    self.outOfOrderSegments += 1;
+    self
+      .interface
+      .outOfOrderSegments
+      .fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_extension_fallback(&mut self, extension: Extension) {
+    let (fallbacks, counter) = match extension {
+      Extension::WindowScale => (
+        &mut self.windowScaleFallbacks,
+        &self.interface.windowScaleFallbacks,
+      ),
+      Extension::SelectiveAcknowledgement => (
+        &mut self.selectiveAcknowledgementFallbacks,
+        &self.interface.selectiveAcknowledgementFallbacks,
+      ),
+      Extension::Timestamps => (
+        &mut self.timestampFallbacks,
+        &self.interface.timestampFallbacks,
+      ),
+    };
+    *fallbacks += 1;
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // Records the connection getting established, which only the interface counters keep track of.
+  pub fn record_established(&mut self, isPassiveOpen: bool) {
+    let counter = if isPassiveOpen {
+      &self.interface.passiveOpens
+    }
+    else {
+      &self.interface.activeOpens
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  // Records which side sent its FIN first. Only the first call counts.
+  pub fn record_close(&mut self, isActiveClose: bool) {
+    if self.isActiveClose.is_some() {
+      return;
+    }
+    self.isActiveClose = Some(isActiveClose);
+
+    let counter = if isActiveClose {
+      &self.interface.activeCloses
+    }
+    else {
+      &self.interface.passiveCloses
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_reset_sent(&mut self) {
+    self.interface.resetsSent.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_timeout(&mut self) {
+    self.interface.timeouts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn retransmissions(&self) -> u64 {
+    self.retransmissions
+  }
+
+  pub fn writer_wakeups(&self) -> u64 {
+    self.writerWakeups
+  }
+
+  pub fn nonsensical_segments(&self) -> u64 {
+    self.flags.nonsensical
+  }
+
+  pub fn duplicate_syn_acks(&self) -> u64 {
+    self.duplicateSYNACKs
+  }
+
+  pub fn challenge_acknowledgements(&self) -> u64 {
+    self.challengeAcknowledgements
+  }
+
+  pub fn overlapping_segments(&self) -> u64 {
+    self.overlappingSegments
+  }
+
+  pub fn window_updates_sent(&self) -> u64 {
+    self.windowUpdatesSent
+  }
+
+  pub fn window_updates_suppressed(&self) -> u64 {
+    self.windowUpdatesSuppressed
+  }
+
+  pub fn extension_fallbacks(&self, extension: Extension) -> u64 {
+    match extension {
+      Extension::WindowScale => self.windowScaleFallbacks,
+      Extension::SelectiveAcknowledgement => self.selectiveAcknowledgementFallbacks,
+      Extension::Timestamps => self.timestampFallbacks,
+    }
+  }
+
+  // Received segments carrying the option of the given kind (unknown kinds counted together).
+  pub fn options_received(&self, kind: u8) -> u64 {
+    match kind {
+      1 => self.options.nop,
+      2 => self.options.maximumSegmentSize,
+      3 => self.options.windowScale,
+      4 => self.options.selectiveAcknowledgementPermitted,
+      5 => self.options.selectiveAcknowledgement,
+      8 => self.options.timestamp,
+      _ => self.options.unknown,
+    }
+  }
+
+  // Received segments, bucketed by their payload size like in the Display output.
+  pub fn payload_sizes(&self) -> [u64; 5] {
+    self.payloadSizes
+  }
+}
+
+impl OptionCounters {
+  fn record(&mut self, options: &[u8]) {
+    for kind in tcp::option_kinds(options) {
+      let counter = match kind {
+        1 => &mut self.nop,
+        2 => &mut self.maximumSegmentSize,
+        3 => &mut self.windowScale,
+        4 => &mut self.selectiveAcknowledgementPermitted,
+        5 => &mut self.selectiveAcknowledgement,
+        8 => &mut self.timestamp,
+        _ => &mut self.unknown,
+      };
+      *counter += 1;
+    }
+  }
+}
+
+impl Display for ConnectionStats {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let FlagCounters {
+      syn,
+      fin,
+      rst,
+      psh,
+      urg,
+      ece,
+      cwr,
+      nonsensical,
+    } = self.flags;
+    writeln!(
+      f,
+      "  flags : SYN {} FIN {} RST {} PSH {} URG {} ECE {} CWR {} | nonsensical {}",
+      syn, fin, rst, psh, urg, ece, cwr, nonsensical
+    )?;
+
+    let OptionCounters {
+      nop,
+      maximumSegmentSize,
+      windowScale,
+      selectiveAcknowledgementPermitted,
+      selectiveAcknowledgement,
+      timestamp,
+      unknown,
+    } = self.options;
+    writeln!(
+      f,
+      "  options : NOP {} MSS {} WS {} SACK-permitted {} SACK {} TS {} unknown {}",
+      nop,
+      maximumSegmentSize,
+      windowScale,
+      selectiveAcknowledgementPermitted,
+      selectiveAcknowledgement,
+      timestamp,
+      unknown
+    )?;
+
+    let [empty, tiny, small, upToMSS, aboveMSS] = self.payloadSizes;
+    writeln!(
+      f,
+      "  payload sizes : 0 {} | 1-64 {} | 65-512 {} | 513-MSS {} | >MSS {}",
+      empty, tiny, small, upToMSS, aboveMSS
+    )?;
+
+    writeln!(
+      f,
+      "  duplicate SYN-ACKs : {} | deferred wakeups : {} | writer wakeups : {}",
+      self.duplicateSYNACKs, self.deferredWakeups, self.writerWakeups
+    )?;
+
+    writeln!(
+      f,
+      "  overlapping segments : {} | reverse loss suspected : {} | control budget hits : {}",
+      self.overlappingSegments, self.reverseLossSuspicions, self.controlSegmentBudgetHits
+    )?;
+
+    writeln!(
+      f,
+      "  challenge ACKs : {} | window updates : sent {} | suppressed {} | retransmissions : {}",
+      self.challengeAcknowledgements,
+      self.windowUpdatesSent,
+      self.windowUpdatesSuppressed,
+      self.retransmissions
+    )?;
+
+    let close = match self.isActiveClose {
+      Some(true) => "active",
+      Some(false) => "passive",
+      None => "-",
+    };
+    writeln!(
+      f,
+      "  out of order segments : {} | fallbacks : WS {} SACK {} TS {} | close : {}",
+      self.outOfOrderSegments,
+      self.windowScaleFallbacks,
+      self.selectiveAcknowledgementFallbacks,
+      self.timestampFallbacks,
+      close
+    )
+  }
+}
+
+impl ToJson for ConnectionStats {
+  fn write_json(&self, json: &mut String) {
+    let mut flags = String::new();
+    JsonObject::new(&mut flags)
+      .field("syn", &self.flags.syn)
+      .field("fin", &self.flags.fin)
+      .field("rst", &self.flags.rst)
+      .field("psh", &self.flags.psh)
+      .field("urg", &self.flags.urg)
+      .field("ece", &self.flags.ece)
+      .field("cwr", &self.flags.cwr)
+      .field("nonsensical", &self.flags.nonsensical)
+      .finish();
+
+    let mut options = String::new();
+    JsonObject::new(&mut options)
+      .field("nop", &self.options.nop)
+      .field("mss", &self.options.maximumSegmentSize)
+      .field("window_scale", &self.options.windowScale)
+      .field(
+        "sack_permitted",
+        &self.options.selectiveAcknowledgementPermitted,
+      )
+      .field("sack", &self.options.selectiveAcknowledgement)
+      .field("timestamp", &self.options.timestamp)
+      .field("unknown", &self.options.unknown)
+      .finish();
+
+    // Bucketed as 0, 1-64, 65-512, 513-MSS and >MSS bytes, like in the Display output.
+    let [empty, tiny, small, upToMSS, aboveMSS] = self.payloadSizes;
+    let mut payloadSizes = String::new();
+    JsonObject::new(&mut payloadSizes)
+      .field("empty", &empty)
+      .field("up_to_64", &tiny)
+      .field("up_to_512", &small)
+      .field("up_to_mss", &upToMSS)
+      .field("above_mss", &aboveMSS)
+      .finish();
+
+    JsonObject::new(json)
+      .field("flags", &RawJson(flags))
+      .field("options", &RawJson(options))
+      .field("payload_sizes", &RawJson(payloadSizes))
+      .field("duplicate_syn_acks", &self.duplicateSYNACKs)
+      .field("challenge_acks", &self.challengeAcknowledgements)
+      .field("overlapping_segments", &self.overlappingSegments)
+      .field("reverse_loss_suspicions", &self.reverseLossSuspicions)
+      .field("control_budget_hits", &self.controlSegmentBudgetHits)
+      .field("deferred_wakeups", &self.deferredWakeups)
+      .field("writer_wakeups", &self.writerWakeups)
+      .field("window_updates_sent", &self.windowUpdatesSent)
+      .field("window_updates_suppressed", &self.windowUpdatesSuppressed)
+      .field("retransmissions", &self.retransmissions)
+      .field("out_of_order_segments", &self.outOfOrderSegments)
+      .field("window_scale_fallbacks", &self.windowScaleFallbacks)
+      .field("sack_fallbacks", &self.selectiveAcknowledgementFallbacks)
+      .field("timestamp_fallbacks", &self.timestampFallbacks)
+      .field(
+        "close",
+        &self.isActiveClose.map(|isActiveClose| {
+          if isActiveClose {
+            "active"
+          }
+          else {
+            "passive"
+          }
+        }),
+      )
+      .finish();
+  }
+}
+
+impl TcpCounters {
+  /*
+    Reads every counter. When resetting, each one starts over from 0 right as it's read, so that
+    the next read shows what happened in between. The number of connections is a gauge, which
+    doesn't get reset. Its peak starts over from the current number of connections though.
+  */
+  pub fn read(&self, reset: bool) -> TcpCountersSnapshot {
+    let read = |counter: &AtomicU64| {
+      if reset {
+        counter.swap(0, Ordering::Relaxed)
+      }
+      else {
+        counter.load(Ordering::Relaxed)
+      }
+    };
+
+    let currentConnections = self.currentConnections.load(Ordering::Relaxed);
+    let peakConnections = if reset {
+      self
+        .peakConnections
+        .swap(currentConnections, Ordering::Relaxed)
+    }
+    else {
+      self.peakConnections.load(Ordering::Relaxed)
+    };
+
+    TcpCountersSnapshot {
+      passiveOpens: read(&self.passiveOpens),
+      activeOpens: read(&self.activeOpens),
+      passiveCloses: read(&self.passiveCloses),
+      activeCloses: read(&self.activeCloses),
+      resetsSent: read(&self.resetsSent),
+      resetsReceived: read(&self.resetsReceived),
+      retransmittedSegments: read(&self.retransmittedSegments),
+      timeouts: read(&self.timeouts),
+      challengeAcknowledgements: read(&self.challengeAcknowledgements),
+      outOfOrderSegments: read(&self.outOfOrderSegments),
+      windowScaleFallbacks: read(&self.windowScaleFallbacks),
+      selectiveAcknowledgementFallbacks: read(&self.selectiveAcknowledgementFallbacks),
+      timestampFallbacks: read(&self.timestampFallbacks),
+      currentConnections,
+      peakConnections,
+    }
+  }
+
+  // Updates the gauge, after a connection got inserted into or removed from the connection map.
+  pub fn record_connection_count(&self, connections: usize) {
+    let connections = connections as u64;
+
+    self
+      .currentConnections
+      .store(connections, Ordering::Relaxed);
+    self
+      .peakConnections
+      .fetch_max(connections, Ordering::Relaxed);
+  }
+}
+
+impl Display for TcpCountersSnapshot {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    writeln!(f, "passiveOpens {}", self.passiveOpens)?;
+    writeln!(f, "activeOpens {}", self.activeOpens)?;
+    writeln!(f, "passiveCloses {}", self.passiveCloses)?;
+    writeln!(f, "activeCloses {}", self.activeCloses)?;
+    writeln!(f, "resetsSent {}", self.resetsSent)?;
+    writeln!(f, "resetsReceived {}", self.resetsReceived)?;
+    writeln!(f, "retransmittedSegments {}", self.retransmittedSegments)?;
+    writeln!(f, "timeouts {}", self.timeouts)?;
+    writeln!(
+      f,
+      "challengeAcknowledgements {}",
+      self.challengeAcknowledgements
+    )?;
+    writeln!(f, "outOfOrderSegments {}", self.outOfOrderSegments)?;
+    writeln!(f, "windowScaleFallbacks {}", self.windowScaleFallbacks)?;
+    writeln!(
+      f,
+      "selectiveAcknowledgementFallbacks {}",
+      self.selectiveAcknowledgementFallbacks
+    )?;
+    writeln!(f, "timestampFallbacks {}", self.timestampFallbacks)?;
+    writeln!(f, "currentConnections {}", self.currentConnections)?;
+    writeln!(f, "peakConnections {}", self.peakConnections)
+  }
+}
+
+impl ToJson for TcpCountersSnapshot {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("passive_opens", &self.passiveOpens)
+      .field("active_opens", &self.activeOpens)
+      .field("passive_closes", &self.passiveCloses)
+      .field("active_closes", &self.activeCloses)
+      .field("resets_sent", &self.resetsSent)
+      .field("resets_received", &self.resetsReceived)
+      .field("retransmitted_segments", &self.retransmittedSegments)
+      .field("timeouts", &self.timeouts)
+      .field("challenge_acks", &self.challengeAcknowledgements)
+      .field("out_of_order_segments", &self.outOfOrderSegments)
+      .field("window_scale_fallbacks", &self.windowScaleFallbacks)
+      .field("sack_fallbacks", &self.selectiveAcknowledgementFallbacks)
+      .field("timestamp_fallbacks", &self.timestampFallbacks)
+      .field("current_connections", &self.currentConnections)
+      .field("peak_connections", &self.peakConnections)
+      .finish();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    etherparse::{TcpHeader, TcpOptionElement},
+  };
+
+  fn header(configure: impl FnOnce(&mut TcpHeader)) -> Vec<u8> {
+    let mut tcpHeader = TcpHeader::new(51514, 8080, 1000, 65535);
+    tcpHeader.ack = true;
+    configure(&mut tcpHeader);
+    tcpHeader.to_bytes().to_vec()
+  }
+
+  fn record_segment(stats: &mut ConnectionStats, bytes: &[u8], payloadLength: usize) {
+    let tcpHeader = TcpHeaderSlice::from_slice(bytes).unwrap();
+    stats.record_segment(&tcpHeader, payloadLength, 1460);
+  }
+
+  #[test]
+  fn segments_get_bucketed_by_flags_options_and_payload_size() {
+    let mut stats = ConnectionStats::new(Arc::default());
+
+    let syn = header(|tcpHeader| {
+      tcpHeader.syn = true;
+      tcpHeader
+        .set_options(&[
+          TcpOptionElement::MaximumSegmentSize(1460),
+          TcpOptionElement::Noop,
+          TcpOptionElement::WindowScale(7),
+          TcpOptionElement::SelectiveAcknowledgementPermitted,
+          TcpOptionElement::Timestamp(1, 0),
+        ])
+        .unwrap();
+    });
+    record_segment(&mut stats, &syn, 0);
+
+    let data = header(|tcpHeader| tcpHeader.psh = true);
+    for payloadLength in [1, 64, 65, 512, 513, 1460, 1461] {
+      record_segment(&mut stats, &data, payloadLength);
+    }
+    record_segment(&mut stats, &header(|_| {}), 0);
+
+    assert_eq!(stats.flags.syn, 1);
+    assert_eq!(stats.flags.psh, 7);
+    assert_eq!(stats.flags.fin + stats.flags.rst + stats.flags.urg, 0);
+    assert_eq!(stats.options_received(1), 1);
+    assert_eq!(stats.options_received(2), 1);
+    assert_eq!(stats.options_received(3), 1);
+    assert_eq!(stats.options_received(4), 1);
+    assert_eq!(stats.options_received(8), 1);
+    assert_eq!(stats.options_received(5), 0);
+    assert_eq!(stats.payload_sizes(), [2, 2, 2, 2, 1]);
+  }
+
+  #[test]
+  fn connections_sum_up_into_the_interface_counters() {
+    let counters = Arc::new(TcpCounters::default());
+    let mut passive = ConnectionStats::new(counters.clone());
+    let mut active = ConnectionStats::new(counters.clone());
+
+    passive.record_established(true);
+    active.record_established(false);
+
+    // Only the first side to send its FIN counts.
+    passive.record_close(false);
+    passive.record_close(true);
+    active.record_close(true);
+
+    for _ in 0..3 {
+      passive.record_reset_sent();
+    }
+    let reset = header(|tcpHeader| tcpHeader.rst = true);
+    record_segment(&mut active, &reset, 0);
+    record_segment(&mut passive, &reset, 0);
+
+    for _ in 0..4 {
+      active.record_retransmission();
+    }
+    active.record_timeout();
+    for _ in 0..5 {
+      passive.record_challenge_acknowledgement();
+    }
+    for _ in 0..6 {
+      active.record_out_of_order_segment();
+    }
+    active.record_extension_fallback(Extension::Timestamps);
+    passive.record_extension_fallback(Extension::Timestamps);
+    passive.record_extension_fallback(Extension::SelectiveAcknowledgement);
+
+    counters.record_connection_count(3);
+    counters.record_connection_count(7);
+    counters.record_connection_count(2);
+
+    let expected = TcpCountersSnapshot {
+      passiveOpens: 1,
+      activeOpens: 1,
+      passiveCloses: 1,
+      activeCloses: 1,
+      resetsSent: 3,
+      resetsReceived: 2,
+      retransmittedSegments: 4,
+      timeouts: 1,
+      challengeAcknowledgements: 5,
+      outOfOrderSegments: 6,
+      windowScaleFallbacks: 0,
+      selectiveAcknowledgementFallbacks: 1,
+      timestampFallbacks: 2,
+      currentConnections: 2,
+      peakConnections: 7,
+    };
+    assert_eq!(counters.read(false), expected);
+    assert_eq!(counters.read(true), expected);
+
+    // The per-connection stats don't get reset along.
+    assert_eq!(active.retransmissions(), 4);
+    assert_eq!(passive.challengeAcknowledgements, 5);
+  }
+
+  #[test]
+  fn reading_with_reset_starts_the_counters_over() {
+    let counters = Arc::new(TcpCounters::default());
+    let mut stats = ConnectionStats::new(counters.clone());
+
+    stats.record_retransmission();
+    stats.record_timeout();
+    counters.record_connection_count(9);
+    counters.record_connection_count(4);
+    counters.read(true);
+
+    // The peak restarts from the current number of connections, which is a gauge.
+    assert_eq!(
+      counters.read(false),
+      TcpCountersSnapshot {
+        currentConnections: 4,
+        peakConnections: 4,
+        ..TcpCountersSnapshot::default()
+      }
+    );
+
+    stats.record_out_of_order_segment();
+    counters.record_connection_count(6);
+    counters.record_connection_count(1);
+    assert_eq!(
+      counters.read(true),
+      TcpCountersSnapshot {
+        outOfOrderSegments: 1,
+        currentConnections: 1,
+        peakConnections: 6,
+        ..TcpCountersSnapshot::default()
+      }
+    );
+    assert_eq!(counters.read(false).peakConnections, 1);
+  }
+}
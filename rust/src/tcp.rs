@@ -1,33 +1,492 @@
 use {
+  crate::{
+    clock::Clock,
+    error::TcpError,
+    extensions::{Extension, ExtensionOptions, Extensions, TimestampCheck},
+    isn,
+    json::{JsonObject, ToJson},
+    nic::{Nic, SegmentKind},
+    send_buffer::{InFlightSegment, SendBuffer, SEND_BUFFER_CAPACITY},
+    stats::{ConnectionStats, TcpCounters},
+    tuning::{PeerViolationPolicy, ReceiveCoalescing, TcpTuning},
+  },
   anyhow::anyhow,
-  etherparse::{IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice},
-  std::net::Ipv4Addr,
+  etherparse::{
+    IpNumber, Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice, TcpOptionElement,
+  },
+  std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+    iter,
+    net::Ipv4Addr,
+    str::FromStr,
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Arc,
+    },
+    time::{Duration, Instant},
+  },
 };
 
-#[derive(PartialEq, Eq, Hash)]
+// The MSS assumed for a peer whose SYN carried no MSS option (RFC 9293 section 3.7.1).
+pub const DEFAULT_MAXIMUM_SEGMENT_SIZE: usize = 536;
+
+// Lengths of the IPv4 and TCP headers, without any options.
+pub const HEADERS_LENGTH: usize = 40;
+
+/*
+  The largest payload which fits in an IP packet of the given MTU. It's the MSS option of our SYN,
+  so that the peer never sends us segments the vNIC would have to fragment (RFC 9293 section
+  3.7.1).
+*/
+pub fn maximum_segment_size(mtu: u16) -> usize {
+  (mtu as usize).saturating_sub(HEADERS_LENGTH)
+}
+
+/*
+  Most bare ACKs, RSTs and SYN-ACKs a connection sends in response to a single received segment. No segment
+  legitimately needs more than about two (an ACK sent twice, while reverse loss is suspected), so
+  going past it points at a bug which could turn us into an amplifier. Debug builds panic on it. In
+  release builds the extra ACKs get deferred to the next tick instead, where a single one covers
+  them all, since ACKs are cumulative. Data segments are only bound by the send window.
+*/
+pub const CONTROL_SEGMENT_BUDGET: u32 = 4;
+
+// Number of segments we've sent, which failed self-validation. Only counted in release builds,
+// since debug builds panic on them instead.
+pub static INVALID_SEGMENTS_SENT: AtomicU64 = AtomicU64::new(0);
+
+// Size of the buffer holding received data till the user reads it. The receive window we
+// advertise is the free space left in it.
+const RECEIVE_BUFFER_CAPACITY: usize = 1024;
+
+// By how many bytes an ACK may overshoot SND.NXT, and still be taken as acknowledging SND.NXT under
+// the lenient PeerViolationPolicy. Middleboxes rewriting sequence numbers sometimes get them off by
+// a few bytes.
+const UNSENT_DATA_ACKNOWLEDGEMENT_SLACK: u32 = 8;
+
+// How long a connection lingers in the TIME-WAIT state : twice the Maximum Segment Lifetime, with
+// the MSL taken to be 30 seconds like Linux does.
+const TIME_WAIT_DURATION: Duration = Duration::from_secs(60);
+
+// How long a sent segment may stay unacknowledged, before it gets retransmitted. This is the
+// initial RTO recommended by RFC 6298.
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Location {
   pub address: Ipv4Addr,
   pub port: u16,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+impl Display for Location {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}", self.address, self.port)
+  }
+}
+
+impl Location {
+  /*
+    Whether no connection can be had with this location, which makes a segment from it a martian :
+    its address is broadcast, multicast (224.0.0.0/4), unspecified or loopback (which never
+    arrives over the wire), or its port is 0 (RFC 1122 section 3.2.1.3, RFC 9293 section 3.9.1.1).
+  */
+  pub fn is_martian(&self) -> bool {
+    is_martian_address(self.address) || self.port == 0
+  }
+}
+
+// Whether the address is broadcast, multicast, unspecified or loopback, and thus can't be at either
+// end of a connection.
+pub fn is_martian_address(address: Ipv4Addr) -> bool {
+  address.is_broadcast()
+    || address.is_multicast()
+    || address.is_unspecified()
+    || address.is_loopback()
+}
+
+// Like {"address":"10.0.0.2","port":51514}.
+impl ToJson for Location {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("address", &self.address.to_string())
+      .field("port", &self.port)
+      .finish();
+  }
+}
+
+// Parses a location of the form <IPv4 address>:<port>, like 10.0.0.2:51514.
+impl FromStr for Location {
+  type Err = anyhow::Error;
+
+  fn from_str(location: &str) -> anyhow::Result<Self> {
+    let (address, port) = location.rsplit_once(':').ok_or_else(|| {
+      anyhow!(
+        "Expected a location of the form <address>:<port>, got '{}'",
+        location
+      )
+    })?;
+
+    let address = address
+      .parse::<Ipv4Addr>()
+      .map_err(|error| anyhow!("Invalid IPv4 address '{}' : {}", address, error))?;
+
+    let port = port
+      .parse::<u16>()
+      .map_err(|error| anyhow!("Invalid port '{}' : {}", port, error))?;
+
+    Ok(Self { address, port })
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct ConnectionQuad {
   pub source: Location,
   pub destiation: Location,
 }
 
-#[derive(Default)]
+// Formatted as '<source> <destination>', so that the output can be fed back to FromStr.
+impl Display for ConnectionQuad {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{} {}", self.source, self.destiation)
+  }
+}
+
+impl ToJson for ConnectionQuad {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("source", &self.source)
+      .field("destination", &self.destiation)
+      .finish();
+  }
+}
+
+// Parses a connection quad of the form <source address>:<source port> <destination
+// address>:<destination port>, like 10.0.0.2:51514 10.0.0.1:8080.
+impl FromStr for ConnectionQuad {
+  type Err = anyhow::Error;
+
+  fn from_str(connectionQuad: &str) -> anyhow::Result<Self> {
+    let locations = connectionQuad.split_whitespace().collect::<Vec<_>>();
+
+    let [source, destiation] = locations[..]
+    else {
+      return Err(anyhow!(
+        "Expected a connection quad of the form <source> <destination>, got '{}'",
+        connectionQuad
+      ));
+    };
+
+    Ok(Self {
+      source: source
+        .parse()
+        .map_err(|error| anyhow!("Invalid source location : {}", error))?,
+      destiation: destiation
+        .parse()
+        .map_err(|error| anyhow!("Invalid destination location : {}", error))?,
+    })
+  }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum TCPConnectionState {
   #[default]
   Closed,
 
   Listen,
 
+  // We've sent a SYN, and are waiting for the peer's SYN in return.
+  SYNSent,
+
   SYNReceived,
 
   Established,
+
+  // We've closed our side of the connection, and are waiting for our FIN to be acknowledged.
+  FinWait1,
+
+  // Our FIN has been acknowledged, and we're waiting for the peer's FIN.
+  FinWait2,
+
+  // Both sides sent their FINs at the same time, and we're waiting for ours to be acknowledged.
+  Closing,
+
+  // Both sides have closed, and we linger in case our last ACK got lost.
+  TimeWait,
+
+  // The peer has closed its side of the connection, by sending a FIN.
+  CloseWait,
+
+  // Both sides have closed, the peer first. We're waiting for our FIN to be acknowledged.
+  LastAck,
+}
+
+impl TCPConnectionState {
+  /*
+    Whether the RFC 9293 connection state diagram (section 3.3.2) has an edge from this state to
+    the given one. Beyond the diagram, every state may move to the CLOSED state, since a connection
+    can get reset or aborted at any point.
+
+    A TCB only gets created in the LISTEN or SYN-SENT state, so no edge leaves the CLOSED state.
+  */
+  pub fn can_transition_to(self, to: Self) -> bool {
+    use TCPConnectionState::*;
+
+    match (self, to) {
+      (Closed, _) => false,
+      (_, Closed) => true,
+
+      (Listen, SYNReceived) => true,
+      (SYNSent, SYNReceived | Established) => true,
+      (SYNReceived, Established | FinWait1) => true,
+      (Established, FinWait1 | CloseWait) => true,
+      (FinWait1, FinWait2 | Closing | TimeWait) => true,
+      (FinWait2, TimeWait) => true,
+      (Closing, TimeWait) => true,
+      (CloseWait, LastAck) => true,
+
+      _ => false,
+    }
+  }
+
+  // Whether both sides have synchronized their sequence numbers, by completing the handshake.
+  pub fn is_synchronized(&self) -> bool {
+    !matches!(
+      self,
+      Self::Closed | Self::Listen | Self::SYNSent | Self::SYNReceived
+    )
+  }
+
+  // Whether the user may still write in this state, even if only before the handshake completes.
+  pub fn is_writable(&self) -> bool {
+    matches!(
+      self,
+      Self::SYNSent | Self::SYNReceived | Self::Established | Self::CloseWait
+    )
+  }
+}
+
+// Uses the state names from the RFC 9293 connection state diagram.
+impl Display for TCPConnectionState {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Closed => "CLOSED",
+      Self::Listen => "LISTEN",
+      Self::SYNSent => "SYN-SENT",
+      Self::SYNReceived => "SYN-RECEIVED",
+      Self::Established => "ESTABLISHED",
+      Self::FinWait1 => "FIN-WAIT-1",
+      Self::FinWait2 => "FIN-WAIT-2",
+      Self::Closing => "CLOSING",
+      Self::TimeWait => "TIME-WAIT",
+      Self::CloseWait => "CLOSE-WAIT",
+      Self::LastAck => "LAST-ACK",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+// The RFC 9293 name, like "FIN-WAIT-2".
+impl ToJson for TCPConnectionState {
+  fn write_json(&self, json: &mut String) {
+    self.to_string().write_json(json)
+  }
+}
+
+// Why a connection ended up in the CLOSED state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+  // Both sides closed the connection, and every FIN got acknowledged.
+  Graceful,
+
+  // The peer reset the connection.
+  Reset,
+
+  // The connection got aborted locally, for example through the control socket.
+  Aborted,
+
+  // The peer violated the spec, and the strict PeerViolationPolicy is in effect.
+  PeerViolation,
+
+  // Data sent by us stayed unacknowledged for longer than the user timeout.
+  UserTimeout,
+
+  // The peer answered our SYN with a RST.
+  Refused,
+
+  // The peer didn't answer our SYN in time.
+  ConnectTimeout,
+
+  // The vNIC failed for good, so the peer can't be reached anymore.
+  NicFailed,
+
+  // The connection attempted a state transition, which the state diagram doesn't allow.
+  Desync,
+
+  // The peer acknowledged our FIN, but didn't send its own within the FIN-WAIT-2 timeout.
+  FinWait2Timeout,
+}
+
+impl Display for CloseReason {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let description = match self {
+      Self::Graceful => "closed gracefully",
+      Self::Reset => "reset by the peer",
+      Self::Aborted => "aborted",
+      Self::PeerViolation => "peer violated the spec",
+      Self::UserTimeout => "user timeout expired",
+      Self::Refused => "refused by the peer",
+      Self::ConnectTimeout => "connect timed out",
+      Self::NicFailed => "the vNIC failed",
+      Self::Desync => "the connection state got desynchronized",
+      Self::FinWait2Timeout => "the peer never sent its FIN",
+    };
+
+    write!(f, "{}", description)
+  }
+}
+
+impl ToJson for CloseReason {
+  fn write_json(&self, json: &mut String) {
+    let name = match self {
+      Self::Graceful => "graceful",
+      Self::Reset => "reset",
+      Self::Aborted => "aborted",
+      Self::PeerViolation => "peer-violation",
+      Self::UserTimeout => "user-timeout",
+      Self::Refused => "refused",
+      Self::ConnectTimeout => "connect-timeout",
+      Self::NicFailed => "nic-failed",
+      Self::Desync => "desync",
+      Self::FinWait2Timeout => "fin-wait-2-timeout",
+    };
+
+    name.write_json(json)
+  }
+}
+
+// What made a connection move from one state to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransitionReason {
+  // The peer's SYN arrived.
+  SYNReceived,
+
+  // The three way handshake got completed.
+  HandshakeCompleted,
+
+  // The user closed the connection.
+  UserClose,
+
+  // The peer's FIN arrived.
+  FinReceived,
+
+  // Our FIN got acknowledged.
+  FinAcknowledged,
+
+  // The connection got closed, for the given reason.
+  Closed(CloseReason),
+}
+
+impl Display for TransitionReason {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::SYNReceived => write!(f, "SYN received"),
+      Self::HandshakeCompleted => write!(f, "handshake completed"),
+      Self::UserClose => write!(f, "closed by the user"),
+      Self::FinReceived => write!(f, "FIN received"),
+      Self::FinAcknowledged => write!(f, "FIN acknowledged"),
+      Self::Closed(closeReason) => write!(f, "{}", closeReason),
+    }
+  }
+}
+
+impl ToJson for TransitionReason {
+  fn write_json(&self, json: &mut String) {
+    let name = match self {
+      Self::SYNReceived => "syn-received",
+      Self::HandshakeCompleted => "handshake-completed",
+      Self::UserClose => "user-close",
+      Self::FinReceived => "fin-received",
+      Self::FinAcknowledged => "fin-acknowledged",
+      Self::Closed(closeReason) => return closeReason.write_json(json),
+    };
+
+    name.write_json(json)
+  }
+}
+
+// A state change, as recorded in the transition history of a connection.
+#[derive(Clone, Copy, Debug)]
+pub struct StateTransition {
+  pub from: TCPConnectionState,
+  pub to: TCPConnectionState,
+
+  pub reason: TransitionReason,
+
+  pub at: Instant,
+}
+
+// What the sampler records about a connection, at a point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionSample {
+  pub state: TCPConnectionState,
+
+  // SND.NXT - SND.UNA.
+  pub bytesInFlight: u32,
+
+  // The peer's window (SND.WND), and the one we advertise (RCV.WND).
+  pub sendWindow: u16,
+  pub receiveWindow: u16,
+
+  // Bytes written by the user but not acknowledged yet, and bytes received but not read yet.
+  pub sendQueue: usize,
+  pub receiveQueue: usize,
+
+  pub retransmissions: u64,
+}
+
+impl Display for StateTransition {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(f, "{} -> {} ({})", self.from, self.to, self.reason)
+  }
+}
+
+impl ToJson for StateTransition {
+  fn write_json(&self, json: &mut String) {
+    JsonObject::new(json)
+      .field("from", &self.from)
+      .field("to", &self.to)
+      .field("reason", &self.reason)
+      .finish();
+  }
+}
+
+// A state change, which the RFC 9293 connection state diagram doesn't allow.
+#[derive(Debug)]
+pub struct InvalidTransition {
+  pub from: TCPConnectionState,
+  pub to: TCPConnectionState,
+
+  pub reason: TransitionReason,
+}
+
+impl Display for InvalidTransition {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "Invalid state transition {} -> {} ({})",
+      self.from, self.to, self.reason
+    )
+  }
 }
 
+impl std::error::Error for InvalidTransition {}
+
+// How many of its latest state transitions a connection remembers.
+const TRANSITION_HISTORY_LENGTH: usize = 16;
+
 /*
   (1) Sequence Numbers :
 
@@ -74,9 +533,6 @@ struct ReceiveSequenceVariables {
   // Indicates how much buffer space is available for incoming data at the receiver.
   windowSize: u16, // wnd.
 
-  // Tracks the sequence number offset of urgent data in the receive buffer.
-  up: bool, // up.
-
   // The sequence number chosen during the initial handshake as the starting point for the receive
   // side.
   initialReceiveSequenceNumber: u32, // irs.
@@ -92,9 +548,6 @@ struct SendSequenceVariables {
   // Send window.
   windowSize: u16, // wnd.
 
-  // Send urgent pointer.
-  up: bool,
-
   // Segment sequence number used for last window update.
   lastWindowUpdateSegmentSequenceNumber: u32, // wl1.
 
@@ -105,16 +558,205 @@ struct SendSequenceVariables {
   initialSendSequenceNumber: u32, // iss.
 }
 
+// A segment received on a connection : its TCP header, and the payload following it.
+pub struct SegmentView<'segment> {
+  pub header: TcpHeaderSlice<'segment>,
+  pub payload: &'segment [u8],
+}
+
+// Whatever the state machine needs for sending segments in response.
+pub struct SendContext<'context> {
+  pub nic: &'context Nic,
+}
+
+/*
+  Deliberate deviations from how the connection normally behaves, with which the conformance checks
+  see how the peer copes. They're only set for the duration of a check.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BehaviorOverrides {
+  // Advertise a zero window, whatever the free space in the receive buffer.
+  pub advertiseZeroWindow: bool,
+
+  // Send the next two data segments the wrong way round. Cleared once they've been sent.
+  pub reorderNextSegments: bool,
+
+  // Use this ISS, rather than one from the ISN generator, so that the sequence numbers are known
+  // ahead. Only has an effect if set before the SYN (or SYN-ACK) gets sent.
+  pub initialSendSequenceNumber: Option<u32>,
+}
+
+// What the caller of TCPConnection::handle( ) should do with the TCB afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  Keep,
+
+  // The connection got closed, or never got opened.
+  Remove,
+
+  // The three way handshake of a passively opened connection just got completed.
+  MoveToAcceptQueue,
+}
+
 // Represents the TCB.
 /*
   The maintenance of a TCP connection requires remembering several variables. We conceive of these
   variables being stored in a connection record called a Transmission Control Block (TCB).
 */
 pub struct TCPConnection {
+  quad: ConnectionQuad,
+
+  tuning: TcpTuning,
+
+  // Where every timestamp and timer of the connection reads the time from.
+  clock: Arc<dyn Clock>,
+
+  // Only ever changed through transition( ).
   state: TCPConnectionState,
 
+  // The latest state transitions, oldest first.
+  transitions: VecDeque<StateTransition>,
+
+  createdAt: Instant,
+  establishedAt: Option<Instant>,
+  stateEnteredAt: Instant,
+
+  // Whether a warning has been logged about the connection being stuck in its current state.
+  isStuckWarned: bool,
+
+  // Whether the connection got opened by a peer connecting to one of our listeners.
+  isPassiveOpen: bool,
+
+  // The MSS option of the peer's SYN, if it carried one.
+  peerMaximumSegmentSize: Option<u16>,
+
+  // Which of window scaling, SACK and timestamps got negotiated, along with their state.
+  extensions: Extensions,
+
+  // The MSS option of our SYN, derived from the MTU of the vNIC when the TCB got created. An MTU
+  // change later on doesn't affect it.
+  maximumSegmentSize: usize,
+
+  // Set once the connection moves to the CLOSED state.
+  closeReason: Option<CloseReason>,
+
   receiveSequenceVariables: ReceiveSequenceVariables,
   sendSequenceVariables: SendSequenceVariables,
+
+  // In-order data received from the peer, which is yet to be read by the user.
+  receiveBuffer: VecDeque<u8>,
+
+  /*
+    Segments which arrived ahead of RCV.NXT, keyed by how far ahead of RCV.NXT they start. They
+    wait here till the gap before them gets filled by retransmissions.
+
+    Keying them by their sequence number would order them wrongly, whenever the receive window
+    spans the point where sequence numbers wrap around. So the keys get rebased every time RCV.NXT
+    advances, which is cheap since the receive window only fits a few segments.
+  */
+  outOfOrderSegments: BTreeMap<u32, Vec<u8>>,
+
+  // Sequence number of the peer's FIN, once a segment carrying it has arrived. The FIN is only
+  // consumed once RCV.NXT reaches it, meaning every byte before it has arrived too.
+  finSequenceNumber: Option<u32>,
+
+  // Data written by the user, which is yet to be sent or acknowledged.
+  sendBuffer: SendBuffer,
+
+  // Set once the user closes the connection. Our FIN gets sent after everything written before.
+  finQueued: bool,
+
+  // Set when a write( ) doesn't fit the send buffer, and cleared once the low watermark worth of
+  // room has freed up. is_writable( ) holds the writer back meanwhile.
+  isWriterBlocked: bool,
+  sendLowWatermark: usize,
+
+  // Set when the writer held back by isWriterBlocked may go on : the low watermark got crossed, or
+  // the connection is closing or failed. Taken by the manager, which then wakes the writer up.
+  isWriterWakeupPending: bool,
+
+  // Sequence number of our FIN, once sent, along with when it was first and last sent.
+  sentFinSequenceNumber: Option<u32>,
+  finFirstSentAt: Instant,
+  finSentAt: Instant,
+
+  // When the TIME-WAIT state ends.
+  timeWaitEndsAt: Option<Instant>,
+
+  // Whether the user has shut down reading, so no data from the peer is waited for anymore.
+  isReadShutdown: bool,
+
+  // When the connection gets dropped, if it's still in the FIN-WAIT-2 state by then.
+  finWait2EndsAt: Option<Instant>,
+
+  // Whether the receive window has grown enough to be worth advertising, since we last did.
+  isWindowUpdatePending: bool,
+
+  // The receive window, as last advertised to the peer.
+  advertisedWindowSize: u16,
+
+  // When the last unsolicited window update got sent.
+  lastWindowUpdateAt: Option<Instant>,
+
+  /*
+    Retransmissions of already acknowledged data received since RCV.NXT last moved. Past the
+    reverseLossThreshold, our ACKs are suspected to be getting lost on the way to the peer, and
+    each one answering such a retransmission gets sent twice, till RCV.NXT moves again.
+  */
+  duplicateSegments: u32,
+  isReverseLossSuspected: bool,
+
+  // Set when reverse loss gets suspected, till the connection manager picks it up.
+  isReverseLossUnreported: bool,
+
+  // How many more bare ACKs and RSTs may be sent in response to the segment being processed. None
+  // outside of handle( ).
+  controlSegmentBudget: Option<u32>,
+
+  // Whether an ACK went over the control segment budget, and is owed to the peer on the next tick.
+  isAcknowledgementDeferred: bool,
+
+  /*
+    TCP User Timeout (RFC 5482) : how long sent data (or our FIN) may stay unacknowledged, before
+    the connection gets aborted.
+
+    The deadline runs from when the oldest unacknowledged byte was first sent (see
+    oldest_unacked_age( )), so retransmitting it doesn't push the deadline back, while every ACK
+    moving SND.UNA forward does.
+  */
+  userTimeout: Option<Duration>,
+
+  // Retransmission of our SYN while in the SYN-SENT state, or of our SYN-ACK while in the
+  // SYN-RECEIVED state.
+  synRetransmission: Option<SYNRetransmission>,
+
+  // Since when the peer's window has been shut, with data of ours waiting for it to open. See
+  // probe_zero_window( ).
+  zeroWindowSince: Option<Instant>,
+
+  receiveCoalescing: Option<ReceiveCoalescing>,
+
+  // Since when received data has been held back from the readers, if it is.
+  coalescingSince: Option<Instant>,
+
+  // Whether the readers shouldn't be woken up, after the last segment got processed.
+  isWakeupDeferred: bool,
+
+  overrides: BehaviorOverrides,
+
+  stats: ConnectionStats,
+}
+
+struct SYNRetransmission {
+  // How many times the SYN (or SYN-ACK) has been sent so far.
+  transmissions: u32,
+
+  // Doubles with every retransmission.
+  retransmissionTimeout: Duration,
+  retransmitAt: Instant,
+
+  // When the handshake gets given up on, no matter how many SYNs have been sent by then.
+  deadline: Instant,
 }
 
 /*
@@ -139,7 +781,8 @@ pub struct TCPConnection {
   low order bit is incremented roughly every 4 microseconds. Thus, the ISN cycles approximately
   every 4.55 hours. Since we assume that segments will stay in the network no more than the
   Maximum Segment Lifetime (MSL) and that the MSL is less than 4.55 hours we can reasonably assume
-  that ISN's will be unique.
+  that ISN's will be unique. Ours (see isn.rs) adds a keyed hash of the connection quad to such a
+  clock, as per RFC 6528, so that the ISN's can't be guessed by an off-path attacker either.
 
   For each connection there is a send sequence number and a receive sequence number. The initial
   send sequence number (ISS) is chosen by the data sending TCP, and the initial receive sequence
@@ -170,80 +813,4270 @@ pub struct TCPConnection {
   ask the sender to verify this SYN.
 */
 impl TCPConnection {
-  pub fn accept<'connection>(
-    incomingPacketIPv4Header: Ipv4HeaderSlice<'connection>,
-    incomingPacketTCPHeader: TcpHeaderSlice<'connection>,
-    data: &'connection [u8],
-    nic: &mut tun::Device,
-  ) -> anyhow::Result<Self> {
-    if !incomingPacketTCPHeader.syn() {
-      return Err(anyhow!("Three way handshake not done"));
-    }
+  /*
+    Creates a TCB in the LISTEN state, for a segment arriving at a port someone is listening on.
+    Feeding it that segment through handle( ) either makes it answer a connection request, or
+    leaves it in the LISTEN state to be discarded.
+  */
+  pub fn listen(
+    quad: ConnectionQuad,
+    tuning: TcpTuning,
+    maximumSegmentSize: usize,
+    counters: Arc<TcpCounters>,
+    clock: Arc<dyn Clock>,
+  ) -> Self {
+    Self::new(
+      quad,
+      tuning,
+      maximumSegmentSize,
+      counters,
+      clock,
+      TCPConnectionState::Listen,
+    )
+  }
 
-    // We've received a SYN packet from the client.
-    // Start establishing a connection, by sending back a SYN ACK packet.
+  /*
+    Creates a TCB in the SYN-SENT state, for actively opening a connection to the peer. Nothing
+    gets sent till open( ) is called.
+  */
+  pub fn connect(
+    quad: ConnectionQuad,
+    tuning: TcpTuning,
+    maximumSegmentSize: usize,
+    counters: Arc<TcpCounters>,
+    clock: Arc<dyn Clock>,
+  ) -> Self {
+    Self::new(
+      quad,
+      tuning,
+      maximumSegmentSize,
+      counters,
+      clock,
+      TCPConnectionState::SYNSent,
+    )
+  }
+
+  // The stats of the connection get summed up into the given interface counters, and its timers run
+  // on the given clock.
+  fn new(
+    quad: ConnectionQuad,
+    tuning: TcpTuning,
+    maximumSegmentSize: usize,
+    counters: Arc<TcpCounters>,
+    clock: Arc<dyn Clock>,
+    state: TCPConnectionState,
+  ) -> Self {
+    let now = clock.now();
 
-    let initialSendSequenceNumber = 0;
-    let sendWindowSize = 1024;
+    let sendBuffer =
+      SendBuffer::with_capacity(tuning.sendBufferCapacity.unwrap_or(SEND_BUFFER_CAPACITY));
+    let sendLowWatermark = tuning
+      .sendLowWatermark
+      .unwrap_or((sendBuffer.capacity() / 4).max(DEFAULT_MAXIMUM_SEGMENT_SIZE))
+      .clamp(1, sendBuffer.capacity());
 
-    let mut connection = Self {
-      state: TCPConnectionState::SYNReceived,
+    Self {
+      quad,
+      tuning,
+      clock,
+
+      state,
+      transitions: VecDeque::with_capacity(TRANSITION_HISTORY_LENGTH),
+      createdAt: now,
+      establishedAt: None,
+      stateEnteredAt: now,
+      isStuckWarned: false,
+      isPassiveOpen: state == TCPConnectionState::Listen,
+      closeReason: None,
 
       receiveSequenceVariables: ReceiveSequenceVariables {
-        initialReceiveSequenceNumber: incomingPacketTCPHeader.sequence_number(),
-        nextByteSequenceNumber: incomingPacketTCPHeader.sequence_number() + 1,
-        windowSize: incomingPacketTCPHeader.window_size(),
-        up: false,
+        initialReceiveSequenceNumber: 0,
+        nextByteSequenceNumber: 0,
+        windowSize: RECEIVE_BUFFER_CAPACITY as u16,
       },
 
       sendSequenceVariables: SendSequenceVariables {
-        initialSendSequenceNumber,
-        oldestUnacknowledgedSequenceNumber: initialSendSequenceNumber,
-        nextSequenceNumber: initialSendSequenceNumber,
-        windowSize: sendWindowSize,
-        up: false,
-        lastWindowUpdateSegmentSequenceNumber: initialSendSequenceNumber,
-        lastWindowUpdateAcknowledgementNumber: initialSendSequenceNumber,
+        initialSendSequenceNumber: 0,
+        oldestUnacknowledgedSequenceNumber: 0,
+        nextSequenceNumber: 0,
+        windowSize: 0,
+        lastWindowUpdateSegmentSequenceNumber: 0,
+        lastWindowUpdateAcknowledgementNumber: 0,
       },
-    };
 
-    // You can view the TCP header format here :
-    // https://datatracker.ietf.org/doc/html/rfc9293#section-3.1
-    let mut synAckPacketTCPHeader = TcpHeader::new(
-      incomingPacketTCPHeader.destination_port(),
-      incomingPacketTCPHeader.source_port(),
-      0,
-      10,
+      peerMaximumSegmentSize: None,
+      maximumSegmentSize,
+      extensions: Extensions::default(),
+
+      userTimeout: tuning.userTimeout,
+
+      synRetransmission: None,
+      zeroWindowSince: None,
+
+      receiveCoalescing: tuning.receiveCoalescing,
+      coalescingSince: None,
+      isWakeupDeferred: false,
+
+      receiveBuffer: VecDeque::with_capacity(RECEIVE_BUFFER_CAPACITY),
+      outOfOrderSegments: BTreeMap::default(),
+      finSequenceNumber: None,
+
+      sendBuffer,
+      isWriterBlocked: false,
+      isWriterWakeupPending: false,
+      sendLowWatermark,
+
+      finQueued: false,
+      sentFinSequenceNumber: None,
+      finFirstSentAt: now,
+      finSentAt: now,
+
+      timeWaitEndsAt: None,
+      isReadShutdown: false,
+      finWait2EndsAt: None,
+
+      isWindowUpdatePending: false,
+      advertisedWindowSize: RECEIVE_BUFFER_CAPACITY as u16,
+      lastWindowUpdateAt: None,
+
+      duplicateSegments: 0,
+      isReverseLossSuspected: false,
+      isReverseLossUnreported: false,
+
+      controlSegmentBudget: None,
+      isAcknowledgementDeferred: false,
+
+      overrides: BehaviorOverrides::default(),
+
+      stats: ConnectionStats::new(counters),
+    }
+  }
+
+  /*
+    The single entry point of the state machine : processes a segment received on this connection,
+    as per the current state, and tells the caller what to do with the TCB afterwards.
+
+    Failures to write a response are logged here, since the state the connection is left in is
+    all the caller needs to act upon.
+  */
+  pub fn handle(&mut self, segment: &SegmentView, ctx: &mut SendContext) -> Action {
+    let previousState = self.state;
+    let previousOldestUnacknowledgedSequenceNumber = self
+      .sendSequenceVariables
+      .oldestUnacknowledgedSequenceNumber;
+    let previousReceiveBufferLength = self.receiveBuffer.len();
+
+    if let Some(result) =
+      self.on_pure_acknowledgement(&segment.header, segment.payload.len(), ctx.nic)
+    {
+      if let Err(error) = result {
+        eprintln!("Failed processing segment for {} : {}", self.quad, error);
+      }
+
+      self.isWakeupDeferred = false;
+      self.coalescingSince = None;
+      return Action::Keep;
+    }
+
+    if let Err(error) = self.on_segment(segment, ctx.nic) {
+      eprintln!("Failed processing segment for {} : {}", self.quad, error);
+    }
+
+    // Whether the segment did nothing but add data to the receive buffer.
+    let isDataOnly = self.state == previousState
+      && self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber
+        == previousOldestUnacknowledgedSequenceNumber
+      && self.receiveBuffer.len() > previousReceiveBufferLength
+      && !segment.header.psh()
+      && !segment.header.fin();
+    self.isWakeupDeferred = isDataOnly && self.coalesce(self.clock.now());
+    if !self.isWakeupDeferred {
+      self.coalescingSince = None;
+    }
+
+    match self.state {
+      // A TCB still in the LISTEN state didn't get a connection request.
+      TCPConnectionState::Closed | TCPConnectionState::Listen => Action::Remove,
+
+      // The handshake completed, even if the connection moved on right away : to FIN-WAIT-1 with
+      // a FIN the user queued meanwhile, or to CLOSE-WAIT with a FIN along with the ACK.
+      state
+        if self.isPassiveOpen
+          && previousState == TCPConnectionState::SYNReceived
+          && state.is_synchronized() =>
+      {
+        Action::MoveToAcceptQueue
+      }
+
+      _ => Action::Keep,
+    }
+  }
+
+  /*
+    The regular path, which every segment not taken by the fast path for pure ACKs goes through :
+    the processing of RFC 9293 section 3.10.7, as per the state of the connection.
+  */
+  fn on_segment(&mut self, segment: &SegmentView, nic: &Nic) -> anyhow::Result<()> {
+    self.stats.record_segment(
+      &segment.header,
+      segment.payload.len(),
+      self.maximumSegmentSize,
     );
-    synAckPacketTCPHeader.acknowledgment_number = incomingPacketTCPHeader.sequence_number() + 1;
-    synAckPacketTCPHeader.ack = true;
-    synAckPacketTCPHeader.syn = true;
 
-    // You can view the IPv4 header format here :
-    // https://datatracker.ietf.org/doc/html/rfc791#section-3.1.
-    let synAckPacketIPv4Header = Ipv4Header::new(
-      synAckPacketTCPHeader.to_bytes().len() as u16,
-      64,
-      IpNumber::TCP,
-      incomingPacketIPv4Header.destination(),
-      incomingPacketIPv4Header.source(),
-    )?;
+    self.controlSegmentBudget = Some(CONTROL_SEGMENT_BUDGET);
+    let result = match self.state {
+      TCPConnectionState::Closed => {
+        send_reset(&self.quad, &segment.header, segment.payload.len(), nic)
+      }
+
+      TCPConnectionState::Listen => self.on_listen_segment(&segment.header, nic),
+
+      TCPConnectionState::SYNSent => self.on_syn_sent_segment(&segment.header, nic),
+
+      TCPConnectionState::SYNReceived
+      | TCPConnectionState::Established
+      | TCPConnectionState::FinWait1
+      | TCPConnectionState::FinWait2
+      | TCPConnectionState::Closing
+      | TCPConnectionState::TimeWait
+      | TCPConnectionState::CloseWait
+      | TCPConnectionState::LastAck => {
+        self.on_synchronized_segment(&segment.header, segment.payload, nic)
+      }
+    };
+    self.controlSegmentBudget = None;
+
+    result
+  }
+
+  // Processes a segment as per the LISTEN state (RFC 9293 section 3.10.7.2).
+  fn on_listen_segment(
+    &mut self,
+    incomingPacketTCPHeader: &TcpHeaderSlice,
+    nic: &Nic,
+  ) -> anyhow::Result<()> {
+    // An incoming RST is ignored.
+    if incomingPacketTCPHeader.rst() {
+      return Ok(());
+    }
+
+    // Any acknowledgment is bad, since nothing has been sent yet on this incarnation of the
+    // connection. It gets answered with <SEQ=SEG.ACK><CTL=RST>.
+    if incomingPacketTCPHeader.ack() {
+      return send_reset(&self.quad, incomingPacketTCPHeader, 0, nic);
+    }
+
+    // Neither SYN nor ACK is set, so the segment is dropped.
+    if !incomingPacketTCPHeader.syn() {
+      return Ok(());
+    }
+
+    // A SYN can't be closing the connection it's opening, so a SYN+FIN gets rejected.
+    if incomingPacketTCPHeader.fin() {
+      return send_reset(&self.quad, incomingPacketTCPHeader, 0, nic);
+    }
+
+    // We've received a SYN packet from the client.
+    // Start establishing a connection, by sending back a SYN ACK packet.
 
-    let mut arrayBuffer = [0u8; 1024];
+    let initialSendSequenceNumber = self.choose_initial_send_sequence_number();
 
-    let arrayBufferEmptyPortionLength = {
-      let mut sliceBuffer = &mut arrayBuffer[..]; // Convertion from fixed-size array to slice.
+    self.receiveSequenceVariables.initialReceiveSequenceNumber =
+      incomingPacketTCPHeader.sequence_number();
+    self.receiveSequenceVariables.nextByteSequenceNumber =
+      incomingPacketTCPHeader.sequence_number().wrapping_add(1);
 
-      synAckPacketIPv4Header.write(&mut sliceBuffer)?;
-      synAckPacketTCPHeader.write(&mut sliceBuffer)?;
+    self.peerMaximumSegmentSize = maximum_segment_size_option(incomingPacketTCPHeader.options());
+    self
+      .extensions
+      .on_syn(ExtensionOptions::of(incomingPacketTCPHeader), false);
 
-      sliceBuffer.len()
+    self.sendSequenceVariables = SendSequenceVariables {
+      initialSendSequenceNumber,
+      oldestUnacknowledgedSequenceNumber: initialSendSequenceNumber,
+      nextSequenceNumber: initialSendSequenceNumber,
+      windowSize: incomingPacketTCPHeader.window_size(),
+      lastWindowUpdateSegmentSequenceNumber: incomingPacketTCPHeader.sequence_number(),
+      lastWindowUpdateAcknowledgementNumber: initialSendSequenceNumber,
     };
 
-    let arrayBufferUsedPortionLength = arrayBuffer.len() - arrayBufferEmptyPortionLength;
+    let synAckPacketTCPHeader = self.create_syn_header();
+    self.send_segment(synAckPacketTCPHeader, &[], nic)?;
+    self.start_syn_retransmission();
 
-    nic.send(&arrayBuffer[..arrayBufferUsedPortionLength])?;
+    self.enter(
+      TCPConnectionState::SYNReceived,
+      TransitionReason::SYNReceived,
+    );
+    Ok(())
+  }
+
+  // Whether the connection got opened by a peer connecting to one of our listeners.
+  pub fn is_passive_open(&self) -> bool {
+    self.isPassiveOpen
+  }
+
+  pub fn quad(&self) -> ConnectionQuad {
+    self.quad
+  }
+
+  pub fn state(&self) -> TCPConnectionState {
+    self.state
+  }
+
+  // How long ago the TCB got created.
+  pub fn age(&self) -> Duration {
+    self.clock.now().saturating_duration_since(self.createdAt)
+  }
+
+  // How long the connection has been in its current state.
+  pub fn time_in_state(&self) -> Duration {
+    self
+      .clock
+      .now()
+      .saturating_duration_since(self.stateEnteredAt)
+  }
+
+  /*
+    How long the oldest unacknowledged byte has been waiting for its acknowledgment, since it was
+    first sent : head-of-line blocking, as far as we can tell. Our FIN counts too, once every byte
+    before it has been acknowledged. None if everything sent has been acknowledged.
+  */
+  pub fn oldest_unacked_age(&self) -> Option<Duration> {
+    let now = self.clock.now();
+
+    self
+      .oldest_unacked_sent_at()
+      .map(|sentAt| now.saturating_duration_since(sentAt))
+  }
+
+  fn oldest_unacked_sent_at(&self) -> Option<Instant> {
+    if let Some(sentAt) = self.sendBuffer.oldest_in_flight_sent_at() {
+      return Some(sentAt);
+    }
+
+    let isFinUnacknowledged = self.sentFinSequenceNumber.is_some()
+      && self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber
+        != self.sendSequenceVariables.nextSequenceNumber;
+    isFinUnacknowledged.then_some(self.finFirstSentAt)
+  }
+
+  // When the connection got established, if it has been.
+  pub fn established_at(&self) -> Option<Instant> {
+    self.establishedAt
+  }
+
+  pub fn close_reason(&self) -> Option<CloseReason> {
+    self.closeReason
+  }
+
+  pub fn stats(&self) -> &ConnectionStats {
+    &self.stats
+  }
+
+  pub fn sample(&self) -> ConnectionSample {
+    ConnectionSample {
+      state: self.state,
+      bytesInFlight: self.sendSequenceVariables.nextSequenceNumber.wrapping_sub(
+        self
+          .sendSequenceVariables
+          .oldestUnacknowledgedSequenceNumber,
+      ),
+      sendWindow: self.sendSequenceVariables.windowSize,
+      receiveWindow: self.receiveSequenceVariables.windowSize,
+      sendQueue: self.sendBuffer.len(),
+      receiveQueue: self.receiveBuffer.len(),
+      retransmissions: self.stats.retransmissions(),
+    }
+  }
+
+  /*
+    Like FIONREAD, these are snapshots : by the time the caller looks at them, the connection may
+    have received, sent or gotten acknowledged more data.
+  */
+
+  // In-order bytes received, which are yet to be read.
+  pub fn bytes_to_read(&self) -> usize {
+    self.receiveBuffer.len()
+  }
+
+  // Bytes written, which are yet to be sent.
+  pub fn bytes_queued(&self) -> usize {
+    self.sendBuffer.unsent_len()
+  }
+
+  // Bytes sent, which are yet to be acknowledged. Our SYN and FIN don't count.
+  pub fn bytes_unacked(&self) -> usize {
+    self.sendBuffer.in_flight_len()
+  }
+
+  /*
+    The largest payload the peer is prepared to receive in a segment : the MSS option of its SYN,
+    or the default of 536 bytes when it sent none (RFC 9293 section 3.7.1).
+  */
+  pub fn peer_maximum_segment_size(&self) -> usize {
+    self
+      .peerMaximumSegmentSize
+      .map_or(DEFAULT_MAXIMUM_SEGMENT_SIZE, usize::from)
+  }
+
+  // The largest payload we're prepared to receive in a segment, which our SYN advertised.
+  pub fn maximum_segment_size(&self) -> usize {
+    self.maximumSegmentSize
+  }
+
+  // The largest payload we send in a segment : what the peer takes in, and fits in our MTU, less
+  // the options every segment carries (RFC 6691).
+  pub fn send_maximum_segment_size(&self) -> usize {
+    self
+      .peer_maximum_segment_size()
+      .min(self.maximumSegmentSize)
+      .saturating_sub(self.extensions.options_length())
+      .max(1)
+  }
+
+  // Whether the given extension got negotiated, and is still in use.
+  pub fn is_extension_enabled(&self, extension: Extension) -> bool {
+    self.extensions.is_enabled(extension)
+  }
+
+  pub fn user_timeout(&self) -> Option<Duration> {
+    self.userTimeout
+  }
+
+  // Sets the TCP User Timeout. None disables it.
+  pub fn set_user_timeout(&mut self, userTimeout: Option<Duration>) {
+    self.userTimeout = userTimeout;
+  }
+
+  pub fn overrides(&self) -> BehaviorOverrides {
+    self.overrides
+  }
+
+  // Replaces the behavior overrides. Once the window stops being held at zero, the peer gets told
+  // about the real one right away, rather than through its next zero window probe.
+  pub fn set_overrides(
+    &mut self,
+    overrides: BehaviorOverrides,
+    ctx: &mut SendContext,
+  ) -> anyhow::Result<()> {
+    let isWindowReopened = self.overrides.advertiseZeroWindow && !overrides.advertiseZeroWindow;
+
+    self.overrides = overrides;
+    self.update_receive_window();
+
+    if isWindowReopened && self.state.is_synchronized() {
+      self.send_acknowledgement(ctx.nic)?;
+    }
+    Ok(())
+  }
+
+  // None disables receive coalescing, for latency sensitive connections.
+  pub fn set_receive_coalescing(&mut self, receiveCoalescing: Option<ReceiveCoalescing>) {
+    self.receiveCoalescing = receiveCoalescing;
+  }
+
+  // Whether the readers shouldn't be woken up, since the last processed segment only added data
+  // which is being coalesced.
+  pub fn is_wakeup_deferred(&self) -> bool {
+    self.isWakeupDeferred
+  }
+
+  // Whether reverse loss got suspected since the last call, along with how many retransmissions
+  // of acknowledged data made it so.
+  pub fn take_reverse_loss_suspicion(&mut self) -> Option<u32> {
+    std::mem::take(&mut self.isReverseLossUnreported).then_some(self.duplicateSegments)
+  }
+
+  // Whether a writer blocked on a full send buffer may go on since the last call.
+  pub fn take_writer_wakeup(&mut self) -> bool {
+    std::mem::take(&mut self.isWriterWakeupPending)
+  }
+
+  /*
+    Counts a received retransmission of data we've all acknowledged already. Returns whether our
+    ACKs are suspected to be getting lost, in which case the ACK answering it gets sent twice.
+  */
+  fn on_duplicate_segment(&mut self) -> bool {
+    self.duplicateSegments = self.duplicateSegments.saturating_add(1);
+
+    let isThresholdExceeded = self
+      .tuning
+      .reverseLossThreshold
+      .is_some_and(|threshold| self.duplicateSegments > threshold);
+
+    if isThresholdExceeded && !self.isReverseLossSuspected {
+      self.isReverseLossSuspected = true;
+      self.isReverseLossUnreported = true;
+      self.stats.record_reverse_loss_suspicion();
+
+      eprintln!(
+        "WARN : Connection {} received {} retransmissions of acknowledged data, our ACKs are \
+         likely getting lost",
+        self.quad, self.duplicateSegments
+      );
+    }
+    self.isReverseLossSuspected
+  }
+
+  // Whether the data just added to the receive buffer may be held back from the readers.
+  fn coalesce(&mut self, now: Instant) -> bool {
+    let Some(receiveCoalescing) = self.receiveCoalescing
+    else {
+      return false;
+    };
+
+    let threshold = receiveCoalescing.threshold.min(RECEIVE_BUFFER_CAPACITY / 2);
+    let coalescingSince = *self.coalescingSince.get_or_insert(now);
+
+    if self.receiveBuffer.len() >= threshold
+      || now.duration_since(coalescingSince) >= receiveCoalescing.budget
+    {
+      return false;
+    }
+
+    self.stats.record_deferred_wakeup();
+    true
+  }
+
+  // Fires the expired timers of the connection. Once this leaves the connection in the CLOSED
+  // state, the caller is responsible for deleting the TCB.
+  pub fn on_tick(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    // The readers get woken up after every tick, along with the held back data.
+    self.coalescingSince = None;
+
+    self.warn_if_stuck(now);
+
+    if let Some(userTimeout) = self.userTimeout {
+      let isTimedOut = self
+        .oldest_unacked_sent_at()
+        .is_some_and(|sentAt| now.saturating_duration_since(sentAt) >= userTimeout);
+
+      if isTimedOut {
+        return self.abort(CloseReason::UserTimeout, nic);
+      }
+    }
+
+    if self
+      .timeWaitEndsAt
+      .is_some_and(|timeWaitEndsAt| now >= timeWaitEndsAt)
+    {
+      self.enter_closed(CloseReason::Graceful);
+      return Ok(());
+    }
+
+    // Nothing gets sent to the peer, which is most likely gone anyway.
+    if self.state == TCPConnectionState::FinWait2
+      && self
+        .finWait2EndsAt
+        .is_some_and(|finWait2EndsAt| now >= finWait2EndsAt)
+    {
+      self.enter_closed(CloseReason::FinWait2Timeout);
+      return Ok(());
+    }
+
+    if std::mem::take(&mut self.isAcknowledgementDeferred) {
+      self.send_acknowledgement(nic)?;
+    }
+
+    self.retransmit_syn(now, nic)?;
+    self.probe_zero_window(now, nic)?;
+    self.retransmit(now, nic)?;
+
+    self.flush_window_update(now, nic)
+  }
+
+  /*
+    Takes in as much of the given data as the send buffer has room for, and sends whatever the
+    peer's window allows right away (or once the connection gets established, if it hasn't yet).
+    Returns WouldBlock when the send buffer is full.
+
+    Like with std, writing no data returns Ok(0) right away (unless the connection doesn't allow
+    writing), without sending anything.
+  */
+  pub fn write(&mut self, data: &[u8], ctx: &mut SendContext) -> Result<usize, TcpError> {
+    match self.state {
+      // Data written before the connection gets established is buffered, and transmitted once it
+      // does.
+      TCPConnectionState::SYNSent
+      | TCPConnectionState::SYNReceived
+      | TCPConnectionState::Established
+      | TCPConnectionState::CloseWait => {}
+
+      // Tells why the connection couldn't be established, if it couldn't.
+      TCPConnectionState::Closed => return Err(self.closed_error()),
+
+      _ => return Err(TcpError::NotConnected),
+    }
+
+    if data.is_empty() {
+      return Ok(0);
+    }
+
+    let bytesWritten = self.sendBuffer.write(data);
+    if bytesWritten < data.len() {
+      self.isWriterBlocked = true;
+    }
+    if bytesWritten == 0 {
+      return Err(TcpError::WouldBlock);
+    }
+
+    self.transmit(ctx.nic).map_err(nic_error)?;
+    Ok(bytesWritten)
+  }
+
+  // Whether read( ) would return without blocking : either there's data to read, or it's
+  // reporting the end of the stream / an error.
+  pub fn is_readable(&self) -> bool {
+    let mayStillReceive = matches!(
+      self.state,
+      TCPConnectionState::Listen
+        | TCPConnectionState::SYNSent
+        | TCPConnectionState::SYNReceived
+        | TCPConnectionState::Established
+        | TCPConnectionState::FinWait1
+        | TCPConnectionState::FinWait2
+    );
+
+    !self.receiveBuffer.is_empty() || !mayStillReceive
+  }
+
+  /*
+    Whether write( ) would return without blocking : either the send buffer has room, or it's
+    reporting an error. After the send buffer got filled up, that room has to be at least the low
+    watermark, unless the connection is closing or failed, which the writer learns about right away.
+  */
+  pub fn is_writable(&self) -> bool {
+    (!self.isWriterBlocked && self.sendBuffer.room() > 0) || !self.state.is_writable()
+  }
+
+  /*
+    CLOSE (RFC 9293 section 3.10.4) : closes our side of the connection. Everything written so far
+    still gets sent, followed by our FIN. Data from the peer can still be read, till it closes its
+    side too.
+  */
+  pub fn close(&mut self, ctx: &mut SendContext) -> Result<(), TcpError> {
+    match self.state {
+      TCPConnectionState::Listen | TCPConnectionState::SYNSent => {
+        self.synRetransmission = None;
+        self.enter_closed(CloseReason::Graceful);
+        return Ok(());
+      }
+
+      // The FIN waits till the connection gets established.
+      TCPConnectionState::SYNReceived => {}
+
+      TCPConnectionState::Established => {
+        self.enter(TCPConnectionState::FinWait1, TransitionReason::UserClose)
+      }
+      TCPConnectionState::CloseWait => {
+        self.enter(TCPConnectionState::LastAck, TransitionReason::UserClose)
+      }
+
+      _ => return Err(TcpError::NotConnected),
+    }
+    self.finQueued = true;
+
+    self.transmit(ctx.nic).map_err(nic_error)
+  }
+
+  /*
+    Shuts down reading : the user won't read from the connection anymore. Together with close( ),
+    this fully closes the connection, which starts the FIN-WAIT-2 timeout once our FIN gets
+    acknowledged. After just close( ), the peer may still send data for as long as it likes.
+  */
+  pub fn shutdown_read(&mut self) {
+    self.isReadShutdown = true;
+
+    if self.state == TCPConnectionState::FinWait2 {
+      self.start_fin_wait_2_timer();
+    }
+  }
+
+  // Closes both sides of the connection.
+  pub fn close_fully(&mut self, ctx: &mut SendContext) -> Result<(), TcpError> {
+    self.shutdown_read();
+    self.close(ctx)
+  }
+
+  fn start_fin_wait_2_timer(&mut self) {
+    if !self.isReadShutdown || self.finWait2EndsAt.is_some() {
+      return;
+    }
+    self.finWait2EndsAt = self
+      .tuning
+      .finWait2Timeout
+      .map(|finWait2Timeout| self.clock.now() + finWait2Timeout);
+  }
+
+  /*
+    Reads the in-order data received so far. Returns WouldBlock when there's nothing to read yet,
+    and Ok(0) once the peer has closed its side of the connection and everything it sent before
+    its FIN has been read.
+
+    Like with std, reading into an empty buffer returns Ok(0) right away, whatever the state of the
+    connection. Nothing gets consumed, so neither does a pending end of stream (or error).
+  */
+  pub fn read(&mut self, buffer: &mut [u8], ctx: &mut SendContext) -> Result<usize, TcpError> {
+    if buffer.is_empty() {
+      return Ok(0);
+    }
+
+    if self.receiveBuffer.is_empty() {
+      return match self.state {
+        TCPConnectionState::CloseWait
+        | TCPConnectionState::Closing
+        | TCPConnectionState::TimeWait
+        | TCPConnectionState::LastAck => Ok(0),
+
+        TCPConnectionState::Closed if self.closeReason == Some(CloseReason::Graceful) => Ok(0),
+        TCPConnectionState::Closed => Err(self.closed_error()),
+
+        _ => Err(TcpError::WouldBlock),
+      };
+    }
+
+    let bytesRead = buffer.len().min(self.receiveBuffer.len());
+    for (byte, receivedByte) in buffer.iter_mut().zip(self.receiveBuffer.drain(..bytesRead)) {
+      *byte = receivedByte;
+    }
+    self.on_received_data_consumed(ctx.nic).map_err(nic_error)?;
+
+    Ok(bytesRead)
+  }
+
+  // Why user calls fail, once the connection is closed. Only a graceful close isn't a failure.
+  fn closed_error(&self) -> TcpError {
+    match self.closeReason {
+      None | Some(CloseReason::Graceful) => TcpError::NotConnected,
+      Some(CloseReason::Refused) => TcpError::ConnectionRefused,
+      Some(CloseReason::ConnectTimeout | CloseReason::UserTimeout) => TcpError::TimedOut,
+      Some(
+        reason @ (CloseReason::Reset
+        | CloseReason::Aborted
+        | CloseReason::PeerViolation
+        | CloseReason::NicFailed
+        | CloseReason::Desync
+        | CloseReason::FinWait2Timeout),
+      ) => TcpError::ConnectionReset { reason },
+    }
+  }
+
+  // The received data which is yet to be read, as the two contiguous halves of the receive
+  // buffer.
+  pub(crate) fn received_data(&self) -> (&[u8], &[u8]) {
+    self.receiveBuffer.as_slices()
+  }
+
+  // Discards the given number of bytes from the front of the received data, once the user has
+  // processed them in place.
+  pub(crate) fn consume_received_data(&mut self, length: usize, nic: &Nic) -> anyhow::Result<()> {
+    self
+      .receiveBuffer
+      .drain(..length.min(self.receiveBuffer.len()));
+    self.on_received_data_consumed(nic)
+  }
+
+  /*
+    The receive window grows as the user consumes data. The peer learns about it through a window
+    update ACK, unless some other segment carries it first. A reader consuming a byte at a time
+    would have us send an ACK per byte though, so :
+
+      (1) The window only gets advertised once it has grown by at least the smaller of 1 MSS and
+          half the receive buffer, beyond what we last advertised (receiver side silly window
+          syndrome avoidance, RFC 9293 section 3.8.6.2.2).
+
+      (2) Unsolicited window updates are sent at most once per windowUpdateInterval. One held
+          back meanwhile goes out on a later tick.
+
+      (3) Except when the window we last advertised was zero, since the peer is blocked on it.
+  */
+  fn on_received_data_consumed(&mut self, nic: &Nic) -> anyhow::Result<()> {
+    self.update_receive_window();
+
+    let threshold = self.maximumSegmentSize.min(RECEIVE_BUFFER_CAPACITY / 2) as u16;
+    if self
+      .receiveSequenceVariables
+      .windowSize
+      .saturating_sub(self.advertisedWindowSize)
+      < threshold
+    {
+      return Ok(());
+    }
+    self.isWindowUpdatePending = true;
+
+    if self.advertisedWindowSize == 0 {
+      return self.send_window_update(self.clock.now(), nic);
+    }
+    self.flush_window_update(self.clock.now(), nic)
+  }
+
+  // Sends the pending window update, unless the rate limit holds it back.
+  fn flush_window_update(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    if !self.isWindowUpdatePending || !self.state.is_synchronized() {
+      return Ok(());
+    }
+
+    let isRateLimited = self.lastWindowUpdateAt.is_some_and(|lastWindowUpdateAt| {
+      now.duration_since(lastWindowUpdateAt) < self.tuning.windowUpdateInterval
+    });
+    if isRateLimited {
+      self.stats.record_window_update_suppressed();
+      return Ok(());
+    }
+
+    self.send_window_update(now, nic)
+  }
+
+  fn send_window_update(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    if !self.state.is_synchronized() {
+      return Ok(());
+    }
+
+    self.lastWindowUpdateAt = Some(now);
+    self.stats.record_window_update_sent();
+
+    self.send_acknowledgement(nic)
+  }
+
+  /*
+    Starts actively opening the connection, by sending our SYN : <SEQ=ISS><CTL=SYN>.
+
+    The SYN gets retransmitted with exponential backoff, till either the peer answers it, or the
+    configured number of transmissions / the connect timeout runs out.
+  */
+  pub fn open(&mut self, nic: &Nic) -> anyhow::Result<()> {
+    let initialSendSequenceNumber = self.choose_initial_send_sequence_number();
+    self.sendSequenceVariables = SendSequenceVariables {
+      initialSendSequenceNumber,
+      oldestUnacknowledgedSequenceNumber: initialSendSequenceNumber,
+      nextSequenceNumber: initialSendSequenceNumber,
+      windowSize: 0,
+      lastWindowUpdateSegmentSequenceNumber: 0,
+      lastWindowUpdateAcknowledgementNumber: initialSendSequenceNumber,
+    };
+
+    self.start_syn_retransmission();
+
+    let synPacketTCPHeader = self.create_syn_header();
+    self.send_segment(synPacketTCPHeader, &[], nic)
+  }
+
+  fn choose_initial_send_sequence_number(&self) -> u32 {
+    self
+      .overrides
+      .initialSendSequenceNumber
+      .unwrap_or_else(|| isn::initial_sequence_number(&self.quad))
+  }
+
+  // Arms the retransmission of the SYN (or SYN-ACK) which just got sent for the first time.
+  fn start_syn_retransmission(&mut self) {
+    let now = self.clock.now();
+
+    self.synRetransmission = Some(SYNRetransmission {
+      transmissions: 1,
+      retransmissionTimeout: self.tuning.initialSYNRetransmissionTimeout,
+      retransmitAt: now + self.tuning.initialSYNRetransmissionTimeout,
+      deadline: now + self.tuning.connectTimeout,
+    });
+  }
+
+  // Processes a segment as per the SYN-SENT state (RFC 9293 section 3.10.7.3).
+  fn on_syn_sent_segment(
+    &mut self,
+    incomingPacketTCPHeader: &TcpHeaderSlice,
+    nic: &Nic,
+  ) -> anyhow::Result<()> {
+    let initialSendSequenceNumber = self.sendSequenceVariables.initialSendSequenceNumber;
+
+    // (1) Check the ACK bit. It must acknowledge our SYN : ISS < SEG.ACK =< SND.NXT.
+    let acknowledgementNumber = incomingPacketTCPHeader.acknowledgment_number();
+    let isAcknowledgementAcceptable = incomingPacketTCPHeader.ack()
+      && sequence_lt(initialSendSequenceNumber, acknowledgementNumber)
+      && sequence_le(
+        acknowledgementNumber,
+        self.sendSequenceVariables.nextSequenceNumber,
+      );
+
+    if incomingPacketTCPHeader.ack() && !isAcknowledgementAcceptable {
+      // Unless the segment is a RST itself, it gets answered with <SEQ=SEG.ACK><CTL=RST>.
+      if incomingPacketTCPHeader.rst() {
+        return Ok(());
+      }
+      return send_reset(&self.quad, incomingPacketTCPHeader, 0, nic);
+    }
+
+    // (2) Check the RST bit. A RST acknowledging our SYN means the peer refuses the connection.
+    if incomingPacketTCPHeader.rst() {
+      if isAcknowledgementAcceptable {
+        self.synRetransmission = None;
+        self.enter_closed(CloseReason::Refused);
+      }
+      return Ok(());
+    }
+
+    // (4) Check the SYN bit. A segment with neither SYN nor RST is dropped.
+    if !incomingPacketTCPHeader.syn() {
+      return Ok(());
+    }
+
+    let sequenceNumber = incomingPacketTCPHeader.sequence_number();
+
+    self.receiveSequenceVariables.initialReceiveSequenceNumber = sequenceNumber;
+    self.receiveSequenceVariables.nextByteSequenceNumber = sequenceNumber.wrapping_add(1);
+
+    self.peerMaximumSegmentSize = maximum_segment_size_option(incomingPacketTCPHeader.options());
+
+    // A SYN-ACK answers our SYN, so whatever our SYN offered and it doesn't got declined.
+    let declinedExtensions = self.extensions.on_syn(
+      ExtensionOptions::of(incomingPacketTCPHeader),
+      isAcknowledgementAcceptable,
+    );
+    for extension in declinedExtensions {
+      self.stats.record_extension_fallback(extension);
+    }
+
+    self.sendSequenceVariables.windowSize = incomingPacketTCPHeader.window_size();
+    self
+      .sendSequenceVariables
+      .lastWindowUpdateSegmentSequenceNumber = sequenceNumber;
+
+    self.synRetransmission = None;
+
+    if isAcknowledgementAcceptable {
+      // Our SYN has been acknowledged, so the connection is established.
+      self
+        .sendSequenceVariables
+        .lastWindowUpdateAcknowledgementNumber = acknowledgementNumber;
+      self.acknowledge(acknowledgementNumber);
+
+      self.enter(
+        TCPConnectionState::Established,
+        TransitionReason::HandshakeCompleted,
+      );
+
+      // Data written while connecting goes out right away, carrying the ACK completing the
+      // handshake.
+      return self.transmit_or_acknowledge(nic);
+    }
+
+    // Simultaneous open : the peer's SYN crossed ours. Our SYN gets sent again, now acknowledging
+    // the peer's : <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>. Its sequence number gets accounted for
+    // again along the way.
+    self.sendSequenceVariables.nextSequenceNumber = initialSendSequenceNumber;
+    self
+      .sendSequenceVariables
+      .lastWindowUpdateAcknowledgementNumber = initialSendSequenceNumber;
+    self.enter(
+      TCPConnectionState::SYNReceived,
+      TransitionReason::SYNReceived,
+    );
+    self.start_syn_retransmission();
+
+    let synAckPacketTCPHeader = self.create_syn_header();
+    self.send_segment(synAckPacketTCPHeader, &[], nic)
+  }
+
+  fn retransmit_fin(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    let Some(sentFinSequenceNumber) = self.sentFinSequenceNumber
+    else {
+      return Ok(());
+    };
+
+    let isFinAcknowledged = sequence_lt(
+      sentFinSequenceNumber,
+      self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber,
+    );
+    if isFinAcknowledged || now.duration_since(self.finSentAt) < RETRANSMISSION_TIMEOUT {
+      return Ok(());
+    }
+    self.finSentAt = now;
+    self.stats.record_retransmission();
+
+    let mut finPacketTCPHeader = self.create_fin_header();
+    finPacketTCPHeader.sequence_number = sentFinSequenceNumber;
+
+    self.assert_send_invariants(&finPacketTCPHeader, 0);
+    write_segment(&self.quad, finPacketTCPHeader, &[], nic)
+  }
+
+  // Retransmits our SYN while in the SYN-SENT state (or our SYN-ACK while in the SYN-RECEIVED
+  // state), or gives up on the handshake.
+  fn retransmit_syn(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    let maximumSYNTransmissions = match self.state {
+      TCPConnectionState::SYNReceived => self.tuning.maximumSYNACKTransmissions,
+      _ => self.tuning.maximumSYNTransmissions,
+    };
+
+    let Some(synRetransmission) = &mut self.synRetransmission
+    else {
+      return Ok(());
+    };
+
+    if now < synRetransmission.retransmitAt && now < synRetransmission.deadline {
+      return Ok(());
+    }
+
+    if now >= synRetransmission.deadline
+      || synRetransmission.transmissions >= maximumSYNTransmissions
+    {
+      self.synRetransmission = None;
+      self.enter_closed(CloseReason::ConnectTimeout);
+      return Ok(());
+    }
+
+    synRetransmission.transmissions += 1;
+    synRetransmission.retransmissionTimeout *= 2;
+    synRetransmission.retransmitAt = now + synRetransmission.retransmissionTimeout;
+
+    let synPacketTCPHeader = self.create_syn_header();
+    self.assert_send_invariants(&synPacketTCPHeader, 0);
+    write_segment(&self.quad, synPacketTCPHeader, &[], nic)
+  }
+
+  // <SEQ=ISS><CTL=SYN> in the SYN-SENT state, and <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK> otherwise.
+  // Along with the MSS, it offers the extensions (see extensions.rs).
+  fn create_syn_header(&self) -> TcpHeader {
+    let mut synPacketTCPHeader = self.create_tcp_header();
+    synPacketTCPHeader.sequence_number = self.sendSequenceVariables.initialSendSequenceNumber;
+    synPacketTCPHeader.syn = true;
+
+    let mut options = vec![TcpOptionElement::MaximumSegmentSize(
+      self.maximumSegmentSize as u16,
+    )];
+    options.extend(
+      self
+        .extensions
+        .syn_options(self.state != TCPConnectionState::SYNSent, self.timestamp()),
+    );
+    synPacketTCPHeader
+      .set_options(&options)
+      .expect("SYN options don't fit in the TCP header");
+
+    // Only our SYN in the SYN-SENT state has nothing to acknowledge yet.
+    if self.state == TCPConnectionState::SYNSent {
+      synPacketTCPHeader.acknowledgment_number = 0;
+    }
+    else {
+      synPacketTCPHeader.ack = true;
+    }
+
+    synPacketTCPHeader
+  }
+
+  // Processes a segment as per the SYN-RECEIVED and synchronized states (RFC 9293 section
+  // 3.10.7.4).
+  fn on_synchronized_segment(
+    &mut self,
+    incomingPacketTCPHeader: &TcpHeaderSlice,
+    incomingPacketPayload: &[u8],
+    nic: &Nic,
+  ) -> anyhow::Result<()> {
+    let sequenceNumber = incomingPacketTCPHeader.sequence_number();
+
+    /*
+      A retransmission of the SYN-ACK which established an actively opened connection, sent since
+      our handshake ACK got lost. The ACK gets sent again. Its SYN lies before RCV.NXT, so the
+      segment would be dropped as unacceptable (and acknowledged) anyway, but it never gets near
+      the SYN handling below, which would challenge it.
+    */
+    if self.is_duplicate_syn_ack(incomingPacketTCPHeader, incomingPacketPayload.len()) {
+      self.stats.record_duplicate_syn_ack();
+      return self.send_acknowledgement(nic);
+    }
+
+    // PAWS (RFC 7323 section 5.3) : a segment carrying an older timestamp than the last one is an
+    // old duplicate, which gets acknowledged and dropped like an unacceptable one.
+    let options = ExtensionOptions::of(incomingPacketTCPHeader);
+    match self
+      .extensions
+      .check_timestamp(&options, incomingPacketTCPHeader.rst())
+    {
+      TimestampCheck::Accepted => {}
+      TimestampCheck::Old => return self.send_acknowledgement(nic),
+      TimestampCheck::Missing => self.fall_back(Extension::Timestamps),
+    }
+
+    /*
+      (1) Check the sequence number.
+
+      With RCV.WND zero, no segment carrying data (or a FIN) is acceptable. One sitting right at
+      RCV.NXT still gets processed like an empty one though, so that its ACK and RST don't get lost
+      (RFC 9293 section 3.10.7.4) : like a window probe acknowledging our own data. Only its text
+      gets dropped, and acknowledged to tell the peer the window is still shut.
+    */
+    let isTextDropped = self.receiveSequenceVariables.windowSize == 0
+      && sequenceNumber == self.receiveSequenceVariables.nextByteSequenceNumber
+      && !incomingPacketTCPHeader.syn()
+      && (!incomingPacketPayload.is_empty() || incomingPacketTCPHeader.fin());
+    if !isTextDropped
+      && !self.is_segment_acceptable(incomingPacketTCPHeader, incomingPacketPayload.len())
+    {
+      // If an incoming segment is not acceptable, an acknowledgment should be sent in reply
+      // (unless the RST bit is set) and the segment dropped.
+      //
+      // A SYN gets challenged irrespective of its sequence number (RFC 5961 section 4.2). Most
+      // land here rather than in the SYN check below : a client which restarted and reused its
+      // source port picks a fresh ISN, which is hardly ever in the window. Its reply to the
+      // challenge is a RST with SEQ = our RCV.NXT, which aborts this connection, so that its
+      // retransmitted SYN finds the quad free.
+      if !incomingPacketTCPHeader.rst() {
+        if incomingPacketTCPHeader.syn() {
+          self.stats.record_challenge_acknowledgement();
+        }
+        self.send_acknowledgement(nic)?;
+
+        let isDuplicate = !incomingPacketTCPHeader.syn()
+          && !incomingPacketPayload.is_empty()
+          && sequence_le(
+            sequenceNumber.wrapping_add(incomingPacketPayload.len() as u32),
+            self.receiveSequenceVariables.nextByteSequenceNumber,
+          );
+        if isDuplicate && self.on_duplicate_segment() {
+          self.send_acknowledgement(nic)?;
+        }
+      }
+      return Ok(());
+    }
+
+    // The timestamp to echo back is the one of the segment carrying the data we acknowledge next.
+    if sequence_le(
+      sequenceNumber,
+      self.receiveSequenceVariables.nextByteSequenceNumber,
+    ) {
+      self.extensions.update_recent_timestamp(&options);
+    }
+
+    /*
+      (2) Check the RST bit. The RST wins over any other flag set along with it.
+
+      As per RFC 5961 section 3.2, only a RST whose sequence number is exactly RCV.NXT resets the
+      connection. Any other RST in the window gets answered with a challenge ACK, which a genuine
+      peer answers with a RST carrying the right sequence number. This way, an attacker has to
+      guess the exact sequence number, instead of just any number in the window.
+    */
+    if incomingPacketTCPHeader.rst() {
+      if sequenceNumber != self.receiveSequenceVariables.nextByteSequenceNumber {
+        self.stats.record_challenge_acknowledgement();
+        return self.send_acknowledgement(nic);
+      }
+
+      // The connection gets reset, and any data which the user is yet to read gets flushed. A
+      // connection we opened, reset before getting established (after a simultaneous open), got
+      // refused (RFC 9293 section 3.10.7.4).
+      let reason = if self.state == TCPConnectionState::SYNReceived && !self.isPassiveOpen {
+        CloseReason::Refused
+      }
+      else {
+        CloseReason::Reset
+      };
+
+      self.receiveBuffer.clear();
+      self.enter_closed(reason);
+      return Ok(());
+    }
+
+    // A SYN+FIN, or a segment without either of SYN and ACK (like FIN+PSH+URG), can't come from a
+    // sane peer. It gets dropped, leaving the connection untouched.
+    if has_nonsensical_flags(incomingPacketTCPHeader) {
+      self.stats.record_nonsensical_segment();
+      return Ok(());
+    }
+
+    // (3) Check the SYN bit. A SYN in the window is an error, which gets answered with a
+    // challenge ACK (RFC 5961 section 4) rather than a reset : a genuine peer which lost its
+    // connection answers the challenge with a RST, while an attacker blindly guessing sequence
+    // numbers learns nothing.
+    if incomingPacketTCPHeader.syn() {
+      self.stats.record_challenge_acknowledgement();
+      return self.send_acknowledgement(nic);
+    }
+
+    // The MSS, window scale and SACK-permitted options may only be sent on SYN segments.
+    let carriesSYNOnlyOptions =
+      option_kinds(incomingPacketTCPHeader.options()).any(|kind| matches!(kind, 2..=4));
+
+    if carriesSYNOnlyOptions && !self.tolerate(PeerViolation::SYNOnlyOptionsOnNonSYN, nic)? {
+      return Ok(());
+    }
+
+    // (4) Check the ACK field. Segments without an ACK are dropped.
+    if !incomingPacketTCPHeader.ack() {
+      return Ok(());
+    }
+    let acknowledgementNumber = incomingPacketTCPHeader.acknowledgment_number();
+
+    let sendWindowEnd = self
+      .sendSequenceVariables
+      .oldestUnacknowledgedSequenceNumber
+      .wrapping_add(self.sendSequenceVariables.windowSize as u32);
+
+    let isAcknowledgementAcceptable = sequence_lt(
+      self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber,
+      acknowledgementNumber,
+    ) && sequence_le(
+      acknowledgementNumber,
+      self.sendSequenceVariables.nextSequenceNumber,
+    );
+
+    if self.state == TCPConnectionState::SYNReceived {
+      // The ACK completing the three way handshake must acknowledge our SYN. Otherwise the
+      // segment can only be from an older incarnation of the connection, so it gets reset.
+      if !isAcknowledgementAcceptable {
+        let mut rstPacketTCPHeader = self.create_tcp_header();
+        rstPacketTCPHeader.sequence_number = acknowledgementNumber;
+        rstPacketTCPHeader.acknowledgment_number = 0;
+        rstPacketTCPHeader.rst = true;
+
+        self.stats.record_reset_sent();
+        return self.send_segment(rstPacketTCPHeader, &[], nic);
+      }
+
+      self.synRetransmission = None;
+
+      // If the user closed the connection meanwhile, our FIN can go out now.
+      let nextState = if self.finQueued {
+        TCPConnectionState::FinWait1
+      }
+      else {
+        TCPConnectionState::Established
+      };
+      self.enter(nextState, TransitionReason::HandshakeCompleted);
+    }
+
+    let windowSize = self
+      .extensions
+      .peer_window(incomingPacketTCPHeader.window_size());
+
+    let isDuplicateAcknowledgement = acknowledgementNumber
+      == self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber
+      && incomingPacketPayload.is_empty()
+      && !incomingPacketTCPHeader.fin()
+      && windowSize == self.sendSequenceVariables.windowSize
+      && self.sendBuffer.in_flight_len() > 0;
+    if self.extensions.on_acknowledgement(
+      &options,
+      isDuplicateAcknowledgement,
+      self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber,
+      self.sendSequenceVariables.nextSequenceNumber,
+    ) {
+      self.fall_back(Extension::SelectiveAcknowledgement);
+    }
+
+    if isAcknowledgementAcceptable {
+      self.acknowledge(acknowledgementNumber);
+    }
+    else if sequence_lt(
+      self.sendSequenceVariables.nextSequenceNumber,
+      acknowledgementNumber,
+    ) {
+      // The ACK acknowledges something not yet sent. Send an ACK and drop the segment, unless the
+      // ACK is only off by a few bytes and is tolerated.
+      let excess =
+        acknowledgementNumber.wrapping_sub(self.sendSequenceVariables.nextSequenceNumber);
+
+      if excess > UNSENT_DATA_ACKNOWLEDGEMENT_SLACK
+        || !self.tolerate(PeerViolation::AcknowledgedUnsentData, nic)?
+      {
+        return self.send_acknowledgement(nic);
+      }
+
+      self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber = self.sendSequenceVariables.nextSequenceNumber;
+    }
+
+    // Update the send window, unless the segment is older than the one last used to do so.
+    if self.is_window_update(sequenceNumber, acknowledgementNumber) {
+      // The peer shrinks its window by moving the right edge of the window to the left.
+      let newSendWindowEnd = acknowledgementNumber.wrapping_add(windowSize as u32);
+
+      if sequence_lt(newSendWindowEnd, sendWindowEnd)
+        && !self.tolerate(PeerViolation::ShrunkWindow, nic)?
+      {
+        return Ok(());
+      }
+
+      self.update_send_window(sequenceNumber, acknowledgementNumber, windowSize);
+    }
+
+    // Once our FIN has been acknowledged, our side of the connection is done.
+    let isFinAcknowledged = self
+      .sentFinSequenceNumber
+      .is_some_and(|sentFinSequenceNumber| {
+        sequence_lt(
+          sentFinSequenceNumber,
+          self
+            .sendSequenceVariables
+            .oldestUnacknowledgedSequenceNumber,
+        )
+      });
+
+    if isFinAcknowledged {
+      match self.state {
+        TCPConnectionState::FinWait1 => {
+          self.enter(
+            TCPConnectionState::FinWait2,
+            TransitionReason::FinAcknowledged,
+          );
+          self.start_fin_wait_2_timer();
+        }
+        TCPConnectionState::Closing => self.enter_time_wait(TransitionReason::FinAcknowledged),
+
+        TCPConnectionState::LastAck => {
+          self.enter_closed(CloseReason::Graceful);
+          return Ok(());
+        }
+
+        _ => {}
+      }
+    }
+
+    // (5) Process the segment text, and (6) check the FIN bit.
+    if isTextDropped {
+      return self.transmit_or_acknowledge(nic);
+    }
+    let mut payload = incomingPacketPayload;
+    let mut fin = incomingPacketTCPHeader.fin();
+
+    // The peer may neither move its FIN once sent, nor send data beyond it.
+    if let Some(finSequenceNumber) = self.finSequenceNumber {
+      let segmentEnd = sequenceNumber.wrapping_add(payload.len() as u32);
+
+      if fin && segmentEnd != finSequenceNumber {
+        if !self.tolerate(PeerViolation::MovedFIN, nic)? {
+          return Ok(());
+        }
+        fin = false;
+      }
+
+      if sequence_lt(finSequenceNumber, segmentEnd) {
+        if !self.tolerate(PeerViolation::DataAfterFIN, nic)? {
+          return Ok(());
+        }
+
+        let dataBeforeFINLength = if sequence_lt(sequenceNumber, finSequenceNumber) {
+          finSequenceNumber.wrapping_sub(sequenceNumber) as usize
+        }
+        else {
+          0
+        };
+        payload = &payload[..dataBeforeFINLength];
+      }
+    }
+
+    // Once the peer's FIN has been consumed, nothing it sends afterwards is processed any further.
+    let isReceiving = matches!(
+      self.state,
+      TCPConnectionState::Established | TCPConnectionState::FinWait1 | TCPConnectionState::FinWait2
+    );
+
+    if isReceiving && (fin || !payload.is_empty()) {
+      self.receive(sequenceNumber, payload, fin);
+
+      /*
+        Acknowledge everything received in order so far. Without a gap, the ACK rides on whatever
+        data the send window lets out, like a response written while the connection was still
+        being established (which this very segment may have just completed). Otherwise it's a
+        duplicate ACK telling the peer where the gap begins, which has to be a bare one for the
+        peer to count it as such.
+      */
+      if self.outOfOrderSegments.is_empty() {
+        return self.transmit_or_acknowledge(nic);
+      }
+      self.send_acknowledgement(nic)?;
+    }
+
+    // The acknowledgment may have opened up the send window.
+    self.transmit(nic)
+  }
+
+  /*
+    Takes in the data and FIN carried by an acceptable segment.
+
+    Data gets delivered to the receive buffer in sequence order only. Anything which arrives ahead
+    of RCV.NXT is stashed away, till retransmissions fill the gap before it.
+
+    Likewise, the FIN gets consumed only when RCV.NXT reaches its sequence number. Consider the peer
+    sending segments A (seq 100-199) and B (seq 200-299, FIN), with A getting lost and retransmitted
+    later. If the FIN was consumed when B arrived, the user would see EOF after missing 100 bytes.
+
+    A segment can also overlap RCV.NXT : having received 100-199 and lost 200-299, the peer may
+    retransmit 150-349 in one segment. Its head (150-199) is dropped and its tail (200-349)
+    delivered. Delivering it rebases the stash, which drops whatever the tail duplicated of the
+    stashed segments and trims the ones it partially covers, so that the stashed bytes after 349
+    get delivered next, exactly once. The single ACK sent afterwards then covers all of it.
+  */
+  fn receive(&mut self, sequenceNumber: u32, payload: &[u8], fin: bool) {
+    if fin && self.finSequenceNumber.is_none() {
+      self.finSequenceNumber = Some(sequenceNumber.wrapping_add(payload.len() as u32));
+    }
+
+    // Drop the part of the payload we've already received, and the part beyond the window.
+    let receiveNext = self.receiveSequenceVariables.nextByteSequenceNumber;
+
+    let alreadyReceivedLength = if sequence_lt(sequenceNumber, receiveNext) {
+      receiveNext.wrapping_sub(sequenceNumber) as usize
+    }
+    else {
+      0
+    };
+    let start = alreadyReceivedLength.min(payload.len());
+
+    let offset = sequenceNumber
+      .wrapping_add(start as u32)
+      .wrapping_sub(receiveNext) as usize;
+    let end = payload
+      .len()
+      .min(start + (self.receiveSequenceVariables.windowSize as usize).saturating_sub(offset));
+
+    if start < end {
+      let data = &payload[start..end];
+      if alreadyReceivedLength > 0 {
+        self.stats.record_overlapping_segment();
+      }
+
+      if offset == 0 {
+        self.deliver(data);
+      }
+      else {
+        self.stats.record_out_of_order_segment();
+        let stashedData = self.outOfOrderSegments.entry(offset as u32).or_default();
+
+        if stashedData.len() < data.len() {
+          *stashedData = data.to_vec();
+        }
+      }
+    }
+
+    // Deliver every stashed segment whose gap has now been filled. Rebasing has moved those to
+    // the front, trimmed to start right at RCV.NXT.
+    while let Some(data) = self.outOfOrderSegments.remove(&0) {
+      self.deliver(&data);
+    }
+
+    // The FIN occupies the sequence number right after the last data byte.
+    if self.finSequenceNumber == Some(self.receiveSequenceVariables.nextByteSequenceNumber) {
+      self.receiveSequenceVariables.nextByteSequenceNumber = self
+        .receiveSequenceVariables
+        .nextByteSequenceNumber
+        .wrapping_add(1);
+
+      self.outOfOrderSegments.clear();
+
+      match self.state {
+        TCPConnectionState::FinWait1 => {
+          self.enter(TCPConnectionState::Closing, TransitionReason::FinReceived)
+        }
+        TCPConnectionState::FinWait2 => self.enter_time_wait(TransitionReason::FinReceived),
+        _ => self.enter(TCPConnectionState::CloseWait, TransitionReason::FinReceived),
+      }
+    }
+  }
+
+  fn enter_time_wait(&mut self, reason: TransitionReason) {
+    self.enter(TCPConnectionState::TimeWait, reason);
+    self.timeWaitEndsAt = Some(self.clock.now() + TIME_WAIT_DURATION);
+  }
+
+  // Advances SND.UNA, upon the peer acknowledging new data.
+  fn acknowledge(&mut self, acknowledgementNumber: u32) {
+    self
+      .sendSequenceVariables
+      .oldestUnacknowledgedSequenceNumber = acknowledgementNumber;
+    self.sendBuffer.acknowledge(acknowledgementNumber);
+    self.extensions.forget_acknowledged(acknowledgementNumber);
+
+    if self.isWriterBlocked && self.sendBuffer.room() >= self.sendLowWatermark {
+      self.isWriterBlocked = false;
+      self.isWriterWakeupPending = true;
+      self.stats.record_writer_wakeup();
+    }
+  }
+
+  // Sends as much of the unsent data as the peer's window allows, in segments of at most the MSS.
+  // Once all of it has been sent, follows up with our FIN if the user has closed the connection.
+  fn transmit(&mut self, nic: &Nic) -> anyhow::Result<()> {
+    if !self.state.is_synchronized() {
+      return Ok(());
+    }
+
+    // A segment held back by the reorderNextSegments override, till the one after it has been sent.
+    let mut heldBackSegment = None;
+
+    loop {
+      let bytesInFlight = self.sendSequenceVariables.nextSequenceNumber.wrapping_sub(
+        self
+          .sendSequenceVariables
+          .oldestUnacknowledgedSequenceNumber,
+      );
+      let usableWindow =
+        (self.sendSequenceVariables.windowSize as u32).saturating_sub(bytesInFlight) as usize;
+
+      let Some(segment) = self.sendBuffer.next_segment(
+        self.sendSequenceVariables.nextSequenceNumber,
+        usableWindow.min(self.send_maximum_segment_size()),
+        self.clock.now(),
+      )
+      else {
+        break;
+      };
+
+      let mut dataPacketTCPHeader = self.create_tcp_header();
+      dataPacketTCPHeader.ack = true;
+      // Push once the send buffer has been emptied out.
+      dataPacketTCPHeader.psh = !self.sendBuffer.has_unsent_data();
+
+      if self.overrides.reorderNextSegments && heldBackSegment.is_none() {
+        self.account_segment(&dataPacketTCPHeader, segment.payload().len());
+        heldBackSegment = Some((dataPacketTCPHeader, segment));
+        continue;
+      }
+
+      self.send_segment(dataPacketTCPHeader, segment.payload(), nic)?;
+
+      if let Some(heldBackSegment) = heldBackSegment.take() {
+        self.overrides.reorderNextSegments = false;
+        self.send_held_back_segment(heldBackSegment, nic)?;
+      }
+    }
+
+    // Nothing followed the held back segment, so it goes out in order after all.
+    if let Some(heldBackSegment) = heldBackSegment {
+      self.send_held_back_segment(heldBackSegment, nic)?;
+    }
+
+    if self.finQueued && self.sentFinSequenceNumber.is_none() && !self.sendBuffer.has_unsent_data()
+    {
+      self.sentFinSequenceNumber = Some(self.sendSequenceVariables.nextSequenceNumber);
+      self.finFirstSentAt = self.clock.now();
+      self.finSentAt = self.finFirstSentAt;
+
+      let finPacketTCPHeader = self.create_fin_header();
+      self.send_segment(finPacketTCPHeader, &[], nic)?;
+    }
+    Ok(())
+  }
+
+  // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=FIN,ACK>
+  fn create_fin_header(&self) -> TcpHeader {
+    let mut finPacketTCPHeader = self.create_tcp_header();
+    finPacketTCPHeader.ack = true;
+    finPacketTCPHeader.fin = true;
+
+    finPacketTCPHeader
+  }
+
+  /*
+    Resends the oldest unacknowledged segment, once it has been in flight for longer than the
+    retransmission timeout, along with every later one the peer's SACKs show to be lost too. Our
+    FIN gets resent likewise, once every byte before it has been acknowledged.
+  */
+  fn retransmit(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    let Some(segment) = self.sendBuffer.oldest_in_flight_segment_mut()
+    else {
+      return self.retransmit_fin(now, nic);
+    };
+
+    if now.duration_since(segment.sentAt) < RETRANSMISSION_TIMEOUT {
+      return Ok(());
+    }
+    segment.sentAt = now;
+    let segment = segment.clone();
+    self.stats.record_retransmission();
+
+    let extensions = &self.extensions;
+    let lostSegments: Vec<InFlightSegment> = self
+      .sendBuffer
+      .in_flight_segments_mut()
+      .skip(1)
+      .filter(|segment| {
+        let segmentEnd = segment
+          .sequenceNumber
+          .wrapping_add(segment.payload().len() as u32);
+        extensions.is_lost(segment.sequenceNumber, segmentEnd)
+      })
+      .map(|segment| {
+        segment.sentAt = now;
+        segment.clone()
+      })
+      .collect();
+
+    for _ in &lostSegments {
+      self.stats.record_retransmission();
+    }
+
+    for segment in iter::once(segment).chain(lostSegments) {
+      let mut dataPacketTCPHeader = self.create_tcp_header();
+      dataPacketTCPHeader.sequence_number = segment.sequenceNumber;
+      dataPacketTCPHeader.ack = true;
+
+      self.assert_send_invariants(&dataPacketTCPHeader, segment.payload().len());
+      write_segment(&self.quad, dataPacketTCPHeader, segment.payload(), nic)?;
+    }
+    Ok(())
+  }
+
+  /*
+    A shut window gets reopened by an ACK of the peer, which may get lost like any other segment.
+    Nothing would ever be sent to the peer again then, so nothing would ever make it repeat that
+    ACK. Once the window has been shut for RETRANSMISSION_TIMEOUT, a byte gets sent past it
+    instead : the peer either takes it in, or answers it with its current window. The byte is in
+    flight like any other, so it gets retransmitted till it's acknowledged, which keeps probing the
+    window.
+  */
+  fn probe_zero_window(&mut self, now: Instant, nic: &Nic) -> anyhow::Result<()> {
+    let isWindowShut = self.state.is_synchronized()
+      && self.sendSequenceVariables.windowSize == 0
+      && self.sendBuffer.in_flight_len() == 0
+      && self.sendBuffer.has_unsent_data();
+    if !isWindowShut {
+      self.zeroWindowSince = None;
+      return Ok(());
+    }
+
+    let zeroWindowSince = *self.zeroWindowSince.get_or_insert(now);
+    if now.duration_since(zeroWindowSince) < RETRANSMISSION_TIMEOUT {
+      return Ok(());
+    }
+    self.zeroWindowSince = None;
+
+    let Some(segment) =
+      self
+        .sendBuffer
+        .next_segment(self.sendSequenceVariables.nextSequenceNumber, 1, now)
+    else {
+      return Ok(());
+    };
+
+    let mut dataPacketTCPHeader = self.create_tcp_header();
+    dataPacketTCPHeader.ack = true;
+    self.send_segment(dataPacketTCPHeader, segment.payload(), nic)
+  }
+
+  // Appends in-order data to the receive buffer, and advances RCV.NXT past it.
+  fn deliver(&mut self, data: &[u8]) {
+    self.receiveBuffer.extend(data);
+
+    // The peer is making progress, so it got our ACKs after all.
+    self.duplicateSegments = 0;
+    self.isReverseLossSuspected = false;
+
+    self.receiveSequenceVariables.nextByteSequenceNumber = self
+      .receiveSequenceVariables
+      .nextByteSequenceNumber
+      .wrapping_add(data.len() as u32);
+    self.rebase_out_of_order_segments(data.len() as u32);
+
+    self.update_receive_window();
+  }
+
+  // Keeps the stashed segments keyed by their distance from RCV.NXT, after it advanced by the given
+  // number of bytes. The part of a segment which got delivered meanwhile is dropped.
+  fn rebase_out_of_order_segments(&mut self, advancedLength: u32) {
+    if self.outOfOrderSegments.is_empty() || advancedLength == 0 {
+      return;
+    }
+
+    let stashedSegments = std::mem::take(&mut self.outOfOrderSegments);
+    for (offset, data) in stashedSegments {
+      let (offset, data) = match offset.checked_sub(advancedLength) {
+        Some(offset) => (offset, data),
+
+        None => {
+          let alreadyReceivedLength = (advancedLength - offset) as usize;
+          if alreadyReceivedLength >= data.len() {
+            continue;
+          }
+          (0, data[alreadyReceivedLength..].to_vec())
+        }
+      };
+
+      let stashedData = self.outOfOrderSegments.entry(offset).or_default();
+      if stashedData.len() < data.len() {
+        *stashedData = data;
+      }
+    }
+  }
+
+  // The window is exactly the free space in the receive buffer. A segment which exactly fills it
+  // drives it to zero.
+  fn update_receive_window(&mut self) {
+    if self.overrides.advertiseZeroWindow {
+      self.receiveSequenceVariables.windowSize = 0;
+      return;
+    }
+
+    let freeReceiveBufferSpace = RECEIVE_BUFFER_CAPACITY.saturating_sub(self.receiveBuffer.len());
+
+    self.receiveSequenceVariables.windowSize = freeReceiveBufferSpace.min(u16::MAX as usize) as u16;
+  }
+
+  /*
+    The fast path for pure ACKs, which make up about half of the segments received during a bulk
+    transfer : an in-sequence segment in the ESTABLISHED state, carrying nothing but an acceptable
+    acknowledgment and a window which doesn't shrink (and maybe timestamps). Such a segment only
+    needs SND.UNA and SND.WND updated, and whatever the window allows sent.
+
+    Returns None, without touching the TCB, for any other segment, which then takes the regular
+    path.
+  */
+  fn on_pure_acknowledgement(
+    &mut self,
+    tcpHeader: &TcpHeaderSlice,
+    payloadLength: usize,
+    nic: &Nic,
+  ) -> Option<anyhow::Result<()>> {
+    let sequenceNumber = tcpHeader.sequence_number();
+    let acknowledgementNumber = tcpHeader.acknowledgment_number();
+    let oldestUnacknowledgedSequenceNumber = self
+      .sendSequenceVariables
+      .oldestUnacknowledgedSequenceNumber;
+
+    let isPureAcknowledgement = self.state == TCPConnectionState::Established
+      && tcpHeader.ack()
+      && !(tcpHeader.syn() || tcpHeader.fin() || tcpHeader.rst() || tcpHeader.urg())
+      && payloadLength == 0
+      && sequenceNumber == self.receiveSequenceVariables.nextByteSequenceNumber
+      && sequence_le(oldestUnacknowledgedSequenceNumber, acknowledgementNumber)
+      && sequence_le(
+        acknowledgementNumber,
+        self.sendSequenceVariables.nextSequenceNumber,
+      )
+      && option_kinds(tcpHeader.options()).all(|kind| matches!(kind, 1 | 8));
+    if !isPureAcknowledgement {
+      return None;
+    }
+
+    // An old or missing timestamp, and a duplicate ACK which may count towards disabling SACK,
+    // are for the regular path to deal with.
+    let options = ExtensionOptions::of(tcpHeader);
+    if self.extensions.check_timestamp(&options, false) != TimestampCheck::Accepted {
+      return None;
+    }
+
+    let windowSize = self.extensions.peer_window(tcpHeader.window_size());
+    let isDuplicateAcknowledgement = acknowledgementNumber == oldestUnacknowledgedSequenceNumber
+      && windowSize == self.sendSequenceVariables.windowSize
+      && self.sendBuffer.in_flight_len() > 0;
+    if isDuplicateAcknowledgement
+      && self
+        .extensions
+        .is_enabled(Extension::SelectiveAcknowledgement)
+    {
+      return None;
+    }
+
+    let isWindowUpdate = self.is_window_update(sequenceNumber, acknowledgementNumber);
+    if isWindowUpdate {
+      let sendWindowEnd = oldestUnacknowledgedSequenceNumber
+        .wrapping_add(self.sendSequenceVariables.windowSize as u32);
+      let newSendWindowEnd = acknowledgementNumber.wrapping_add(windowSize as u32);
+
+      // A shrinking window is a peer violation, which the regular path deals with.
+      if sequence_lt(newSendWindowEnd, sendWindowEnd) {
+        return None;
+      }
+    }
+
+    // The regular path would accept the segment too.
+    debug_assert!(self.is_segment_acceptable(tcpHeader, payloadLength));
+
+    self
+      .stats
+      .record_segment(tcpHeader, payloadLength, self.maximumSegmentSize);
+    self.extensions.update_recent_timestamp(&options);
+
+    if acknowledgementNumber != oldestUnacknowledgedSequenceNumber {
+      self.acknowledge(acknowledgementNumber);
+    }
+    if isWindowUpdate {
+      self.update_send_window(sequenceNumber, acknowledgementNumber, windowSize);
+    }
+
+    Some(self.transmit(nic))
+  }
+
+  // Whether a segment may update the send window : it mustn't be older than the segment last used
+  // to do so (SND.WL1 < SEG.SEQ or (SND.WL1 = SEG.SEQ and SND.WL2 =< SEG.ACK)).
+  fn is_window_update(&self, sequenceNumber: u32, acknowledgementNumber: u32) -> bool {
+    let sendSequenceVariables = &self.sendSequenceVariables;
+
+    sequence_lt(
+      sendSequenceVariables.lastWindowUpdateSegmentSequenceNumber,
+      sequenceNumber,
+    ) || (sendSequenceVariables.lastWindowUpdateSegmentSequenceNumber == sequenceNumber
+      && sequence_le(
+        sendSequenceVariables.lastWindowUpdateAcknowledgementNumber,
+        acknowledgementNumber,
+      ))
+  }
+
+  fn update_send_window(
+    &mut self,
+    sequenceNumber: u32,
+    acknowledgementNumber: u32,
+    windowSize: u16,
+  ) {
+    self.sendSequenceVariables.windowSize = windowSize;
+    self
+      .sendSequenceVariables
+      .lastWindowUpdateSegmentSequenceNumber = sequenceNumber;
+    self
+      .sendSequenceVariables
+      .lastWindowUpdateAcknowledgementNumber = acknowledgementNumber;
+  }
+
+  // Whether the segment is exactly the SYN-ACK which got this actively opened connection
+  // established : <SEQ=IRS><ACK=ISS+1><CTL=SYN,ACK>, with no payload.
+  fn is_duplicate_syn_ack(&self, tcpHeader: &TcpHeaderSlice, payloadLength: usize) -> bool {
+    !self.isPassiveOpen
+      && self.state != TCPConnectionState::SYNReceived
+      && tcpHeader.syn()
+      && tcpHeader.ack()
+      && !tcpHeader.rst()
+      && !tcpHeader.fin()
+      && payloadLength == 0
+      && tcpHeader.sequence_number() == self.receiveSequenceVariables.initialReceiveSequenceNumber
+      && tcpHeader.acknowledgment_number()
+        == self
+          .sendSequenceVariables
+          .initialSendSequenceNumber
+          .wrapping_add(1)
+  }
+
+  /*
+    Segment acceptability test (RFC 9293 section 3.10.7.4) :
+
+      Segment Length  Receive Window  Test
+      --------------  --------------  -----------------------------------------------------------
+      0               0               SEG.SEQ = RCV.NXT
+      0               >0              RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+      >0              0               not acceptable
+      >0              >0              RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+                                      or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+  */
+  fn is_segment_acceptable(&self, tcpHeader: &TcpHeaderSlice, payloadLength: usize) -> bool {
+    let receiveNext = self.receiveSequenceVariables.nextByteSequenceNumber;
+    let receiveWindowEnd =
+      receiveNext.wrapping_add(self.receiveSequenceVariables.windowSize as u32);
+
+    let sequenceNumber = tcpHeader.sequence_number();
+
+    // SYN and FIN each occupy one sequence number.
+    let segmentLength = payloadLength as u32 + tcpHeader.syn() as u32 + tcpHeader.fin() as u32;
+
+    let isInWindow = |sequenceNumber: u32| {
+      sequence_le(receiveNext, sequenceNumber) && sequence_lt(sequenceNumber, receiveWindowEnd)
+    };
+
+    match (segmentLength, self.receiveSequenceVariables.windowSize) {
+      (0, 0) => sequenceNumber == receiveNext,
+      (0, _) => isInWindow(sequenceNumber),
+      (_, 0) => false,
+      (_, _) => {
+        isInWindow(sequenceNumber) || isInWindow(sequenceNumber.wrapping_add(segmentLength - 1))
+      }
+    }
+  }
+
+  // Decides how to deal with the peer violating the spec, as per the PeerViolationPolicy. Returns
+  // whether processing of the segment should carry on.
+  fn tolerate(&mut self, violation: PeerViolation, nic: &Nic) -> anyhow::Result<bool> {
+    if self.tuning.peerViolationPolicy == PeerViolationPolicy::Lenient {
+      return Ok(true);
+    }
+
+    if violation.is_fatal() {
+      eprintln!(
+        "WARN : Resetting connection {}, since the peer {}",
+        self.quad, violation
+      );
+      self.abort(CloseReason::PeerViolation, nic)?;
+    }
+    else {
+      eprintln!(
+        "WARN : Dropping segment of connection {}, since the peer {}",
+        self.quad, violation
+      );
+    }
+
+    Ok(false)
+  }
+
+  // Sends an empty segment, acknowledging everything received in order so far :
+  // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>.
+  // Like transmit( ), but sends a bare ACK if nothing got sent which could carry it.
+  fn transmit_or_acknowledge(&mut self, nic: &Nic) -> anyhow::Result<()> {
+    let nextSequenceNumber = self.sendSequenceVariables.nextSequenceNumber;
+
+    self.transmit(nic)?;
+
+    if self.sendSequenceVariables.nextSequenceNumber == nextSequenceNumber {
+      self.send_acknowledgement(nic)?;
+    }
+    Ok(())
+  }
+
+  fn send_acknowledgement(&mut self, nic: &Nic) -> anyhow::Result<()> {
+    let mut ackPacketTCPHeader = self.create_tcp_header();
+    ackPacketTCPHeader.ack = true;
+
+    // A duplicate ACK tells the peer which of the data past the gap has arrived, with SACK.
+    let receiveNext = self.receiveSequenceVariables.nextByteSequenceNumber;
+    let ranges = self.outOfOrderSegments.iter().map(|(offset, data)| {
+      let start = receiveNext.wrapping_add(*offset);
+      (start, start.wrapping_add(data.len() as u32))
+    });
+    if let Some(selectiveAcknowledgementOption) =
+      self.extensions.selective_acknowledgement_option(ranges)
+    {
+      let mut options = self.timestamp_options();
+      options.extend([
+        TcpOptionElement::Noop,
+        TcpOptionElement::Noop,
+        selectiveAcknowledgementOption,
+      ]);
+      ackPacketTCPHeader
+        .set_options(&options)
+        .expect("SACK option doesn't fit in the TCP header");
+    }
+
+    self.send_segment(ackPacketTCPHeader, &[], nic)
+  }
+
+  /*
+    ABORT (RFC 9293 section 3.10.5) :
+
+    If the connection is in the SYN-RECEIVED, ESTABLISHED, FIN-WAIT-1, FIN-WAIT-2 or CLOSE-WAIT
+    state, a reset segment <SEQ=SND.NXT><CTL=RST> is sent to the peer and the connection moves to
+    the CLOSED state. In any other state (the peer either never learnt of the connection, or has
+    closed its side already), the connection just moves to the CLOSED state. The caller is
+    responsible for deleting the TCB afterwards.
+  */
+  pub fn abort(&mut self, reason: CloseReason, nic: &Nic) -> anyhow::Result<()> {
+    let isResetSent = matches!(
+      self.state,
+      TCPConnectionState::SYNReceived
+        | TCPConnectionState::Established
+        | TCPConnectionState::FinWait1
+        | TCPConnectionState::FinWait2
+        | TCPConnectionState::CloseWait
+    );
+
+    let mut rstPacketTCPHeader = self.create_tcp_header();
+    rstPacketTCPHeader.rst = true;
+
+    self.enter_closed(reason);
+
+    if !isResetSent {
+      return Ok(());
+    }
+    self.stats.record_reset_sent();
+    self.send_segment(rstPacketTCPHeader, &[], nic)
+  }
+
+  // Closes the connection without telling the peer, since it can't be reached anymore.
+  pub fn discard(&mut self, reason: CloseReason) {
+    self.synRetransmission = None;
+    self.enter_closed(reason);
+  }
+
+  /*
+    Moves the connection to the given state, recording the transition in its history. Moves which
+    the state diagram doesn't allow get rejected, leaving the state untouched.
+  */
+  pub fn transition(
+    &mut self,
+    to: TCPConnectionState,
+    reason: TransitionReason,
+  ) -> Result<(), InvalidTransition> {
+    let from = self.state;
+    if !from.can_transition_to(to) {
+      return Err(InvalidTransition { from, to, reason });
+    }
+    let now = self.clock.now();
+
+    if self.transitions.len() == TRANSITION_HISTORY_LENGTH {
+      self.transitions.pop_front();
+    }
+    self.transitions.push_back(StateTransition {
+      from,
+      to,
+      reason,
+      at: now,
+    });
+
+    self.state = to;
+    self.stateEnteredAt = now;
+    self.isStuckWarned = false;
+
+    // The writer learns about the connection closing or failing right away, see is_writable( ).
+    if self.isWriterBlocked && !to.is_writable() {
+      self.isWriterBlocked = false;
+      self.isWriterWakeupPending = true;
+      self.stats.record_writer_wakeup();
+    }
+
+    // A connection the user closed during the handshake skips ESTABLISHED, for FIN-WAIT-1.
+    if to.is_synchronized() && self.establishedAt.is_none() {
+      self.establishedAt = Some(self.stateEnteredAt);
+      self.stats.record_established(self.isPassiveOpen);
+    }
+    match to {
+      TCPConnectionState::FinWait1 => self.stats.record_close(true),
+      TCPConnectionState::CloseWait => self.stats.record_close(false),
+
+      _ => {}
+    }
+    Ok(())
+  }
+
+  // Logs a warning once, when the connection has been in its current state for longer than it
+  // should.
+  fn warn_if_stuck(&mut self, now: Instant) {
+    let threshold = self.tuning.stuckStateThresholds.threshold(self.state);
+    let timeInState = now.saturating_duration_since(self.stateEnteredAt);
+    if self.isStuckWarned || threshold.is_none_or(|threshold| timeInState < threshold) {
+      return;
+    }
+    self.isStuckWarned = true;
+
+    eprintln!(
+      "WARN : connection {} has been in the {} state for {:?}",
+      self.quad, self.state, timeInState
+    );
+  }
+
+  /*
+    Like transition( ), but for the state changes the TCB itself makes, where an invalid transition
+    is a bug. It panics in debug builds. In release builds, the connection gets closed instead,
+    since its state can't be trusted anymore.
+  */
+  fn enter(&mut self, to: TCPConnectionState, reason: TransitionReason) {
+    let Err(error) = self.transition(to, reason)
+    else {
+      return;
+    };
+
+    if cfg!(debug_assertions) {
+      panic!("{} for {}", error, self.quad);
+    }
+    eprintln!("ERROR : {} for {}, so closing it", error, self.quad);
+
+    self.synRetransmission = None;
+    self.enter_closed(CloseReason::Desync);
+  }
+
+  // The latest state transitions, oldest first.
+  pub fn transitions(&self) -> impl Iterator<Item = &StateTransition> {
+    self.transitions.iter()
+  }
+
+  fn enter_closed(&mut self, reason: CloseReason) {
+    // The first reason sticks, if the connection is closed again (when it gets aborted, say).
+    if self.state == TCPConnectionState::Closed {
+      return;
+    }
+
+    self.enter(TCPConnectionState::Closed, TransitionReason::Closed(reason));
+    self.closeReason = Some(reason);
+
+    if matches!(
+      reason,
+      CloseReason::UserTimeout | CloseReason::ConnectTimeout | CloseReason::FinWait2Timeout
+    ) {
+      self.stats.record_timeout();
+    }
+
+    // Whatever is left unsent or unacknowledged, including data written before the connection
+    // failed to get established, is discarded.
+    self.sendBuffer = SendBuffer::with_capacity(self.sendBuffer.capacity());
+  }
+
+  // Creates the TCP header for the next outgoing segment : its sequence number is SND.NXT, and it
+  // acknowledges everything up to RCV.NXT while advertising RCV.WND.
+  fn create_tcp_header(&self) -> TcpHeader {
+    // You can view the TCP header format here :
+    // https://datatracker.ietf.org/doc/html/rfc9293#section-3.1
+    let mut tcpHeader = TcpHeader::new(
+      self.quad.destiation.port,
+      self.quad.source.port,
+      self.sendSequenceVariables.nextSequenceNumber,
+      self.receiveSequenceVariables.windowSize,
+    );
+    tcpHeader.acknowledgment_number = self.receiveSequenceVariables.nextByteSequenceNumber;
+
+    tcpHeader
+      .set_options(&self.timestamp_options())
+      .expect("Timestamp option doesn't fit in the TCP header");
+
+    tcpHeader
+  }
+
+  // The timestamp option every segment carries once timestamps got negotiated, padded to 12 bytes.
+  fn timestamp_options(&self) -> Vec<TcpOptionElement> {
+    match self.extensions.timestamp_option(self.timestamp()) {
+      Some(timestampOption) => vec![
+        TcpOptionElement::Noop,
+        TcpOptionElement::Noop,
+        timestampOption,
+      ],
+      None => Vec::new(),
+    }
+  }
+
+  // Our TSval : milliseconds since the TCB got created, starting from the ISS rather than 0.
+  fn timestamp(&self) -> u32 {
+    let milliseconds = self
+      .clock
+      .now()
+      .saturating_duration_since(self.createdAt)
+      .as_millis() as u32;
+
+    self
+      .sendSequenceVariables
+      .initialSendSequenceNumber
+      .wrapping_add(milliseconds)
+  }
+
+  // Stops using an extension whose options the peer stopped sending, mid-connection.
+  fn fall_back(&mut self, extension: Extension) {
+    self.extensions.disable(extension);
+    self.stats.record_extension_fallback(extension);
+
+    eprintln!(
+      "WARN : Connection {} stopped receiving {} options, so carrying on without them",
+      self.quad, extension
+    );
+  }
+
+  /*
+    Advances SND.NXT past the sequence space the given segment occupies, and then writes it to the
+    NIC.
+
+    SND.NXT gets advanced even if writing fails, since the segment is already accounted for (in the
+    send buffer, for instance). Such a segment is no different from one lost in the network.
+
+    Past the handshake, this (through account_segment( )) is the only place SND.NXT moves.
+    Retransmissions resend sequence space already accounted for, so they go through
+    write_segment( ) directly, outside of segment processing.
+
+    Every reply to a segment goes through here, so that it gets spent from the control segment
+    budget. That includes RSTs, which occupy no sequence space : there's nothing to account for,
+    and their sequence number may lie outside of ours (SEQ=SEG.ACK, answering a bad ACK).
+  */
+  fn send_segment(
+    &mut self,
+    tcpHeader: TcpHeader,
+    payload: &[u8],
+    nic: &Nic,
+  ) -> anyhow::Result<()> {
+    let isControlSegment = payload.is_empty() && !tcpHeader.fin;
+
+    if let Some(controlSegmentBudget) = self.controlSegmentBudget.filter(|_| isControlSegment) {
+      if controlSegmentBudget == 0 {
+        debug_assert!(
+          false,
+          "Connection {} went over the control segment budget",
+          self.quad
+        );
+        self.stats.record_control_segment_budget_hit();
+
+        // A SYN-ACK gets accounted for like a lost one, which the SYN retransmission timer sends
+        // again.
+        if tcpHeader.syn {
+          self.account_segment(&tcpHeader, 0);
+          return Ok(());
+        }
+
+        // A RST can't wait, since the TCB is going away.
+        if !tcpHeader.rst {
+          self.isAcknowledgementDeferred = true;
+          return Ok(());
+        }
+      }
+      self.controlSegmentBudget = Some(controlSegmentBudget.saturating_sub(1));
+    }
+
+    if !tcpHeader.rst {
+      self.account_segment(&tcpHeader, payload.len());
+      self.assert_send_invariants(&tcpHeader, payload.len());
+    }
+    write_segment(&self.quad, tcpHeader, payload, nic)
+  }
+
+  // The bookkeeping of send_segment( ), for a segment which gets written to the NIC later on. Only
+  // the reorderNextSegments override holds segments back like that.
+  fn account_segment(&mut self, tcpHeader: &TcpHeader, payloadLength: usize) {
+    // SYN and FIN each occupy one sequence number.
+    let sequenceSpaceLength = payloadLength as u32 + tcpHeader.syn as u32 + tcpHeader.fin as u32;
+
+    self.sendSequenceVariables.nextSequenceNumber = self
+      .sendSequenceVariables
+      .nextSequenceNumber
+      .wrapping_add(sequenceSpaceLength);
+
+    // Every segment we send advertises the current receive window, and acknowledges everything
+    // received so far.
+    self.isWindowUpdatePending = false;
+    self.isAcknowledgementDeferred &= !tcpHeader.ack;
+    self.advertisedWindowSize = tcpHeader.window_size;
+  }
+
+  // Writes a segment which transmit( ) held back, and already accounted for.
+  fn send_held_back_segment(
+    &self,
+    (tcpHeader, segment): (TcpHeader, InFlightSegment),
+    nic: &Nic,
+  ) -> anyhow::Result<()> {
+    self.assert_send_invariants(&tcpHeader, segment.payload().len());
+    write_segment(&self.quad, tcpHeader, segment.payload(), nic)
+  }
+
+  /*
+    Checks the segment about to be sent against the TCB. It must be called once SND.NXT has been
+    advanced past the segment :
+
+      (1) The segment lies within SND.UNA =< SEG.SEQ and SEG.SEQ+SEG.LEN =< SND.NXT.
+      (2) Anything acknowledged is exactly RCV.NXT.
+      (3) The advertised window never exceeds the free space in the receive buffer.
+      (4) A SYN is only sent while opening the connection, and never along with data or a FIN.
+      (5) A FIN occupies the last sequence number sent so far.
+      (6) Data is only sent on a synchronized connection, in segments of at most the MSS, and never
+          along with a RST.
+
+    A violation is reported through report_invalid_segment( ).
+  */
+  fn assert_send_invariants(&self, tcpHeader: &TcpHeader, payloadLength: usize) {
+    let sequenceNumber = tcpHeader.sequence_number;
+    let segmentLength = payloadLength as u32 + tcpHeader.syn as u32 + tcpHeader.fin as u32;
+    let segmentEnd = sequenceNumber.wrapping_add(segmentLength);
+
+    let oldestUnacknowledgedSequenceNumber = self
+      .sendSequenceVariables
+      .oldestUnacknowledgedSequenceNumber;
+    let nextSequenceNumber = self.sendSequenceVariables.nextSequenceNumber;
+
+    let freeReceiveBufferSpace = RECEIVE_BUFFER_CAPACITY - self.receiveBuffer.len();
+
+    let isOpening = matches!(
+      self.state,
+      TCPConnectionState::Listen | TCPConnectionState::SYNSent | TCPConnectionState::SYNReceived
+    );
+    let isSynchronized = self.state.is_synchronized();
+
+    let problem = if !sequence_le(oldestUnacknowledgedSequenceNumber, sequenceNumber)
+      || !sequence_le(segmentEnd, nextSequenceNumber)
+    {
+      Some(format!(
+        "(1) sequence numbers {}..{} lie outside SND.UNA {} and SND.NXT {}",
+        sequenceNumber, segmentEnd, oldestUnacknowledgedSequenceNumber, nextSequenceNumber
+      ))
+    }
+    else if tcpHeader.ack
+      && tcpHeader.acknowledgment_number != self.receiveSequenceVariables.nextByteSequenceNumber
+    {
+      Some(format!(
+        "(2) acknowledges {} instead of RCV.NXT {}",
+        tcpHeader.acknowledgment_number, self.receiveSequenceVariables.nextByteSequenceNumber
+      ))
+    }
+    else if tcpHeader.window_size as usize > freeReceiveBufferSpace {
+      Some(format!(
+        "(3) advertises a window of {}, with only {} bytes free in the receive buffer",
+        tcpHeader.window_size, freeReceiveBufferSpace
+      ))
+    }
+    else if tcpHeader.syn && (!isOpening || payloadLength > 0 || tcpHeader.fin) {
+      Some(format!(
+        "(4) SYN sent in the {} state, with {} bytes of data and FIN {}",
+        self.state, payloadLength, tcpHeader.fin
+      ))
+    }
+    else if tcpHeader.fin && segmentEnd != nextSequenceNumber {
+      Some(format!(
+        "(5) FIN at {} isn't the last sequence number sent (SND.NXT {})",
+        segmentEnd.wrapping_sub(1),
+        nextSequenceNumber
+      ))
+    }
+    else if payloadLength > 0
+      && (!isSynchronized || payloadLength > self.send_maximum_segment_size() || tcpHeader.rst)
+    {
+      Some(format!(
+        "(6) {} bytes of data sent in the {} state, with RST {}",
+        payloadLength, self.state, tcpHeader.rst
+      ))
+    }
+    else {
+      None
+    };
+
+    if let Some(problem) = problem {
+      report_invalid_segment(
+        &self.quad,
+        &problem,
+        &format!("{:?}\n{}", tcpHeader, self.describe_tcb()),
+      );
+    }
+  }
+
+  // Dumps the TCB variables, for debugging.
+  fn describe_tcb(&self) -> String {
+    format!(
+      "state {} SND.UNA {} SND.NXT {} SND.WND {} ISS {} RCV.NXT {} RCV.WND {} IRS {}",
+      self.state,
+      self
+        .sendSequenceVariables
+        .oldestUnacknowledgedSequenceNumber,
+      self.sendSequenceVariables.nextSequenceNumber,
+      self.sendSequenceVariables.windowSize,
+      self.sendSequenceVariables.initialSendSequenceNumber,
+      self.receiveSequenceVariables.nextByteSequenceNumber,
+      self.receiveSequenceVariables.windowSize,
+      self.receiveSequenceVariables.initialReceiveSequenceNumber
+    )
+  }
+}
+
+// Ways in which a peer can violate the spec, which get tolerated or not, depending on the
+// PeerViolationPolicy.
+#[derive(Clone, Copy, Debug)]
+enum PeerViolation {
+  DataAfterFIN,
+  MovedFIN,
+  ShrunkWindow,
+  SYNOnlyOptionsOnNonSYN,
+  AcknowledgedUnsentData,
+}
+
+impl PeerViolation {
+  // Whether the strict PeerViolationPolicy resets the connection, rather than just dropping the
+  // offending segment.
+  fn is_fatal(&self) -> bool {
+    matches!(self, Self::DataAfterFIN | Self::MovedFIN)
+  }
+}
+
+impl Display for PeerViolation {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let description = match self {
+      Self::DataAfterFIN => "sent data after its FIN",
+      Self::MovedFIN => "moved its FIN",
+      Self::ShrunkWindow => "shrunk its window",
+      Self::SYNOnlyOptionsOnNonSYN => "sent SYN only options on a non SYN segment",
+      Self::AcknowledgedUnsentData => "acknowledged data we haven't sent",
+    };
+
+    write!(f, "{}", description)
+  }
+}
+
+/*
+  Every TCP option is of one of these two formats (RFC 9293 section 3.1) :
+
+    (1) A single octet of option-kind.
+
+    (2) An octet of option-kind, an octet of option-length (which counts the two octets of
+        option-kind and option-length), and the actual option-data octets.
+
+  The option list ends at an End of Option List option (kind 0), or where the TCP header ends.
+*/
+pub fn option_kinds(mut options: &[u8]) -> impl Iterator<Item = u8> + '_ {
+  iter::from_fn(move || {
+    let (&kind, rest) = options.split_first()?;
+
+    let optionLength = match kind {
+      0 => return None,
+      1 => 1,
+
+      _ => match rest.first() {
+        Some(&optionLength) if optionLength >= 2 => optionLength as usize,
+
+        // A malformed length would make us loop forever or read past the options.
+        _ => 0,
+      },
+    };
+
+    options = match optionLength {
+      0 => &[],
+      _ => options.get(optionLength..).unwrap_or_default(),
+    };
+
+    Some(kind)
+  })
+}
+
+// The value of the MSS option (kind 2, length 4), if the option list carries one.
+pub fn maximum_segment_size_option(mut options: &[u8]) -> Option<u16> {
+  while let Some((&kind, rest)) = options.split_first() {
+    match kind {
+      0 => return None,
+      1 => options = rest,
+
+      _ => {
+        let optionLength = *rest.first()? as usize;
+        if optionLength < 2 {
+          return None;
+        }
+
+        if kind == 2 && optionLength == 4 {
+          let value = options.get(2..4)?;
+          return Some(u16::from_be_bytes([value[0], value[1]]));
+        }
+        options = options.get(optionLength..)?;
+      }
+    }
+  }
+  None
+}
+
+/*
+  Reset generation for segments which don't belong to any connection (RFC 9293 section 3.10.7.1) :
+
+  An incoming segment containing a RST is discarded. An incoming segment not containing a RST causes
+  a RST to be sent in response. The acknowledgment and sequence field values are selected to make
+  the reset sequence acceptable to the TCP endpoint that sent the offending segment :
+
+    (1) If the ACK bit is off, sequence number zero is used,
+
+          <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+
+    (2) If the ACK bit is on,
+
+          <SEQ=SEG.ACK><CTL=RST>
+
+  Getting this right matters for half-open connections, for example the ones left behind when we
+  restart while peers still hold established connections : the peer only accepts a RST whose
+  sequence number lies in its receive window, and keeps retransmitting to us otherwise.
+*/
+pub fn send_reset(
+  quad: &ConnectionQuad,
+  incomingPacketTCPHeader: &TcpHeaderSlice,
+  incomingPacketPayloadLength: usize,
+  nic: &Nic,
+) -> anyhow::Result<()> {
+  if incomingPacketTCPHeader.rst() {
+    return Ok(());
+  }
+
+  let mut rstPacketTCPHeader = TcpHeader::new(quad.destiation.port, quad.source.port, 0, 0);
+  rstPacketTCPHeader.rst = true;
+
+  if incomingPacketTCPHeader.ack() {
+    rstPacketTCPHeader.sequence_number = incomingPacketTCPHeader.acknowledgment_number();
+  }
+  else {
+    // SYN and FIN each occupy one sequence number.
+    let incomingSegmentLength = incomingPacketPayloadLength as u32
+      + incomingPacketTCPHeader.syn() as u32
+      + incomingPacketTCPHeader.fin() as u32;
+
+    rstPacketTCPHeader.acknowledgment_number = incomingPacketTCPHeader
+      .sequence_number()
+      .wrapping_add(incomingSegmentLength);
+    rstPacketTCPHeader.ack = true;
+  }
+
+  write_segment(quad, rstPacketTCPHeader, &[], nic)
+}
+
+// What read( ) and write( ) fail with, once the vNIC has failed.
+fn nic_error(error: anyhow::Error) -> TcpError {
+  TcpError::Nic(io::Error::other(error))
+}
+
+// Wraps the given TCP header and payload in an IPv4 packet, addressed to the source of the given
+// connection quad, and writes it to the NIC.
+fn write_segment(
+  quad: &ConnectionQuad,
+  mut tcpHeader: TcpHeader,
+  payload: &[u8],
+  nic: &Nic,
+) -> anyhow::Result<()> {
+  // The manager never creates a TCB for a martian peer, but nothing else may reflect a segment
+  // (a RST, a challenge ACK) to one either.
+  if quad.source.is_martian() {
+    return Err(anyhow!(
+      "Refusing to send a segment to {}, which isn't a unicast peer",
+      quad.source
+    ));
+  }
+
+  // You can view the IPv4 header format here :
+  // https://datatracker.ietf.org/doc/html/rfc791#section-3.1.
+  let ipv4Header = Ipv4Header::new(
+    (tcpHeader.header_len() + payload.len()) as u16,
+    64,
+    IpNumber::TCP,
+    quad.destiation.address.octets(),
+    quad.source.address.octets(),
+  )?;
+
+  tcpHeader.checksum = tcpHeader.calc_checksum_ipv4(&ipv4Header, payload)?;
+
+  let mut arrayBuffer = [0u8; 1500];
+
+  let arrayBufferEmptyPortionLength = {
+    let mut sliceBuffer = &mut arrayBuffer[..]; // Convertion from fixed-size array to slice.
+
+    ipv4Header.write(&mut sliceBuffer)?;
+    tcpHeader.write(&mut sliceBuffer)?;
+    sliceBuffer.write_all(payload)?;
+
+    sliceBuffer.len()
+  };
+
+  let arrayBufferUsedPortionLength = arrayBuffer.len() - arrayBufferEmptyPortionLength;
+  let packet = &arrayBuffer[..arrayBufferUsedPortionLength];
+
+  if let Err(error) = validate_serialized_segment(quad, packet, payload.len()) {
+    report_invalid_segment(quad, &error.to_string(), &hex_dump(packet));
+  }
+
+  // Only segments carrying data are sure to get retransmitted, should they get lost.
+  let segmentKind = if payload.is_empty() {
+    SegmentKind::Control
+  }
+  else {
+    SegmentKind::Data
+  };
+  nic.send(quad, packet, segmentKind)?;
+
+  Ok(())
+}
+
+// Re-parses a serialized segment, and checks its lengths, checksums and addressing.
+fn validate_serialized_segment(
+  quad: &ConnectionQuad,
+  packet: &[u8],
+  payloadLength: usize,
+) -> anyhow::Result<()> {
+  let ipv4Header = Ipv4HeaderSlice::from_slice(packet)?;
+
+  if ipv4Header.total_len() as usize != packet.len() {
+    return Err(anyhow!(
+      "IPv4 total length {} doesn't match the packet length {}",
+      ipv4Header.total_len(),
+      packet.len()
+    ));
+  }
+
+  let ipv4HeaderChecksum = ipv4Header.to_header().calc_header_checksum();
+  if ipv4Header.header_checksum() != ipv4HeaderChecksum {
+    return Err(anyhow!(
+      "IPv4 header checksum {:#06x} should be {:#06x}",
+      ipv4Header.header_checksum(),
+      ipv4HeaderChecksum
+    ));
+  }
+
+  if ipv4Header.protocol() != IpNumber::TCP
+    || ipv4Header.source_addr() != quad.destiation.address
+    || ipv4Header.destination_addr() != quad.source.address
+  {
+    return Err(anyhow!(
+      "IPv4 packet from {} to {} (protocol {:?}) isn't a TCP segment of this connection",
+      ipv4Header.source_addr(),
+      ipv4Header.destination_addr(),
+      ipv4Header.protocol()
+    ));
+  }
+
+  let ipv4Payload = &packet[ipv4Header.slice().len()..];
+  let tcpHeader = TcpHeaderSlice::from_slice(ipv4Payload)?;
+  let tcpPayload = &ipv4Payload[tcpHeader.slice().len()..];
+
+  if tcpPayload.len() != payloadLength {
+    return Err(anyhow!(
+      "TCP payload is {} bytes long instead of {}",
+      tcpPayload.len(),
+      payloadLength
+    ));
+  }
+
+  if tcpHeader.source_port() != quad.destiation.port
+    || tcpHeader.destination_port() != quad.source.port
+  {
+    return Err(anyhow!(
+      "TCP segment from port {} to port {} isn't a segment of this connection",
+      tcpHeader.source_port(),
+      tcpHeader.destination_port()
+    ));
+  }
+
+  let tcpChecksum = tcpHeader.calc_checksum_ipv4(&ipv4Header, tcpPayload)?;
+  if tcpHeader.checksum() != tcpChecksum {
+    return Err(anyhow!(
+      "TCP checksum {:#06x} should be {:#06x}",
+      tcpHeader.checksum(),
+      tcpChecksum
+    ));
+  }
+
+  Ok(())
+}
+
+/*
+  Reports a segment we're sending, which fails self-validation. Such a segment means there's a bug
+  in our sender, so debug builds panic right away with the given details. Release builds count and
+  log it instead, and send the segment anyway.
+*/
+fn report_invalid_segment(quad: &ConnectionQuad, problem: &str, details: &str) {
+  if cfg!(debug_assertions) {
+    panic!(
+      "Invalid segment sent on {} : {}\n{}",
+      quad, problem, details
+    );
+  }
+
+  INVALID_SEGMENTS_SENT.fetch_add(1, Ordering::Relaxed);
+  eprintln!("ERROR : Invalid segment sent on {} : {}", quad, problem);
+}
+
+fn hex_dump(bytes: &[u8]) -> String {
+  bytes
+    .chunks(16)
+    .map(|line| {
+      line
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+    })
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+// Whether the given non-RST segment carries a combination of flags no sane peer sends.
+pub fn has_nonsensical_flags(tcpHeader: &TcpHeaderSlice) -> bool {
+  let (syn, ack, fin) = (tcpHeader.syn(), tcpHeader.ack(), tcpHeader.fin());
+
+  (syn && fin) || (!syn && !ack)
+}
+
+/*
+  Sequence number comparisons.
+
+  Since the sequence number space wraps around after 2**32 - 1, sequence numbers are compared
+  modulo 2**32 : a is less than b if b lies within the 2**31 numbers following a.
+*/
+
+pub(crate) fn sequence_lt(a: u32, b: u32) -> bool {
+  (a.wrapping_sub(b) as i32) < 0
+}
+
+pub(crate) fn sequence_le(a: u32, b: u32) -> bool {
+  a == b || sequence_lt(a, b)
+}
+
+#[cfg(test)]
+mod tests {
+  use {
+    super::*,
+    crate::{
+      channel_nic::ChannelNic,
+      clock::{SystemClock, VirtualClock},
+      manager::TICK_INTERVAL,
+      nic::{NicDevice, NicSendPolicy, Readiness},
+    },
+    etherparse::PacketBuilder,
+  };
+
+  fn connection() -> TCPConnection {
+    TCPConnection::listen(
+      "10.0.0.2:51514 10.0.0.1:8080".parse().unwrap(),
+      TcpTuning::default(),
+      DEFAULT_MAXIMUM_SEGMENT_SIZE,
+      Arc::default(),
+      Arc::new(SystemClock),
+    )
+  }
+
+  fn header(sequenceNumber: u32, fin: bool) -> Vec<u8> {
+    let mut tcpHeader = TcpHeader::new(51514, 8080, sequenceNumber, 1000);
+    tcpHeader.fin = fin;
+    tcpHeader.ack = true;
+    tcpHeader.to_bytes().to_vec()
+  }
+
+  fn is_acceptable(
+    connection: &TCPConnection,
+    sequenceNumber: u32,
+    payloadLength: usize,
+    fin: bool,
+  ) -> bool {
+    let bytes = header(sequenceNumber, fin);
+    let tcpHeader = TcpHeaderSlice::from_slice(&bytes).unwrap();
+    connection.is_segment_acceptable(&tcpHeader, payloadLength)
+  }
+
+  #[test]
+  fn acceptability_across_the_sequence_number_wraparound() {
+    let mut connection = connection();
+    let receiveNext = u32::MAX - 10;
+    connection.receiveSequenceVariables.nextByteSequenceNumber = receiveNext;
+    connection.receiveSequenceVariables.windowSize = 100;
+
+    // The window is [u32::MAX - 10, 89).
+    assert!(is_acceptable(&connection, receiveNext, 0, false));
+    assert!(is_acceptable(&connection, u32::MAX, 0, false));
+    assert!(is_acceptable(&connection, 0, 0, false));
+    assert!(is_acceptable(&connection, 88, 0, false));
+    assert!(!is_acceptable(&connection, 89, 0, false));
+    assert!(!is_acceptable(&connection, receiveNext - 1, 0, false));
+
+    // Data straddling the wraparound, or starting after it.
+    assert!(is_acceptable(&connection, u32::MAX - 5, 50, false));
+    assert!(is_acceptable(&connection, 5, 50, false));
+    assert!(is_acceptable(&connection, 88, 10, false));
+    assert!(!is_acceptable(&connection, 89, 10, false));
+
+    // Data partly before the window is acceptable, data entirely before it isn't.
+    assert!(is_acceptable(&connection, receiveNext - 10, 11, false));
+    assert!(!is_acceptable(&connection, receiveNext - 10, 10, false));
+
+    // The FIN occupies a sequence number of its own.
+    assert!(!is_acceptable(&connection, receiveNext - 1, 0, true));
+    assert!(is_acceptable(&connection, receiveNext - 1, 1, true));
+  }
+
+  #[test]
+  fn acceptability_with_a_zero_window() {
+    let mut connection = connection();
+    let receiveNext = u32::MAX;
+    connection.receiveSequenceVariables.nextByteSequenceNumber = receiveNext;
+    connection.receiveSequenceVariables.windowSize = 0;
+
+    assert!(is_acceptable(&connection, receiveNext, 0, false));
+    assert!(!is_acceptable(&connection, 0, 0, false));
+    assert!(!is_acceptable(&connection, receiveNext, 1, false));
+    assert!(!is_acceptable(&connection, receiveNext, 0, true));
+  }
+
+  #[test]
+  fn rebasing_drops_what_got_delivered() {
+    let mut connection = connection();
+    connection.outOfOrderSegments = BTreeMap::from([
+      (10, vec![b'a'; 5]),
+      (12, vec![b'b'; 10]),
+      (20, vec![b'c'; 10]),
+    ]);
+
+    connection.rebase_out_of_order_segments(0);
+    assert_eq!(connection.outOfOrderSegments.len(), 3);
+
+    // The first segment got delivered entirely, the second one partly.
+    connection.rebase_out_of_order_segments(15);
+    assert_eq!(
+      connection.outOfOrderSegments,
+      BTreeMap::from([(0, vec![b'b'; 7]), (5, vec![b'c'; 10])])
+    );
+  }
+
+  #[test]
+  fn rebasing_keeps_the_longer_of_overlapping_segments() {
+    let mut connection = connection();
+    connection.outOfOrderSegments = BTreeMap::from([(3, vec![b'x'; 4]), (5, vec![b'y'; 10])]);
+
+    connection.rebase_out_of_order_segments(5);
+    assert_eq!(
+      connection.outOfOrderSegments,
+      BTreeMap::from([(0, vec![b'y'; 10])])
+    );
+
+    connection.rebase_out_of_order_segments(u32::MAX);
+    assert!(connection.outOfOrderSegments.is_empty());
+  }
+
+  #[test]
+  fn a_shut_window_gets_probed() {
+    let (device, peerDevice) = ChannelNic::pair();
+    let nic = Nic::new(device, 1500, NicSendPolicy::default());
+
+    let mut connection = connection();
+    connection.state = TCPConnectionState::Established;
+    connection.sendSequenceVariables.windowSize = 0;
+    connection.sendBuffer.write(b"hello");
+    let nextSequenceNumber = connection.sendSequenceVariables.nextSequenceNumber;
+
+    // Nothing gets sent, till the window has been shut for a while.
+    let now = Instant::now();
+    connection.on_tick(now, &nic).unwrap();
+    assert_eq!(
+      connection.sendSequenceVariables.nextSequenceNumber,
+      nextSequenceNumber
+    );
+
+    connection
+      .on_tick(now + RETRANSMISSION_TIMEOUT, &nic)
+      .unwrap();
+    assert_eq!(
+      connection.sendSequenceVariables.nextSequenceNumber,
+      nextSequenceNumber.wrapping_add(1)
+    );
+
+    let mut packet = [0u8; 1500];
+    let packetLength = peerDevice.recv(&mut packet).unwrap();
+    let ipv4Header = Ipv4HeaderSlice::from_slice(&packet[..packetLength]).unwrap();
+    let tcpHeader = TcpHeaderSlice::from_slice(&packet[ipv4Header.slice().len()..]).unwrap();
+    assert_eq!(tcpHeader.sequence_number(), nextSequenceNumber);
+    assert_eq!(
+      &packet[ipv4Header.slice().len() + tcpHeader.slice().len()..packetLength],
+      b"h"
+    );
+  }
+
+  #[test]
+  fn calls_on_a_closed_connection_fail_with_why_it_got_closed() {
+    let (device, _peerDevice) = ChannelNic::pair();
+    let nic = Nic::new(device, 1500, NicSendPolicy::default());
+    let mut ctx = SendContext { nic: &nic };
+
+    let connectionReset = |reason| TcpError::ConnectionReset { reason };
+    for (reason, expectedError, expectedKind) in [
+      (
+        CloseReason::Refused,
+        TcpError::ConnectionRefused,
+        io::ErrorKind::ConnectionRefused,
+      ),
+      (
+        CloseReason::ConnectTimeout,
+        TcpError::TimedOut,
+        io::ErrorKind::TimedOut,
+      ),
+      (
+        CloseReason::UserTimeout,
+        TcpError::TimedOut,
+        io::ErrorKind::TimedOut,
+      ),
+      (
+        CloseReason::Reset,
+        connectionReset(CloseReason::Reset),
+        io::ErrorKind::ConnectionReset,
+      ),
+      (
+        CloseReason::Aborted,
+        connectionReset(CloseReason::Aborted),
+        io::ErrorKind::ConnectionReset,
+      ),
+      (
+        CloseReason::PeerViolation,
+        connectionReset(CloseReason::PeerViolation),
+        io::ErrorKind::ConnectionReset,
+      ),
+      (
+        CloseReason::Desync,
+        connectionReset(CloseReason::Desync),
+        io::ErrorKind::ConnectionReset,
+      ),
+      (
+        CloseReason::FinWait2Timeout,
+        connectionReset(CloseReason::FinWait2Timeout),
+        io::ErrorKind::ConnectionReset,
+      ),
+      (
+        CloseReason::NicFailed,
+        connectionReset(CloseReason::NicFailed),
+        io::ErrorKind::BrokenPipe,
+      ),
+    ] {
+      let mut connection = connection();
+      connection.enter_closed(reason);
+
+      let readError = connection.read(&mut [0; 16], &mut ctx).unwrap_err();
+      let writeError = connection.write(b"hello", &mut ctx).unwrap_err();
+      for error in [readError, writeError] {
+        assert_eq!(format!("{:?}", error), format!("{:?}", expectedError));
+        assert_eq!(io::Error::from(error).kind(), expectedKind, "{}", reason);
+      }
+    }
+
+    // A graceful close is no failure : reading reports the end of the stream.
+    let mut closedConnection = connection();
+    closedConnection.enter_closed(CloseReason::Graceful);
+    assert_eq!(closedConnection.read(&mut [0; 16], &mut ctx).unwrap(), 0);
+    assert!(matches!(
+      closedConnection.write(b"hello", &mut ctx),
+      Err(TcpError::NotConnected)
+    ));
+
+    // Nor is writing after shutting down our side, before the connection got closed.
+    let mut closingConnection = connection();
+    closingConnection.state = TCPConnectionState::Established;
+    closingConnection.close(&mut ctx).unwrap();
+    assert_eq!(closingConnection.state(), TCPConnectionState::FinWait1);
+    let error = closingConnection.write(b"hello", &mut ctx).unwrap_err();
+    assert!(matches!(error, TcpError::NotConnected));
+    assert_eq!(io::Error::from(error).kind(), io::ErrorKind::NotConnected);
+  }
+
+  // What a middlebox between two connections does to the packets crossing it.
+  #[derive(Clone, Copy, Default)]
+  struct Middlebox {
+    // Option kinds stripped off the client's SYN, and off the server's SYN-ACK.
+    strippedOffSYN: &'static [u8],
+    strippedOffSYNACK: &'static [u8],
+
+    // Option kinds stripped off every packet, once the given number of packets has crossed.
+    strippedLater: &'static [u8],
+    strippingStartsAfter: usize,
+
+    // Every how many data segments going either way one gets dropped.
+    lossInterval: usize,
+  }
+
+  // Overwrites the options of the given kinds in an IPv4 packet with NOPs.
+  fn strip_options(packet: &mut [u8], kinds: &[u8]) {
+    let ipv4HeaderLength = (packet[0] & 0x0f) as usize * 4;
+    let tcpHeaderLength = (packet[ipv4HeaderLength + 12] >> 4) as usize * 4;
+    let options = &mut packet[ipv4HeaderLength + 20..ipv4HeaderLength + tcpHeaderLength];
+
+    let mut index = 0;
+    while index < options.len() {
+      let length = match options[index] {
+        0 => break,
+        1 => 1,
+        _ => options[index + 1] as usize,
+      };
+      if kinds.contains(&options[index]) {
+        options[index..index + length].fill(1);
+      }
+      index += length;
+    }
+  }
+
+  // The TCP segment carried by an IPv4 packet.
+  fn segment_view(packet: &[u8]) -> SegmentView<'_> {
+    let ipv4Header = Ipv4HeaderSlice::from_slice(packet).unwrap();
+    let segment = &packet[ipv4Header.slice().len()..];
+    let header = TcpHeaderSlice::from_slice(segment).unwrap();
+    let payload = &segment[header.slice().len()..];
+
+    SegmentView { header, payload }
+  }
+
+  struct Endpoint {
+    connection: TCPConnection,
+    nic: Nic,
+
+    // The other end of the NIC, which the packets the connection sends come out of.
+    wire: ChannelNic,
+
+    receivedData: Vec<u8>,
+    writtenLength: usize,
+  }
+
+  impl Endpoint {
+    fn new(connection: TCPConnection) -> Self {
+      let (device, wire) = ChannelNic::pair();
+
+      Self {
+        connection,
+        nic: Nic::new(device, 1500, NicSendPolicy::default()),
+        wire,
+        receivedData: Vec::new(),
+        writtenLength: 0,
+      }
+    }
+
+    fn sent_packets(&self) -> Vec<Vec<u8>> {
+      let mut packets = Vec::new();
+      let mut buffer = [0u8; 1500];
+
+      while self.wire.wait(Readiness::Readable, Instant::now()).unwrap() {
+        let packetLength = self.wire.recv(&mut buffer).unwrap();
+        packets.push(buffer[..packetLength].to_vec());
+      }
+      packets
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Action {
+      self
+        .connection
+        .handle(&segment_view(packet), &mut SendContext { nic: &self.nic })
+    }
+
+    // Writes as much of the data as fits, and reads whatever has arrived.
+    fn write_and_read(&mut self, data: &[u8]) {
+      let mut ctx = SendContext { nic: &self.nic };
+
+      if let Ok(writtenLength) = self.connection.write(&data[self.writtenLength..], &mut ctx) {
+        self.writtenLength += writtenLength;
+      }
+
+      let mut buffer = [0u8; RECEIVE_BUFFER_CAPACITY];
+      while let Ok(readLength @ 1..) = self.connection.read(&mut buffer, &mut ctx) {
+        self.receivedData.extend_from_slice(&buffer[..readLength]);
+      }
+    }
+  }
+
+  /*
+    Connects a client to a server through the middlebox, and has both of them send the other
+    16KB at once. Checks that all of it arrives intact, without the connection getting reset, and
+    returns both ends for a look at their extensions.
+  */
+  fn transfer_through(middlebox: Middlebox) -> (TCPConnection, TCPConnection) {
+    let clock = Arc::new(VirtualClock::default());
+    let counters = Arc::new(TcpCounters::default());
+
+    let mut client = Endpoint::new(TCPConnection::connect(
+      "10.0.0.1:8080 10.0.0.2:51514".parse().unwrap(),
+      TcpTuning::default(),
+      DEFAULT_MAXIMUM_SEGMENT_SIZE,
+      counters.clone(),
+      clock.clone(),
+    ));
+    let mut server = Endpoint::new(TCPConnection::listen(
+      "10.0.0.2:51514 10.0.0.1:8080".parse().unwrap(),
+      TcpTuning::default(),
+      DEFAULT_MAXIMUM_SEGMENT_SIZE,
+      counters,
+      clock.clone(),
+    ));
+    client.connection.open(&client.nic).unwrap();
+
+    let data: Vec<u8> = (0..16 * 1024).map(|index| (index % 251) as u8).collect();
+    let mut crossedPackets = 0;
+    let mut dataSegments = 0;
+
+    for _ in 0..10_000 {
+      if client.receivedData.len() == data.len() && server.receivedData.len() == data.len() {
+        break;
+      }
+      if client.connection.state().is_synchronized() {
+        client.write_and_read(&data);
+      }
+      if server.connection.state().is_synchronized() {
+        server.write_and_read(&data);
+      }
+
+      let mut isIdle = true;
+      loop {
+        let clientPackets = client.sent_packets();
+        let serverPackets = server.sent_packets();
+        if clientPackets.is_empty() && serverPackets.is_empty() {
+          break;
+        }
+        isIdle = false;
+
+        for (isFromClient, mut packet) in iter::repeat(true)
+          .zip(clientPackets)
+          .chain(iter::repeat(false).zip(serverPackets))
+        {
+          let ipv4HeaderLength = (packet[0] & 0x0f) as usize * 4;
+          let flags = packet[ipv4HeaderLength + 13];
+          let (isSYN, isACK) = (flags & 0x02 != 0, flags & 0x10 != 0);
+
+          if isSYN && !isACK {
+            strip_options(&mut packet, middlebox.strippedOffSYN);
+          }
+          if isSYN && isACK {
+            strip_options(&mut packet, middlebox.strippedOffSYNACK);
+          }
+          if crossedPackets >= middlebox.strippingStartsAfter {
+            strip_options(&mut packet, middlebox.strippedLater);
+          }
+          crossedPackets += 1;
+
+          let tcpHeaderLength = (packet[ipv4HeaderLength + 12] >> 4) as usize * 4;
+          if packet.len() > ipv4HeaderLength + tcpHeaderLength {
+            dataSegments += 1;
+            if middlebox.lossInterval > 0 && dataSegments % middlebox.lossInterval == 0 {
+              continue;
+            }
+          }
+
+          match isFromClient {
+            true => server.handle(&packet),
+            false => client.handle(&packet),
+          };
+        }
+      }
+
+      // Nothing moves till a timer fires.
+      if isIdle {
+        clock.advance(Duration::from_millis(100));
+        client.connection.on_tick(clock.now(), &client.nic).unwrap();
+        server.connection.on_tick(clock.now(), &server.nic).unwrap();
+      }
+    }
+
+    for endpoint in [&client, &server] {
+      assert_eq!(endpoint.connection.state(), TCPConnectionState::Established);
+      assert!(endpoint.receivedData == data, "the data got corrupted");
+    }
+    (client.connection, server.connection)
+  }
+
+  fn fallbacks(connection: &TCPConnection) -> [u64; 3] {
+    Extension::ALL.map(|extension| connection.stats().extension_fallbacks(extension))
+  }
+
+  fn is_enabled(connection: &TCPConnection) -> [bool; 3] {
+    Extension::ALL.map(|extension| connection.is_extension_enabled(extension))
+  }
+
+  #[test]
+  fn every_extension_gets_used_when_nothing_strips_them() {
+    let (client, server) = transfer_through(Middlebox {
+      lossInterval: 5,
+      ..Middlebox::default()
+    });
+
+    for connection in [&client, &server] {
+      assert_eq!(is_enabled(connection), [true; 3]);
+      assert_eq!(fallbacks(connection), [0; 3]);
+      assert_eq!(connection.send_maximum_segment_size(), 536 - 12);
+
+      // The losses got SACKed, and retransmitted.
+      assert!(connection.stats().options_received(5) > 0);
+      assert!(connection.stats().retransmissions() > 0);
+    }
+  }
+
+  /*
+    WS, SACK-permitted (along with SACK) and TS are indexed like Extension::ALL. Whatever gets
+    stripped off the handshake ends up unused on both ends, except for window scaling stripped
+    off the SYN-ACK, which the server can't tell (see extensions.rs).
+  */
+  const STRIPPED_OPTIONS: [(&[u8], [bool; 3]); 4] = [
+    (&[3], [true, false, false]),
+    (&[4, 5], [false, true, false]),
+    (&[8], [false, false, true]),
+    (&[3, 4, 5, 8], [true, true, true]),
+  ];
+
+  #[test]
+  fn extensions_stripped_off_the_syn_fall_back() {
+    for (kinds, isStripped) in STRIPPED_OPTIONS {
+      let (client, server) = transfer_through(Middlebox {
+        strippedOffSYN: kinds,
+        lossInterval: 5,
+        ..Middlebox::default()
+      });
+
+      // The server never saw the offer, so only the client falls back.
+      let isEnabled = isStripped.map(|isStripped| !isStripped);
+      assert_eq!(is_enabled(&client), isEnabled, "stripping {:?}", kinds);
+      assert_eq!(is_enabled(&server), isEnabled, "stripping {:?}", kinds);
+      assert_eq!(fallbacks(&client), isStripped.map(u64::from));
+      assert_eq!(fallbacks(&server), [0; 3]);
+    }
+  }
+
+  #[test]
+  fn extensions_stripped_off_the_syn_ack_fall_back() {
+    for (kinds, isStripped) in STRIPPED_OPTIONS {
+      let (client, server) = transfer_through(Middlebox {
+        strippedOffSYNACK: kinds,
+        lossInterval: 5,
+        ..Middlebox::default()
+      });
+
+      let [_, isSACKStripped, isTimestampStripped] = isStripped;
+      assert_eq!(
+        is_enabled(&client),
+        isStripped.map(|isStripped| !isStripped),
+        "stripping {:?}",
+        kinds
+      );
+      assert_eq!(fallbacks(&client), isStripped.map(u64::from));
+
+      assert_eq!(
+        is_enabled(&server),
+        [true, !isSACKStripped, !isTimestampStripped],
+        "stripping {:?}",
+        kinds
+      );
+      assert_eq!(
+        fallbacks(&server),
+        [false, isSACKStripped, isTimestampStripped].map(u64::from)
+      );
+    }
+  }
+
+  #[test]
+  fn extensions_stripped_off_the_whole_handshake_fall_back() {
+    for (kinds, isStripped) in STRIPPED_OPTIONS {
+      let (client, server) = transfer_through(Middlebox {
+        strippedOffSYN: kinds,
+        strippedOffSYNACK: kinds,
+        lossInterval: 5,
+        ..Middlebox::default()
+      });
+
+      let isEnabled = isStripped.map(|isStripped| !isStripped);
+      assert_eq!(is_enabled(&client), isEnabled, "stripping {:?}", kinds);
+      assert_eq!(is_enabled(&server), isEnabled, "stripping {:?}", kinds);
+      assert_eq!(fallbacks(&client), isStripped.map(u64::from));
+      assert_eq!(fallbacks(&server), [0; 3]);
+    }
+  }
+
+  #[test]
+  fn timestamps_stopping_mid_connection_fall_back() {
+    let (client, server) = transfer_through(Middlebox {
+      strippedLater: &[8],
+      strippingStartsAfter: 20,
+      lossInterval: 5,
+      ..Middlebox::default()
+    });
+
+    for connection in [&client, &server] {
+      assert_eq!(is_enabled(connection), [true, true, false]);
+      assert_eq!(fallbacks(connection), [0, 0, 1]);
+      assert_eq!(connection.send_maximum_segment_size(), 536);
+    }
+  }
+
+  #[test]
+  fn sack_stopping_mid_connection_falls_back() {
+    let (client, server) = transfer_through(Middlebox {
+      strippedLater: &[5],
+      strippingStartsAfter: 20,
+      lossInterval: 5,
+      ..Middlebox::default()
+    });
+
+    for connection in [&client, &server] {
+      assert_eq!(is_enabled(connection), [true, false, true]);
+      assert_eq!(fallbacks(connection), [0, 1, 0]);
+    }
+  }
+
+  // The ISSs of the endpoints below.
+  const CLIENT_ISS: u32 = 1000;
+  const SERVER_ISS: u32 = 5000;
+
+  /*
+    A client which sent its SYN (still on the wire) to a listening server, both with the given
+    tuning. The ISSs are fixed, so that the sequence numbers are known.
+  */
+  fn endpoints(clock: &Arc<VirtualClock>, tuning: TcpTuning) -> (Endpoint, Endpoint) {
+    let counters = Arc::new(TcpCounters::default());
+
+    let mut client = Endpoint::new(TCPConnection::connect(
+      "10.0.0.1:8080 10.0.0.2:51514".parse().unwrap(),
+      tuning,
+      DEFAULT_MAXIMUM_SEGMENT_SIZE,
+      counters.clone(),
+      clock.clone(),
+    ));
+    let mut server = Endpoint::new(TCPConnection::listen(
+      "10.0.0.2:51514 10.0.0.1:8080".parse().unwrap(),
+      tuning,
+      DEFAULT_MAXIMUM_SEGMENT_SIZE,
+      counters,
+      clock.clone(),
+    ));
+    client.connection.overrides.initialSendSequenceNumber = Some(CLIENT_ISS);
+    server.connection.overrides.initialSendSequenceNumber = Some(SERVER_ISS);
+
+    client.connection.open(&client.nic).unwrap();
+    (client, server)
+  }
+
+  // Delivers what either endpoint sent to the other, till neither sends anything more.
+  fn exchange(client: &mut Endpoint, server: &mut Endpoint) {
+    loop {
+      let (clientPackets, serverPackets) = (client.sent_packets(), server.sent_packets());
+      if clientPackets.is_empty() && serverPackets.is_empty() {
+        return;
+      }
+
+      for packet in clientPackets {
+        server.handle(&packet);
+      }
+      for packet in serverPackets {
+        client.handle(&packet);
+      }
+    }
+  }
+
+  // Endpoints with an established connection between them.
+  fn established_endpoints(clock: &Arc<VirtualClock>, tuning: TcpTuning) -> (Endpoint, Endpoint) {
+    let (mut client, mut server) = endpoints(clock, tuning);
+    exchange(&mut client, &mut server);
+
+    assert_eq!(client.connection.state(), TCPConnectionState::Established);
+    assert_eq!(server.connection.state(), TCPConnectionState::Established);
+    (client, server)
+  }
+
+  /*
+    A client which wrote 4KB to a server over an established connection, along with the packets
+    the server answered the first flight with. It gets built the same way every time : the ISSs are
+    fixed and the clock doesn't move, so that a second call makes a twin of the first.
+  */
+  fn sender_with_data_in_flight(clock: &Arc<VirtualClock>) -> (Endpoint, Vec<Vec<u8>>) {
+    let (mut client, mut server) = established_endpoints(clock, TcpTuning::default());
+
+    client.write_and_read(&[7; 4096]);
+    for packet in client.sent_packets() {
+      server.handle(&packet);
+    }
+
+    // Reading the data has the server reopen its window too.
+    server.write_and_read(&[]);
+    (client, server.sent_packets())
+  }
+
+  // What processing a pure ACK affects, which both paths should agree on.
+  fn observable_state(connection: &TCPConnection) -> String {
+    let sendSequenceVariables = &connection.sendSequenceVariables;
+
+    format!(
+      "{} {} {} {} {} {} {} {} {} {:?} {}",
+      connection.state,
+      sendSequenceVariables.oldestUnacknowledgedSequenceNumber,
+      sendSequenceVariables.nextSequenceNumber,
+      sendSequenceVariables.windowSize,
+      sendSequenceVariables.lastWindowUpdateSegmentSequenceNumber,
+      sendSequenceVariables.lastWindowUpdateAcknowledgementNumber,
+      connection.bytes_unacked(),
+      connection.bytes_queued(),
+      connection.isWriterBlocked,
+      connection.extensions,
+      connection.stats.to_json()
+    )
+  }
+
+  #[test]
+  fn the_fast_path_agrees_with_the_regular_path_on_pure_acks() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut fast, mut acknowledgements) = sender_with_data_in_flight(&clock);
+    let (mut regular, regularAcknowledgements) = sender_with_data_in_flight(&clock);
+
+    assert_eq!(acknowledgements, regularAcknowledgements);
+    assert_eq!(
+      observable_state(&fast.connection),
+      observable_state(&regular.connection)
+    );
+
+    // A duplicate of the last ACK, which the fast path leaves to the regular one since SACK is in
+    // use.
+    acknowledgements.push(acknowledgements.last().unwrap().clone());
+
+    let mut fastPathAcknowledgements = 0;
+    for packet in &acknowledgements {
+      let segment = segment_view(packet);
+
+      match fast.connection.on_pure_acknowledgement(
+        &segment.header,
+        segment.payload.len(),
+        &fast.nic,
+      ) {
+        Some(result) => {
+          result.unwrap();
+          fastPathAcknowledgements += 1;
+        }
+        None => {
+          fast.handle(packet);
+        }
+      }
+      regular
+        .connection
+        .on_segment(&segment, &regular.nic)
+        .unwrap();
+
+      assert_eq!(
+        observable_state(&fast.connection),
+        observable_state(&regular.connection)
+      );
+      assert_eq!(fast.sent_packets(), regular.sent_packets());
+    }
+
+    assert!(fastPathAcknowledgements >= 2);
+    assert!(fastPathAcknowledgements < acknowledgements.len());
+  }
+
+  #[test]
+  fn state_transitions_follow_the_rfc_9293_diagram() {
+    use TCPConnectionState::*;
+
+    const STATES: [TCPConnectionState; 11] = [
+      Closed,
+      Listen,
+      SYNSent,
+      SYNReceived,
+      Established,
+      FinWait1,
+      FinWait2,
+      Closing,
+      TimeWait,
+      CloseWait,
+      LastAck,
+    ];
+
+    // Every edge of the diagram in RFC 9293 section 3.3.2, and whether a TCB ever takes it.
+    let diagram = [
+      // A TCB gets created in the LISTEN or the SYN-SENT state, rather than moving there.
+      (Closed, Listen, false),
+      (Closed, SYNSent, false),
+      (Listen, SYNReceived, true),
+      (Listen, Closed, true),
+      // Sending on a listening port isn't supported.
+      (Listen, SYNSent, false),
+      (SYNSent, SYNReceived, true),
+      (SYNSent, Established, true),
+      (SYNSent, Closed, true),
+      (SYNReceived, Established, true),
+      (SYNReceived, FinWait1, true),
+      // The listener has a TCB of its own, so a reset connection request just gets closed.
+      (SYNReceived, Listen, false),
+      (Established, FinWait1, true),
+      (Established, CloseWait, true),
+      (FinWait1, FinWait2, true),
+      (FinWait1, Closing, true),
+      (FinWait2, TimeWait, true),
+      (Closing, TimeWait, true),
+      (TimeWait, Closed, true),
+      (CloseWait, LastAck, true),
+      (LastAck, Closed, true),
+    ];
+    let isExpected = |from: TCPConnectionState, to: TCPConnectionState| {
+      diagram.contains(&(from, to, true))
+        // Our FIN and the peer's can get acknowledged by the same segment (section 3.10.7.4).
+        || (from, to) == (FinWait1, TimeWait)
+        // A reset or an abort closes a connection in any state.
+        || (from != Closed && to == Closed)
+    };
+
+    for from in STATES {
+      for to in STATES {
+        assert_eq!(
+          from.can_transition_to(to),
+          isExpected(from, to),
+          "{} -> {}",
+          from,
+          to
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn a_connection_closed_during_its_handshake_still_gets_accepted() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = endpoints(&clock, TcpTuning::default());
+
+    for packet in client.sent_packets() {
+      assert_eq!(server.handle(&packet), Action::Keep);
+    }
+    assert_eq!(server.connection.state(), TCPConnectionState::SYNReceived);
+
+    // The FIN waits for the handshake to complete.
+    server
+      .connection
+      .close(&mut SendContext { nic: &server.nic })
+      .unwrap();
+    assert_eq!(server.connection.state(), TCPConnectionState::SYNReceived);
+
+    for packet in server.sent_packets() {
+      client.handle(&packet);
+    }
+    let handshakeCompletingAcknowledgements = client.sent_packets();
+    assert_eq!(handshakeCompletingAcknowledgements.len(), 1);
+
+    // Completing the handshake moves the connection to FIN-WAIT-1, as well as into the accept
+    // queue, where the user gets to read whatever the peer sends till it closes too.
+    assert_eq!(
+      server.handle(&handshakeCompletingAcknowledgements[0]),
+      Action::MoveToAcceptQueue
+    );
+    assert_eq!(server.connection.state(), TCPConnectionState::FinWait1);
+    assert!(server.connection.establishedAt.is_some());
+    assert!(server
+      .sent_packets()
+      .iter()
+      .any(|packet| segment_view(packet).header.fin()));
+  }
+
+  /*
+    A segment the server endpoint could send the client endpoint, once the connection between them
+    is established : carrying the server's data from the given offset on, and acknowledging the
+    given amount of the client's data.
+  */
+  fn segment_to_client(offset: u32, payload: &[u8], fin: bool, acknowledgedLength: u32) -> Vec<u8> {
+    let mut tcpHeader = TcpHeader::new(8080, 51514, (SERVER_ISS + 1).wrapping_add(offset), 1024);
+    tcpHeader.ack = true;
+    tcpHeader.acknowledgment_number = CLIENT_ISS + 1 + acknowledgedLength;
+    tcpHeader.fin = fin;
+
+    ipv4_packet(tcpHeader, payload)
+  }
+
+  // An IPv4 packet carrying the given segment. The endpoints only look at the TCP segment, so the
+  // addresses don't matter.
+  fn ipv4_packet(tcpHeader: TcpHeader, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+      .tcp_header(tcpHeader)
+      .write(&mut packet, payload)
+      .unwrap();
+    packet
+  }
+
+  // Reads whatever has arrived. Returns whether the read hit EOF.
+  fn read_available(endpoint: &mut Endpoint) -> bool {
+    let mut ctx = SendContext { nic: &endpoint.nic };
+
+    let mut buffer = [0u8; RECEIVE_BUFFER_CAPACITY];
+    loop {
+      match endpoint.connection.read(&mut buffer, &mut ctx) {
+        Ok(0) => return true,
+        Ok(readLength) => endpoint
+          .receivedData
+          .extend_from_slice(&buffer[..readLength]),
+        Err(TcpError::WouldBlock) => return false,
+        Err(error) => panic!("Failed reading : {}", error),
+      }
+    }
+  }
+
+  #[test]
+  fn a_shut_receive_window_still_takes_in_acknowledgements() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+
+    // Nothing gets read, so the server's data shuts the client's receive window.
+    client.handle(&segment_to_client(
+      0,
+      &[1; RECEIVE_BUFFER_CAPACITY],
+      false,
+      0,
+    ));
+    client.sent_packets();
+    assert_eq!(client.connection.receiveSequenceVariables.windowSize, 0);
+
+    client
+      .connection
+      .write(&[2; 100], &mut SendContext { nic: &client.nic })
+      .unwrap();
+    assert_eq!(client.sent_packets().len(), 1);
+    assert_eq!(client.connection.bytes_unacked(), 100);
+
+    // The server probes the shut window, acknowledging the client's data along the way. The probed
+    // byte gets dropped, but not the acknowledgment.
+    let receiveNext = SERVER_ISS + 1 + RECEIVE_BUFFER_CAPACITY as u32;
+    client.handle(&segment_to_client(
+      RECEIVE_BUFFER_CAPACITY as u32,
+      &[3],
+      false,
+      100,
+    ));
+    assert_eq!(client.connection.bytes_unacked(), 0);
+    assert_eq!(client.connection.bytes_to_read(), RECEIVE_BUFFER_CAPACITY);
+
+    let sentPackets = client.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    let acknowledgement = segment_view(&sentPackets[0]);
+    assert!(acknowledgement.payload.is_empty());
+    assert_eq!(acknowledgement.header.acknowledgment_number(), receiveNext);
+    assert_eq!(acknowledgement.header.window_size(), 0);
+
+    // A FIN doesn't fit in a shut window either.
+    client.handle(&segment_to_client(
+      RECEIVE_BUFFER_CAPACITY as u32,
+      &[],
+      true,
+      100,
+    ));
+    assert_eq!(client.connection.state(), TCPConnectionState::Established);
+    let sentPackets = client.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    assert_eq!(
+      segment_view(&sentPackets[0]).header.acknowledgment_number(),
+      receiveNext
+    );
+  }
+
+  /*
+    The server's 800 bytes reach the client as 4 segments of 200, the last one carrying the FIN.
+    The first 2 got lost, and arrive as retransmissions after the third one, with the last one
+    delivered in between wherever the given order puts it. Till the gap is filled, the reader
+    mustn't see EOF, nor the FIN get acknowledged. Then it sees the whole stream, and EOF.
+  */
+  fn receive_around_a_gap(order: [usize; 4]) {
+    const SEGMENT_SIZE: usize = 200;
+
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+
+    let data: Vec<u8> = (0..4 * SEGMENT_SIZE)
+      .map(|index| (index % 251) as u8)
+      .collect();
+    let mut isDelivered = [false; 4];
+
+    for index in order {
+      let offset = index * SEGMENT_SIZE;
+      client.handle(&segment_to_client(
+        offset as u32,
+        &data[offset..offset + SEGMENT_SIZE],
+        index == 3,
+        0,
+      ));
+      isDelivered[index] = true;
+
+      let deliveredInOrder = isDelivered
+        .iter()
+        .take_while(|isDelivered| **isDelivered)
+        .count();
+      let isComplete = deliveredInOrder == 4;
+      let isEOF = read_available(&mut client);
+
+      assert_eq!(isEOF, isComplete, "{:?} after segment {}", order, index);
+      assert_eq!(
+        client.receivedData,
+        data[..deliveredInOrder * SEGMENT_SIZE],
+        "{:?} after segment {}",
+        order,
+        index
+      );
+
+      // Every ACK points at the gap, till the FIN gets consumed.
+      let receiveNext =
+        SERVER_ISS + 1 + (deliveredInOrder * SEGMENT_SIZE) as u32 + isComplete as u32;
+      for packet in client.sent_packets() {
+        assert_eq!(
+          segment_view(&packet).header.acknowledgment_number(),
+          receiveNext,
+          "{:?} after segment {}",
+          order,
+          index
+        );
+      }
+    }
+
+    assert_eq!(client.connection.state(), TCPConnectionState::CloseWait);
+    assert!(read_available(&mut client));
+    assert_eq!(client.receivedData, data);
+  }
+
+  #[test]
+  fn a_fin_arriving_before_the_gap_filling_retransmissions() {
+    receive_around_a_gap([2, 3, 0, 1]);
+  }
+
+  #[test]
+  fn a_fin_arriving_in_between_the_gap_filling_retransmissions() {
+    receive_around_a_gap([2, 0, 3, 1]);
+  }
+
+  #[test]
+  fn a_fin_arriving_after_the_gap_filling_retransmissions() {
+    receive_around_a_gap([2, 0, 1, 3]);
+  }
+
+  /*
+    Processes the segment the way on_segment( ) does, and returns the replies to it. Checks that
+    every one of them got spent from the control segment budget, which on_segment( ) drops once
+    done.
+  */
+  fn replies_within_budget(endpoint: &mut Endpoint, packet: &[u8]) -> Vec<Vec<u8>> {
+    let segment = segment_view(packet);
+    let connection = &mut endpoint.connection;
+
+    connection.controlSegmentBudget = Some(CONTROL_SEGMENT_BUDGET);
+    match connection.state {
+      TCPConnectionState::SYNSent => connection.on_syn_sent_segment(&segment.header, &endpoint.nic),
+      _ => connection.on_synchronized_segment(&segment.header, segment.payload, &endpoint.nic),
+    }
+    .unwrap();
+
+    let replies = endpoint.sent_packets();
+    assert!(replies.len() as u32 <= CONTROL_SEGMENT_BUDGET);
+    assert_eq!(
+      endpoint.connection.controlSegmentBudget,
+      Some(CONTROL_SEGMENT_BUDGET - replies.len() as u32),
+      "A reply bypassed the budget"
+    );
+    replies
+  }
+
+  fn is_reset(packet: &[u8]) -> bool {
+    segment_view(packet).header.rst()
+  }
+
+  #[test]
+  fn an_unacceptable_segment_gets_at_most_the_budgeted_replies() {
+    let clock = Arc::new(VirtualClock::default());
+
+    // SYN-RECEIVED, answering an ACK which doesn't acknowledge our SYN with a RST.
+    let (client, mut server) = endpoints(&clock, TcpTuning::default());
+    for packet in client.sent_packets() {
+      server.handle(&packet);
+    }
+    server.sent_packets();
+    let mut badAcknowledgement = TcpHeader::new(51514, 8080, CLIENT_ISS + 1, 1024);
+    badAcknowledgement.ack = true;
+    badAcknowledgement.acknowledgment_number = SERVER_ISS + 1000;
+    let replies = replies_within_budget(&mut server, &ipv4_packet(badAcknowledgement, &[]));
+    assert_eq!(replies.len(), 1);
+    assert!(is_reset(&replies[0]));
+
+    // SYN-SENT, answering the peer's crossing SYN with a SYN-ACK (simultaneous open).
+    let (mut client, _) = endpoints(&clock, TcpTuning::default());
+    client.sent_packets();
+    let mut crossingSYN = TcpHeader::new(8080, 51514, SERVER_ISS, 1024);
+    crossingSYN.syn = true;
+    let replies = replies_within_budget(&mut client, &ipv4_packet(crossingSYN, &[]));
+    assert_eq!(replies.len(), 1);
+    let synACK = segment_view(&replies[0]);
+    assert!(synACK.header.syn() && synACK.header.ack());
+    assert_eq!(synACK.header.sequence_number(), CLIENT_ISS);
+    assert_eq!(client.connection.state(), TCPConnectionState::SYNReceived);
+    assert_eq!(
+      client.connection.sendSequenceVariables.nextSequenceNumber,
+      CLIENT_ISS + 1
+    );
+
+    // ESTABLISHED, answering segments outside the window, old duplicates (over and over, for
+    // the reverse loss detection to kick in), in-window SYNs and RSTs with challenge ACKs.
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    let receiveWindowEnd = RECEIVE_BUFFER_CAPACITY as u32;
+    let unacceptableSegments = [
+      segment_to_client(receiveWindowEnd + 100, &[1; 100], false, 0),
+      segment_to_client(u32::MAX - 200, &[1; 100], false, 0),
+    ];
+    for packet in unacceptableSegments.iter().cycle().take(20) {
+      let replies = replies_within_budget(&mut client, packet);
+      assert!(!replies.is_empty());
+      assert!(!replies.iter().any(|reply| is_reset(reply)));
+    }
+
+    for (syn, rst) in [(true, false), (false, true)] {
+      let mut header = TcpHeader::new(8080, 51514, SERVER_ISS + 1 + 10, 1024);
+      header.syn = syn;
+      header.rst = rst;
+
+      let replies = replies_within_budget(&mut client, &ipv4_packet(header, &[]));
+      assert_eq!(replies.len(), 1);
+      assert!(!is_reset(&replies[0]));
+    }
+    assert_eq!(client.connection.state(), TCPConnectionState::Established);
+  }
+
+  #[test]
+  fn data_written_while_connecting_gets_sent_once_established() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = endpoints(&clock, TcpTuning::default());
+
+    // Nothing but the SYN goes out, however much gets written.
+    client.write_and_read(&[1; 1000]);
+    assert_eq!(client.writtenLength, 1000);
+    assert_eq!(client.connection.bytes_queued(), 1000);
+    let sentPackets = client.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    assert!(segment_view(&sentPackets[0]).header.syn());
+    assert!(segment_view(&sentPackets[0]).payload.is_empty());
+
+    server.handle(&sentPackets[0]);
+    let synACK = server.sent_packets();
+    client.handle(&synACK[0]);
+
+    // The handshake completing ACK gets followed by the data, right from ISS + 1.
+    let sentPackets = client.sent_packets();
+    let dataSegments: Vec<_> = sentPackets
+      .iter()
+      .map(|packet| segment_view(packet))
+      .filter(|segment| !segment.payload.is_empty())
+      .collect();
+    assert_eq!(dataSegments.len(), 2);
+    assert_eq!(dataSegments[0].header.sequence_number(), CLIENT_ISS + 1);
+    assert_eq!(
+      dataSegments
+        .iter()
+        .map(|segment| segment.payload.len())
+        .sum::<usize>(),
+      1000
+    );
+
+    for packet in sentPackets {
+      server.handle(&packet);
+    }
+    exchange(&mut client, &mut server);
+    server.write_and_read(&[]);
+    assert_eq!(server.receivedData, [1; 1000]);
+  }
+
+  #[test]
+  fn data_written_in_syn_received_gets_sent_once_established() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = endpoints(&clock, TcpTuning::default());
+
+    for packet in client.sent_packets() {
+      server.handle(&packet);
+    }
+    assert_eq!(server.connection.state(), TCPConnectionState::SYNReceived);
+
+    // Only the SYN-ACK goes out.
+    server.write_and_read(&[2; 300]);
+    assert_eq!(server.writtenLength, 300);
+    let sentPackets = server.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    assert!(segment_view(&sentPackets[0]).payload.is_empty());
+
+    for packet in sentPackets {
+      client.handle(&packet);
+    }
+    for packet in client.sent_packets() {
+      server.handle(&packet);
+    }
+    assert_eq!(server.connection.state(), TCPConnectionState::Established);
+
+    let sentPackets = server.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    let dataSegment = segment_view(&sentPackets[0]);
+    assert_eq!(dataSegment.header.sequence_number(), SERVER_ISS + 1);
+    assert_eq!(dataSegment.payload, [2; 300]);
+
+    client.handle(&sentPackets[0]);
+    client.write_and_read(&[]);
+    assert_eq!(client.receivedData, [2; 300]);
+  }
+
+  #[test]
+  fn data_written_while_connecting_gets_discarded_once_refused() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = endpoints(&clock, TcpTuning::default());
+    client.sent_packets();
+
+    client.write_and_read(&[1; 1000]);
+    assert_eq!(client.connection.bytes_queued(), 1000);
+
+    let mut reset = TcpHeader::new(8080, 51514, 0, 0);
+    reset.rst = true;
+    reset.ack = true;
+    reset.acknowledgment_number = CLIENT_ISS + 1;
+    client.handle(&ipv4_packet(reset, &[]));
+
+    assert_eq!(client.connection.state(), TCPConnectionState::Closed);
+    assert_eq!(client.connection.bytes_queued(), 0);
+    assert!(client.sent_packets().is_empty());
+    assert!(matches!(
+      client
+        .connection
+        .write(&[1], &mut SendContext { nic: &client.nic }),
+      Err(TcpError::ConnectionRefused)
+    ));
+  }
+
+  #[test]
+  fn a_duplicate_syn_ack_gets_acknowledged_once_more() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = endpoints(&clock, TcpTuning::default());
+    for packet in client.sent_packets() {
+      server.handle(&packet);
+    }
+    let synACK = server.sent_packets().pop().unwrap();
+
+    // The ACK completing the handshake gets lost, so the server retransmits its SYN-ACK.
+    client.handle(&synACK);
+    assert_eq!(client.connection.state(), TCPConnectionState::Established);
+    let lostAcknowledgement = client.sent_packets().pop().unwrap();
+
+    client.handle(&synACK);
+    let sentPackets = client.sent_packets();
+    assert_eq!(sentPackets, [lostAcknowledgement]);
+    let acknowledgement = segment_view(&sentPackets[0]);
+    assert!(!acknowledgement.header.syn() && acknowledgement.payload.is_empty());
+    assert_eq!(acknowledgement.header.sequence_number(), CLIENT_ISS + 1);
+    assert_eq!(
+      acknowledgement.header.acknowledgment_number(),
+      SERVER_ISS + 1
+    );
+
+    // It's told apart from a SYN on an established connection, which gets a challenge ACK.
+    assert_eq!(client.connection.state(), TCPConnectionState::Established);
+    assert_eq!(client.connection.stats().duplicate_syn_acks(), 1);
+    assert_eq!(client.connection.stats().challenge_acknowledgements(), 0);
+
+    server.handle(&sentPackets[0]);
+    assert_eq!(server.connection.state(), TCPConnectionState::Established);
+    assert!(server.sent_packets().is_empty());
+  }
+
+  #[test]
+  fn a_reader_consuming_a_byte_at_a_time_gets_paced_window_updates() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    let threshold = DEFAULT_MAXIMUM_SEGMENT_SIZE.min(RECEIVE_BUFFER_CAPACITY / 2);
+
+    client.handle(&segment_to_client(
+      0,
+      &[1; RECEIVE_BUFFER_CAPACITY],
+      false,
+      0,
+    ));
+    client.sent_packets();
+    assert_eq!(client.connection.advertisedWindowSize, 0);
+
+    // Returns the windows advertised by the updates sent meanwhile.
+    let readBytes = |client: &mut Endpoint, count: usize| {
+      let mut ctx = SendContext { nic: &client.nic };
+      for _ in 0..count {
+        assert_eq!(client.connection.read(&mut [0], &mut ctx).unwrap(), 1);
+      }
+      client
+        .sent_packets()
+        .iter()
+        .map(|packet| segment_view(packet).header.window_size() as usize)
+        .collect::<Vec<_>>()
+    };
+
+    // Nothing till the window has grown by the threshold. Since the peer is blocked on the shut
+    // window, that update goes out right away.
+    assert!(readBytes(&mut client, threshold - 1).is_empty());
+    assert_eq!(readBytes(&mut client, 1), [threshold]);
+
+    // The next one is due once the window has grown by the threshold again, but gets held back, as
+    // an update just went out.
+    assert!(readBytes(&mut client, RECEIVE_BUFFER_CAPACITY - threshold).is_empty());
+    assert_eq!(client.connection.stats().window_updates_suppressed(), 1);
+
+    // And goes out on the tick following the interval.
+    clock.advance(TcpTuning::default().windowUpdateInterval);
+    client.connection.on_tick(clock.now(), &client.nic).unwrap();
+    let windowUpdates = readBytes(&mut client, 0);
+    assert_eq!(windowUpdates, [RECEIVE_BUFFER_CAPACITY]);
+
+    // 2 ACKs for the 1024 reads.
+    assert_eq!(client.connection.stats().window_updates_sent(), 2);
+  }
+
+  // The payload lengths of the data segments among the packets.
+  fn payload_lengths(packets: &[Vec<u8>]) -> Vec<usize> {
+    packets
+      .iter()
+      .map(|packet| segment_view(packet).payload.len())
+      .filter(|length| *length > 0)
+      .collect()
+  }
+
+  #[test]
+  fn a_write_of_exactly_the_mss_goes_out_as_one_segment() {
+    let clock = Arc::new(VirtualClock::default());
+
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    let maximumSegmentSize = client.connection.send_maximum_segment_size();
+    client.write_and_read(&vec![1; maximumSegmentSize]);
+    assert_eq!(
+      payload_lengths(&client.sent_packets()),
+      [maximumSegmentSize]
+    );
+
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    client.write_and_read(&vec![1; maximumSegmentSize + 1]);
+    assert_eq!(
+      payload_lengths(&client.sent_packets()),
+      [maximumSegmentSize, 1]
+    );
+  }
+
+  #[test]
+  fn a_write_of_exactly_the_peer_window_fills_it() {
+    let clock = Arc::new(VirtualClock::default());
+    let peerWindow = RECEIVE_BUFFER_CAPACITY;
+
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    assert_eq!(
+      client.connection.sendSequenceVariables.windowSize as usize,
+      peerWindow
+    );
+    client.write_and_read(&vec![1; peerWindow]);
+    let payloadLengths = payload_lengths(&client.sent_packets());
+    assert_eq!(payloadLengths.iter().sum::<usize>(), peerWindow);
+    assert_eq!(client.connection.bytes_unacked(), peerWindow);
+    assert_eq!(client.connection.bytes_queued(), 0);
+
+    // A byte more waits for the window to open.
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    client.write_and_read(&vec![1; peerWindow + 1]);
+    assert_eq!(payload_lengths(&client.sent_packets()), payloadLengths);
+    assert_eq!(client.connection.bytes_unacked(), peerWindow);
+    assert_eq!(client.connection.bytes_queued(), 1);
+  }
+
+  #[test]
+  fn a_segment_of_exactly_the_receive_buffer_fills_it() {
+    let clock = Arc::new(VirtualClock::default());
+    let receiveWindowEnd = SERVER_ISS + 1 + RECEIVE_BUFFER_CAPACITY as u32;
+
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    client.handle(&segment_to_client(
+      0,
+      &[1; RECEIVE_BUFFER_CAPACITY],
+      false,
+      0,
+    ));
+    assert_eq!(client.connection.bytes_to_read(), RECEIVE_BUFFER_CAPACITY);
+    let acknowledgement = client.sent_packets().pop().unwrap();
+    assert_eq!(
+      segment_view(&acknowledgement)
+        .header
+        .acknowledgment_number(),
+      receiveWindowEnd
+    );
+    assert_eq!(segment_view(&acknowledgement).header.window_size(), 0);
+
+    // A byte more gets trimmed off.
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    client.handle(&segment_to_client(
+      0,
+      &[1; RECEIVE_BUFFER_CAPACITY + 1],
+      false,
+      0,
+    ));
+    assert_eq!(client.connection.bytes_to_read(), RECEIVE_BUFFER_CAPACITY);
+    let acknowledgement = client.sent_packets().pop().unwrap();
+    assert_eq!(
+      segment_view(&acknowledgement)
+        .header
+        .acknowledgment_number(),
+      receiveWindowEnd
+    );
+
+    // And a segment starting right past the window isn't acceptable.
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    client.handle(&segment_to_client(
+      RECEIVE_BUFFER_CAPACITY as u32,
+      &[1],
+      false,
+      0,
+    ));
+    assert_eq!(client.connection.bytes_to_read(), 0);
+    let acknowledgement = client.sent_packets().pop().unwrap();
+    assert_eq!(
+      segment_view(&acknowledgement)
+        .header
+        .acknowledgment_number(),
+      SERVER_ISS + 1
+    );
+  }
+
+  const FIN_WAIT_2_TIMEOUT: Duration = Duration::from_secs(5);
+
+  // A client whose FIN the server acknowledged, without sending its own, along with the server.
+  fn endpoints_in_fin_wait_2(
+    clock: &Arc<VirtualClock>,
+    close: impl FnOnce(&mut TCPConnection, &mut SendContext),
+  ) -> (Endpoint, Endpoint) {
+    let tuning = TcpTuning {
+      finWait2Timeout: Some(FIN_WAIT_2_TIMEOUT),
+      ..TcpTuning::default()
+    };
+    let (mut client, mut server) = established_endpoints(clock, tuning);
+
+    close(
+      &mut client.connection,
+      &mut SendContext { nic: &client.nic },
+    );
+    exchange(&mut client, &mut server);
+    assert_eq!(client.connection.state(), TCPConnectionState::FinWait2);
+    assert_eq!(server.connection.state(), TCPConnectionState::CloseWait);
+    (client, server)
+  }
+
+  // Advances the clock by the given amount, a tick at a time.
+  fn tick_for(clock: &VirtualClock, endpoint: &mut Endpoint, duration: Duration) {
+    let deadline = clock.now() + duration;
+    while clock.now() < deadline {
+      clock.advance(TICK_INTERVAL);
+      endpoint
+        .connection
+        .on_tick(clock.now(), &endpoint.nic)
+        .unwrap();
+    }
+  }
+
+  #[test]
+  fn fin_wait_2_times_out_once_fully_closed() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = endpoints_in_fin_wait_2(&clock, |connection, ctx| {
+      connection.close_fully(ctx).unwrap();
+    });
+
+    tick_for(&clock, &mut client, FIN_WAIT_2_TIMEOUT - TICK_INTERVAL);
+    assert_eq!(client.connection.state(), TCPConnectionState::FinWait2);
+
+    tick_for(&clock, &mut client, TICK_INTERVAL);
+    assert_eq!(client.connection.state(), TCPConnectionState::Closed);
+    assert_eq!(
+      client.connection.close_reason(),
+      Some(CloseReason::FinWait2Timeout)
+    );
+    assert!(client.sent_packets().is_empty());
+  }
+
+  #[test]
+  fn fin_wait_2_doesnt_time_out_after_only_shutting_down_writing() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = endpoints_in_fin_wait_2(&clock, |connection, ctx| {
+      connection.close(ctx).unwrap();
+    });
+
+    // The peer may carry on sending for as long as it likes.
+    tick_for(&clock, &mut client, FIN_WAIT_2_TIMEOUT * 3);
+    assert_eq!(client.connection.state(), TCPConnectionState::FinWait2);
+
+    server.write_and_read(&[3; 100]);
+    server
+      .connection
+      .close(&mut SendContext { nic: &server.nic })
+      .unwrap();
+    exchange(&mut client, &mut server);
+    assert!(read_available(&mut client));
+    assert_eq!(client.receivedData, [3; 100]);
+    assert_eq!(client.connection.state(), TCPConnectionState::TimeWait);
+  }
+
+  #[test]
+  fn shutting_down_reading_in_fin_wait_2_starts_the_timeout() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = endpoints_in_fin_wait_2(&clock, |connection, ctx| {
+      connection.close(ctx).unwrap();
+    });
+
+    tick_for(&clock, &mut client, FIN_WAIT_2_TIMEOUT * 2);
+    client.connection.shutdown_read();
+
+    tick_for(&clock, &mut client, FIN_WAIT_2_TIMEOUT - TICK_INTERVAL);
+    assert_eq!(client.connection.state(), TCPConnectionState::FinWait2);
+    tick_for(&clock, &mut client, TICK_INTERVAL);
+    assert_eq!(
+      client.connection.close_reason(),
+      Some(CloseReason::FinWait2Timeout)
+    );
+  }
+
+  // bytes_to_read( ), bytes_queued( ) and bytes_unacked( ) of the connection.
+  fn byte_counts(connection: &TCPConnection) -> [usize; 3] {
+    [
+      connection.bytes_to_read(),
+      connection.bytes_queued(),
+      connection.bytes_unacked(),
+    ]
+  }
+
+  #[test]
+  fn byte_counts_follow_a_transfer() {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, mut server) = established_endpoints(&clock, TcpTuning::default());
+    assert_eq!(byte_counts(&client.connection), [0, 0, 0]);
+
+    // The server's window takes in 1024 of the 2000 bytes.
+    client
+      .connection
+      .write(&[1; 2000], &mut SendContext { nic: &client.nic })
+      .unwrap();
+    assert_eq!(byte_counts(&client.connection), [0, 976, 1024]);
+
+    let sentPackets = client.sent_packets();
+    let (first, rest) = sentPackets.split_first().unwrap();
+    let firstLength = segment_view(first).payload.len();
+
+    // The first segment arrives, but isn't read.
+    server.handle(first);
+    assert_eq!(byte_counts(&server.connection), [firstLength, 0, 0]);
+    for packet in server.sent_packets() {
+      client.handle(&packet);
+    }
+    assert_eq!(
+      byte_counts(&client.connection),
+      [0, 976, 1024 - firstLength]
+    );
+
+    // The rest of the flight, which shuts the window.
+    for packet in rest {
+      server.handle(packet);
+    }
+    assert_eq!(byte_counts(&server.connection), [1024, 0, 0]);
+    for packet in server.sent_packets() {
+      client.handle(&packet);
+    }
+    assert_eq!(byte_counts(&client.connection), [0, 976, 0]);
+
+    // Reading reopens the window, letting the rest through.
+    let mut buffer = [0; 600];
+    server
+      .connection
+      .read(&mut buffer, &mut SendContext { nic: &server.nic })
+      .unwrap();
+    assert_eq!(byte_counts(&server.connection), [424, 0, 0]);
+    for packet in server.sent_packets() {
+      client.handle(&packet);
+    }
+    assert_eq!(byte_counts(&client.connection), [0, 376, 600]);
+
+    for _ in 0..4 {
+      exchange(&mut client, &mut server);
+      read_available(&mut server);
+    }
+    exchange(&mut client, &mut server);
+    assert_eq!(server.receivedData.len(), 2000 - 600);
+    assert_eq!(byte_counts(&server.connection), [0, 0, 0]);
+    assert_eq!(byte_counts(&client.connection), [0, 0, 0]);
+  }
+
+  /*
+    The client received the server's bytes 0-199 (as 0-99 and 100-199), lost 200-299, and gets
+    150-349 retransmitted in one segment, overlapping RCV.NXT. The given segments arrive before the
+    retransmission, past the gap. The reader should see every byte exactly once, up to the given
+    end, with a single ACK for the retransmission.
+  */
+  fn receive_overlapping_receive_next(stashedSegments: &[(usize, usize)], end: usize) {
+    let clock = Arc::new(VirtualClock::default());
+    let (mut client, _) = established_endpoints(&clock, TcpTuning::default());
+    let data: Vec<u8> = (0..400).map(|index| (index % 251) as u8).collect();
+
+    for (start, end) in [(0, 100), (100, 200)].iter().chain(stashedSegments) {
+      client.handle(&segment_to_client(
+        *start as u32,
+        &data[*start..*end],
+        false,
+        0,
+      ));
+    }
+    read_available(&mut client);
+    assert_eq!(client.receivedData, data[..200]);
+    client.sent_packets();
+
+    client.handle(&segment_to_client(150, &data[150..350], false, 0));
+    let sentPackets = client.sent_packets();
+    assert_eq!(sentPackets.len(), 1);
+    assert_eq!(
+      segment_view(&sentPackets[0]).header.acknowledgment_number(),
+      SERVER_ISS + 1 + end as u32
+    );
+
+    read_available(&mut client);
+    assert_eq!(client.receivedData, data[..end]);
+    assert_eq!(client.connection.stats().overlapping_segments(), 1);
+  }
+
+  #[test]
+  fn a_retransmission_overlapping_receive_next_gets_delivered_once() {
+    receive_overlapping_receive_next(&[], 350);
+  }
 
-    Ok(connection)
+  #[test]
+  fn a_retransmission_overlapping_receive_next_and_the_stash_gets_delivered_once() {
+    receive_overlapping_receive_next(&[(300, 400)], 400);
   }
 }
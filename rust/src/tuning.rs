@@ -0,0 +1,244 @@
+use {
+  crate::tcp::TCPConnectionState,
+  anyhow::anyhow,
+  std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    time::Duration,
+  },
+};
+
+// Knobs controlling the behaviour of every connection on an Interface.
+#[derive(Clone, Copy)]
+pub struct TcpTuning {
+  pub peerViolationPolicy: PeerViolationPolicy,
+
+  // Default TCP User Timeout of new connections. None disables it.
+  pub userTimeout: Option<Duration>,
+
+  // How long to wait for the answer to our first SYN, before retransmitting it. The wait doubles
+  // with every retransmission.
+  pub initialSYNRetransmissionTimeout: Duration,
+
+  // How many times our SYN gets sent in total, before giving up on connecting.
+  pub maximumSYNTransmissions: u32,
+
+  // How many times our SYN-ACK gets sent in total, before giving up on a passively opened
+  // connection whose handshake doesn't complete. It uses the same timeouts as the SYN.
+  pub maximumSYNACKTransmissions: u32,
+
+  // How long connecting may take at most, no matter how many SYNs have been sent by then.
+  pub connectTimeout: Duration,
+
+  // Default receive coalescing of new connections. None disables it.
+  pub receiveCoalescing: Option<ReceiveCoalescing>,
+
+  // Most bytes written to a connection which may be waiting to be sent or acknowledged at once,
+  // rounded up to a whole number of send buffer chunks. None picks SEND_BUFFER_CAPACITY.
+  pub sendBufferCapacity: Option<usize>,
+
+  /*
+    How much room has to free up in the send buffer, before a writer which filled it up gets woken
+    up. Waking it up on every acknowledged segment would have it write a segment's worth at a time.
+    None picks the larger of 1 MSS and a quarter of the send buffer.
+  */
+  pub sendLowWatermark: Option<usize>,
+
+  // Least time between two unsolicited window updates of a connection, since we don't measure the
+  // RTT. Updates reopening a zero window don't wait for it.
+  pub windowUpdateInterval: Duration,
+
+  /*
+    How long a connection may stay in the FIN-WAIT-2 state, once the user has shut down reading
+    too, like Linux's tcp_fin_timeout. Without it, a peer which acknowledges our FIN and then
+    disappears would hold the TCB forever. None disables it.
+  */
+  pub finWait2Timeout: Option<Duration>,
+
+  /*
+    How many connections the Interface is expected to hold at once. The connection map gets sized
+    for that many upfront, so that it doesn't need to grow (holding the map's lock all the while)
+    under load. Nor does it shrink below that.
+  */
+  pub expectedConnections: Option<usize>,
+
+  // How long a connection may sit in a state, before a warning gets logged about it.
+  pub stuckStateThresholds: StuckStateThresholds,
+
+  /*
+    How many retransmissions of data we've already acknowledged a connection may receive without
+    RCV.NXT moving, before it suspects the peer isn't getting our ACKs. None disables the
+    detection.
+  */
+  pub reverseLossThreshold: Option<u32>,
+}
+
+/*
+  Connections sitting in one of these states for long usually mean trouble : a handshake which
+  never completes, a peer which never sends its FIN, or a user who never closes the connection.
+  None disables the warning for that state.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StuckStateThresholds {
+  pub synReceived: Option<Duration>,
+  pub finWait1: Option<Duration>,
+  pub finWait2: Option<Duration>,
+  pub closing: Option<Duration>,
+  pub closeWait: Option<Duration>,
+  pub lastAck: Option<Duration>,
+}
+
+impl Default for StuckStateThresholds {
+  fn default() -> Self {
+    Self {
+      synReceived: Some(Duration::from_secs(60)),
+      finWait1: Some(Duration::from_secs(5 * 60)),
+      finWait2: Some(Duration::from_secs(10 * 60)),
+      closing: Some(Duration::from_secs(5 * 60)),
+      closeWait: Some(Duration::from_secs(10 * 60)),
+      lastAck: Some(Duration::from_secs(5 * 60)),
+    }
+  }
+}
+
+impl StuckStateThresholds {
+  // The states a threshold can be set for.
+  pub const STATES: [TCPConnectionState; 6] = [
+    TCPConnectionState::SYNReceived,
+    TCPConnectionState::FinWait1,
+    TCPConnectionState::FinWait2,
+    TCPConnectionState::Closing,
+    TCPConnectionState::CloseWait,
+    TCPConnectionState::LastAck,
+  ];
+
+  pub fn threshold(&self, state: TCPConnectionState) -> Option<Duration> {
+    match state {
+      TCPConnectionState::SYNReceived => self.synReceived,
+      TCPConnectionState::FinWait1 => self.finWait1,
+      TCPConnectionState::FinWait2 => self.finWait2,
+      TCPConnectionState::Closing => self.closing,
+      TCPConnectionState::CloseWait => self.closeWait,
+      TCPConnectionState::LastAck => self.lastAck,
+      _ => None,
+    }
+  }
+
+  // Parses "<state> <milliseconds>" or "<state> off", like "FIN-WAIT-2 600000", and sets the
+  // threshold of that state.
+  pub fn set(&mut self, entry: &str) -> anyhow::Result<()> {
+    let (state, threshold) = entry
+      .split_once(' ')
+      .ok_or_else(|| anyhow!("Expected <state> <milliseconds | off>"))?;
+
+    let threshold = match threshold.trim() {
+      "off" => None,
+      milliseconds => Some(Duration::from_millis(milliseconds.parse::<u64>().map_err(
+        |error| anyhow!("Invalid threshold '{}' : {}", milliseconds, error),
+      )?)),
+    };
+
+    let slot = match state {
+      "SYN-RECEIVED" => &mut self.synReceived,
+      "FIN-WAIT-1" => &mut self.finWait1,
+      "FIN-WAIT-2" => &mut self.finWait2,
+      "CLOSING" => &mut self.closing,
+      "CLOSE-WAIT" => &mut self.closeWait,
+      "LAST-ACK" => &mut self.lastAck,
+      _ => return Err(anyhow!("No threshold can be set for the state '{}'", state)),
+    };
+
+    *slot = threshold;
+    Ok(())
+  }
+}
+
+/*
+  Small in-order segments, which arrive while a reader is blocked, don't wake it up right away.
+  Instead, the data accumulates till either :
+
+    (1) threshold bytes (or half of the receive buffer, if that's smaller) are waiting,
+    (2) a segment with PSH or FIN arrives,
+    (3) anything other than data arrives (an ACK of our data, a state change etc.),
+    (4) or budget has passed since the first held back segment, which is checked whenever a segment
+        arrives and on every tick.
+
+  The reader then gets woken up once, with the aggregate.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReceiveCoalescing {
+  pub budget: Duration,
+  pub threshold: usize,
+}
+
+impl Default for ReceiveCoalescing {
+  fn default() -> Self {
+    Self {
+      budget: Duration::from_millis(1),
+      threshold: 4 * 1024,
+    }
+  }
+}
+
+impl Default for TcpTuning {
+  fn default() -> Self {
+    Self {
+      peerViolationPolicy: PeerViolationPolicy::default(),
+      userTimeout: None,
+      initialSYNRetransmissionTimeout: Duration::from_secs(1),
+      maximumSYNTransmissions: 6,
+      maximumSYNACKTransmissions: 6,
+      connectTimeout: Duration::from_secs(75),
+      receiveCoalescing: None,
+      sendBufferCapacity: None,
+      sendLowWatermark: None,
+      windowUpdateInterval: Duration::from_millis(100),
+      finWait2Timeout: Some(Duration::from_secs(60)),
+      expectedConnections: None,
+      stuckStateThresholds: StuckStateThresholds::default(),
+      reverseLossThreshold: Some(8),
+    }
+  }
+}
+
+/*
+  Real-world peers violate the spec in small ways : data after FIN, shrinking windows, options on
+  non-SYN segments they shouldn't send, ACKs of unsent data which are off by a few bytes due to
+  middleboxes etc.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PeerViolationPolicy {
+  // The offending segment is dropped or the connection is reset, with a warning logged. Useful for
+  // conformance testing other TCP stacks against us.
+  Strict,
+
+  // The violation is tolerated, and the connection carries on as best as it can.
+  #[default]
+  Lenient,
+}
+
+impl Display for PeerViolationPolicy {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let name = match self {
+      Self::Strict => "strict",
+      Self::Lenient => "lenient",
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for PeerViolationPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(policy: &str) -> anyhow::Result<Self> {
+    match policy {
+      "strict" => Ok(Self::Strict),
+      "lenient" => Ok(Self::Lenient),
+      _ => Err(anyhow!(
+        "Unknown peer violation policy '{}', expected strict or lenient",
+        policy
+      )),
+    }
+  }
+}
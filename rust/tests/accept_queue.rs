@@ -0,0 +1,155 @@
+#![allow(non_snake_case)]
+
+mod common;
+
+use {
+  common::{state, Direction, Network},
+  std::{sync::atomic::Ordering, time::Duration},
+  tcp_server::{
+    manager::{self, AcceptQueueOverflow, ListenerOptions},
+    tcp::{CloseReason, TCPConnectionState},
+  },
+};
+
+const PORT: u16 = 8080;
+
+fn listen(network: &Network, overflowPolicy: AcceptQueueOverflow) {
+  network.server_manager().listen_with(
+    PORT,
+    ListenerOptions {
+      backlog: 2,
+      overflowPolicy,
+      ..ListenerOptions::default()
+    },
+  );
+}
+
+// Connections on the server, by their state.
+fn server_states(network: &Network) -> Vec<TCPConnectionState> {
+  let mut states: Vec<_> = network
+    .server_manager()
+    .connections()
+    .iter()
+    .map(|(_, connection)| state(connection))
+    .collect();
+  states.sort_by_key(|state| state.to_string());
+  states
+}
+
+#[test]
+fn refuse_newest_holds_the_handshakes_back_till_theres_room() {
+  let mut network = Network::default();
+  listen(&network, AcceptQueueOverflow::RefuseNewest);
+
+  let clients: Vec<_> = (0..5).map(|_| network.connect(PORT).unwrap()).collect();
+  network.pump();
+
+  // Every client got its SYN-ACK, but only 2 handshakes got completed on the server. The others
+  // wait in SYN-RECEIVED, rather than being established with nobody to accept them.
+  for client in &clients {
+    assert_eq!(state(client), TCPConnectionState::Established);
+  }
+  assert_eq!(
+    server_states(&network),
+    [
+      TCPConnectionState::Established,
+      TCPConnectionState::Established,
+      TCPConnectionState::SYNReceived,
+      TCPConnectionState::SYNReceived,
+      TCPConnectionState::SYNReceived,
+    ]
+  );
+  let serverManager = network.server_manager();
+  let counters = serverManager.counters();
+  assert_eq!(
+    counters
+      .handshakesRefusedByFullAcceptQueue
+      .load(Ordering::Relaxed),
+    3
+  );
+  assert_eq!(
+    counters.acceptQueueOverflowAborts.load(Ordering::Relaxed),
+    0
+  );
+
+  // A retransmitted SYN still gets processed (and answered), rather than dropped along with the
+  // handshake completing ACKs.
+  let synIndex = network
+    .log
+    .iter()
+    .position(|packet| packet.direction == Direction::ToServer && packet.is_syn())
+    .unwrap();
+  let syn = network.log[synIndex].bytes.clone();
+  let logLength = network.log.len();
+  network.server.process_packet(&syn);
+  network.pump();
+  assert_eq!(
+    network.packets_since(logLength, Direction::ToClient).len(),
+    1
+  );
+
+  // Accepting makes room, and the SYN-ACK retransmissions get the remaining handshakes completed.
+  let mut accepted = Vec::new();
+  let isEveryoneAccepted = network.run_until(Duration::from_secs(30), |network| {
+    while let Some(connection) = network.server_manager().try_accept(PORT) {
+      accepted.push(connection);
+    }
+    accepted.len() == 5
+  });
+  assert!(isEveryoneAccepted);
+
+  for connection in accepted.iter().chain(&clients) {
+    assert_eq!(state(connection), TCPConnectionState::Established);
+  }
+  assert_eq!(
+    counters.acceptQueueOverflowAborts.load(Ordering::Relaxed),
+    0
+  );
+}
+
+#[test]
+fn abort_oldest_resets_the_connections_waiting_the_longest() {
+  let mut network = Network::default();
+  listen(&network, AcceptQueueOverflow::AbortOldest);
+
+  let clients: Vec<_> = (0..5).map(|_| network.connect(PORT).unwrap()).collect();
+  network.pump();
+
+  // The 3 oldest got reset as the newer ones completed their handshakes, so that only the 2
+  // newest are left waiting to be accepted.
+  assert_eq!(
+    server_states(&network),
+    [
+      TCPConnectionState::Established,
+      TCPConnectionState::Established
+    ]
+  );
+  let serverManager = network.server_manager();
+  let counters = serverManager.counters();
+  assert_eq!(
+    counters.acceptQueueOverflowAborts.load(Ordering::Relaxed),
+    3
+  );
+  assert_eq!(
+    counters
+      .handshakesRefusedByFullAcceptQueue
+      .load(Ordering::Relaxed),
+    0
+  );
+
+  for (index, client) in clients.iter().enumerate() {
+    let tcb = manager::lock_connection(client);
+    if index < 3 {
+      assert_eq!(tcb.state(), TCPConnectionState::Closed);
+      assert_eq!(tcb.close_reason(), Some(CloseReason::Reset));
+    }
+    else {
+      assert_eq!(tcb.state(), TCPConnectionState::Established);
+    }
+  }
+
+  let accepted: Vec<_> = (0..3)
+    .map_while(|_| serverManager.try_accept(PORT))
+    .collect();
+  assert_eq!(accepted.len(), 2);
+}
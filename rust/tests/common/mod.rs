@@ -0,0 +1,326 @@
+#![allow(dead_code)]
+
+/*
+  A client and a server interface, each over its own ChannelNic pair, with the packets between
+  them shuttled by the test itself on a virtual clock. Every packet crossing gets logged, and may
+  be dropped or altered on the way by a filter, so that a test can script what the network does.
+
+  Nothing runs in the background : the connections only move when the test pumps the packets, or
+  advances the clock (firing the timers of both interfaces).
+*/
+
+use {
+  etherparse::{Ipv4HeaderSlice, TcpHeaderSlice},
+  std::{
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+  },
+  tcp_server::{
+    channel_nic::ChannelNic,
+    clock::{Clock, VirtualClock},
+    error::TcpError,
+    interface::{Interface, InterfaceConfig},
+    manager::{self, ConnectionManager, SharedConnection, TICK_INTERVAL},
+    nic::{NicDevice, Readiness},
+    tcp::{Location, TCPConnectionState},
+  },
+};
+
+pub const CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+  ToServer,
+  ToClient,
+}
+
+// A packet which crossed the network, or got dropped on the way.
+#[derive(Clone, Debug)]
+pub struct Packet {
+  pub direction: Direction,
+  pub bytes: Vec<u8>,
+  pub isDropped: bool,
+  pub at: Duration,
+}
+
+impl Packet {
+  pub fn tcp_header(&self) -> TcpHeaderSlice<'_> {
+    let ipv4Header = Ipv4HeaderSlice::from_slice(&self.bytes).unwrap();
+    TcpHeaderSlice::from_slice(&self.bytes[ipv4Header.slice().len()..]).unwrap()
+  }
+
+  pub fn payload(&self) -> &[u8] {
+    let ipv4Header = Ipv4HeaderSlice::from_slice(&self.bytes).unwrap();
+    let tcpHeader = self.tcp_header();
+    &self.bytes[ipv4Header.slice().len() + tcpHeader.slice().len()..]
+  }
+
+  pub fn sequence_number(&self) -> u32 {
+    self.tcp_header().sequence_number()
+  }
+
+  pub fn acknowledgement_number(&self) -> u32 {
+    self.tcp_header().acknowledgment_number()
+  }
+
+  pub fn is_syn(&self) -> bool {
+    self.tcp_header().syn()
+  }
+
+  pub fn is_rst(&self) -> bool {
+    self.tcp_header().rst()
+  }
+
+  pub fn is_fin(&self) -> bool {
+    self.tcp_header().fin()
+  }
+
+  // Carries nothing but an acknowledgment.
+  pub fn is_bare_ack(&self) -> bool {
+    let tcpHeader = self.tcp_header();
+    tcpHeader.ack()
+      && !(tcpHeader.syn() || tcpHeader.fin() || tcpHeader.rst())
+      && self.payload().is_empty()
+  }
+}
+
+// What the network does to a packet crossing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+  Deliver,
+  Drop,
+}
+
+type Filter = Box<dyn FnMut(&mut Packet) -> Verdict>;
+
+pub struct Network {
+  pub clock: Arc<VirtualClock>,
+  pub client: Interface,
+  pub server: Interface,
+
+  // The other ends of the NICs of the client and the server.
+  clientWire: ChannelNic,
+  serverWire: ChannelNic,
+
+  startedAt: Instant,
+
+  pub log: Vec<Packet>,
+
+  filter: Filter,
+}
+
+impl Default for Network {
+  fn default() -> Self {
+    Self::new(InterfaceConfig::default(), InterfaceConfig::default())
+  }
+}
+
+impl Network {
+  // The addresses and names of the configurations get overridden.
+  pub fn new(clientConfig: InterfaceConfig, serverConfig: InterfaceConfig) -> Self {
+    let clock = Arc::new(VirtualClock::default());
+
+    let (clientDevice, clientWire) = ChannelNic::pair();
+    let (serverDevice, serverWire) = ChannelNic::pair();
+
+    let client = Interface::with_device_and_clock(
+      InterfaceConfig {
+        name: "client".to_string(),
+        address: CLIENT_ADDRESS,
+        ..clientConfig
+      },
+      clientDevice,
+      clock.clone(),
+    )
+    .unwrap();
+    let server = Interface::with_device_and_clock(
+      InterfaceConfig {
+        name: "server".to_string(),
+        address: SERVER_ADDRESS,
+        ..serverConfig
+      },
+      serverDevice,
+      clock.clone(),
+    )
+    .unwrap();
+
+    Self {
+      startedAt: clock.now(),
+      clock,
+      client,
+      server,
+      clientWire,
+      serverWire,
+      log: Vec::new(),
+      filter: Box::new(|_| Verdict::Deliver),
+    }
+  }
+
+  pub fn client_manager(&self) -> Arc<ConnectionManager> {
+    self.client.connection_manager().clone()
+  }
+
+  pub fn server_manager(&self) -> Arc<ConnectionManager> {
+    self.server.connection_manager().clone()
+  }
+
+  pub fn set_filter(&mut self, filter: impl FnMut(&mut Packet) -> Verdict + 'static) {
+    self.filter = Box::new(filter);
+  }
+
+  // Time advanced since the network got created.
+  pub fn elapsed(&self) -> Duration {
+    self.clock.now().saturating_duration_since(self.startedAt)
+  }
+
+  // Connects the client to the given port of the server. The SYN waits for the next pump( ).
+  pub fn connect(&self, port: u16) -> Result<Arc<SharedConnection>, TcpError> {
+    self.client.connection_manager().start_connect(Location {
+      address: SERVER_ADDRESS,
+      port,
+    })
+  }
+
+  // Shuttles packets both ways, till neither end has anything left to send. Returns how many
+  // packets crossed (or got dropped).
+  pub fn pump(&mut self) -> usize {
+    let mut crossedPackets = 0;
+
+    loop {
+      let packets = self.receive_sent_packets();
+      if packets.is_empty() {
+        return crossedPackets;
+      }
+
+      for mut packet in packets {
+        crossedPackets += 1;
+        packet.isDropped = (self.filter)(&mut packet) == Verdict::Drop;
+
+        if !packet.isDropped {
+          match packet.direction {
+            Direction::ToServer => self.server.process_packet(&packet.bytes),
+            Direction::ToClient => self.client.process_packet(&packet.bytes),
+          }
+        }
+        self.log.push(packet);
+      }
+    }
+  }
+
+  // Takes whatever both ends sent, without delivering it. The packets get logged as dropped.
+  pub fn intercept(&mut self) -> Vec<Packet> {
+    let mut packets = self.receive_sent_packets();
+    for packet in &mut packets {
+      packet.isDropped = true;
+    }
+
+    self.log.extend(packets.iter().cloned());
+    packets
+  }
+
+  // Whatever both ends sent since the last time.
+  fn receive_sent_packets(&self) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    for (wire, direction) in [
+      (&self.clientWire, Direction::ToServer),
+      (&self.serverWire, Direction::ToClient),
+    ] {
+      let mut buffer = [0u8; 65536];
+      while wire.wait(Readiness::Readable, Instant::now()).unwrap() {
+        let packetLength = wire.recv(&mut buffer).unwrap();
+        packets.push(Packet {
+          direction,
+          bytes: buffer[..packetLength].to_vec(),
+          isDropped: false,
+          at: self.elapsed(),
+        });
+      }
+    }
+    packets
+  }
+
+  // Advances the clock by a tick, fires the timers of both ends, and pumps whatever they sent.
+  pub fn tick(&mut self) {
+    self.clock.advance(TICK_INTERVAL);
+    self.client.connection_manager().on_tick();
+    self.server.connection_manager().on_tick();
+    self.pump();
+  }
+
+  // Pumps and ticks till the condition holds, or the given amount of virtual time has passed.
+  // Returns whether the condition held.
+  pub fn run_until(&mut self, limit: Duration, mut condition: impl FnMut(&Self) -> bool) -> bool {
+    let deadline = self.elapsed() + limit;
+
+    loop {
+      self.pump();
+      if condition(self) {
+        return true;
+      }
+      if self.elapsed() >= deadline {
+        return false;
+      }
+      self.tick();
+    }
+  }
+
+  // Runs for the given amount of virtual time.
+  pub fn run_for(&mut self, duration: Duration) {
+    self.run_until(duration, |_| false);
+  }
+
+  // The packets logged since the given index, going the given way.
+  pub fn packets_since(&self, index: usize, direction: Direction) -> Vec<&Packet> {
+    self.log[index..]
+      .iter()
+      .filter(|packet| packet.direction == direction)
+      .collect()
+  }
+}
+
+pub fn state(connection: &SharedConnection) -> TCPConnectionState {
+  manager::lock_connection(connection).state()
+}
+
+// Writes as much of the data as the send buffer takes in, and returns how much that was.
+pub fn write(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  data: &[u8],
+) -> Result<usize, TcpError> {
+  let mut tcb = manager::lock_connection(connection);
+  match tcb.write(data, &mut connectionManager.send_context()) {
+    Err(TcpError::WouldBlock) => Ok(0),
+    result => result,
+  }
+}
+
+// Reads whatever has arrived, appending it to the given buffer. Returns false once at EOF.
+pub fn read(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  receivedData: &mut Vec<u8>,
+) -> Result<bool, TcpError> {
+  let mut buffer = [0u8; 4096];
+  let mut tcb = manager::lock_connection(connection);
+
+  loop {
+    match tcb.read(&mut buffer, &mut connectionManager.send_context()) {
+      Ok(0) => return Ok(false),
+      Ok(readLength) => receivedData.extend_from_slice(&buffer[..readLength]),
+      Err(TcpError::WouldBlock) => return Ok(true),
+      Err(error) => return Err(error),
+    }
+  }
+}
+
+// The byte at the given offset of the data the tests send.
+pub fn pattern(offset: usize) -> u8 {
+  (offset % 251) as u8
+}
+
+pub fn patterned_data(length: usize) -> Vec<u8> {
+  (0..length).map(pattern).collect()
+}
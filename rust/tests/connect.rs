@@ -0,0 +1,178 @@
+#![allow(non_snake_case)]
+
+/*
+  Actively opening connections with ConnectionManager::connect( ), which blocks till the handshake
+  completes or fails, against a server which answers the SYNs (or doesn't) as scripted. connect( )
+  runs on its own thread, while the test pumps the packets and advances the virtual clock.
+*/
+
+mod common;
+
+use {
+  common::{state, Direction, Network, Verdict, SERVER_ADDRESS},
+  std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::Duration,
+  },
+  tcp_server::{
+    error::TcpError,
+    interface::InterfaceConfig,
+    manager::SharedConnection,
+    tcp::{Location, TCPConnectionState},
+    tuning::TcpTuning,
+  },
+};
+
+const PORT: u16 = 8080;
+
+type Connecting = JoinHandle<Result<Arc<SharedConnection>, TcpError>>;
+
+fn start_connecting(network: &Network) -> Connecting {
+  let clientManager = network.client_manager();
+  thread::spawn(move || {
+    clientManager.connect(Location {
+      address: SERVER_ADDRESS,
+      port: PORT,
+    })
+  })
+}
+
+// When the client's SYNs got sent, relative to the first one.
+fn syn_times(network: &Network) -> Vec<Duration> {
+  let syns: Vec<_> = network
+    .log
+    .iter()
+    .filter(|packet| packet.direction == Direction::ToServer && packet.is_syn())
+    .map(|packet| packet.at)
+    .collect();
+  syns.iter().map(|at| *at - syns[0]).collect()
+}
+
+// Runs till the client has no connection left (or a connection got established), and returns
+// when that happened, relative to the first SYN.
+fn run_till_connect_returns(network: &mut Network, connecting: &Connecting) -> Duration {
+  // The SYN gets sent from the connect( ) thread.
+  while !network
+    .log
+    .iter()
+    .any(|packet| packet.direction == Direction::ToServer)
+  {
+    network.pump();
+    thread::yield_now();
+  }
+  let firstSYNAt = network.log[0].at;
+
+  let isDone = network.run_until(Duration::from_secs(5 * 60), |network| {
+    network
+      .client_manager()
+      .connections()
+      .iter()
+      .all(|(_, connection)| state(connection) != TCPConnectionState::SYNSent)
+  });
+  assert!(isDone);
+  let doneAt = network.elapsed() - firstSYNAt;
+
+  while !connecting.is_finished() {
+    thread::yield_now();
+  }
+  doneAt
+}
+
+#[test]
+fn connecting_to_a_black_holed_peer_times_out() {
+  let mut network = Network::default();
+  network.server_manager().listen(PORT);
+  network.set_filter(|packet| match packet.direction {
+    Direction::ToServer => Verdict::Drop,
+    Direction::ToClient => Verdict::Deliver,
+  });
+
+  let connecting = start_connecting(&network);
+  let doneAt = run_till_connect_returns(&mut network, &connecting);
+
+  // SYNs sent after 0, 1, 3, 7, 15 and 31 seconds, and the answer to the last one waited for as
+  // long again as the one before.
+  assert_eq!(
+    syn_times(&network),
+    [0, 1, 3, 7, 15, 31].map(Duration::from_secs)
+  );
+  assert_eq!(doneAt, Duration::from_secs(63));
+  assert!(matches!(
+    connecting.join().unwrap(),
+    Err(TcpError::TimedOut)
+  ));
+  assert!(network.server_manager().connections().is_empty());
+}
+
+#[test]
+fn connecting_gives_up_at_the_connect_timeout() {
+  let tuning = TcpTuning {
+    connectTimeout: Duration::from_secs(10),
+    ..TcpTuning::default()
+  };
+  let mut network = Network::new(
+    InterfaceConfig {
+      tuning,
+      ..InterfaceConfig::default()
+    },
+    InterfaceConfig::default(),
+  );
+  network.set_filter(|_| Verdict::Drop);
+
+  let connecting = start_connecting(&network);
+  let doneAt = run_till_connect_returns(&mut network, &connecting);
+
+  assert_eq!(syn_times(&network), [0, 1, 3, 7].map(Duration::from_secs));
+  assert_eq!(doneAt, Duration::from_secs(10));
+  assert!(matches!(
+    connecting.join().unwrap(),
+    Err(TcpError::TimedOut)
+  ));
+}
+
+#[test]
+fn the_third_syn_getting_answered_still_establishes_the_connection() {
+  let mut network = Network::default();
+  network.server_manager().listen(PORT);
+  let mut droppedSYNs = 0;
+  network.set_filter(move |packet| {
+    if packet.direction == Direction::ToServer && packet.is_syn() && droppedSYNs < 2 {
+      droppedSYNs += 1;
+      return Verdict::Drop;
+    }
+    Verdict::Deliver
+  });
+
+  let connecting = start_connecting(&network);
+  let doneAt = run_till_connect_returns(&mut network, &connecting);
+
+  assert_eq!(syn_times(&network), [0, 1, 3].map(Duration::from_secs));
+  assert_eq!(doneAt, Duration::from_secs(3));
+
+  let client = connecting.join().unwrap().unwrap();
+  assert_eq!(state(&client), TCPConnectionState::Established);
+  let server = network.server_manager().try_accept(PORT).unwrap();
+  assert_eq!(state(&server), TCPConnectionState::Established);
+}
+
+#[test]
+fn a_reset_answering_the_syn_refuses_the_connection_right_away() {
+  // Nothing listens on the port, so the server answers the SYN with a RST.
+  let mut network = Network::default();
+
+  let connecting = start_connecting(&network);
+  let doneAt = run_till_connect_returns(&mut network, &connecting);
+
+  // Without waiting for any retransmission timeout.
+  assert_eq!(syn_times(&network), [Duration::ZERO]);
+  assert_eq!(doneAt, Duration::ZERO);
+  assert!(network
+    .log
+    .iter()
+    .any(|packet| packet.direction == Direction::ToClient && packet.is_rst()));
+  assert!(matches!(
+    connecting.join().unwrap(),
+    Err(TcpError::ConnectionRefused)
+  ));
+}
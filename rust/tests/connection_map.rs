@@ -0,0 +1,273 @@
+#![allow(non_snake_case)]
+
+/*
+  A spike of connection requests growing the server's connection map, and the map giving the
+  memory back once the connections are gone. A map pre-sized for the spike (through
+  expectedConnections) shouldn't have to grow, and thus rehash, during it.
+
+  And threads inserting and removing connections all at once, while the timer thread scans the
+  map. The local port index has to keep holding exactly the connections of the map, which debug
+  builds check on every insertion, removal and tick.
+*/
+
+mod common;
+
+use {
+  common::{Network, CLIENT_ADDRESS, SERVER_ADDRESS},
+  etherparse::PacketBuilder,
+  std::{
+    sync::{
+      atomic::{AtomicBool, Ordering},
+      Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+  },
+  tcp_server::{
+    channel_nic::ChannelNic,
+    interface::{Interface, InterfaceConfig},
+    manager::{self, ListenerOptions, TICK_INTERVAL},
+    nic::{NicDevice, Readiness},
+    tcp::{ConnectionQuad, Location, TCPConnectionState},
+    tuning::TcpTuning,
+  },
+};
+
+const PORT: u16 = 8080;
+
+const SPIKE: usize = 50_000;
+
+// The connections left after the spike, which get established.
+const SURVIVORS: usize = 100;
+
+// How long the connection map waits, once sparse, before shrinking.
+const SHRINK_DELAY: Duration = Duration::from_secs(60);
+
+fn quad(index: usize) -> ConnectionQuad {
+  ConnectionQuad {
+    source: Location {
+      address: CLIENT_ADDRESS,
+      port: 10_000 + index as u16,
+    },
+    destiation: Location {
+      address: SERVER_ADDRESS,
+      port: PORT,
+    },
+  }
+}
+
+fn segment(index: usize, syn: bool, acknowledgementNumber: u32) -> Vec<u8> {
+  let builder = PacketBuilder::ipv4(CLIENT_ADDRESS.octets(), SERVER_ADDRESS.octets(), 64).tcp(
+    quad(index).source.port,
+    PORT,
+    if syn { 1000 } else { 1001 },
+    1024,
+  );
+  let builder = match syn {
+    true => builder.syn(),
+    false => builder.ack(acknowledgementNumber),
+  };
+
+  let mut packet = Vec::with_capacity(builder.size(0));
+  builder.write(&mut packet, &[]).unwrap();
+  packet
+}
+
+/*
+  SPIKE connection requests, of which the first SURVIVORS get their handshakes completed, while
+  the rest get aborted. Returns the capacity of the server's connection map at the peak.
+*/
+fn spike(network: &mut Network) -> usize {
+  let serverManager = network.server_manager();
+  serverManager.listen_with(
+    PORT,
+    ListenerOptions {
+      backlog: SURVIVORS,
+      ..ListenerOptions::default()
+    },
+  );
+
+  let mut survivorISSs = Vec::new();
+  for index in 0..SPIKE {
+    network.server.process_packet(&segment(index, true, 0));
+
+    let synACK = network.intercept().pop().unwrap();
+    if index < SURVIVORS {
+      survivorISSs.push(synACK.sequence_number());
+    }
+  }
+  assert_eq!(serverManager.connections().len(), SPIKE);
+  let peakCapacity = serverManager.connection_map_capacity();
+  assert!(peakCapacity >= SPIKE);
+
+  for (index, survivorISS) in survivorISSs.into_iter().enumerate() {
+    network
+      .server
+      .process_packet(&segment(index, false, survivorISS.wrapping_add(1)));
+  }
+  for index in SURVIVORS..SPIKE {
+    assert!(serverManager.abort_quad(&quad(index)));
+    network.intercept();
+  }
+
+  let connections = serverManager.connections();
+  assert_eq!(connections.len(), SURVIVORS);
+  for (_, connection) in &connections {
+    assert_eq!(common::state(connection), TCPConnectionState::Established);
+  }
+  peakCapacity
+}
+
+#[test]
+fn the_connection_map_shrinks_after_a_spike() {
+  let mut network = Network::default();
+  let peakCapacity = spike(&mut network);
+
+  let serverManager = network.server_manager();
+  let counters = serverManager.counters();
+  assert!(counters.connectionMapGrowths.load(Ordering::Relaxed) > 0);
+
+  // Nothing till the map has been sparse for long enough.
+  network.run_for(SHRINK_DELAY - TICK_INTERVAL);
+  assert_eq!(counters.connectionMapShrinks.load(Ordering::Relaxed), 0);
+
+  network.run_for(TICK_INTERVAL * 2);
+  assert_eq!(counters.connectionMapShrinks.load(Ordering::Relaxed), 1);
+  let capacity = serverManager.connection_map_capacity();
+  assert!(
+    (2 * SURVIVORS..peakCapacity / 10).contains(&capacity),
+    "Shrunk from a capacity of {} to {}",
+    peakCapacity,
+    capacity
+  );
+  assert_eq!(serverManager.connections().len(), SURVIVORS);
+}
+
+#[test]
+fn a_connection_map_sized_for_the_spike_doesnt_grow() {
+  let serverConfig = InterfaceConfig {
+    tuning: TcpTuning {
+      expectedConnections: Some(SPIKE),
+      ..TcpTuning::default()
+    },
+    ..InterfaceConfig::default()
+  };
+  let mut network = Network::new(InterfaceConfig::default(), serverConfig);
+  let initialCapacity = network.server_manager().connection_map_capacity();
+  assert!(initialCapacity >= SPIKE);
+
+  // The spike fits in the room it got sized for.
+  assert_eq!(spike(&mut network), initialCapacity);
+  let serverManager = network.server_manager();
+  let counters = serverManager.counters();
+  assert_eq!(counters.connectionMapGrowths.load(Ordering::Relaxed), 0);
+
+  // Which it keeps, ready for the next one.
+  network.run_for(SHRINK_DELAY * 2);
+  assert_eq!(counters.connectionMapShrinks.load(Ordering::Relaxed), 0);
+}
+
+const HAMMERING_THREADS: usize = 8;
+
+const HAMMERINGS: usize = 2000;
+
+/*
+  Half of the threads connect out and abort right away, each connection getting a local port of
+  its own. The other half send SYNs to the listening port, from ports of their own, so that their
+  connections share the local port, and abort them. Meanwhile, the timer thread ticks, scanning the
+  map, and another one keeps listing the connections on the listening port.
+*/
+#[test]
+fn concurrent_insertions_and_removals_keep_the_connection_map_consistent() {
+  let (device, wire) = ChannelNic::pair();
+  let server = Interface::with_device(
+    InterfaceConfig {
+      address: SERVER_ADDRESS,
+      ..InterfaceConfig::default()
+    },
+    device,
+  )
+  .unwrap();
+  let serverManager = server.connection_manager().clone();
+  serverManager.listen_with(
+    PORT,
+    ListenerOptions {
+      backlog: HAMMERING_THREADS * HAMMERINGS,
+      ..ListenerOptions::default()
+    },
+  );
+
+  let isDone = Arc::new(AtomicBool::new(false));
+  let mut background = Vec::new();
+
+  // Throws away whatever the server sends.
+  background.push({
+    let isDone = isDone.clone();
+    thread::spawn(move || {
+      let mut buffer = [0u8; 65536];
+      while !isDone.load(Ordering::Relaxed) {
+        while wire.wait(Readiness::Readable, Instant::now()).unwrap() {
+          wire.recv(&mut buffer).unwrap();
+        }
+        thread::yield_now();
+      }
+    })
+  });
+  background.push({
+    let (isDone, serverManager) = (isDone.clone(), serverManager.clone());
+    thread::spawn(move || {
+      while !isDone.load(Ordering::Relaxed) {
+        serverManager.on_tick();
+      }
+    })
+  });
+  background.push({
+    let (isDone, serverManager) = (isDone.clone(), serverManager.clone());
+    thread::spawn(move || {
+      while !isDone.load(Ordering::Relaxed) {
+        for (connectionQuad, _) in serverManager.connections_on_port(PORT) {
+          assert_eq!(connectionQuad.destiation.port, PORT);
+        }
+      }
+    })
+  });
+
+  let hammering: Vec<_> = (0..HAMMERING_THREADS)
+    .map(|threadIndex| {
+      let (server, serverManager) = (server.clone(), serverManager.clone());
+      thread::spawn(move || {
+        for iteration in 0..HAMMERINGS {
+          let connectionQuad = if threadIndex % 2 == 0 {
+            let connection = serverManager
+              .start_connect(Location {
+                address: CLIENT_ADDRESS,
+                port: 80,
+              })
+              .unwrap();
+            let connectionQuad = manager::lock_connection(&connection).quad();
+            connectionQuad
+          }
+          else {
+            let index = threadIndex / 2 * HAMMERINGS + iteration;
+            server.process_packet(&segment(index, true, 0));
+            quad(index)
+          };
+
+          assert!(serverManager.abort_quad(&connectionQuad));
+        }
+      })
+    })
+    .collect();
+  for thread in hammering {
+    thread.join().unwrap();
+  }
+
+  isDone.store(true, Ordering::Relaxed);
+  for thread in background {
+    thread.join().unwrap();
+  }
+
+  assert!(serverManager.connections().is_empty());
+  assert!(serverManager.connections_on_port(PORT).is_empty());
+  serverManager.on_tick();
+}
@@ -0,0 +1,147 @@
+#![allow(non_snake_case)]
+
+/*
+  Golden file tests of the JSON the control socket answers list --json --verbose and stats --json
+  with, which scripts parse. Any change to a field's name, type or place shows up as a diff against
+  the files in tests/golden, which then need updating along with whatever consumes the output.
+  After an intended change, rewrite them with :
+
+    UPDATE_GOLDEN_FILES=1 cargo test --test control_output
+
+  The connections run on a virtual clock, and everything they exchange is scripted, so the output
+  is the same every run.
+*/
+
+mod common;
+
+use {
+  common::{patterned_data, read, write, Direction, Network, Packet, Verdict},
+  std::{env, fs, path::PathBuf, time::Duration},
+  tcp_server::{control::ControlCommand, manager},
+};
+
+const PORT: u16 = 8080;
+
+const TRANSFER_SIZE: usize = 3000;
+
+// Overwrites the options of the given kind in the packet with NOPs. Nothing checks the checksums
+// on the way in, so they don't need fixing up.
+fn strip_option(packet: &mut Packet, kind: u8) {
+  let ipv4HeaderLength = (packet.bytes[0] & 0x0f) as usize * 4;
+  let tcpHeaderLength = (packet.bytes[ipv4HeaderLength + 12] >> 4) as usize * 4;
+  let options = &mut packet.bytes[ipv4HeaderLength + 20..ipv4HeaderLength + tcpHeaderLength];
+
+  let mut index = 0;
+  while index < options.len() {
+    let length = match options[index] {
+      0 => break,
+      1 => 1,
+      _ => options[index + 1] as usize,
+    };
+    if options[index] == kind {
+      options[index..index + length].fill(1);
+    }
+    index += length;
+  }
+}
+
+/*
+  A connection which went through a bit of everything the output covers : the server declined
+  SACK (its SYN-ACK got the option stripped), the first data segment got lost and retransmitted,
+  and the client closed its side once the server read everything.
+*/
+fn scripted_network() -> Network {
+  let mut network = Network::default();
+  network.server_manager().listen(PORT);
+
+  let mut isDataDropped = false;
+  network.set_filter(move |packet| {
+    if packet.direction == Direction::ToClient && packet.is_syn() {
+      strip_option(packet, 4);
+    }
+    if packet.direction == Direction::ToServer && !packet.payload().is_empty() && !isDataDropped {
+      isDataDropped = true;
+      return Verdict::Drop;
+    }
+    Verdict::Deliver
+  });
+
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  let server = network.server_manager().try_accept(PORT).unwrap();
+
+  let data = patterned_data(TRANSFER_SIZE);
+  let (clientManager, serverManager) = (network.client_manager(), network.server_manager());
+  let mut writtenLength = 0;
+  let mut receivedData = Vec::new();
+  let isTransferred = network.run_until(Duration::from_secs(10), |_| {
+    writtenLength += write(&clientManager, &client, &data[writtenLength..]).unwrap();
+    read(&serverManager, &server, &mut receivedData).unwrap();
+    receivedData.len() == TRANSFER_SIZE
+  });
+  assert!(isTransferred);
+  assert_eq!(receivedData, data);
+
+  manager::lock_connection(&client)
+    .close(&mut clientManager.send_context())
+    .unwrap();
+  network.run_for(Duration::from_secs(1));
+  network
+}
+
+// The responses of both ends to the command, the client's first.
+fn execute(network: &Network, command: &str) -> String {
+  [network.client_manager(), network.server_manager()]
+    .iter()
+    .map(|connectionManager| {
+      command
+        .parse::<ControlCommand>()
+        .unwrap()
+        .execute(connectionManager)
+    })
+    .collect()
+}
+
+fn check_against_golden_file(name: &str, output: &str) {
+  let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("tests/golden")
+    .join(name);
+
+  if env::var_os("UPDATE_GOLDEN_FILES").is_some() {
+    fs::write(&path, output).unwrap();
+    return;
+  }
+
+  let golden = fs::read_to_string(&path)
+    .unwrap_or_else(|error| panic!("Failed reading {} : {}", path.display(), error));
+  for (index, (line, goldenLine)) in output.lines().zip(golden.lines()).enumerate() {
+    assert_eq!(
+      line,
+      goldenLine,
+      "Line {} differs from {}",
+      index + 1,
+      path.display()
+    );
+  }
+  assert_eq!(
+    output.lines().count(),
+    golden.lines().count(),
+    "The output has another number of lines than {}",
+    path.display()
+  );
+}
+
+#[test]
+fn list_json_verbose_matches_the_golden_file() {
+  let network = scripted_network();
+  check_against_golden_file(
+    "list_json_verbose.jsonl",
+    &execute(&network, "list --json --verbose"),
+  );
+}
+
+#[test]
+fn stats_json_matches_the_golden_file() {
+  let network = scripted_network();
+  check_against_golden_file("stats_json.jsonl", &execute(&network, "stats --json"));
+}
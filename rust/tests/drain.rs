@@ -0,0 +1,211 @@
+#![allow(non_snake_case)]
+
+/*
+  Draining the server : it stops taking new connections, and stops once the existing ones are
+  gone, or gets rid of them at the drain deadline as its DrainPolicy says.
+*/
+
+mod common;
+
+use {
+  common::{patterned_data, read, state, write, Direction, Network},
+  std::{
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+  },
+  tcp_server::{
+    interface::InterfaceConfig,
+    lifecycle::{DrainDeadlineAction, DrainPolicy, InterfaceState, DRAIN_CLOSE_GRACE},
+    manager::{self, SharedConnection, TICK_INTERVAL},
+    tcp::{CloseReason, TCPConnectionState},
+  },
+};
+
+const PORT: u16 = 8080;
+
+const DRAIN_DEADLINE: Duration = Duration::from_secs(10);
+
+fn network(atDeadline: DrainDeadlineAction) -> Network {
+  let network = Network::new(
+    InterfaceConfig::default(),
+    InterfaceConfig {
+      drainPolicy: DrainPolicy {
+        deadline: DRAIN_DEADLINE,
+        atDeadline,
+      },
+      ..InterfaceConfig::default()
+    },
+  );
+  network.server_manager().listen(PORT);
+  network
+}
+
+// Connects the client, and returns both ends of the connection.
+fn connect(network: &mut Network) -> (Arc<SharedConnection>, Arc<SharedConnection>) {
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  let server = network.server_manager().try_accept(PORT).unwrap();
+  assert_eq!(state(&client), TCPConnectionState::Established);
+  (client, server)
+}
+
+// Runs till the server has stopped, and returns how long that took.
+fn run_till_stopped(network: &mut Network, limit: Duration) -> Duration {
+  let startedAt = network.elapsed();
+  let isStopped = network.run_until(limit, |network| {
+    network.server_manager().state() == InterfaceState::Stopped
+  });
+  assert!(isStopped, "The server didn't stop within {:?}", limit);
+  network.elapsed() - startedAt
+}
+
+#[test]
+fn a_transfer_started_before_draining_completes() {
+  let mut network = network(DrainDeadlineAction::Reset);
+  let (client, server) = connect(&mut network);
+
+  let serverManager = network.server_manager();
+  assert_eq!(serverManager.state(), InterfaceState::Running);
+  assert_eq!(serverManager.drain(), 1);
+  assert_eq!(serverManager.state(), InterfaceState::Draining);
+
+  // The connection carries on as usual.
+  let data = patterned_data(16 * 1024);
+  let clientManager = network.client_manager();
+  let (mut writtenLength, mut receivedData) = (0, Vec::new());
+  let isTransferred = network.run_until(Duration::from_secs(5), |_| {
+    writtenLength += write(&clientManager, &client, &data[writtenLength..]).unwrap();
+    read(&serverManager, &server, &mut receivedData).unwrap();
+    receivedData.len() == data.len()
+  });
+  assert!(isTransferred);
+  assert_eq!(receivedData, data);
+  assert_eq!(serverManager.state(), InterfaceState::Draining);
+
+  // Closing both sides completes the drain, well before the deadline.
+  manager::lock_connection(&client)
+    .close(&mut clientManager.send_context())
+    .unwrap();
+  network.pump();
+  manager::lock_connection(&server)
+    .close(&mut serverManager.send_context())
+    .unwrap();
+  assert!(run_till_stopped(&mut network, DRAIN_DEADLINE) < DRAIN_DEADLINE);
+
+  let tcb = manager::lock_connection(&server);
+  assert_eq!(tcb.state(), TCPConnectionState::Closed);
+  assert_eq!(tcb.close_reason(), Some(CloseReason::Graceful));
+  assert_eq!(state(&client), TCPConnectionState::TimeWait);
+}
+
+#[test]
+fn a_new_connection_gets_refused_while_draining() {
+  let mut network = network(DrainDeadlineAction::Close);
+  let (_client, _server) = connect(&mut network);
+
+  let serverManager = network.server_manager();
+  serverManager.drain();
+
+  let logLength = network.log.len();
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+
+  let answers = network.packets_since(logLength, Direction::ToClient);
+  assert_eq!(answers.len(), 1);
+  assert!(answers[0].is_rst());
+
+  let tcb = manager::lock_connection(&client);
+  assert_eq!(tcb.state(), TCPConnectionState::Closed);
+  assert_eq!(tcb.close_reason(), Some(CloseReason::Refused));
+
+  assert_eq!(serverManager.connections().len(), 1);
+  assert_eq!(
+    serverManager
+      .counters()
+      .refusals
+      .draining
+      .load(Ordering::Relaxed),
+    1
+  );
+  assert!(serverManager.try_accept(PORT).is_none());
+}
+
+#[test]
+fn the_close_policy_sends_a_fin_at_the_deadline_and_a_rst_after_the_grace_period() {
+  let mut network = network(DrainDeadlineAction::Close);
+  let (client, _server) = connect(&mut network);
+
+  let logLength = network.log.len();
+  network.server_manager().drain();
+  let stoppedAfter = run_till_stopped(&mut network, DRAIN_DEADLINE + DRAIN_CLOSE_GRACE * 2);
+
+  // The client never closes its side, so the connection can't complete the close.
+  let sent = network.packets_since(logLength, Direction::ToClient);
+  assert_eq!(sent.len(), 2, "{:?}", sent);
+  assert!(sent[0].is_fin());
+  assert_eq!(sent[0].at, DRAIN_DEADLINE);
+  assert!(sent[1].is_rst());
+  assert_eq!(sent[1].at, DRAIN_DEADLINE + DRAIN_CLOSE_GRACE);
+  assert_eq!(stoppedAfter, DRAIN_DEADLINE + DRAIN_CLOSE_GRACE);
+
+  let tcb = manager::lock_connection(&client);
+  assert_eq!(tcb.state(), TCPConnectionState::Closed);
+  assert_eq!(tcb.close_reason(), Some(CloseReason::Reset));
+}
+
+#[test]
+fn the_close_policy_stops_as_soon_as_the_connections_complete_closing() {
+  let mut network = network(DrainDeadlineAction::Close);
+  let (client, _server) = connect(&mut network);
+
+  network.server_manager().drain();
+  let clientManager = network.client_manager();
+  let logLength = network.log.len();
+  let isFINReceived = network.run_until(DRAIN_DEADLINE * 2, |network| {
+    !network
+      .packets_since(logLength, Direction::ToClient)
+      .is_empty()
+  });
+  assert!(isFINReceived);
+  assert_eq!(state(&client), TCPConnectionState::CloseWait);
+
+  // The client closes its side in turn, within the grace period, and the drain notices by the
+  // next tick.
+  manager::lock_connection(&client)
+    .close(&mut clientManager.send_context())
+    .unwrap();
+  assert_eq!(
+    run_till_stopped(&mut network, DRAIN_CLOSE_GRACE),
+    TICK_INTERVAL
+  );
+
+  let tcb = manager::lock_connection(&client);
+  assert_eq!(tcb.state(), TCPConnectionState::Closed);
+  assert_eq!(tcb.close_reason(), Some(CloseReason::Graceful));
+}
+
+#[test]
+fn the_reset_policy_resets_the_connections_at_the_deadline() {
+  let mut network = network(DrainDeadlineAction::Reset);
+  let (client, server) = connect(&mut network);
+
+  let logLength = network.log.len();
+  network.server_manager().drain();
+  assert_eq!(
+    run_till_stopped(&mut network, DRAIN_DEADLINE * 2),
+    DRAIN_DEADLINE
+  );
+
+  let sent = network.packets_since(logLength, Direction::ToClient);
+  assert_eq!(sent.len(), 1, "{:?}", sent);
+  assert!(sent[0].is_rst());
+  assert_eq!(sent[0].at, DRAIN_DEADLINE);
+
+  let tcb = manager::lock_connection(&client);
+  assert_eq!(tcb.state(), TCPConnectionState::Closed);
+  assert_eq!(tcb.close_reason(), Some(CloseReason::Reset));
+  assert_eq!(
+    manager::lock_connection(&server).close_reason(),
+    Some(CloseReason::Aborted)
+  );
+}
@@ -0,0 +1,340 @@
+#![allow(non_snake_case)]
+
+/*
+  Every one of the 64 combinations of SYN, ACK, FIN, RST, PSH and URG, sent to a listening port and
+  to an established connection. What each combination should do follows from the order RFC 9293
+  section 3.10.7 checks the flags in, with a RST winning over everything else :
+
+    - on a listening port, a RST gets ignored, anything acknowledging gets a RST, a SYN+FIN gets a
+      RST too (rather than a connection), and a SYN gets a SYN-ACK. Anything else gets ignored.
+    - on an established connection, a RST with the exact sequence number resets it. A SYN+FIN, or
+      anything carrying neither SYN nor ACK, gets counted as nonsensical and dropped. A SYN gets
+      a challenge ACK, and then a FIN gets acknowledged, moving the connection to CLOSE-WAIT.
+
+  PSH and URG change nothing, since the segments carry no data.
+*/
+
+mod common;
+
+use {
+  common::{Direction, Network, Packet, CLIENT_ADDRESS, SERVER_ADDRESS},
+  etherparse::PacketBuilder,
+  std::sync::atomic::Ordering,
+  tcp_server::{
+    manager,
+    tcp::{CloseReason, ConnectionQuad, Location, TCPConnectionState},
+  },
+};
+
+const PORT: u16 = 8080;
+
+const CLIENT_PORT: u16 = 40000;
+
+#[derive(Clone, Copy, Debug)]
+struct Flags {
+  syn: bool,
+  ack: bool,
+  fin: bool,
+  rst: bool,
+  psh: bool,
+  urg: bool,
+}
+
+impl Flags {
+  // Every combination, a bit per flag.
+  fn all() -> impl Iterator<Item = Self> {
+    (0..64u8).map(|bits| Self {
+      syn: bits & 1 != 0,
+      ack: bits & 2 != 0,
+      fin: bits & 4 != 0,
+      rst: bits & 8 != 0,
+      psh: bits & 16 != 0,
+      urg: bits & 32 != 0,
+    })
+  }
+}
+
+// What the server answered with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Response {
+  Nothing,
+  Reset { sequenceNumber: u32 },
+  SYNAcknowledgement { acknowledgementNumber: u32 },
+  Acknowledgement { acknowledgementNumber: u32 },
+}
+
+// Everything a row asserts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Outcome {
+  // Of the server's TCB for the quad, if any.
+  state: Option<TCPConnectionState>,
+  closeReason: Option<CloseReason>,
+
+  response: Response,
+
+  resetsToUnknownConnections: u64,
+  resetsToSYNFINs: u64,
+  challengeAcknowledgements: u64,
+  resetsReceived: u64,
+  nonsensicalSegments: u64,
+}
+
+impl Default for Outcome {
+  fn default() -> Self {
+    Self {
+      state: None,
+      closeReason: None,
+      response: Response::Nothing,
+      resetsToUnknownConnections: 0,
+      resetsToSYNFINs: 0,
+      challengeAcknowledgements: 0,
+      resetsReceived: 0,
+      nonsensicalSegments: 0,
+    }
+  }
+}
+
+fn quad() -> ConnectionQuad {
+  ConnectionQuad {
+    source: Location {
+      address: CLIENT_ADDRESS,
+      port: CLIENT_PORT,
+    },
+    destiation: Location {
+      address: SERVER_ADDRESS,
+      port: PORT,
+    },
+  }
+}
+
+// A segment without payload from the client's quad to the server.
+fn segment(flags: Flags, sequenceNumber: u32, acknowledgementNumber: u32) -> Vec<u8> {
+  let mut builder = PacketBuilder::ipv4(CLIENT_ADDRESS.octets(), SERVER_ADDRESS.octets(), 64).tcp(
+    CLIENT_PORT,
+    PORT,
+    sequenceNumber,
+    1024,
+  );
+  if flags.syn {
+    builder = builder.syn();
+  }
+  if flags.ack {
+    builder = builder.ack(acknowledgementNumber);
+  }
+  if flags.fin {
+    builder = builder.fin();
+  }
+  if flags.rst {
+    builder = builder.rst();
+  }
+  if flags.psh {
+    builder = builder.psh();
+  }
+  if flags.urg {
+    builder = builder.urg(0);
+  }
+
+  let mut packet = Vec::with_capacity(builder.size(0));
+  builder.write(&mut packet, &[]).unwrap();
+  packet
+}
+
+fn response(sent: &[Packet]) -> Response {
+  let toClient: Vec<_> = sent
+    .iter()
+    .filter(|packet| packet.direction == Direction::ToClient)
+    .collect();
+  assert!(
+    toClient.len() <= 1,
+    "More than one response : {:?}",
+    toClient
+  );
+
+  let Some(packet) = toClient.first()
+  else {
+    return Response::Nothing;
+  };
+  if packet.is_rst() {
+    Response::Reset {
+      sequenceNumber: packet.sequence_number(),
+    }
+  }
+  else if packet.is_syn() {
+    Response::SYNAcknowledgement {
+      acknowledgementNumber: packet.acknowledgement_number(),
+    }
+  }
+  else {
+    assert!(packet.is_bare_ack(), "Unexpected response : {:?}", packet);
+    Response::Acknowledgement {
+      acknowledgementNumber: packet.acknowledgement_number(),
+    }
+  }
+}
+
+// Sends the segment to the server, and collects what came of it.
+fn outcome(network: &mut Network, packet: &[u8]) -> Outcome {
+  let serverManager = network.server_manager();
+  let interfaceCounters = network.server.tcp_counters().read(false);
+
+  network.server.process_packet(packet);
+  let sent = network.intercept();
+
+  let connection = serverManager
+    .connections()
+    .into_iter()
+    .find(|(connectionQuad, _)| *connectionQuad == quad())
+    .map(|(_, connection)| connection);
+  let tcb = connection.as_deref().map(manager::lock_connection);
+
+  let counters = serverManager.counters();
+  let interfaceCountersAfter = network.server.tcp_counters().read(false);
+  Outcome {
+    state: tcb.as_ref().map(|tcb| tcb.state()),
+    closeReason: tcb.as_ref().and_then(|tcb| tcb.close_reason()),
+    response: response(&sent),
+    resetsToUnknownConnections: counters.resetsToUnknownConnections.load(Ordering::Relaxed),
+    resetsToSYNFINs: counters.resetsToSYNFINs.load(Ordering::Relaxed),
+    challengeAcknowledgements: interfaceCountersAfter.challengeAcknowledgements
+      - interfaceCounters.challengeAcknowledgements,
+    resetsReceived: interfaceCountersAfter.resetsReceived - interfaceCounters.resetsReceived,
+    nonsensicalSegments: tcb
+      .as_ref()
+      .map_or(0, |tcb| tcb.stats().nonsensical_segments()),
+  }
+}
+
+#[test]
+fn every_flag_combination_sent_to_a_listening_port() {
+  const SEQUENCE_NUMBER: u32 = 1000;
+  const ACKNOWLEDGEMENT_NUMBER: u32 = 5000;
+
+  for flags in Flags::all() {
+    let expected = if flags.rst {
+      Outcome::default()
+    }
+    else if flags.ack {
+      Outcome {
+        response: Response::Reset {
+          sequenceNumber: ACKNOWLEDGEMENT_NUMBER,
+        },
+        resetsToUnknownConnections: 1,
+        ..Outcome::default()
+      }
+    }
+    else if flags.syn && flags.fin {
+      Outcome {
+        response: Response::Reset { sequenceNumber: 0 },
+        resetsToSYNFINs: 1,
+        ..Outcome::default()
+      }
+    }
+    else if flags.syn {
+      Outcome {
+        state: Some(TCPConnectionState::SYNReceived),
+        response: Response::SYNAcknowledgement {
+          acknowledgementNumber: SEQUENCE_NUMBER + 1,
+        },
+        ..Outcome::default()
+      }
+    }
+    else {
+      Outcome::default()
+    };
+
+    let mut network = Network::default();
+    network.server_manager().listen(PORT);
+
+    let packet = segment(flags, SEQUENCE_NUMBER, ACKNOWLEDGEMENT_NUMBER);
+    assert_eq!(outcome(&mut network, &packet), expected, "{:?}", flags);
+  }
+}
+
+#[test]
+fn every_flag_combination_sent_to_an_established_connection() {
+  for flags in Flags::all() {
+    let mut network = Network::default();
+    network.server_manager().listen(PORT);
+
+    /*
+      The handshake gets scripted, from the client's quad, so that the sequence numbers of the
+      segment under test are known : exactly RCV.NXT, and acknowledging everything.
+    */
+    let syn = Flags {
+      syn: true,
+      ack: false,
+      fin: false,
+      rst: false,
+      psh: false,
+      urg: false,
+    };
+    network.server.process_packet(&segment(syn, 1000, 0));
+    let synACK = network.intercept().pop().unwrap();
+    let (receiveNext, sendNext) = (1001, synACK.sequence_number().wrapping_add(1));
+    network.server.process_packet(&segment(
+      Flags {
+        syn: false,
+        ack: true,
+        ..syn
+      },
+      receiveNext,
+      sendNext,
+    ));
+    assert!(network.intercept().is_empty());
+
+    let established = Outcome {
+      state: Some(TCPConnectionState::Established),
+      ..Outcome::default()
+    };
+    let expected = if flags.rst {
+      Outcome {
+        resetsReceived: 1,
+        ..Outcome::default()
+      }
+    }
+    else if (flags.syn && flags.fin) || !(flags.syn || flags.ack) {
+      Outcome {
+        nonsensicalSegments: 1,
+        ..established
+      }
+    }
+    else if flags.syn {
+      Outcome {
+        response: Response::Acknowledgement {
+          acknowledgementNumber: receiveNext,
+        },
+        challengeAcknowledgements: 1,
+        ..established
+      }
+    }
+    else if flags.fin {
+      Outcome {
+        state: Some(TCPConnectionState::CloseWait),
+        response: Response::Acknowledgement {
+          acknowledgementNumber: receiveNext + 1,
+        },
+        ..established
+      }
+    }
+    else {
+      established
+    };
+
+    let connection = network
+      .server_manager()
+      .connections()
+      .into_iter()
+      .map(|(_, connection)| connection)
+      .next()
+      .unwrap();
+    let packet = segment(flags, receiveNext, sendNext);
+    assert_eq!(outcome(&mut network, &packet), expected, "{:?}", flags);
+
+    // A RST removes the connection from the map, so its TCB gets checked directly.
+    if flags.rst {
+      let tcb = manager::lock_connection(&connection);
+      assert_eq!(tcb.state(), TCPConnectionState::Closed, "{:?}", flags);
+      assert_eq!(tcb.close_reason(), Some(CloseReason::Reset), "{:?}", flags);
+    }
+  }
+}
@@ -0,0 +1,153 @@
+#![allow(non_snake_case)]
+
+/*
+  Segments which can't have come from a peer : from martian sources (broadcast, multicast,
+  unspecified or loopback addresses, the network and broadcast addresses of our subnet, or port 0),
+  and SYNs from our own address and port to themselves (LAND attacks). They get dropped and counted,
+  without a TCB getting created or anything getting sent back, whether the port is listened on
+  or not.
+*/
+
+mod common;
+
+use {
+  common::{Network, SERVER_ADDRESS},
+  etherparse::PacketBuilder,
+  std::{
+    net::Ipv4Addr,
+    sync::atomic::{AtomicU64, Ordering},
+  },
+  tcp_server::{error::TcpError, manager::ConnectionManagerCounters, tcp::Location},
+};
+
+const PORT: u16 = 8080;
+
+const CLOSED_PORT: u16 = 8081;
+
+fn segment(source: Location, destinationPort: u16, isSYN: bool) -> Vec<u8> {
+  let builder = PacketBuilder::ipv4(source.address.octets(), SERVER_ADDRESS.octets(), 64).tcp(
+    source.port,
+    destinationPort,
+    1000,
+    1024,
+  );
+  let builder = match isSYN {
+    true => builder.syn(),
+    false => builder.ack(5000),
+  };
+
+  let mut packet = Vec::with_capacity(builder.size(0));
+  builder.write(&mut packet, &[]).unwrap();
+  packet
+}
+
+// Sends the segment to the server, and checks that it got dropped without a trace but the given
+// counter.
+fn assert_dropped(
+  network: &mut Network,
+  packet: &[u8],
+  counter: impl Fn(&ConnectionManagerCounters) -> &AtomicU64,
+) {
+  let serverManager = network.server_manager();
+  let before = counter(serverManager.counters()).load(Ordering::Relaxed);
+
+  network.server.process_packet(packet);
+
+  let sent = network.intercept();
+  assert!(sent.is_empty(), "{:?}", sent);
+  assert!(serverManager.connections().is_empty());
+  assert_eq!(
+    counter(serverManager.counters()).load(Ordering::Relaxed),
+    before + 1
+  );
+}
+
+#[test]
+fn segments_from_martian_sources_get_dropped() {
+  let mut network = Network::default();
+  network.server_manager().listen(PORT);
+
+  let martians = [
+    Location {
+      address: Ipv4Addr::BROADCAST,
+      port: 40000,
+    },
+    Location {
+      address: Ipv4Addr::new(224, 0, 0, 1),
+      port: 40000,
+    },
+    Location {
+      address: Ipv4Addr::UNSPECIFIED,
+      port: 40000,
+    },
+    Location {
+      address: Ipv4Addr::LOCALHOST,
+      port: 40000,
+    },
+    // The network and broadcast addresses of the 10.0.0.0/24 subnet.
+    Location {
+      address: Ipv4Addr::new(10, 0, 0, 0),
+      port: 40000,
+    },
+    Location {
+      address: Ipv4Addr::new(10, 0, 0, 255),
+      port: 40000,
+    },
+    Location {
+      address: Ipv4Addr::new(10, 0, 0, 1),
+      port: 0,
+    },
+  ];
+  for martian in martians {
+    for destinationPort in [PORT, CLOSED_PORT] {
+      for isSYN in [true, false] {
+        assert_dropped(
+          &mut network,
+          &segment(martian, destinationPort, isSYN),
+          |counters| &counters.martianSegments,
+        );
+      }
+    }
+  }
+}
+
+#[test]
+fn a_syn_from_ourselves_to_ourselves_gets_dropped() {
+  let mut network = Network::default();
+  network.server_manager().listen(PORT);
+
+  for port in [PORT, CLOSED_PORT] {
+    let land = segment(
+      Location {
+        address: SERVER_ADDRESS,
+        port,
+      },
+      port,
+      true,
+    );
+    assert_dropped(&mut network, &land, |counters| &counters.landSYNs);
+  }
+}
+
+#[test]
+fn connecting_to_a_martian_peer_fails() {
+  let network = Network::default();
+  let clientManager = network.client_manager();
+
+  for peer in [
+    Location {
+      address: Ipv4Addr::BROADCAST,
+      port: PORT,
+    },
+    Location {
+      address: SERVER_ADDRESS,
+      port: 0,
+    },
+  ] {
+    assert!(matches!(
+      clientManager.start_connect(peer),
+      Err(TcpError::MartianPeer(martian)) if martian == peer
+    ));
+  }
+  assert!(clientManager.connections().is_empty());
+}
@@ -0,0 +1,170 @@
+#![allow(non_snake_case)]
+
+/*
+  A client which restarted, and reuses the source port of a connection we still hold as
+  established. Its new SYN gets a challenge ACK, which it answers with a RST carrying the sequence
+  number the ACK acknowledged. That tears the stale connection down, and the client's retransmitted
+  SYN then opens a new one (RFC 9293 section 3.10.7.4, RFC 5961 section 4).
+*/
+
+mod common;
+
+use {
+  common::{Direction, Network, CLIENT_ADDRESS, SERVER_ADDRESS},
+  etherparse::PacketBuilder,
+  std::sync::atomic::Ordering,
+  tcp_server::{
+    manager,
+    tcp::{CloseReason, TCPConnectionState},
+  },
+};
+
+const PORT: u16 = 8080;
+
+const CLIENT_PORT: u16 = 40000;
+
+// The ISS of the connection which got left behind.
+const OLD_ISS: u32 = 1000;
+
+#[derive(Clone, Copy, Default)]
+struct Flags {
+  syn: bool,
+  ack: bool,
+  rst: bool,
+}
+
+fn segment(flags: Flags, sequenceNumber: u32, acknowledgementNumber: u32) -> Vec<u8> {
+  let mut builder = PacketBuilder::ipv4(CLIENT_ADDRESS.octets(), SERVER_ADDRESS.octets(), 64).tcp(
+    CLIENT_PORT,
+    PORT,
+    sequenceNumber,
+    1024,
+  );
+  if flags.syn {
+    builder = builder.syn();
+  }
+  if flags.ack {
+    builder = builder.ack(acknowledgementNumber);
+  }
+  if flags.rst {
+    builder = builder.rst();
+  }
+
+  let mut packet = Vec::with_capacity(builder.size(0));
+  builder.write(&mut packet, &[]).unwrap();
+  packet
+}
+
+fn syn(sequenceNumber: u32) -> Vec<u8> {
+  segment(
+    Flags {
+      syn: true,
+      ..Flags::default()
+    },
+    sequenceNumber,
+    0,
+  )
+}
+
+// The recovery, with the restarted client's ISS being the given one.
+fn recover_the_quad(newISS: u32) {
+  let mut network = Network::default();
+  let serverManager = network.server_manager();
+  serverManager.listen(PORT);
+
+  // The connection from before the restart.
+  network.server.process_packet(&syn(OLD_ISS));
+  let synACK = network.intercept().pop().unwrap();
+  network.server.process_packet(&segment(
+    Flags {
+      ack: true,
+      ..Flags::default()
+    },
+    OLD_ISS + 1,
+    synACK.sequence_number().wrapping_add(1),
+  ));
+  let old = serverManager.try_accept(PORT).unwrap();
+  assert!(network.intercept().is_empty());
+
+  // The new SYN gets a challenge ACK, for what the old connection expects next.
+  let challengeAcknowledgements = |network: &Network| {
+    network
+      .server
+      .tcp_counters()
+      .read(false)
+      .challengeAcknowledgements
+  };
+  let challengeAcknowledgementsBefore = challengeAcknowledgements(&network);
+  network.server.process_packet(&syn(newISS));
+  let sent = network.intercept();
+  assert_eq!(sent.len(), 1, "{:?}", sent);
+  let challengeAcknowledgement = &sent[0];
+  assert_eq!(challengeAcknowledgement.direction, Direction::ToClient);
+  assert!(challengeAcknowledgement.is_bare_ack());
+  assert_eq!(
+    challengeAcknowledgement.acknowledgement_number(),
+    OLD_ISS + 1
+  );
+  assert_eq!(
+    challengeAcknowledgements(&network) - challengeAcknowledgementsBefore,
+    1
+  );
+  assert_eq!(
+    manager::lock_connection(&old)
+      .stats()
+      .challenge_acknowledgements(),
+    1
+  );
+  assert_eq!(
+    manager::lock_connection(&old).state(),
+    TCPConnectionState::Established
+  );
+
+  // The client knows nothing of the old connection, so it answers with a RST.
+  network.server.process_packet(&segment(
+    Flags {
+      rst: true,
+      ..Flags::default()
+    },
+    challengeAcknowledgement.acknowledgement_number(),
+    0,
+  ));
+  assert!(network.intercept().is_empty());
+  {
+    let tcb = manager::lock_connection(&old);
+    assert_eq!(tcb.state(), TCPConnectionState::Closed);
+    assert_eq!(tcb.close_reason(), Some(CloseReason::Reset));
+  }
+  assert!(serverManager.connections().is_empty());
+
+  // The retransmitted SYN opens a new connection.
+  network.server.process_packet(&syn(newISS));
+  let sent = network.intercept();
+  assert_eq!(sent.len(), 1, "{:?}", sent);
+  assert!(sent[0].is_syn());
+  assert_eq!(sent[0].acknowledgement_number(), newISS.wrapping_add(1));
+
+  let connections = serverManager.connections();
+  assert_eq!(connections.len(), 1);
+  assert_eq!(
+    manager::lock_connection(&connections[0].1).state(),
+    TCPConnectionState::SYNReceived
+  );
+  assert_eq!(
+    serverManager
+      .counters()
+      .resetsToUnknownConnections
+      .load(Ordering::Relaxed),
+    0
+  );
+}
+
+#[test]
+fn a_reused_quad_with_a_syn_in_the_old_window_gets_recovered() {
+  recover_the_quad(OLD_ISS + 100);
+}
+
+#[test]
+fn a_reused_quad_with_a_syn_outside_the_old_window_gets_recovered() {
+  recover_the_quad(OLD_ISS.wrapping_add(3_000_000_000));
+}
@@ -0,0 +1,657 @@
+#![cfg(feature = "soak")]
+#![allow(non_snake_case)]
+
+/*
+  Soak test : 2 interfaces wired to each other through a ChannelNic pair, which loses and reorders
+  packets, churning through short lived connections alongside a few long lived bulk transfers.
+
+  Run it with :
+
+    cargo test --features soak -- --ignored soak_
+
+  It runs for SOAK_DURATION_SECS seconds (180 by default), and then waits for the connections
+  left in TIME-WAIT to go away. It fails if :
+
+    - a connection spends more than STALL_TIMEOUT in one of the transient states (SYN-RECEIVED,
+      FIN-WAIT-1, FIN-WAIT-2, CLOSING, CLOSE-WAIT and LAST-ACK), while the soak runs,
+    - either end has any connection left once the soak is over, or its connection count isn't back
+      to 0,
+    - or no exchange, or no bulk transfer got anywhere.
+
+  A failure dumps the state and the event history of every connection which got stuck.
+*/
+
+use {
+  std::{
+    env, fs, io,
+    net::Ipv4Addr,
+    path::PathBuf,
+    process,
+    sync::{
+      atomic::{AtomicU64, Ordering},
+      Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+  },
+  tcp_server::{
+    channel_nic::ChannelNic,
+    error::TcpError,
+    interface::{Interface, InterfaceConfig},
+    json::ToJson,
+    manager::{self, ConnectionManager, SharedConnection, TICK_INTERVAL},
+    nic::{NicDevice, Readiness},
+    tcp::Location,
+    tuning::{StuckStateThresholds, TcpTuning},
+  },
+};
+
+const ECHO_PORT: u16 = 7;
+const DISCARD_PORT: u16 = 9;
+
+// Threads connecting, exchanging MESSAGE_SIZE bytes with the echo server and closing, over and
+// over.
+const CHURNING_CLIENTS: usize = 64;
+
+// Connections streaming to the discard server, for the whole soak.
+const BULK_TRANSFERS: usize = 4;
+
+const MESSAGE_SIZE: usize = 4096;
+
+// Odds (in parts per 10000) of a packet getting lost, or held back till the next one got sent.
+const LOSS_ODDS: u64 = 50;
+const REORDER_ODDS: u64 = 50;
+
+// How long a connection may make no progress, before it's considered stuck.
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long the connections get to go away, once the soak is over. Covers TIME-WAIT.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[test]
+#[ignore]
+fn soak_churn_and_bulk_transfers() {
+  let duration = Duration::from_secs(
+    env::var("SOAK_DURATION_SECS")
+      .map(|seconds| seconds.parse().expect("Invalid SOAK_DURATION_SECS"))
+      .unwrap_or(180),
+  );
+
+  let (clientDevice, serverDevice) = ChannelNic::pair();
+  let client = start("client", Ipv4Addr::new(10, 0, 0, 1), clientDevice, 1);
+  let server = start("server", Ipv4Addr::new(10, 0, 0, 2), serverDevice, 2);
+
+  let failures = Failures::default();
+  serve(&server, ECHO_PORT, &failures, echo);
+  serve(&server, DISCARD_PORT, &failures, discard);
+
+  let deadline = Instant::now() + duration;
+  let exchanges = Arc::new(AtomicU64::new(0));
+  let bulkBytes = Arc::new(AtomicU64::new(0));
+
+  let mut workers = Vec::new();
+  for worker in 0..CHURNING_CLIENTS {
+    let (connectionManager, failures, exchanges) = (
+      client.interface.connection_manager().clone(),
+      failures.clone(),
+      exchanges.clone(),
+    );
+
+    workers.push(thread::spawn(move || {
+      /*
+        Every host address of the subnet is the server's. Spreading the connections over several
+        of them keeps the ones lingering in TIME-WAIT from using up the ephemeral ports.
+      */
+      let peer = Location {
+        address: Ipv4Addr::new(10, 0, 0, 2 + (worker % 32) as u8),
+        port: ECHO_PORT,
+      };
+      let mut random = XorShift::new(worker as u64 + 1);
+
+      while Instant::now() < deadline && failures.is_empty() {
+        match churn(&connectionManager, peer, &mut random) {
+          Ok(()) => {
+            exchanges.fetch_add(1, Ordering::Relaxed);
+          }
+
+          Err(Failure::Retry) => thread::sleep(Duration::from_millis(10)),
+          Err(Failure::Failed(failure)) => failures.push(failure),
+        }
+      }
+    }));
+  }
+
+  for _ in 0..BULK_TRANSFERS {
+    let (connectionManager, failures, bulkBytes) = (
+      client.interface.connection_manager().clone(),
+      failures.clone(),
+      bulkBytes.clone(),
+    );
+
+    workers.push(thread::spawn(move || {
+      let peer = Location {
+        address: Ipv4Addr::new(10, 0, 0, 2),
+        port: DISCARD_PORT,
+      };
+
+      if let Err(Failure::Failed(failure)) =
+        stream(&connectionManager, peer, deadline, &failures, &bulkBytes)
+      {
+        failures.push(failure);
+      }
+    }));
+  }
+
+  // Keeps an eye on the vNICs and the connections, while the soak runs.
+  while Instant::now() < deadline && failures.is_empty() {
+    for end in [&client, &server] {
+      if let Some(nicFailure) = end.interface.nic_failure() {
+        failures.push(format!("{} : the vNIC failed : {}", end.name, nicFailure));
+      }
+      for failure in end.find_stuck_connections() {
+        failures.push(failure);
+      }
+    }
+    thread::sleep(Duration::from_secs(1));
+  }
+
+  for worker in workers {
+    if worker.join().is_err() {
+      failures.push("A client thread panicked".to_string());
+    }
+  }
+  let (exchanges, bulkBytes) = (
+    exchanges.load(Ordering::Relaxed),
+    bulkBytes.load(Ordering::Relaxed),
+  );
+  println!(
+    "{} exchanges and {} bulk bytes in {:?}",
+    exchanges, bulkBytes, duration
+  );
+  if exchanges == 0 || bulkBytes == 0 {
+    failures.push(format!(
+      "Only {} exchanges and {} bulk bytes got through",
+      exchanges, bulkBytes
+    ));
+  }
+
+  // Every connection is done with by now, so each map should get back to empty.
+  let settleDeadline = Instant::now() + SETTLE_TIMEOUT;
+  while Instant::now() < settleDeadline
+    && [&client, &server]
+      .iter()
+      .any(|end| !end.interface.connection_manager().connections().is_empty())
+  {
+    thread::sleep(Duration::from_secs(1));
+  }
+
+  let mut report = failures.take();
+  for end in [&client, &server] {
+    report.extend(end.describe_stuck_connections());
+
+    let currentConnections = end.interface.tcp_counters().read(false).currentConnections;
+    if currentConnections != 0 {
+      report.push(format!(
+        "{} : the connection count is {}, instead of 0",
+        end.name, currentConnections
+      ));
+    }
+  }
+
+  assert!(report.is_empty(), "\n{}", report.join("\n"));
+}
+
+// One of the 2 interfaces, along with where its connection events get logged.
+struct End {
+  name: &'static str,
+  interface: Interface,
+  eventsFilePath: PathBuf,
+}
+
+impl End {
+  // The connections which have spent longer than STALL_TIMEOUT in a transient state.
+  fn find_stuck_connections(&self) -> Vec<String> {
+    let thresholds = stuck_state_thresholds();
+
+    let mut failures = Vec::new();
+    for (connectionQuad, connection) in self.interface.connection_manager().connections() {
+      let tcb = manager::lock_connection(&connection);
+      let timeInState = tcb.time_in_state();
+      if thresholds
+        .threshold(tcb.state())
+        .is_some_and(|threshold| timeInState > threshold)
+      {
+        failures.push(format!(
+          "{} : {} stuck in {} for {:?}",
+          self.name,
+          connectionQuad,
+          tcb.state(),
+          timeInState
+        ));
+      }
+    }
+    failures
+  }
+
+  // The state and the event history of every connection left, one per line.
+  fn describe_stuck_connections(&self) -> Vec<String> {
+    let connectionManager = self.interface.connection_manager();
+    let events = fs::read_to_string(&self.eventsFilePath).unwrap_or_default();
+
+    let mut descriptions = Vec::new();
+    for (connectionQuad, connection) in connectionManager.connections() {
+      let state = manager::lock_connection(&connection).state();
+      descriptions.push(format!(
+        "{} : {} stuck in {} ({} bytes to read, {} queued, {} unacknowledged)",
+        self.name,
+        connectionQuad,
+        state,
+        connection.bytes_to_read(),
+        connection.bytes_queued(),
+        connection.bytes_unacked()
+      ));
+
+      let quad = connectionQuad.to_json();
+      descriptions.extend(
+        events
+          .lines()
+          .filter(|event| event.contains(&quad))
+          .map(|event| format!("    {}", event)),
+      );
+    }
+    descriptions
+  }
+}
+
+// Runs an interface over the given device, made lossy, with the packet and the tick threads main( )
+// would spawn.
+fn start(name: &'static str, address: Ipv4Addr, device: ChannelNic, seed: u64) -> End {
+  let config = InterfaceConfig {
+    address,
+    tuning: TcpTuning {
+      stuckStateThresholds: stuck_state_thresholds(),
+      ..TcpTuning::default()
+    },
+    ..InterfaceConfig::default()
+  };
+  let interface = Interface::with_device(config, LossyNic::new(device, seed)).unwrap();
+
+  let eventsFilePath = env::temp_dir().join(format!("tcpd-soak-{}-{}.jsonl", process::id(), name));
+  interface
+    .connection_manager()
+    .log_events_to(&eventsFilePath)
+    .unwrap();
+
+  let packetThreadInterface = interface.clone();
+  thread::spawn(move || packetThreadInterface.process_packets());
+
+  let connectionManager = interface.connection_manager().clone();
+  thread::spawn(move || loop {
+    thread::sleep(TICK_INTERVAL);
+    connectionManager.on_tick();
+  });
+
+  End {
+    name,
+    interface,
+    eventsFilePath,
+  }
+}
+
+// STALL_TIMEOUT for every transient state, rather than the minutes a long lived daemon allows.
+fn stuck_state_thresholds() -> StuckStateThresholds {
+  let mut thresholds = StuckStateThresholds::default();
+  for state in StuckStateThresholds::STATES {
+    thresholds
+      .set(&format!("{} {}", state, STALL_TIMEOUT.as_millis()))
+      .unwrap();
+  }
+  thresholds
+}
+
+// Accepts connections on the given port, handling each on a thread of its own.
+fn serve(
+  end: &End,
+  port: u16,
+  failures: &Failures,
+  handle: fn(&ConnectionManager, &SharedConnection) -> Result<(), Failure>,
+) {
+  let connectionManager = end.interface.connection_manager().clone();
+  let failures = failures.clone();
+  connectionManager.listen(port);
+
+  thread::spawn(move || loop {
+    let connection = match connectionManager.accept(port) {
+      Ok(connection) => connection,
+      Err(error) => {
+        failures.push(format!("Failed accepting on port {} : {}", port, error));
+        return;
+      }
+    };
+
+    let (connectionManager, failures) = (connectionManager.clone(), failures.clone());
+    thread::spawn(move || {
+      if let Err(Failure::Failed(failure)) = handle(&connectionManager, &connection) {
+        failures.push(failure);
+      }
+    });
+  });
+}
+
+// Connects to the echo server, and checks that a checksummed message comes back intact.
+fn churn(
+  connectionManager: &ConnectionManager,
+  peer: Location,
+  random: &mut XorShift,
+) -> Result<(), Failure> {
+  let connection = match connectionManager.connect(peer) {
+    Ok(connection) => connection,
+    Err(TcpError::AddrNotAvailable) => return Err(Failure::Retry),
+    Err(error) => {
+      return Err(Failure::Failed(format!(
+        "Connecting to {} : {}",
+        peer, error
+      )))
+    }
+  };
+
+  let mut message = vec![0u8; MESSAGE_SIZE];
+  random.fill(&mut message[..MESSAGE_SIZE - 4]);
+  let checksum = fnv1a(&message[..MESSAGE_SIZE - 4]);
+  message[MESSAGE_SIZE - 4..].copy_from_slice(&checksum.to_be_bytes());
+
+  write_all(connectionManager, &connection, &message)?;
+
+  let mut echoed = vec![0u8; MESSAGE_SIZE];
+  read_exact(connectionManager, &connection, &mut echoed)?;
+  if echoed != message {
+    return Err(failed(
+      &connection,
+      "the echoed message got corrupted".to_string(),
+    ));
+  }
+
+  close(connectionManager, &connection)
+}
+
+// Echoes a message back, once its checksum checks out.
+fn echo(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+) -> Result<(), Failure> {
+  let mut message = vec![0u8; MESSAGE_SIZE];
+  read_exact(connectionManager, connection, &mut message)?;
+
+  let checksum = u32::from_be_bytes(message[MESSAGE_SIZE - 4..].try_into().unwrap());
+  if fnv1a(&message[..MESSAGE_SIZE - 4]) != checksum {
+    return Err(failed(
+      connection,
+      "the received message got corrupted".to_string(),
+    ));
+  }
+
+  write_all(connectionManager, connection, &message)?;
+
+  // Waits for the client to close first, so that TIME-WAIT ends up on its side.
+  let mut buffer = [0u8; 1];
+  while read(connectionManager, connection, &mut buffer)? > 0 {}
+  close(connectionManager, connection)
+}
+
+// Streams the pattern to the discard server, till the deadline.
+fn stream(
+  connectionManager: &ConnectionManager,
+  peer: Location,
+  deadline: Instant,
+  failures: &Failures,
+  bulkBytes: &AtomicU64,
+) -> Result<(), Failure> {
+  let connection = connectionManager
+    .connect(peer)
+    .map_err(|error| Failure::Failed(format!("Connecting to {} : {}", peer, error)))?;
+
+  let mut offset = 0u64;
+  let mut chunk = vec![0u8; 64 * 1024];
+  while Instant::now() < deadline && failures.is_empty() {
+    for byte in chunk.iter_mut() {
+      *byte = pattern(offset);
+      offset += 1;
+    }
+
+    write_all(connectionManager, &connection, &chunk)?;
+    bulkBytes.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+  }
+
+  close(connectionManager, &connection)
+}
+
+// Reads the stream till its end, checking every byte against the pattern.
+fn discard(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+) -> Result<(), Failure> {
+  let mut offset = 0u64;
+  let mut buffer = vec![0u8; 64 * 1024];
+
+  loop {
+    let bytesRead = read(connectionManager, connection, &mut buffer)?;
+    if bytesRead == 0 {
+      return close(connectionManager, connection);
+    }
+
+    for byte in &buffer[..bytesRead] {
+      if *byte != pattern(offset) {
+        return Err(failed(
+          connection,
+          format!("the stream got corrupted at byte {}", offset),
+        ));
+      }
+      offset += 1;
+    }
+  }
+}
+
+// Byte at the given offset of a bulk transfer. 251 being prime, segment boundaries never line up
+// with the pattern.
+fn pattern(offset: u64) -> u8 {
+  (offset % 251) as u8
+}
+
+fn read(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  buffer: &mut [u8],
+) -> Result<usize, Failure> {
+  let (mut tcb, timedOut) = connection.wait_timeout_while(STALL_TIMEOUT, |tcb| !tcb.is_readable());
+  if timedOut {
+    return Err(failed(
+      connection,
+      format!("stalled reading in {}", tcb.state()),
+    ));
+  }
+
+  tcb
+    .read(buffer, &mut connectionManager.send_context())
+    .map_err(|error| Failure::Failed(format!("{} : reading : {}", tcb.quad(), error)))
+}
+
+fn read_exact(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  mut buffer: &mut [u8],
+) -> Result<(), Failure> {
+  while !buffer.is_empty() {
+    let bytesRead = read(connectionManager, connection, buffer)?;
+    if bytesRead == 0 {
+      return Err(failed(connection, "the stream ended early".to_string()));
+    }
+    buffer = &mut buffer[bytesRead..];
+  }
+  Ok(())
+}
+
+fn write_all(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+  mut data: &[u8],
+) -> Result<(), Failure> {
+  while !data.is_empty() {
+    let (mut tcb, timedOut) =
+      connection.wait_timeout_while(STALL_TIMEOUT, |tcb| !tcb.is_writable());
+    if timedOut {
+      return Err(failed(
+        connection,
+        format!("stalled writing in {}", tcb.state()),
+      ));
+    }
+
+    let bytesWritten = tcb
+      .write(data, &mut connectionManager.send_context())
+      .map_err(|error| Failure::Failed(format!("{} : writing : {}", tcb.quad(), error)))?;
+    data = &data[bytesWritten..];
+  }
+  Ok(())
+}
+
+fn close(
+  connectionManager: &ConnectionManager,
+  connection: &SharedConnection,
+) -> Result<(), Failure> {
+  let mut tcb = manager::lock_connection(connection);
+
+  tcb
+    .close(&mut connectionManager.send_context())
+    .map_err(|error| Failure::Failed(format!("{} : closing : {}", tcb.quad(), error)))
+}
+
+fn failed(connection: &SharedConnection, failure: String) -> Failure {
+  Failure::Failed(format!(
+    "{} : {}",
+    manager::lock_connection(connection).quad(),
+    failure
+  ))
+}
+
+enum Failure {
+  // No ephemeral port is free right now.
+  Retry,
+
+  Failed(String),
+}
+
+// Failures reported by any of the threads.
+#[derive(Clone, Default)]
+struct Failures(Arc<Mutex<Vec<String>>>);
+
+impl Failures {
+  fn push(&self, failure: String) {
+    self
+      .0
+      .lock()
+      .expect("Failures mutex poisoned")
+      .push(failure);
+  }
+
+  fn is_empty(&self) -> bool {
+    self.0.lock().expect("Failures mutex poisoned").is_empty()
+  }
+
+  fn take(&self) -> Vec<String> {
+    std::mem::take(&mut *self.0.lock().expect("Failures mutex poisoned"))
+  }
+}
+
+/*
+  Loses and reorders the packets sent through it, at random but reproducibly : the randomness is
+  seeded. A packet gets reordered by holding it back till the next one got sent.
+*/
+struct LossyNic {
+  device: ChannelNic,
+
+  impairment: Mutex<Impairment>,
+}
+
+struct Impairment {
+  random: XorShift,
+
+  heldBack: Option<Vec<u8>>,
+}
+
+impl LossyNic {
+  fn new(device: ChannelNic, seed: u64) -> Self {
+    Self {
+      device,
+      impairment: Mutex::new(Impairment {
+        random: XorShift::new(seed),
+        heldBack: None,
+      }),
+    }
+  }
+}
+
+impl NicDevice for LossyNic {
+  fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+    self.device.recv(buffer)
+  }
+
+  fn send(&self, packet: &[u8]) -> io::Result<usize> {
+    let mut impairment = self.impairment.lock().expect("Impairment mutex poisoned");
+
+    let odds = impairment.random.next() % 10_000;
+    if odds < LOSS_ODDS {
+      return Ok(packet.len());
+    }
+    if odds < LOSS_ODDS + REORDER_ODDS && impairment.heldBack.is_none() {
+      impairment.heldBack = Some(packet.to_vec());
+      return Ok(packet.len());
+    }
+
+    self.device.send(packet)?;
+    if let Some(heldBack) = impairment.heldBack.take() {
+      self.device.send(&heldBack)?;
+    }
+    Ok(packet.len())
+  }
+
+  fn wait(&self, readiness: Readiness, deadline: Instant) -> io::Result<bool> {
+    self.device.wait(readiness, deadline)
+  }
+
+  fn name(&self) -> anyhow::Result<String> {
+    self.device.name()
+  }
+
+  fn set_mtu(&self, mtu: u16) -> anyhow::Result<()> {
+    self.device.set_mtu(mtu)
+  }
+}
+
+// A seeded xorshift64 generator.
+struct XorShift(u64);
+
+impl XorShift {
+  fn new(seed: u64) -> Self {
+    // Scrambled, since xorshift gets stuck at 0 and starts off poorly from small seeds.
+    Self(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+  }
+
+  fn next(&mut self) -> u64 {
+    self.0 ^= self.0 << 13;
+    self.0 ^= self.0 >> 7;
+    self.0 ^= self.0 << 17;
+    self.0
+  }
+
+  fn fill(&mut self, buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+      let random = self.next().to_le_bytes();
+      chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+  }
+}
+
+fn fnv1a(data: &[u8]) -> u32 {
+  data.iter().fold(0x811C_9DC5, |hash, byte| {
+    (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+  })
+}
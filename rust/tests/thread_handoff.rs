@@ -0,0 +1,197 @@
+#![allow(non_snake_case)]
+
+/*
+  The handles move between threads the way manager.rs says they may. The types get checked at
+  compile time, and a workload puts the model to use, on real threads : the server hands the
+  connections it accepts over to a pool of workers echoing them back, while each client connection
+  gets shared between a writer and a reader thread.
+*/
+
+use {
+  std::{
+    net::Ipv4Addr,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+  },
+  tcp_server::{
+    channel_nic::ChannelNic,
+    error::TcpError,
+    interface::{Interface, InterfaceConfig},
+    manager::{self, ConnectionManager, ListenerOptions, SharedConnection, TICK_INTERVAL},
+    tcp::Location,
+  },
+};
+
+const PORT: u16 = 7;
+
+const SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+const CONNECTIONS: usize = 8;
+
+const WORKERS: usize = 3;
+
+// Echoed by each connection.
+const TRANSFER_SIZE: usize = 64 * 1024;
+
+fn assert_send_sync<T: Send + Sync>() {}
+
+#[test]
+fn the_handles_are_send_and_sync() {
+  assert_send_sync::<SharedConnection>();
+  assert_send_sync::<Arc<SharedConnection>>();
+  assert_send_sync::<ConnectionManager>();
+  assert_send_sync::<Arc<ConnectionManager>>();
+  assert_send_sync::<ListenerOptions>();
+  assert_send_sync::<Interface>();
+  assert_send_sync::<TcpError>();
+}
+
+fn start(address: Ipv4Addr, device: ChannelNic) -> Interface {
+  let config = InterfaceConfig {
+    address,
+    ..InterfaceConfig::default()
+  };
+  let interface = Interface::with_device(config, device).unwrap();
+
+  let packetThreadInterface = interface.clone();
+  thread::spawn(move || packetThreadInterface.process_packets());
+
+  let connectionManager = interface.connection_manager().clone();
+  thread::spawn(move || loop {
+    thread::sleep(TICK_INTERVAL);
+    connectionManager.on_tick();
+  });
+
+  interface
+}
+
+fn pattern(connectionIndex: usize, offset: usize) -> u8 {
+  ((connectionIndex + offset) % 251) as u8
+}
+
+// Writes back whatever it reads, till the end of the stream, and then closes the connection.
+fn echo(connectionManager: &ConnectionManager, connection: &SharedConnection) -> usize {
+  let mut ctx = connectionManager.send_context();
+
+  let mut buffer = [0u8; 4096];
+  let mut echoedLength = 0;
+  loop {
+    let bytesRead = connection.read(&mut buffer, &mut ctx).unwrap();
+    if bytesRead == 0 {
+      break;
+    }
+
+    let mut writtenLength = 0;
+    while writtenLength < bytesRead {
+      writtenLength += connection
+        .write(&buffer[writtenLength..bytesRead], &mut ctx)
+        .unwrap();
+    }
+    echoedLength += bytesRead;
+  }
+
+  manager::lock_connection(connection)
+    .close(&mut ctx)
+    .unwrap();
+  echoedLength
+}
+
+#[test]
+fn connections_handed_to_a_thread_pool_get_echoed() {
+  let (clientDevice, serverDevice) = ChannelNic::pair();
+  let client = start(Ipv4Addr::new(10, 0, 0, 1), clientDevice);
+  let server = start(SERVER_ADDRESS, serverDevice);
+
+  let serverManager = server.connection_manager().clone();
+  serverManager.listen(PORT);
+
+  // The workers take the accepted connections off a shared queue, any of them any connection.
+  let (sender, receiver) = mpsc::channel::<Arc<SharedConnection>>();
+  let receiver = Arc::new(Mutex::new(receiver));
+  let workers: Vec<_> = (0..WORKERS)
+    .map(|_| {
+      let (serverManager, receiver) = (serverManager.clone(), receiver.clone());
+      thread::spawn(move || {
+        let mut echoedLength = 0;
+        loop {
+          let job = receiver.lock().expect("Job queue poisoned").recv();
+          let Ok(connection) = job
+          else {
+            return echoedLength;
+          };
+          echoedLength += echo(&serverManager, &connection);
+        }
+      })
+    })
+    .collect();
+
+  let acceptor = thread::spawn(move || {
+    for _ in 0..CONNECTIONS {
+      sender.send(serverManager.accept(PORT).unwrap()).unwrap();
+    }
+  });
+
+  // Each client connection gets written on one thread, and read on another.
+  let clientManager = client.connection_manager();
+  let clients: Vec<_> = (0..CONNECTIONS)
+    .map(|connectionIndex| {
+      let connection = clientManager
+        .connect(Location {
+          address: SERVER_ADDRESS,
+          port: PORT,
+        })
+        .unwrap();
+
+      let (writerManager, writerConnection) = (clientManager.clone(), connection.clone());
+      let writer = thread::spawn(move || {
+        let mut ctx = writerManager.send_context();
+
+        let data: Vec<u8> = (0..TRANSFER_SIZE)
+          .map(|offset| pattern(connectionIndex, offset))
+          .collect();
+        let mut writtenLength = 0;
+        while writtenLength < data.len() {
+          writtenLength += writerConnection
+            .write(&data[writtenLength..], &mut ctx)
+            .unwrap();
+        }
+        manager::lock_connection(&writerConnection)
+          .close(&mut ctx)
+          .unwrap();
+      });
+
+      let readerManager = clientManager.clone();
+      let reader = thread::spawn(move || {
+        let mut ctx = readerManager.send_context();
+
+        let mut buffer = [0u8; 4096];
+        let mut receivedLength = 0;
+        loop {
+          let bytesRead = connection.read(&mut buffer, &mut ctx).unwrap();
+          if bytesRead == 0 {
+            return receivedLength;
+          }
+
+          for (index, byte) in buffer[..bytesRead].iter().enumerate() {
+            assert_eq!(*byte, pattern(connectionIndex, receivedLength + index));
+          }
+          receivedLength += bytesRead;
+        }
+      });
+
+      (writer, reader)
+    })
+    .collect();
+
+  for (writer, reader) in clients {
+    writer.join().unwrap();
+    assert_eq!(reader.join().unwrap(), TRANSFER_SIZE);
+  }
+
+  acceptor.join().unwrap();
+  let echoedLength: usize = workers
+    .into_iter()
+    .map(|worker| worker.join().unwrap())
+    .sum();
+  assert_eq!(echoedLength, CONNECTIONS * TRANSFER_SIZE);
+}
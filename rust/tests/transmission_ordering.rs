@@ -0,0 +1,131 @@
+#![allow(non_snake_case)]
+
+/*
+  Several threads writing to the same connection all at once, while the test thread pumps the
+  packets and ticks the clock, with every fifth data segment getting lost on the way (and thus
+  retransmitted by the timer). Every segment gets written to the NIC while holding the
+  connection's lock, so the sequence numbers on the wire have to keep going up, segment after
+  segment. The only ones going back are the retransmissions, of exactly the bytes sent before.
+*/
+
+mod common;
+
+use {
+  common::{read, write, Direction, Network, Verdict},
+  std::{thread, time::Duration},
+  tcp_server::manager,
+};
+
+const PORT: u16 = 8080;
+
+const WRITERS: usize = 4;
+
+// Written by each writer, a byte at a time being its index.
+const WRITTEN_LENGTH: usize = 8 * 1024;
+
+const WRITE_SIZE: usize = 100;
+
+const LOST_SEGMENT_PERIOD: usize = 5;
+
+#[test]
+fn sequence_numbers_only_go_back_for_retransmissions() {
+  let mut network = Network::default();
+  let serverManager = network.server_manager();
+  serverManager.listen(PORT);
+
+  let clientManager = network.client_manager();
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  let server = serverManager.try_accept(PORT).unwrap();
+
+  let mut dataSegments = 0;
+  network.set_filter(move |packet| {
+    if packet.direction == Direction::ToServer && !packet.payload().is_empty() {
+      dataSegments += 1;
+      if dataSegments % LOST_SEGMENT_PERIOD == 0 {
+        return Verdict::Drop;
+      }
+    }
+    Verdict::Deliver
+  });
+
+  let mut receivedData = Vec::new();
+  thread::scope(|scope| {
+    for writerIndex in 0..WRITERS {
+      let (clientManager, client) = (&clientManager, &client);
+      scope.spawn(move || {
+        let data = [writerIndex as u8; WRITE_SIZE];
+        let mut writtenLength = 0;
+        while writtenLength < WRITTEN_LENGTH {
+          let writeSize = WRITE_SIZE.min(WRITTEN_LENGTH - writtenLength);
+          match write(clientManager, client, &data[..writeSize]).unwrap() {
+            0 => thread::yield_now(),
+            written => writtenLength += written,
+          }
+        }
+      });
+    }
+
+    let isTransferred = network.run_until(Duration::from_secs(600), |network| {
+      read(&network.server_manager(), &server, &mut receivedData).unwrap();
+      receivedData.len() == WRITERS * WRITTEN_LENGTH
+    });
+    assert!(isTransferred, "Received {} bytes", receivedData.len());
+  });
+
+  // Whichever way the writes got interleaved, no byte got lost or duplicated.
+  for writerIndex in 0..WRITERS {
+    let writtenLength = receivedData
+      .iter()
+      .filter(|byte| **byte == writerIndex as u8)
+      .count();
+    assert_eq!(writtenLength, WRITTEN_LENGTH, "Writer {}", writerIndex);
+  }
+
+  // The byte stream, as put on the wire by the first transmission of each segment.
+  let initialSequenceNumber = network
+    .log
+    .iter()
+    .find(|packet| packet.direction == Direction::ToServer && packet.is_syn())
+    .unwrap()
+    .sequence_number();
+  let mut sentData = Vec::new();
+  let mut retransmittedSegments = 0;
+  for packet in network.packets_since(0, Direction::ToServer) {
+    let payload = packet.payload();
+    if payload.is_empty() {
+      continue;
+    }
+
+    let offset = packet
+      .sequence_number()
+      .wrapping_sub(initialSequenceNumber.wrapping_add(1)) as usize;
+    assert!(
+      offset <= sentData.len(),
+      "Segment at {} leaves a gap after {}",
+      offset,
+      sentData.len()
+    );
+
+    if offset == sentData.len() {
+      sentData.extend_from_slice(payload);
+    }
+    else {
+      assert!(
+        offset + payload.len() <= sentData.len(),
+        "Segment at {} overlaps new data after {}",
+        offset,
+        sentData.len()
+      );
+      assert_eq!(payload, &sentData[offset..offset + payload.len()]);
+      retransmittedSegments += 1;
+    }
+  }
+  assert_eq!(sentData, receivedData);
+
+  assert!(retransmittedSegments > 0);
+  assert_eq!(
+    retransmittedSegments,
+    manager::lock_connection(&client).stats().retransmissions()
+  );
+}
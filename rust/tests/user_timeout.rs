@@ -0,0 +1,117 @@
+#![allow(non_snake_case)]
+
+/*
+  The TCP User Timeout (RFC 9293 section 3.8.5) : a connection whose data stays unacknowledged
+  for that long gets aborted, with a RST to the peer, even while it's still retransmitting.
+*/
+
+mod common;
+
+use {
+  common::{patterned_data, read, state, write, Direction, Network, Verdict},
+  std::time::Duration,
+  tcp_server::{
+    error::TcpError,
+    interface::InterfaceConfig,
+    manager::{self, TICK_INTERVAL},
+    tcp::{CloseReason, TCPConnectionState},
+    tuning::TcpTuning,
+  },
+};
+
+const PORT: u16 = 8080;
+
+const USER_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn network() -> Network {
+  let network = Network::new(
+    InterfaceConfig {
+      tuning: TcpTuning {
+        userTimeout: Some(USER_TIMEOUT),
+        ..TcpTuning::default()
+      },
+      ..InterfaceConfig::default()
+    },
+    InterfaceConfig::default(),
+  );
+  network.server_manager().listen(PORT);
+  network
+}
+
+#[test]
+fn data_sent_to_a_black_holed_peer_times_out_with_a_reset() {
+  let mut network = network();
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  assert_eq!(state(&client), TCPConnectionState::Established);
+
+  // From now on, nothing gets through either way.
+  network.set_filter(|_| Verdict::Drop);
+
+  let logLength = network.log.len();
+  let clientManager = network.client_manager();
+  assert_eq!(write(&clientManager, &client, b"hello").unwrap(), 5);
+  let sentAt = network.elapsed();
+
+  let isClosed = network.run_until(USER_TIMEOUT * 2, |_| {
+    state(&client) == TCPConnectionState::Closed
+  });
+  assert!(isClosed);
+  // The timer fires on the first tick past the user timeout.
+  assert_eq!(network.elapsed() - sentAt, USER_TIMEOUT);
+
+  {
+    let tcb = manager::lock_connection(&client);
+    assert_eq!(tcb.close_reason(), Some(CloseReason::UserTimeout));
+  }
+  assert!(matches!(
+    write(&clientManager, &client, b"again"),
+    Err(TcpError::TimedOut)
+  ));
+
+  // The data got retransmitted meanwhile, and the last thing sent was the RST.
+  let sent = network.packets_since(logLength, Direction::ToServer);
+  assert!(sent.len() > 2, "{:?}", sent);
+  assert!(sent[..sent.len() - 1]
+    .iter()
+    .all(|packet| packet.payload() == b"hello"));
+  let reset = sent.last().unwrap();
+  assert!(reset.is_rst());
+  assert_eq!(reset.at, sentAt + USER_TIMEOUT);
+  assert!(network.client_manager().connections().is_empty());
+}
+
+#[test]
+fn an_idle_connection_doesnt_time_out() {
+  let mut network = network();
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  network.set_filter(|_| Verdict::Drop);
+
+  // Nothing is waiting to be acknowledged.
+  network.run_for(USER_TIMEOUT * 3);
+  assert_eq!(state(&client), TCPConnectionState::Established);
+}
+
+#[test]
+fn a_transfer_outlasting_the_user_timeout_doesnt_time_out() {
+  let mut network = network();
+  let client = network.connect(PORT).unwrap();
+  network.pump();
+  let server = network.server_manager().try_accept(PORT).unwrap();
+
+  // The server reads a receive buffer's worth per tick, so that the transfer takes well over the user timeout, but every
+  // segment gets acknowledged in time.
+  let data = patterned_data(96 * 1024);
+  let (clientManager, serverManager) = (network.client_manager(), network.server_manager());
+  let (mut writtenLength, mut receivedData) = (0, Vec::new());
+  let isTransferred = network.run_until(USER_TIMEOUT * 4, |_| {
+    writtenLength += write(&clientManager, &client, &data[writtenLength..]).unwrap();
+    read(&serverManager, &server, &mut receivedData).unwrap();
+    receivedData.len() == data.len()
+  });
+  assert!(isTransferred);
+  assert!(network.elapsed() > USER_TIMEOUT + TICK_INTERVAL);
+  assert_eq!(receivedData, data);
+  assert_eq!(state(&client), TCPConnectionState::Established);
+}
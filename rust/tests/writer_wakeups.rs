@@ -0,0 +1,121 @@
+#![allow(non_snake_case)]
+
+/*
+  A writer pushing lots of data through a small send buffer, between 2 interfaces wired to each
+  other through a ChannelNic pair, on real threads. The writer should only get woken up once the
+  low watermark worth of room has freed up, rather than by every ACK and tick in between.
+*/
+
+use {
+  std::{net::Ipv4Addr, thread},
+  tcp_server::{
+    channel_nic::ChannelNic,
+    interface::{Interface, InterfaceConfig},
+    manager::{self, TICK_INTERVAL},
+    send_buffer::CHUNK_SIZE,
+    tcp::Location,
+    tuning::TcpTuning,
+  },
+};
+
+const PORT: u16 = 9;
+
+const TRANSFER_SIZE: usize = 10 * 1024 * 1024;
+
+const SEND_BUFFER_CAPACITY: usize = 16 * 1024;
+
+fn start(address: Ipv4Addr, device: ChannelNic) -> Interface {
+  let config = InterfaceConfig {
+    address,
+    tuning: TcpTuning {
+      sendBufferCapacity: Some(SEND_BUFFER_CAPACITY),
+      ..TcpTuning::default()
+    },
+    ..InterfaceConfig::default()
+  };
+  let interface = Interface::with_device(config, device).unwrap();
+
+  let packetThreadInterface = interface.clone();
+  thread::spawn(move || packetThreadInterface.process_packets());
+
+  let connectionManager = interface.connection_manager().clone();
+  thread::spawn(move || loop {
+    thread::sleep(TICK_INTERVAL);
+    connectionManager.on_tick();
+  });
+
+  interface
+}
+
+fn pattern(offset: usize) -> u8 {
+  (offset % 251) as u8
+}
+
+#[test]
+fn a_writer_gets_woken_up_once_per_low_watermark() {
+  assert!(SEND_BUFFER_CAPACITY.is_multiple_of(CHUNK_SIZE));
+
+  let (clientDevice, serverDevice) = ChannelNic::pair();
+  let client = start(Ipv4Addr::new(10, 0, 0, 1), clientDevice);
+  let server = start(Ipv4Addr::new(10, 0, 0, 2), serverDevice);
+
+  let serverManager = server.connection_manager().clone();
+  serverManager.listen(PORT);
+  let reader = thread::spawn(move || {
+    let connection = serverManager.accept(PORT).unwrap();
+    let mut ctx = serverManager.send_context();
+
+    let mut buffer = [0u8; 4096];
+    let mut receivedLength = 0;
+    loop {
+      let bytesRead = connection.read(&mut buffer, &mut ctx).unwrap();
+      if bytesRead == 0 {
+        return receivedLength;
+      }
+
+      for (index, byte) in buffer[..bytesRead].iter().enumerate() {
+        assert_eq!(*byte, pattern(receivedLength + index));
+      }
+      receivedLength += bytesRead;
+    }
+  });
+
+  let clientManager = client.connection_manager();
+  let connection = clientManager
+    .connect(Location {
+      address: Ipv4Addr::new(10, 0, 0, 2),
+      port: PORT,
+    })
+    .unwrap();
+  let mut ctx = clientManager.send_context();
+
+  let data: Vec<u8> = (0..TRANSFER_SIZE).map(pattern).collect();
+  let mut writtenLength = 0;
+  let mut writes = 0;
+  while writtenLength < data.len() {
+    writtenLength += connection.write(&data[writtenLength..], &mut ctx).unwrap();
+    writes += 1;
+  }
+  manager::lock_connection(&connection)
+    .close(&mut ctx)
+    .unwrap();
+
+  assert_eq!(reader.join().unwrap(), TRANSFER_SIZE);
+
+  // Every write( ) but the first one waited for the chunk in flight to be freed, which happens once
+  // per 16KB : hundreds of times, rather than once per ACK.
+  let writerWakeups = manager::lock_connection(&connection)
+    .stats()
+    .writer_wakeups();
+  assert!(
+    (100..1000).contains(&writerWakeups),
+    "{} writer wakeups",
+    writerWakeups
+  );
+  assert!(
+    writes as u64 <= writerWakeups + 1,
+    "{} writes for {} wakeups",
+    writes,
+    writerWakeups
+  );
+}